@@ -0,0 +1,45 @@
+/* C interface to run catfs inside another process. Link with
+ * libcatfs.a (or libcatfs.so) from cargo build --release.
+ *
+ * Functions return 0 on success or an errno.
+ */
+#ifndef CATFS_H
+#define CATFS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+struct catfs_mount;
+
+struct catfs_stats {
+	uint64_t inodes;
+	uint64_t open_dirs;
+	uint64_t open_files;
+	/* how long the open dir/file that has gone the longest without
+	 * IO has been idle, a large value may be a leaked handle */
+	uint64_t dir_idle_secs;
+	uint64_t file_idle_secs;
+};
+
+/* Exposes from at mountpoint, caching files to to. free is how much
+ * space to keep free in to's filesystem (ex: "10G" or "5%"), or NULL
+ * to never evict. On success *out is the new mount.
+ */
+int catfs_mount(const char *from, const char *to, const char *mountpoint,
+		const char *free, struct catfs_mount **out);
+
+/* Unmounts and frees m, waiting for pending requests to finish. If
+ * the mount is busy this returns EBUSY and m is left mounted.
+ */
+int catfs_unmount(struct catfs_mount *m);
+
+int catfs_stats(const struct catfs_mount *m, struct catfs_stats *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
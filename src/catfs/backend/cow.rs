@@ -0,0 +1,737 @@
+extern crate libc;
+extern crate time;
+extern crate xattr;
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::time::Timespec;
+use self::xattr::FileExt;
+
+use catfs::error;
+use catfs::file::CachePerms;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use catfs::whiteout::Whiteouts;
+use super::{ListedDir, LocalBackend, SourceBackend, SourceDir};
+
+// everything that's changed lives under here in the cache dir, the
+// evicter leaves it alone
+pub const COW_DIR: &'static str = ".catfs-cow";
+
+// src ino of a copied up file, so it's still the same inode
+const COW_INO_XATTR: &'static str = "user.catfs.cow_ino";
+// files that are only in the cache get the cache ino, moved out of
+// the way of src inos
+const UPPER_INO_BIT: u64 = 1 << 63;
+
+fn enoent() -> io::Error {
+    return io::Error::from_raw_os_error(libc::ENOENT);
+}
+
+fn is_dir(st: &rlibc::stat64) -> bool {
+    return (st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+}
+
+fn is_file(st: &rlibc::stat64) -> bool {
+    return (st.st_mode & libc::S_IFMT) == libc::S_IFREG;
+}
+
+fn is_symlink(st: &rlibc::stat64) -> bool {
+    return (st.st_mode & libc::S_IFMT) == libc::S_IFLNK;
+}
+
+// a source that's never written to. Changed files are copied up into
+// the cache dir first and deletes are recorded as whiteouts, what's
+// in the cache hides what's in src
+pub struct CowBackend {
+    src: Arc<dyn SourceBackend>,
+    upper: LocalBackend,
+    upper_dir: PathBuf,
+    // copies are written here and renamed into place once they are
+    // complete
+    work_dir: PathBuf,
+    seq: AtomicUsize,
+    whiteouts: Whiteouts,
+}
+
+impl CowBackend {
+    // perms only applies to the directories that hold the copies,
+    // the copies keep the modes they have in src
+    pub fn new(
+        src: Arc<dyn SourceBackend>,
+        cache: &dyn AsRef<Path>,
+        perms: CachePerms,
+    ) -> io::Result<CowBackend> {
+        let dir = cache.as_ref().join(COW_DIR);
+        let upper_dir = dir.join("upper");
+        let work_dir = dir.join("work");
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true).mode(perms.dir_mode() as u32);
+        builder.create(&upper_dir)?;
+        builder.create(&work_dir)?;
+
+        return Ok(CowBackend {
+            src: src,
+            upper: LocalBackend::new(&upper_dir)?,
+            upper_dir: upper_dir,
+            work_dir: work_dir,
+            seq: AtomicUsize::new(0),
+            whiteouts: Whiteouts::new(cache, perms)?,
+        });
+    }
+
+    fn in_upper(&self, path: &dyn AsRef<Path>) -> io::Result<bool> {
+        match self.upper.stat(path) {
+            Ok(_) => return Ok(true),
+            Err(e) => {
+                if error::is_enoent(&e) {
+                    return Ok(false);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    // src as it's seen through the whiteouts
+    fn src_stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        if self.whiteouts.contains(path) {
+            return Err(enoent());
+        }
+        return self.src.stat(path);
+    }
+
+    fn in_src(&self, path: &dyn AsRef<Path>) -> io::Result<bool> {
+        match self.src_stat(path) {
+            Ok(_) => return Ok(true),
+            Err(e) => {
+                if error::is_enoent(&e) {
+                    return Ok(false);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    fn is_empty_dir(&self, path: &dyn AsRef<Path>) -> io::Result<bool> {
+        let mut dh = self.opendir(path)?;
+        while let Some(en) = dh.readdir()? {
+            let name = en.name();
+            if name != OsStr::new(".") && name != OsStr::new("..") {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    // creates the directories leading up to and including path in
+    // the cache, with the mode and times they have in src
+    fn copy_up_dirs(&self, path: &Path) -> io::Result<()> {
+        let mut p = PathBuf::new();
+
+        for c in path.components() {
+            p.push(c);
+            if self.in_upper(&p)? {
+                continue;
+            }
+
+            let st = self.src_stat(&p)?;
+            if !is_dir(&st) {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+            let mode = (st.st_mode & 0o7777) as libc::mode_t;
+            self.upper.mkdir(&p, mode)?;
+            self.upper.chmod(&p, mode, 0)?;
+            let atime = Timespec::new(st.st_atime as i64, st.st_atime_nsec as i32);
+            let mtime = Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32);
+            self.upper.utimes(&p, &atime, &mtime, 0)?;
+        }
+
+        return Ok(());
+    }
+
+    // makes path in the cache what it is in src, so it can be
+    // changed there
+    fn copy_up(&self, path: &dyn AsRef<Path>, with_data: bool) -> io::Result<()> {
+        let path = path.as_ref();
+        let st = self.src_stat(&path)?;
+        if is_dir(&st) {
+            return self.copy_up_dirs(path);
+        }
+        if is_symlink(&st) {
+            // symlinks can't have xattrs, so this one gets a new ino
+            if let Some(parent) = path.parent() {
+                self.copy_up_dirs(parent)?;
+            }
+            debug!("<-- copy up {:?} symlink", path);
+            return self.upper.symlink(&self.src.readlink(&path)?, &path);
+        }
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        if let Some(parent) = path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        debug!("<-- copy up {:?} {}", path, with_data);
+
+        let tmp_path = self.work_dir.join(format!(
+            "{}-{}",
+            unsafe { libc::getpid() },
+            self.seq.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut to = File::open(
+            &tmp_path,
+            rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC,
+            (st.st_mode & 0o7777) as u32,
+        )?;
+
+        let res = (|| {
+            if with_data {
+                let mut from = self.src.open(&path, rlibc::O_RDONLY, 0)?;
+                let res = copy_data(&from, &to);
+                from.close()?;
+                res?;
+            }
+
+            // keep what xattrs the cache dir can hold, such as the
+            // ones that version the file
+            for name in self.src.list_xattr(&path).unwrap_or_default() {
+                if let Ok(Some(v)) = self.src.get_xattr(&path, &name) {
+                    let _ = to.set_xattr(&name, &v);
+                }
+            }
+            to.set_xattr(OsStr::new(COW_INO_XATTR), st.st_ino.to_string().as_bytes())?;
+
+            let atime = Timespec::new(st.st_atime as i64, st.st_atime_nsec as i32);
+            let mtime = Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32);
+            rlibc::futimens(to.as_raw_fd(), &atime, &mtime)?;
+            return Ok(());
+        })();
+
+        to.close()?;
+        match res {
+            Ok(_) => return fs::rename(&tmp_path, self.upper_dir.join(path)),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+    }
+
+    // path is in the cache now. If src still has a directory there
+    // that was deleted, hide what's in it so it stays deleted
+    fn remove_whiteout(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        if !self.whiteouts.remove(path)? {
+            return Ok(());
+        }
+        if let Ok(mut dh) = self.src.opendir(path) {
+            while let Some(en) = dh.readdir()? {
+                let name = en.name();
+                if name != OsStr::new(".") && name != OsStr::new("..") {
+                    self.whiteouts.add(&path.as_ref().join(&name))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn copy_up_if_needed(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        if !self.in_upper(path)? {
+            self.copy_up(path, true)?;
+        }
+        return Ok(());
+    }
+}
+
+fn copy_data(from: &File, to: &File) -> io::Result<()> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut offset = 0;
+    loop {
+        let nread = from.read_at(&mut buf, offset)?;
+        if nread == 0 {
+            return Ok(());
+        }
+        let mut nwritten = 0;
+        while nwritten < nread {
+            nwritten += to.write_at(&buf[nwritten..nread], offset + nwritten as i64)?;
+        }
+        offset += nread as i64;
+    }
+}
+
+impl SourceBackend for CowBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        if !self.in_upper(path)? {
+            let is_write = (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY ||
+                (flags & (rlibc::O_CREAT | rlibc::O_TRUNC)) != 0;
+
+            match self.src_stat(path) {
+                Ok(_) => {
+                    if !is_write {
+                        return self.src.open(path, flags, mode);
+                    }
+                    if (flags & (rlibc::O_CREAT | rlibc::O_EXCL)) ==
+                        (rlibc::O_CREAT | rlibc::O_EXCL)
+                    {
+                        return Err(io::Error::from_raw_os_error(libc::EEXIST));
+                    }
+                    // no need to copy what's going to be truncated
+                    self.copy_up(path, (flags & rlibc::O_TRUNC) == 0)?;
+                }
+                Err(e) => {
+                    if !error::is_enoent(&e) || (flags & rlibc::O_CREAT) == 0 {
+                        return Err(e);
+                    }
+                    if let Some(parent) = path.as_ref().parent() {
+                        self.copy_up_dirs(parent)?;
+                    }
+                    let f = self.upper.open(path, flags, mode)?;
+                    self.remove_whiteout(path)?;
+                    return Ok(f);
+                }
+            }
+        }
+
+        return self.upper.open(path, flags, mode);
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        let mut st = match self.upper.stat(path) {
+            Ok(st) => st,
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e);
+                }
+                return self.src_stat(path);
+            }
+        };
+
+        if is_dir(&st) {
+            // the same directory as in src, which can't change
+            // underneath us
+            if let Ok(src_st) = self.src_stat(path) {
+                if is_dir(&src_st) {
+                    st.st_ino = src_st.st_ino;
+                    return Ok(st);
+                }
+            }
+        } else if is_file(&st) {
+            // only files are copied up and keep their ino
+            if let Some(v) = self.upper.get_xattr(path, OsStr::new(COW_INO_XATTR))? {
+                if let Ok(ino) = String::from_utf8_lossy(&v).parse::<u64>() {
+                    st.st_ino = ino as _;
+                    return Ok(st);
+                }
+            }
+        }
+
+        st.st_ino = (st.st_ino as u64 | UPPER_INO_BIT) as _;
+        return Ok(st);
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return self.upper.statvfs();
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        let path = path.as_ref();
+        let mut dir = ListedDir::new(path);
+        let mut names = HashSet::new();
+        let mut found = false;
+
+        match self.upper.opendir(&path) {
+            Ok(mut dh) => {
+                found = true;
+                while let Some(en) = dh.readdir()? {
+                    let name = en.name();
+                    if name == OsStr::new(".") || name == OsStr::new("..") {
+                        continue;
+                    }
+                    let st = self.stat(&path.join(&name))?;
+                    dir.entries.push((name.clone(), en.kind(), st.st_ino as u64));
+                    names.insert(name);
+                }
+            }
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        if !self.whiteouts.contains(&path) {
+            match self.src.opendir(&path) {
+                Ok(mut dh) => {
+                    found = true;
+                    while let Some(en) = dh.readdir()? {
+                        let name = en.name();
+                        if name == OsStr::new(".") || name == OsStr::new("..") ||
+                            names.contains(&name) ||
+                            self.whiteouts.contains(&path.join(&name))
+                        {
+                            continue;
+                        }
+                        dir.entries.push((name, en.kind(), en.ino()));
+                    }
+                }
+                Err(e) => {
+                    // the cache may have a directory where src has a
+                    // file that was deleted
+                    let errno = e.raw_os_error().unwrap_or(0);
+                    if !found || (errno != libc::ENOENT && errno != libc::ENOTDIR) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        if !found {
+            return Err(enoent());
+        }
+        return Ok(Box::new(dir));
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        let path = path.as_ref();
+        if self.in_upper(&path)? || self.in_src(&path)? {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if let Some(parent) = path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        self.upper.mkdir(&path, mode)?;
+        return self.remove_whiteout(&path);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        let path = path.as_ref();
+        if self.in_upper(&path)? || self.in_src(&path)? {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if let Some(parent) = path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        self.upper.mknod(&path, mode, rdev)?;
+        return self.remove_whiteout(&path);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if self.in_upper(&path)? || self.in_src(&path)? {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if let Some(parent) = path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        self.upper.symlink(target, &path)?;
+        return self.remove_whiteout(&path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        if self.in_upper(path)? {
+            return self.upper.readlink(path);
+        }
+        self.src_stat(path)?;
+        return self.src.readlink(path);
+    }
+
+    // the file is copied up so both names are the same file in the
+    // cache, and they keep the ino it had in src
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let new_path = new_path.as_ref();
+        if self.in_upper(&new_path)? || self.in_src(&new_path)? {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if !self.in_upper(&path)? {
+            self.copy_up(&path, true)?;
+        }
+        if let Some(parent) = new_path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        self.upper.link(&path, &new_path)?;
+        return self.remove_whiteout(&new_path);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        if self.in_upper(path)? {
+            self.upper.unlink(path)?;
+        } else if is_dir(&self.src_stat(path)?) {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+
+        if self.in_src(path)? {
+            self.whiteouts.add(path)?;
+        }
+        return Ok(());
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        if !self.is_empty_dir(path)? {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        // what's in the cache is never hidden, so it's empty too
+        if self.in_upper(path)? {
+            self.upper.rmdir(path)?;
+        }
+        if self.in_src(path)? {
+            self.whiteouts.add(path)?;
+        }
+        return Ok(());
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        let path = path.as_ref();
+        let new_path = new_path.as_ref();
+        let st = self.stat(&path)?;
+        let in_src = self.in_src(&path)?;
+
+        match self.stat(&new_path) {
+            Ok(new_st) => {
+                if is_dir(&new_st) {
+                    if !is_dir(&st) {
+                        return error::propagate(io::Error::from_raw_os_error(libc::EISDIR));
+                    }
+                    if !self.is_empty_dir(&new_path)? {
+                        return error::propagate(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+                    }
+                } else if is_dir(&st) {
+                    return error::propagate(io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+            }
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        if is_dir(&st) {
+            if in_src {
+                // moving a src directory means copying everything in
+                // it, mv knows to do that
+                return error::propagate(io::Error::from_raw_os_error(libc::EXDEV));
+            }
+        } else {
+            self.copy_up_if_needed(&path)?;
+        }
+
+        if let Some(parent) = new_path.parent() {
+            self.copy_up_dirs(parent)?;
+        }
+        self.upper.rename(&path, &new_path)?;
+        if in_src {
+            self.whiteouts.add(&path)?;
+        }
+        self.remove_whiteout(&new_path)?;
+        return Ok(());
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.copy_up_if_needed(path)?;
+        return self.upper.utimes(path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        self.copy_up_if_needed(path)?;
+        return self.upper.chmod(path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.copy_up_if_needed(path)?;
+        return self.upper.chown(path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        if self.in_upper(path)? {
+            if name == OsStr::new(COW_INO_XATTR) {
+                return Ok(None);
+            }
+            return self.upper.get_xattr(path, name);
+        }
+        self.src_stat(path)?;
+        return self.src.get_xattr(path, name);
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        self.copy_up_if_needed(path)?;
+        return self.upper.set_xattr(path, name, value);
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        if self.in_upper(path)? {
+            let mut names = self.upper.list_xattr(path)?;
+            names.retain(|n| n != OsStr::new(COW_INO_XATTR));
+            return Ok(names);
+        }
+        self.src_stat(path)?;
+        return self.src.list_xattr(path);
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        self.copy_up_if_needed(path)?;
+        return self.upper.remove_xattr(path, name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use catfs;
+    use super::*;
+
+    fn list(src: &dyn SourceBackend, path: &dyn AsRef<Path>) -> Vec<String> {
+        let mut dir = src.opendir(path).unwrap();
+        let mut names = Vec::new();
+        while let Some(en) = dir.readdir().unwrap() {
+            names.push(en.name().into_string().unwrap());
+        }
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let cache = prefix.join("cache");
+        let src = Arc::new(LocalBackend::new(&resources).unwrap());
+        let cow = CowBackend::new(src, &cache, Default::default()).unwrap();
+
+        let ino = cow.stat(&"dir1/file1").unwrap().st_ino;
+        let mut f = cow.open(&"dir1/file1", rlibc::O_WRONLY | rlibc::O_TRUNC, 0).unwrap();
+        f.write_at(b"cow\n", 0).unwrap();
+        f.close().unwrap();
+        assert_eq!(cow.stat(&"dir1/file1").unwrap().st_ino, ino);
+        assert_eq!(
+            fs::read(cache.join(COW_DIR).join("upper/dir1/file1")).unwrap(),
+            b"cow\n"
+        );
+        assert_ne!(fs::read(resources.join("dir1/file1")).unwrap(), b"cow\n");
+
+        let mut f = cow.open(&"file4", rlibc::O_WRONLY | rlibc::O_CREAT, 0o644).unwrap();
+        f.close().unwrap();
+        assert!(cow.stat(&"file4").unwrap().st_ino as u64 & UPPER_INO_BIT != 0);
+
+        cow.unlink(&"file1").unwrap();
+        cow.rename(&"dir1/file2", &"file5").unwrap();
+        assert!(resources.join("file1").exists());
+        assert!(resources.join("dir1/file2").exists());
+        assert!(!resources.join("file4").exists());
+        assert!(cow.stat(&"file1").is_err());
+        assert_eq!(
+            fs::read(cache.join(COW_DIR).join("upper/file5")).unwrap(),
+            fs::read(resources.join("dir1/file2")).unwrap()
+        );
+        assert_eq!(
+            list(&cow, &""),
+            vec![".", "..", "dir1", "file2", "file3", "file4", "file5"]
+        );
+        assert_eq!(list(&cow, &"dir1"), vec![".", "..", "file1"]);
+
+        assert_eq!(
+            cow.rmdir(&"dir1").unwrap_err().raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+        cow.unlink(&"dir1/file1").unwrap();
+        cow.rmdir(&"dir1").unwrap();
+        assert!(resources.join("dir1/file1").exists());
+        cow.mkdir(&"dir1", 0o755).unwrap();
+        assert_eq!(list(&cow, &"dir1"), vec![".", ".."]);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn symlinks() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let cache = prefix.join("cache");
+        symlink("file1", resources.join("link1")).unwrap();
+        let src = Arc::new(LocalBackend::new(&resources).unwrap());
+        let cow = CowBackend::new(src, &cache, Default::default()).unwrap();
+
+        assert!(is_symlink(&cow.stat(&"link1").unwrap()));
+        assert_eq!(cow.readlink(&"link1").unwrap(), Path::new("file1"));
+        cow.rename(&"link1", &"dir1/link1").unwrap();
+        assert_eq!(cow.readlink(&"dir1/link1").unwrap(), Path::new("file1"));
+        assert!(is_symlink(&cow.stat(&"dir1/link1").unwrap()));
+        assert!(cow.stat(&"link1").is_err());
+        assert!(fs::symlink_metadata(resources.join("link1")).is_ok());
+
+        cow.symlink(&"nowhere", &"link2").unwrap();
+        assert_eq!(cow.readlink(&"link2").unwrap(), Path::new("nowhere"));
+        assert!(fs::symlink_metadata(resources.join("link2")).is_err());
+        assert_eq!(
+            cow.symlink(&"file2", &"file1").unwrap_err().raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn fifos() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let cache = prefix.join("cache");
+        let src = Arc::new(LocalBackend::new(&resources).unwrap());
+        let cow = CowBackend::new(src, &cache, Default::default()).unwrap();
+
+        cow.mknod(&"dir1/fifo1", libc::S_IFIFO | 0o644, 0).unwrap();
+        let st = cow.stat(&"dir1/fifo1").unwrap();
+        assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFIFO);
+        // nothing is waiting to write to it
+        assert!(cow.list_xattr(&"dir1/fifo1").is_ok());
+        assert!(fs::symlink_metadata(resources.join("dir1/fifo1")).is_err());
+        assert_eq!(
+            cow.mknod(&"file1", libc::S_IFIFO | 0o644, 0).unwrap_err().raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn links() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let cache = prefix.join("cache");
+        let src = Arc::new(LocalBackend::new(&resources).unwrap());
+        let cow = CowBackend::new(src, &cache, Default::default()).unwrap();
+
+        let st = cow.stat(&"file1").unwrap();
+        cow.link(&"file1", &"dir1/link1").unwrap();
+        let new_st = cow.stat(&"dir1/link1").unwrap();
+        assert_eq!(new_st.st_ino, st.st_ino);
+        assert_eq!(new_st.st_nlink, 2);
+        assert_eq!(cow.stat(&"file1").unwrap().st_ino, st.st_ino);
+        assert!(fs::symlink_metadata(resources.join("dir1/link1")).is_err());
+        assert_eq!(
+            cow.link(&"file1", &"file2").unwrap_err().raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, Instant};
+
+use self::time::Timespec;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use super::{is_root, SourceBackend, SourceDir, SourceHints};
+
+// don't run the command again for a path it just failed on, lookups
+// of names that don't exist anywhere are common
+const FETCH_BACKOFF_SECS: u64 = 30;
+
+// runs a command to put a file into the source when it's not there,
+// and then tries again. The command gets the path relative to the
+// source as $1 and $CATFS_PATH, and the source as $CATFS_FROM
+pub struct FetchBackend {
+    src: Arc<dyn SourceBackend>,
+    cmd: OsString,
+    from: OsString,
+    failed: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl FetchBackend {
+    pub fn new(src: Arc<dyn SourceBackend>, cmd: &OsStr, from: &OsStr) -> FetchBackend {
+        return FetchBackend {
+            src: src,
+            cmd: cmd.to_os_string(),
+            from: from.to_os_string(),
+            failed: Mutex::new(HashMap::new()),
+        };
+    }
+
+    // whether it's worth trying again
+    fn fetch(&self, path: &Path) -> bool {
+        if is_root(&path) {
+            return false;
+        }
+
+        {
+            let mut failed = self.failed.lock().unwrap();
+            let now = Instant::now();
+            failed.retain(|_, until| *until > now);
+            if failed.contains_key(path) {
+                return false;
+            }
+        }
+
+        debug!("<-- fetch {:?}", path);
+        let res = Command::new("sh")
+            .arg("-c")
+            .arg(&self.cmd)
+            .arg("catfs-fetch")
+            .arg(path)
+            .env("CATFS_PATH", path)
+            .env("CATFS_FROM", &self.from)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output();
+
+        match res {
+            Ok(ref out) if out.status.success() => return true,
+            Ok(out) => {
+                debug!(
+                    "fetch {:?} {}: {}",
+                    path,
+                    out.status,
+                    String::from_utf8_lossy(&out.stderr).trim()
+                );
+            }
+            Err(e) => error!("!fetch {:?} = {}", path, e),
+        }
+
+        self.failed.lock().unwrap().insert(
+            path.to_path_buf(),
+            Instant::now() + StdDuration::from_secs(FETCH_BACKOFF_SECS),
+        );
+        return false;
+    }
+
+    fn with_fetch<T, F>(&self, path: &dyn AsRef<Path>, f: F) -> io::Result<T>
+    where
+        F: Fn() -> io::Result<T>,
+    {
+        match f() {
+            Err(e) => {
+                if error::is_enoent(&e) && self.fetch(path.as_ref()) {
+                    return f();
+                }
+                return Err(e);
+            }
+            res => return res,
+        }
+    }
+}
+
+impl SourceBackend for FetchBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        if (flags & rlibc::O_CREAT) != 0 {
+            return self.src.open(path, flags, mode);
+        }
+        return self.with_fetch(path, || self.src.open(path, flags, mode));
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        return self.with_fetch(path, || self.src.stat(path));
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return self.src.statvfs();
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        return self.with_fetch(path, || self.src.opendir(path));
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        return self.src.mkdir(path, mode);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        return self.src.mknod(path, mode, rdev);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.unlink(path);
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.rmdir(path);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.symlink(target, path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return self.src.readlink(path);
+    }
+
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.link(path, new_path);
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return self.src.rename(path, new_path);
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.utimes(path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        return self.src.chmod(path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.chown(path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.src.get_xattr(path, name);
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.src.set_xattr(path, name, value);
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return self.src.list_xattr(path);
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        return self.src.remove_xattr(path, name);
+    }
+
+    fn open_for_locks(&self, path: &dyn AsRef<Path>) -> io::Result<File> {
+        return self.src.open_for_locks(path);
+    }
+
+    fn access(&self, path: &dyn AsRef<Path>, mask: libc::c_int) -> io::Result<()> {
+        return self.src.access(path, mask);
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.src.hints();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use super::super::LocalBackend;
+    use super::*;
+
+    #[test]
+    fn fetch_missing() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let src = Arc::new(LocalBackend::new(&resources).unwrap());
+        let cmd = format!(
+            "echo \"$1\" >> {:?}; [ \"$CATFS_PATH\" = fetched ] && echo hi > \"$CATFS_FROM/$1\"",
+            prefix.join("log")
+        );
+        let fetch = FetchBackend::new(src, OsStr::new(&cmd), resources.as_os_str());
+
+        assert!(fetch.stat(&"file1").is_ok());
+        assert_eq!(fetch.stat(&"fetched").unwrap().st_size, 3);
+        let mut f = fetch.open(&"fetched", rlibc::O_RDONLY, 0).unwrap();
+        f.close().unwrap();
+
+        // failures are remembered for a while
+        for _ in 0..2 {
+            match fetch.stat(&"missing") {
+                Ok(_) => panic!("missing was fetched"),
+                Err(e) => assert!(error::is_enoent(&e)),
+            }
+        }
+        assert_eq!(fs::read_to_string(prefix.join("log")).unwrap(), "fetched\nmissing\n");
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,625 @@
+extern crate libc;
+extern crate time;
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
+
+use self::time::Timespec;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use super::{erofs, percent_decode, percent_encode, readonly_statvfs, remote_stat, ListedDir,
+            SourceBackend, SourceDir, SourceHints, Spool};
+
+const PROPFIND_BODY: &'static str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+<propfind xmlns=\"DAV:\"><prop>\
+<resourcetype/><getcontentlength/><getlastmodified/>\
+</prop></propfind>";
+
+// a read-only source served over plain http. Listing directories and
+// stat'ing them needs WebDAV (PROPFIND), files alone can be served by
+// any http server
+pub struct HttpBackend {
+    // what goes into the Host header, host[:port]
+    host: String,
+    // what we connect to, always host:port
+    addr: String,
+    // path of the root of the source on the server, without trailing /
+    prefix: String,
+    spool: Spool,
+    hints: SourceHints,
+}
+
+struct Response {
+    status: u32,
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        for &(ref k, ref v) in &self.headers {
+            if k.eq_ignore_ascii_case(name) {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    fn is_redirect(&self) -> bool {
+        return match self.status {
+            301 | 302 | 303 | 307 | 308 => true,
+            _ => false,
+        };
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let errno = match self.status {
+            200..=299 => return Ok(()),
+            401 | 403 => libc::EACCES,
+            404 | 410 => libc::ENOENT,
+            405 | 501 => libc::ENOTSUP,
+            _ => libc::EIO,
+        };
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+}
+
+struct Stat {
+    is_dir: bool,
+    size: u64,
+    mtime: i64,
+}
+
+// what the server sent doesn't make sense, which is just an EIO to
+// whoever is reading
+fn bad_response(why: &str) -> io::Error {
+    error!("{}", why);
+    return io::Error::from_raw_os_error(libc::EIO);
+}
+
+pub fn is_url(s: &OsStr) -> bool {
+    return s.as_bytes().starts_with(b"http://");
+}
+
+fn xml_unescape(s: &str) -> String {
+    return s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+}
+
+// returns the contents of every <name> element regardless of its
+// namespace prefix, good enough for a DAV multistatus response
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut res = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        let qname = tag.split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap();
+        let local = qname.rsplit(':').next().unwrap();
+        if local != name {
+            continue;
+        }
+
+        if tag.ends_with('/') {
+            res.push("");
+        } else {
+            let close = format!("</{}>", qname);
+            match rest.find(&close) {
+                Some(pos) => {
+                    res.push(&rest[..pos]);
+                    rest = &rest[pos + close.len()..];
+                }
+                None => break,
+            }
+        }
+    }
+
+    return res;
+}
+
+fn parse_http_date(s: &str) -> Option<i64> {
+    return time::strptime(s.trim(), "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|tm| tm.to_timespec().sec);
+}
+
+struct FileWriter<'a> {
+    file: &'a File,
+    offset: i64,
+}
+
+impl<'a> Write for FileWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, self.offset)?;
+        self.offset += n as i64;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl HttpBackend {
+    pub fn new(url: &OsStr, tmp_dir: &dyn AsRef<Path>) -> io::Result<HttpBackend> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected http://host/path");
+        if !is_url(url) {
+            return Err(invalid());
+        }
+        let url = url.to_str().ok_or_else(&invalid)?;
+        let rest = &url["http://".len()..];
+        let (host, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let addr = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        return Ok(HttpBackend {
+            host: host.to_owned(),
+            addr: addr,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            spool: Spool::new(tmp_dir, "http"),
+            hints: Default::default(),
+        });
+    }
+
+    pub fn set_hints(&mut self, hints: SourceHints) {
+        self.hints = hints;
+    }
+
+    fn url_path(&self, path: &dyn AsRef<Path>, is_dir: bool) -> String {
+        let mut s = self.prefix.clone();
+        s.push('/');
+        s.push_str(&percent_encode(path.as_ref()));
+        if is_dir && !s.ends_with('/') {
+            s.push('/');
+        }
+        return s;
+    }
+
+    // turns a href from the server back into a path relative to the
+    // root of the source
+    fn rel_path(&self, href: &str) -> Option<PathBuf> {
+        let mut href = href.trim();
+        if href.starts_with("http://") {
+            href = &href["http://".len()..];
+            href = &href[href.find('/').unwrap_or(href.len())..];
+        }
+        let path = OsString::from_vec(percent_decode(href));
+        let path = Path::new(&path);
+        let prefix = OsString::from_vec(percent_decode(&self.prefix));
+        let rel = if self.prefix.is_empty() {
+            path.strip_prefix("/").ok()?
+        } else {
+            path.strip_prefix(&prefix).ok()?
+        };
+        return Some(rel.to_path_buf());
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        url_path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        out: &mut dyn Write,
+    ) -> io::Result<Response> {
+        let mut url_path = url_path.to_owned();
+        // follow redirects, ex: directories without a trailing /
+        for _ in 0..3 {
+            let res = self.request_once(method, &url_path, headers, body, out)?;
+            if !res.is_redirect() {
+                return Ok(res);
+            }
+
+            match res.header("Location") {
+                Some(location) => {
+                    let mut location = location.trim();
+                    let origin = format!("http://{}", self.host);
+                    if location.starts_with(&origin) {
+                        location = &location[origin.len()..];
+                    }
+                    if !location.starts_with('/') {
+                        // somewhere we can't follow
+                        return Ok(res);
+                    }
+                    url_path = location.to_owned();
+                }
+                None => return Ok(res),
+            }
+        }
+
+        return Err(io::Error::from_raw_os_error(libc::ELOOP));
+    }
+
+    fn request_once(
+        &self,
+        method: &str,
+        url_path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        out: &mut dyn Write,
+    ) -> io::Result<Response> {
+        let stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(StdDuration::from_secs(60)))?;
+
+        let mut req = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: catfs\r\n\
+             Connection: close\r\nContent-Length: {}\r\n",
+            method,
+            url_path,
+            self.host,
+            body.len()
+        );
+        for &(k, v) in headers {
+            req.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        req.push_str("\r\n");
+        (&stream).write_all(req.as_bytes())?;
+        (&stream).write_all(body)?;
+        debug!("<-- http {} {}", method, url_path);
+
+        let mut r = BufReader::new(stream);
+        let mut line = String::new();
+        r.read_line(&mut line)?;
+        let status = match line.split_whitespace().nth(1).map(|s| s.parse::<u32>()) {
+            Some(Ok(status)) => status,
+            _ => {
+                return Err(bad_response(&format!("bad http status line: {}", line.trim())))
+            }
+        };
+
+        let mut res = Response {
+            status: status,
+            headers: Vec::new(),
+        };
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some(i) = line.find(':') {
+                res.headers.push((
+                    line[..i].trim().to_owned(),
+                    line[i + 1..].trim().to_owned(),
+                ));
+            }
+        }
+
+        if method == "HEAD" || status == 204 || status == 304 || res.is_redirect() ||
+            !(200..300).contains(&status) && status != 207
+        {
+            return Ok(res);
+        }
+
+        let chunked = res.header("Transfer-Encoding")
+            .map(|v| v.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+        let length = res.header("Content-Length").and_then(|v| v.parse::<u64>().ok());
+
+        if chunked {
+            loop {
+                line.clear();
+                r.read_line(&mut line)?;
+                let size = line.split(';').next().unwrap().trim();
+                let size = u64::from_str_radix(size, 16).map_err(|_| bad_response("bad chunk size"))?;
+                if size == 0 {
+                    // skip trailers
+                    loop {
+                        line.clear();
+                        if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                if io::copy(&mut (&mut r).take(size), out)? != size {
+                    return Err(bad_response("short chunk"));
+                }
+                line.clear();
+                r.read_line(&mut line)?;
+            }
+        } else if let Some(length) = length {
+            if io::copy(&mut (&mut r).take(length), out)? != length {
+                return Err(bad_response("short body"));
+            }
+        } else {
+            io::copy(&mut r, out)?;
+        }
+
+        return Ok(res);
+    }
+
+    fn propfind(&self, path: &dyn AsRef<Path>, depth: u32) -> io::Result<Vec<(PathBuf, Stat)>> {
+        let mut body = Vec::new();
+        let depth = depth.to_string();
+        let res = self.request(
+            "PROPFIND",
+            &self.url_path(path, depth != "0"),
+            &[
+                ("Depth", &depth),
+                ("Content-Type", "application/xml"),
+            ],
+            PROPFIND_BODY.as_bytes(),
+            &mut body,
+        )?;
+        res.check()?;
+
+        let body = String::from_utf8_lossy(&body);
+        let mut entries = Vec::new();
+        for response in xml_elements(&body, "response") {
+            let href = match xml_elements(response, "href").first() {
+                Some(href) => xml_unescape(href),
+                None => continue,
+            };
+            let rel = match self.rel_path(&href) {
+                Some(rel) => rel,
+                None => continue,
+            };
+
+            let size = xml_elements(response, "getcontentlength")
+                .first()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            let mtime = xml_elements(response, "getlastmodified")
+                .first()
+                .and_then(|s| parse_http_date(s))
+                .unwrap_or(0);
+            let is_dir = xml_elements(response, "resourcetype")
+                .first()
+                .map(|s| !xml_elements(s, "collection").is_empty())
+                .unwrap_or(false);
+
+            entries.push((
+                rel,
+                Stat {
+                    is_dir: is_dir,
+                    size: size,
+                    mtime: mtime,
+                },
+            ));
+        }
+
+        return Ok(entries);
+    }
+
+    // for servers that don't speak WebDAV
+    fn head(&self, path: &dyn AsRef<Path>) -> io::Result<Stat> {
+        let res = self.request("HEAD", &self.url_path(path, false), &[], &[], &mut io::sink())?;
+        res.check()?;
+
+        let size = res.header("Content-Length")
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let mtime = res.header("Last-Modified")
+            .and_then(|v| parse_http_date(v))
+            .unwrap_or(0);
+        return Ok(Stat {
+            is_dir: path.as_ref() == Path::new(""),
+            size: size,
+            mtime: mtime,
+        });
+    }
+
+    fn do_stat(&self, path: &dyn AsRef<Path>) -> io::Result<Stat> {
+        match self.propfind(path, 0) {
+            Ok(mut entries) => {
+                if entries.is_empty() {
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                return Ok(entries.remove(0).1);
+            }
+            Err(e) => {
+                if e.raw_os_error() == Some(libc::ENOTSUP) {
+                    return self.head(path);
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+}
+
+impl SourceBackend for HttpBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, _mode: libc::mode_t) -> io::Result<File> {
+        if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY ||
+            (flags & (rlibc::O_CREAT | rlibc::O_TRUNC)) != 0
+        {
+            return Err(erofs());
+        }
+
+        let mut file = self.spool.create()?;
+        let res = {
+            let mut w = FileWriter {
+                file: &file,
+                offset: 0,
+            };
+            self.request("GET", &self.url_path(path, false), &[], &[], &mut w)
+        };
+
+        let res = res.and_then(|res| {
+            res.check()?;
+            // the checksum we use to validate the cache includes the
+            // mtime, so make the copy look like the original
+            if let Some(mtime) = res.header("Last-Modified").and_then(|v| parse_http_date(v)) {
+                let mtime = Timespec::new(mtime, 0);
+                rlibc::futimens(file.as_raw_fd(), &mtime, &mtime)?;
+            }
+            return Ok(());
+        });
+
+        if let Err(e) = res {
+            file.close()?;
+            return Err(e);
+        }
+        return Ok(file);
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        let st = self.do_stat(path)?;
+        let mode = if st.is_dir {
+            libc::S_IFDIR | 0o555
+        } else {
+            libc::S_IFREG | 0o444
+        };
+        return Ok(remote_stat(path.as_ref(), mode as u32, st.size, st.mtime));
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return Ok(readonly_statvfs());
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        let path = path.as_ref();
+        let mut dir = ListedDir::new(path);
+
+        for (rel, st) in self.propfind(&path, 1)? {
+            if rel == path {
+                // the directory itself
+                continue;
+            }
+            if let Some(name) = rel.file_name() {
+                dir.push(path, name, st.is_dir);
+            }
+        }
+
+        return Ok(Box::new(dir));
+    }
+
+    fn mkdir(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn mknod(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t, _rdev: u32) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn unlink(&self, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn rmdir(&self, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn symlink(&self, _target: &dyn AsRef<Path>, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    // nothing here is ever a symlink
+    fn readlink(&self, _path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    fn link(&self, _path: &dyn AsRef<Path>, _new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn rename(&self, _path: &dyn AsRef<Path>, _new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return error::propagate(erofs());
+    }
+
+    fn utimes(
+        &self,
+        _path: &dyn AsRef<Path>,
+        _atime: &Timespec,
+        _mtime: &Timespec,
+        _flags: u32,
+    ) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn chmod(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t, _flags: u32) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn get_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return Ok(None);
+    }
+
+    fn set_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn list_xattr(&self, _path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return Ok(Vec::new());
+    }
+
+    fn remove_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.hints.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multistatus() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/files/</D:href><D:propstat><D:prop>
+<D:resourcetype><D:collection/></D:resourcetype>
+<D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>
+</D:prop></D:propstat></D:response>
+<D:response><D:href>/files/a%20b.txt</D:href><D:propstat><D:prop>
+<D:resourcetype/><D:getcontentlength>42</D:getcontentlength>
+</D:prop></D:propstat></D:response>
+</D:multistatus>"#;
+
+        let responses = xml_elements(xml, "response");
+        assert_eq!(responses.len(), 2);
+        assert_eq!(xml_elements(responses[0], "href"), vec!["/files/"]);
+        assert_eq!(xml_elements(responses[0], "collection"), vec![""]);
+        assert!(xml_elements(responses[1], "collection").is_empty());
+        assert_eq!(xml_elements(responses[1], "getcontentlength"), vec!["42"]);
+        assert_eq!(
+            parse_http_date(xml_elements(responses[0], "getlastmodified")[0]),
+            Some(784111777)
+        );
+
+        let http = HttpBackend::new(OsStr::new("http://localhost:8080/files/"), &"/tmp").unwrap();
+        assert_eq!(http.addr, "localhost:8080");
+        assert_eq!(http.rel_path("/files/"), Some(PathBuf::from("")));
+        assert_eq!(
+            http.rel_path("http://localhost:8080/files/a%20b.txt"),
+            Some(PathBuf::from("a b.txt"))
+        );
+        assert_eq!(http.rel_path("/other/a"), None);
+        assert_eq!(http.url_path(&"a b.txt", false), "/files/a%20b.txt");
+        assert_eq!(http.url_path(&"", true), "/files/");
+    }
+}
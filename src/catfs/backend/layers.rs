@@ -0,0 +1,394 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use self::time::Timespec;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use super::{erofs, is_url, ListedDir, SourceBackend, SourceDir, SourceHints};
+
+// inos from every layer but the first get the layer in the top byte,
+// so the same ino in two layers isn't taken for one file
+const LAYER_SHIFT: u32 = 56;
+
+fn enoent() -> io::Error {
+    return io::Error::from_raw_os_error(libc::ENOENT);
+}
+
+fn eexist() -> io::Error {
+    return io::Error::from_raw_os_error(libc::EEXIST);
+}
+
+fn is_dir(st: &rlibc::stat64) -> bool {
+    return (st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+}
+
+// not there, or not there in this layer because something in the way
+// is a file
+fn is_missing(e: &io::Error) -> bool {
+    return error::is_enoent(e) || e.raw_os_error() == Some(libc::ENOTDIR);
+}
+
+fn layer_ino(ino: u64, layer: usize) -> u64 {
+    return ino ^ ((layer as u64) << LAYER_SHIFT);
+}
+
+// <from> is dirA:dirB:..., unless there's a directory by that name
+pub fn is_layers(from: &OsStr) -> bool {
+    return !is_url(from) && from.as_bytes().contains(&b':') && !Path::new(from).exists();
+}
+
+pub fn split_layers(from: &OsStr) -> Vec<PathBuf> {
+    return from.as_bytes()
+        .split(|b| *b == b':')
+        .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+        .collect();
+}
+
+// several sources merged into one, what's in an earlier layer hides
+// what's in a later one. Everything written goes to the first layer,
+// the others are never changed: files that are only in them can be
+// read but not written, removed or renamed
+pub struct UnionBackend {
+    layers: Vec<Arc<dyn SourceBackend>>,
+}
+
+impl UnionBackend {
+    pub fn new(layers: Vec<Arc<dyn SourceBackend>>) -> UnionBackend {
+        assert!(!layers.is_empty());
+        return UnionBackend { layers: layers };
+    }
+
+    fn upper(&self) -> &dyn SourceBackend {
+        return &*self.layers[0];
+    }
+
+    // the first layer that has path, and what it is there
+    fn find(&self, path: &dyn AsRef<Path>) -> io::Result<(usize, rlibc::stat64)> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            match layer.stat(path) {
+                Ok(st) => return Ok((i, st)),
+                Err(e) => {
+                    if !is_missing(&e) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        return Err(enoent());
+    }
+
+    fn first(&self, path: &dyn AsRef<Path>) -> io::Result<&dyn SourceBackend> {
+        let (i, _) = self.find(path)?;
+        return Ok(&*self.layers[i]);
+    }
+
+    // path is there to be changed, which it can only be in the first
+    // layer
+    fn writable(&self, path: &dyn AsRef<Path>) -> io::Result<&dyn SourceBackend> {
+        let (i, _) = self.find(path)?;
+        if i != 0 {
+            return Err(erofs());
+        }
+        return Ok(self.upper());
+    }
+
+    // path can go away from the first layer without what's in the
+    // others showing through
+    fn removable(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        self.writable(path)?;
+        for layer in &self.layers[1..] {
+            match layer.stat(path) {
+                Ok(_) => return Err(erofs()),
+                Err(e) => {
+                    if !is_missing(&e) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // path is about to be created in the first layer, which needs the
+    // directories leading up to it even if they are only in the others
+    fn make_parents(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        let parent = match path.as_ref().parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+
+        let mut p = PathBuf::new();
+        for c in parent.components() {
+            p.push(c);
+            let (i, st) = self.find(&p)?;
+            if !is_dir(&st) {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+            if i != 0 {
+                self.upper().mkdir(&p, (st.st_mode & 0o7777) as libc::mode_t)?;
+            }
+        }
+        return Ok(());
+    }
+
+    // path is about to be created, fails if any layer has it
+    fn create(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        match self.find(path) {
+            Ok(_) => return Err(eexist()),
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e);
+                }
+            }
+        }
+        return self.make_parents(path);
+    }
+}
+
+impl SourceBackend for UnionBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        match self.find(path) {
+            Ok((i, _)) => {
+                if i == 0 {
+                    return self.upper().open(path, flags, mode);
+                }
+                if (flags & (rlibc::O_CREAT | rlibc::O_EXCL)) == (rlibc::O_CREAT | rlibc::O_EXCL) {
+                    return Err(eexist());
+                }
+                if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY || (flags & rlibc::O_TRUNC) != 0 {
+                    return Err(erofs());
+                }
+                return self.layers[i].open(path, flags & !rlibc::O_CREAT, mode);
+            }
+            Err(e) => {
+                if !error::is_enoent(&e) || (flags & rlibc::O_CREAT) == 0 {
+                    return Err(e);
+                }
+                self.make_parents(path)?;
+                return self.upper().open(path, flags, mode);
+            }
+        }
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        let (i, mut st) = self.find(path)?;
+        st.st_ino = layer_ino(st.st_ino as u64, i) as _;
+        return Ok(st);
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return self.upper().statvfs();
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        let path = path.as_ref();
+        let (first, st) = self.find(&path)?;
+        if !is_dir(&st) {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let mut dir = ListedDir::new(path);
+        let mut names = HashSet::new();
+        for (i, layer) in self.layers.iter().enumerate().skip(first) {
+            match layer.stat(&path) {
+                Ok(ref st) if is_dir(st) => (),
+                Ok(_) => continue,
+                Err(e) => {
+                    if is_missing(&e) {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            }
+
+            let mut dh = layer.opendir(&path)?;
+            while let Some(en) = dh.readdir()? {
+                let name = en.name();
+                if name == OsStr::new(".") || name == OsStr::new("..") || names.contains(&name) {
+                    continue;
+                }
+                dir.entries.push((name.clone(), en.kind(), layer_ino(en.ino(), i)));
+                names.insert(name);
+            }
+        }
+        return Ok(Box::new(dir));
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        self.create(path)?;
+        return self.upper().mkdir(path, mode);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        self.create(path)?;
+        return self.upper().mknod(path, mode, rdev);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        self.removable(path)?;
+        return self.upper().unlink(path);
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        self.removable(path)?;
+        return self.upper().rmdir(path);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        self.create(path)?;
+        return self.upper().symlink(target, path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return self.first(path)?.readlink(path);
+    }
+
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        self.writable(path)?;
+        self.create(new_path)?;
+        return self.upper().link(path, new_path);
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        self.removable(path)?;
+        self.make_parents(new_path)?;
+        return self.upper().rename(path, new_path);
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.writable(path)?.utimes(path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        return self.writable(path)?.chmod(path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.writable(path)?.chown(path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.first(path)?.get_xattr(path, name);
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.writable(path)?.set_xattr(path, name, value);
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return self.first(path)?.list_xattr(path);
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        return self.writable(path)?.remove_xattr(path, name);
+    }
+
+    fn open_for_locks(&self, path: &dyn AsRef<Path>) -> io::Result<File> {
+        return self.first(path)?.open_for_locks(path);
+    }
+
+    fn access(&self, path: &dyn AsRef<Path>, mask: libc::c_int) -> io::Result<()> {
+        let (i, _) = self.find(path)?;
+        if i != 0 && (mask & libc::W_OK) != 0 {
+            return Err(erofs());
+        }
+        return self.layers[i].access(path, mask);
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.upper().hints();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use super::super::LocalBackend;
+    use super::*;
+
+    fn list(src: &dyn SourceBackend, path: &dyn AsRef<Path>) -> Vec<String> {
+        let mut dir = src.opendir(path).unwrap();
+        let mut names = Vec::new();
+        while let Some(en) = dir.readdir().unwrap() {
+            names.push(en.name().into_string().unwrap());
+        }
+        names.sort();
+        return names;
+    }
+
+    fn errno<T>(res: io::Result<T>) -> Option<libc::c_int> {
+        return res.err().and_then(|e| e.raw_os_error());
+    }
+
+    #[test]
+    fn union() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let upper = prefix.join("upper");
+        let resources = prefix.join("resources");
+        fs::create_dir_all(&upper).unwrap();
+        fs::write(upper.join("file1"), "upper").unwrap();
+
+        let from = OsString::from(format!("{}:{}", upper.display(), resources.display()));
+        assert!(is_layers(&from));
+        assert!(!is_layers(resources.as_os_str()));
+        let layers: Vec<Arc<dyn SourceBackend>> = split_layers(&from)
+            .iter()
+            .map(|dir| Arc::new(LocalBackend::new(dir).unwrap()) as Arc<dyn SourceBackend>)
+            .collect();
+        let src = UnionBackend::new(layers);
+
+        // the first layer wins
+        assert_eq!(src.stat(&"file1").unwrap().st_size, 5);
+        assert_eq!(
+            list(&src, &""),
+            vec![".", "..", "dir1", "file1", "file2", "file3"]
+        );
+        assert_eq!(src.stat(&"file2").unwrap().st_ino as u64 >> LAYER_SHIFT, 1);
+
+        // the rest can only be read
+        let mut f = src.open(&"dir1/file1", rlibc::O_RDONLY, 0).unwrap();
+        f.close().unwrap();
+        assert_eq!(errno(src.open(&"dir1/file1", rlibc::O_RDWR, 0)), Some(libc::EROFS));
+        assert_eq!(errno(src.unlink(&"file2")), Some(libc::EROFS));
+        assert_eq!(errno(src.chmod(&"file2", 0o600, 0)), Some(libc::EROFS));
+        // or it would come back
+        assert_eq!(errno(src.unlink(&"file1")), Some(libc::EROFS));
+        assert_eq!(errno(src.mkdir(&"dir1", 0o755)), Some(libc::EEXIST));
+
+        // new files go to the first layer
+        let mut f = src.open(&"dir1/new", rlibc::O_WRONLY | rlibc::O_CREAT, 0o644).unwrap();
+        f.close().unwrap();
+        assert!(upper.join("dir1/new").exists());
+        assert!(!resources.join("dir1/new").exists());
+        assert!(list(&src, &"dir1").contains(&String::from("new")));
+        src.rename(&"dir1/new", &"new").unwrap();
+        src.unlink(&"new").unwrap();
+        assert!(src.stat(&"new").is_err());
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,760 @@
+extern crate fuse;
+extern crate libc;
+extern crate time;
+extern crate twox_hash;
+extern crate xattr;
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::time::Timespec;
+use self::twox_hash::XxHash;
+use self::xattr::FileExt;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+
+pub mod cow;
+pub mod fetch;
+pub mod http;
+pub mod layers;
+pub mod peer;
+pub mod retry;
+pub mod sftp;
+
+// where catfs reads files from and writes them back to. Paths are
+// relative to the root of the source. Opened files are handed out as
+// fds so page-in can splice from them, a backend that isn't backed by
+// a local filesystem can spool into an unlinked file. Besides the
+// ones here, programs embedding catfs can implement their own and
+// pass it to MountBuilder::backend
+pub trait SourceBackend: Send + Sync {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File>;
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64>;
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64>;
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>>;
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()>;
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()>;
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()>;
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()>;
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()>;
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf>;
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()>;
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()>;
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()>;
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()>;
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>>;
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()>;
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>>;
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()>;
+
+    // -1 leaves the uid or gid as it is, ENOSYS if the source has no
+    // notion of owners
+    fn chown(
+        &self,
+        _path: &dyn AsRef<Path>,
+        _uid: libc::uid_t,
+        _gid: libc::gid_t,
+        _flags: u32,
+    ) -> io::Result<()> {
+        return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+    }
+
+    // a file to take posix locks on, where everyone else using the
+    // source sees them. Otherwise the kernel keeps locks to the mount
+    fn open_for_locks(&self, _path: &dyn AsRef<Path>) -> io::Result<File> {
+        return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+    }
+
+    // whether we may access a file with the given R_OK/W_OK/X_OK,
+    // ENOSYS if only the mode bits can tell
+    fn access(&self, _path: &dyn AsRef<Path>, _mask: libc::c_int) -> io::Result<()> {
+        return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+    }
+
+    fn hints(&self) -> SourceHints {
+        return Default::default();
+    }
+}
+
+// what a cache file is compared against to tell whether the source
+// changed since it was cached. With none of them a complete cache
+// file is always good
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validation {
+    // xattrs that say which version of a file it is, such as s3.etag
+    pub version: bool,
+    pub mtime: bool,
+    pub size: bool,
+    // the xattrs to look at, instead of the ones goofys, gcsfuse and
+    // rclone set
+    pub version_xattrs: Option<Arc<Vec<String>>>,
+}
+
+impl Default for Validation {
+    fn default() -> Validation {
+        return Validation {
+            version: true,
+            mtime: true,
+            size: true,
+            version_xattrs: None,
+        };
+    }
+}
+
+impl FromStr for Validation {
+    type Err = String;
+
+    // a comma separated list of etag, mtime and size, or none
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut v = Validation {
+            version: false,
+            mtime: false,
+            size: false,
+            version_xattrs: None,
+        };
+        if s.trim() == "none" {
+            return Ok(v);
+        }
+
+        for p in s.split(',') {
+            match p.trim() {
+                "etag" => v.version = true,
+                "mtime" => v.mtime = true,
+                "size" => v.size = true,
+                _ => return Err("unrecognized validation ".to_owned() + p),
+            }
+        }
+        return Ok(v);
+    }
+}
+
+// what catfs should do differently because of what the source is
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct SourceHints {
+    // random writes fail, so writes only go to the cache and the
+    // whole file is copied out when it's flushed
+    pub sequential_writes: bool,
+    // the s3.etag xattr says which version of a file this is, the
+    // mtime can move without the content changing
+    pub etag_versions: bool,
+    // mode and times can't be stored, don't bother sending them
+    pub no_attrs: bool,
+    // files never change once they are written, so a cache file that
+    // has all of the data is good without checking
+    pub immutable: bool,
+    pub validation: Validation,
+    // idempotent reads from the source are tried again when they fail
+    // with what may be a network hiccup
+    pub retry: retry::Retry,
+}
+
+impl SourceHints {
+    // goofys only uploads whole objects, reports whatever mtime it
+    // last listed and has nowhere to keep mode or times
+    pub fn goofys() -> SourceHints {
+        return SourceHints {
+            sequential_writes: true,
+            etag_versions: true,
+            no_attrs: true,
+            immutable: false,
+            validation: Default::default(),
+            retry: Default::default(),
+        };
+    }
+}
+
+// goofys is a fuse filesystem with subtype goofys, fuse doesn't pass
+// the subtype on to statfs so it has to come from mountinfo
+#[cfg(not(target_os = "macos"))]
+pub fn is_goofys(dir: &dyn AsRef<Path>) -> bool {
+    const FUSE_SUPER_MAGIC: i64 = 0x65735546;
+
+    let fd = match rlibc::open(dir, rlibc::O_RDONLY, 0) {
+        Ok(fd) => fd,
+        Err(_) => return false,
+    };
+    let st = rlibc::fstatfs(fd);
+    let _ = rlibc::close(fd);
+    match st {
+        Ok(st) => {
+            if st.f_type as i64 != FUSE_SUPER_MAGIC {
+                return false;
+            }
+        }
+        Err(_) => return false,
+    }
+
+    let dir = match dir.as_ref().canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => return mount_fstype(&mountinfo, &dir) == Some("fuse.goofys"),
+        Err(_) => return false,
+    }
+}
+
+// macFUSE doesn't tell goofys apart from other fuse filesystems,
+// --profile goofys has to be given instead
+#[cfg(target_os = "macos")]
+pub fn is_goofys(_dir: &dyn AsRef<Path>) -> bool {
+    return false;
+}
+
+// fstype of the innermost mount that dir is in
+#[cfg(not(target_os = "macos"))]
+fn mount_fstype<'a>(mountinfo: &'a str, dir: &Path) -> Option<&'a str> {
+    let mut found: Option<(usize, &str)> = None;
+
+    for line in mountinfo.lines() {
+        // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw
+        let mut halves = line.splitn(2, " - ");
+        let mount_point = match halves.next().and_then(|l| l.split(' ').nth(4)) {
+            Some(m) => PathBuf::from(OsStr::from_bytes(&unescape_mountinfo(m))),
+            None => continue,
+        };
+        let fstype = match halves.next().and_then(|l| l.split(' ').next()) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        if dir.starts_with(&mount_point) {
+            let depth = mount_point.components().count();
+            // later mounts on the same point hide the earlier ones
+            if found.map_or(true, |(d, _)| depth >= d) {
+                found = Some((depth, fstype));
+            }
+        }
+    }
+
+    return found.map(|(_, t)| t);
+}
+
+// mountinfo escapes space, tab, newline and backslash as \ooo
+#[cfg(not(target_os = "macos"))]
+fn unescape_mountinfo(s: &str) -> Vec<u8> {
+    let b = s.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let is_octal = |c: &u8| *c >= b'0' && *c <= b'7';
+        if b[i] == b'\\' && i + 3 < b.len() && b[i + 1..i + 4].iter().all(is_octal) {
+            res.push(b[i + 1..i + 4].iter().fold(0, |n, c| n * 8 + (c - b'0')));
+            i += 4;
+        } else {
+            res.push(b[i]);
+            i += 1;
+        }
+    }
+    return res;
+}
+
+// an open directory of a SourceBackend, offsets are whatever the
+// backend returns in Dirent::off()
+pub trait SourceDir: Send {
+    fn seekdir(&mut self, offset: i64);
+    fn readdir(&mut self) -> io::Result<Option<rlibc::Dirent>>;
+}
+
+fn is_root(path: &dyn AsRef<Path>) -> bool {
+    return path.as_ref() == Path::new("");
+}
+
+// besides local directories, catfs can cache from these
+pub fn is_url(s: &OsStr) -> bool {
+    return http::is_url(s) || sftp::is_url(s);
+}
+
+pub fn from_url(
+    url: &OsStr,
+    tmp_dir: &dyn AsRef<Path>,
+    hints: SourceHints,
+) -> io::Result<Arc<dyn SourceBackend>> {
+    if sftp::is_url(url) {
+        let mut src = sftp::SftpBackend::new(url, tmp_dir)?;
+        src.set_hints(hints);
+        return Ok(Arc::new(src));
+    } else {
+        let mut src = http::HttpBackend::new(url, tmp_dir)?;
+        src.set_hints(hints);
+        return Ok(Arc::new(src));
+    }
+}
+
+fn percent_encode(path: &Path) -> String {
+    let mut s = String::new();
+    for b in path.as_os_str().as_bytes() {
+        match *b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                s.push(*b as char)
+            }
+            _ => s.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    return s;
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        // what follows % may not even be ascii
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_digit(bytes[i + 1]), hex_digit(bytes[i + 2])) {
+                res.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    return res;
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    return (b as char).to_digit(16).map(|d| d as u8);
+}
+
+fn erofs() -> io::Error {
+    return io::Error::from_raw_os_error(libc::EROFS);
+}
+
+// remote backends don't have inode numbers, make them up from the path
+// XxHash with a fixed seed gives the same ino for the same path across
+// restarts and rust versions, which nfs clients rely on
+fn remote_ino(path: &Path) -> u64 {
+    let mut hasher = XxHash::with_seed(0);
+    hasher.write(path.as_os_str().as_bytes());
+    let ino = hasher.finish();
+    // 0 is invalid and 1 is the root
+    return if ino < 2 { ino + 2 } else { ino };
+}
+
+// remote files are owned by whoever runs catfs, the remote uid/gid
+// mean nothing here
+fn remote_stat(path: &Path, mode: u32, size: u64, mtime: i64) -> rlibc::stat64 {
+    let mut st: rlibc::stat64 = unsafe { mem::zeroed() };
+    st.st_ino = remote_ino(path) as _;
+    st.st_mode = mode as _;
+    st.st_nlink = if (mode & libc::S_IFMT as u32) == libc::S_IFDIR as u32 {
+        2
+    } else {
+        1
+    };
+    st.st_size = size as _;
+    st.st_blocks = ((size + 511) / 512) as _;
+    st.st_blksize = 4096;
+    st.st_atime = mtime as _;
+    st.st_mtime = mtime as _;
+    st.st_ctime = mtime as _;
+    st.st_uid = unsafe { libc::geteuid() };
+    st.st_gid = unsafe { libc::getegid() };
+    return st;
+}
+
+fn readonly_statvfs() -> rlibc::statvfs64 {
+    let mut st: rlibc::statvfs64 = unsafe { mem::zeroed() };
+    st.f_bsize = 4096;
+    st.f_frsize = 4096;
+    st.f_namemax = 255;
+    st.f_flag = libc::ST_RDONLY as _;
+    return st;
+}
+
+// remote backends hand out files by downloading them into an unlinked
+// file, which lives in the cache directory
+struct Spool {
+    dir: PathBuf,
+    prefix: &'static str,
+    seq: AtomicUsize,
+}
+
+impl Spool {
+    fn new(dir: &dyn AsRef<Path>, prefix: &'static str) -> Spool {
+        return Spool {
+            dir: dir.as_ref().to_path_buf(),
+            prefix: prefix,
+            seq: AtomicUsize::new(0),
+        };
+    }
+
+    fn create(&self) -> io::Result<File> {
+        let path = self.dir.join(format!(
+            ".catfs-{}-{}-{}",
+            self.prefix,
+            unsafe { libc::getpid() },
+            self.seq.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::open(
+            &path,
+            rlibc::O_RDWR | rlibc::O_CREAT | rlibc::O_EXCL,
+            0o600,
+        )?;
+        fs::remove_file(&path)?;
+        return Ok(file);
+    }
+}
+
+// a directory listing that was fetched in one go, offsets are
+// positions in the listing
+struct ListedDir {
+    entries: Vec<(OsString, fuse::FileType, u64)>,
+    pos: usize,
+}
+
+impl ListedDir {
+    // starts the listing with . and ..
+    fn new(path: &Path) -> ListedDir {
+        return ListedDir {
+            entries: vec![
+                (OsString::from("."), fuse::FileType::Directory, remote_ino(path)),
+                (
+                    OsString::from(".."),
+                    fuse::FileType::Directory,
+                    remote_ino(path.parent().unwrap_or(path)),
+                ),
+            ],
+            pos: 0,
+        };
+    }
+
+    fn push(&mut self, dir: &Path, name: &OsStr, is_dir: bool) {
+        let kind = if is_dir {
+            fuse::FileType::Directory
+        } else {
+            fuse::FileType::RegularFile
+        };
+        self.entries.push((name.to_os_string(), kind, remote_ino(&dir.join(name))));
+    }
+}
+
+impl SourceDir for ListedDir {
+    fn seekdir(&mut self, offset: i64) {
+        self.pos = offset as usize;
+    }
+
+    fn readdir(&mut self) -> io::Result<Option<rlibc::Dirent>> {
+        if self.pos >= self.entries.len() {
+            return Ok(None);
+        }
+
+        let (ref name, kind, ino) = self.entries[self.pos];
+        self.pos += 1;
+        return Ok(Some(rlibc::Dirent::new(ino, self.pos as i64, kind, name)));
+    }
+}
+
+// a directory on a locally mounted filesystem
+pub struct LocalBackend {
+    dir: RawFd,
+    hints: SourceHints,
+}
+
+impl LocalBackend {
+    pub fn new(dir: &dyn AsRef<Path>) -> io::Result<LocalBackend> {
+        return Ok(LocalBackend {
+            dir: rlibc::open(dir, rlibc::O_RDONLY, 0)?,
+            hints: Default::default(),
+        });
+    }
+
+    // for when the directory is itself another fuse filesystem
+    pub fn set_hints(&mut self, hints: SourceHints) {
+        self.hints = hints;
+    }
+
+    fn with_file<T, F>(&self, path: &dyn AsRef<Path>, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&File) -> io::Result<T>,
+    {
+        // a fifo would wait for a writer
+        let flags = rlibc::O_RDONLY | rlibc::O_NONBLOCK;
+        let mut file = if is_root(path) {
+            File::openat(self.dir, &".", flags, 0)?
+        } else {
+            File::openat(self.dir, path, flags, 0)?
+        };
+        let res = f(&file);
+        file.close()?;
+        return res;
+    }
+}
+
+impl Drop for LocalBackend {
+    fn drop(&mut self) {
+        if let Err(e) = rlibc::close(self.dir) {
+            error!("!close({}) = {}", self.dir, error::RError::from(e));
+        }
+    }
+}
+
+impl SourceBackend for LocalBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        return File::openat(self.dir, path, flags, mode);
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        if is_root(path) {
+            return rlibc::fstat(self.dir);
+        } else {
+            return rlibc::fstatat(self.dir, path);
+        }
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return rlibc::fstatvfs(self.dir);
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        let fd = if is_root(path) {
+            rlibc::openat(self.dir, &".", rlibc::O_RDONLY, 0)?
+        } else {
+            rlibc::openat(self.dir, path, rlibc::O_RDONLY, 0)?
+        };
+        match rlibc::fdopendir(fd) {
+            Ok(dh) => return Ok(Box::new(LocalDir::new(dh))),
+            Err(e) => {
+                rlibc::close(fd)?;
+                return Err(e);
+            }
+        }
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        return rlibc::mkdirat(self.dir, path, mode);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        return rlibc::mknodat(self.dir, path, mode, rdev as libc::dev_t);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return rlibc::unlinkat(self.dir, path, 0);
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return rlibc::unlinkat(self.dir, path, libc::AT_REMOVEDIR as u32);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return rlibc::symlinkat(target, self.dir, path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return rlibc::readlinkat(self.dir, path);
+    }
+
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return rlibc::linkat(self.dir, path, new_path);
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return rlibc::renameat(self.dir, path, new_path);
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        if self.hints.no_attrs {
+            return Ok(());
+        }
+        return rlibc::utimensat(self.dir, path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        if self.hints.no_attrs {
+            return Ok(());
+        }
+        return rlibc::fchmodat(self.dir, path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        if self.hints.no_attrs {
+            return Ok(());
+        }
+        return rlibc::fchownat(self.dir, path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.with_file(path, |f| f.get_xattr(name));
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.with_file(path, |f| f.set_xattr(name, value));
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return self.with_file(path, |f| Ok(f.list_xattr()?.collect()));
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        return self.with_file(path, |f| f.remove_xattr(name));
+    }
+
+    fn open_for_locks(&self, path: &dyn AsRef<Path>) -> io::Result<File> {
+        // write locks need a writable fd
+        match File::openat(self.dir, path, rlibc::O_RDWR, 0) {
+            Ok(f) => return Ok(f),
+            Err(e) => {
+                if e.raw_os_error() != Some(libc::EACCES) && e.raw_os_error() != Some(libc::EROFS) {
+                    return Err(e);
+                }
+                return File::openat(self.dir, path, rlibc::O_RDONLY, 0);
+            }
+        }
+    }
+
+    fn access(&self, path: &dyn AsRef<Path>, mask: libc::c_int) -> io::Result<()> {
+        return rlibc::faccessat(self.dir, path, mask, libc::AT_EACCESS as u32);
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.hints.clone();
+    }
+}
+
+pub struct LocalDir {
+    dh: *mut libc::DIR,
+}
+
+// no-op to workaround the fact that we send the entire CatFS at start
+// time, but we never send anything. Could have used Unique but that
+// bounds us to rust nightly
+unsafe impl Send for LocalDir {}
+
+impl LocalDir {
+    pub fn new(dh: *mut libc::DIR) -> LocalDir {
+        return LocalDir { dh: dh };
+    }
+}
+
+impl Drop for LocalDir {
+    fn drop(&mut self) {
+        if let Err(e) = rlibc::closedir(self.dh) {
+            error!("!closedir {:?} = {}", self.dh, e);
+        }
+    }
+}
+
+impl SourceDir for LocalDir {
+    fn seekdir(&mut self, offset: i64) {
+        debug!(
+            "seeking {} to {}",
+            unsafe { libc::telldir(self.dh) },
+            offset
+        );
+        rlibc::seekdir(self.dh, offset);
+    }
+
+    fn readdir(&mut self) -> io::Result<Option<rlibc::Dirent>> {
+        return rlibc::readdir(self.dh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn local_backend() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+
+        assert_eq!(
+            src.stat(&"file1").unwrap().st_size as u64,
+            fs::metadata(prefix.join("resources/file1")).unwrap().len()
+        );
+        assert!(src.stat(&"").is_ok());
+
+        let mut dir = src.opendir(&"").unwrap();
+        let mut names = Vec::new();
+        while let Some(en) = dir.readdir().unwrap() {
+            names.push(en.name());
+        }
+        assert!(names.contains(&OsString::from("file1")));
+
+        src.rename(&"file1", &"file4").unwrap();
+        assert!(src.stat(&"file1").is_err());
+        let mut f = src.open(&"file4", rlibc::O_RDONLY, 0).unwrap();
+        f.close().unwrap();
+        src.unlink(&"file4").unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn goofys_mountinfo() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /mnt/s3 rw,nosuid,nodev,relatime shared:2 - fuse.goofys bucket rw
+41 40 0:36 / /mnt/s3/sub\\040dir rw,relatime shared:3 - fuse.sshfs me@host: rw
+";
+        assert_eq!(
+            mount_fstype(mountinfo, Path::new("/mnt/s3/a/b")),
+            Some("fuse.goofys")
+        );
+        assert_eq!(
+            mount_fstype(mountinfo, Path::new("/mnt/s3/sub dir/c")),
+            Some("fuse.sshfs")
+        );
+        assert_eq!(mount_fstype(mountinfo, Path::new("/mnt/s30")), Some("ext4"));
+        assert!(!is_goofys(&"/"));
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(
+            "etag,mtime,size".parse::<Validation>().unwrap(),
+            Validation::default()
+        );
+        let v: Validation = "size".parse().unwrap();
+        assert!(!v.version && !v.mtime && v.size);
+        let v: Validation = "none".parse().unwrap();
+        assert!(!v.version && !v.mtime && !v.size);
+        assert!("mtime,ctime".parse::<Validation>().is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("/a%20b"), b"/a b".to_vec());
+        assert_eq!(percent_decode("%e4%B8%ad"), "\u{4e2d}".as_bytes().to_vec());
+        // not escapes, left as they are
+        assert_eq!(percent_decode("100%"), b"100%".to_vec());
+        assert_eq!(percent_decode("%zz%4"), b"%zz%4".to_vec());
+        assert_eq!(percent_decode("%\u{e9}x"), "%\u{e9}x".as_bytes().to_vec());
+        assert_eq!(percent_decode("%4\u{e9}"), "%4\u{e9}".as_bytes().to_vec());
+    }
+}
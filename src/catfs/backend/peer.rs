@@ -0,0 +1,665 @@
+extern crate libc;
+extern crate time;
+extern crate xattr;
+
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use self::time::Timespec;
+use self::xattr::FileExt;
+
+use catfs::compress;
+use catfs::crypt;
+use catfs::error;
+use catfs::file;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use catfs::throttle::Throttle;
+use super::{percent_decode, percent_encode, SourceBackend, SourceDir, SourceHints, Spool};
+
+// A peer is another catfs that may have the file we want in its
+// cache. The protocol is line based, each request is
+//
+//   READ <src checksum in hex> <offset> <len> <percent encoded path>\n
+//
+// and the peer answers with "OK <n>\n" followed by n bytes (n < len
+// means EOF), or "NO\n" if it doesn't have that version of the file
+// fully cached.
+pub const PEER_READ_CHUNK: u64 = 1024 * 1024;
+
+// how long to leave a peer alone after it failed us
+const PEER_BACKOFF_SECS: u64 = 30;
+
+pub fn to_hex(b: &[u8]) -> String {
+    return b.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+struct Peer {
+    addr: SocketAddr,
+    // idle connections, kept around between files
+    conns: Mutex<Vec<BufReader<TcpStream>>>,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Peer {
+    fn is_down(&self) -> bool {
+        let mut down_until = self.down_until.lock().unwrap();
+        if let Some(until) = *down_until {
+            if Instant::now() < until {
+                return true;
+            }
+            *down_until = None;
+        }
+        return false;
+    }
+
+    fn mark_down(&self) {
+        *self.down_until.lock().unwrap() =
+            Some(Instant::now() + StdDuration::from_secs(PEER_BACKOFF_SECS));
+        self.conns.lock().unwrap().clear();
+    }
+
+    // copies the whole file into `to`, returns false if the peer
+    // doesn't have it
+    fn fetch(&self, path: &Path, chksum: &str, size: u64, to: &File) -> io::Result<bool> {
+        let conn = self.conns.lock().unwrap().pop();
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let stream = TcpStream::connect_timeout(&self.addr, StdDuration::from_secs(1))?;
+                stream.set_read_timeout(Some(StdDuration::from_secs(10)))?;
+                stream.set_nodelay(true)?;
+                BufReader::new(stream)
+            }
+        };
+
+        let res = Peer::fetch_with(&mut conn, path, chksum, size, to);
+        if res.is_ok() {
+            self.conns.lock().unwrap().push(conn);
+        }
+        return res;
+    }
+
+    fn fetch_with(
+        conn: &mut BufReader<TcpStream>,
+        path: &Path,
+        chksum: &str,
+        size: u64,
+        to: &File,
+    ) -> io::Result<bool> {
+        let path = percent_encode(path);
+        let mut buf = vec![0u8; file::DEFAULT_READAHEAD];
+        let mut offset = 0u64;
+        let mut line = String::new();
+
+        loop {
+            conn.get_mut().write_all(
+                format!(
+                    "READ {} {} {} {}\n",
+                    chksum,
+                    offset,
+                    PEER_READ_CHUNK,
+                    path
+                ).as_bytes(),
+            )?;
+
+            line.clear();
+            conn.read_line(&mut line)?;
+            let n = match line.trim_end().split(' ').collect::<Vec<&str>>().as_slice() {
+                ["OK", n] => n.parse::<u64>().map_err(|_| bad_reply())?,
+                ["NO"] => return Ok(false),
+                _ => return Err(bad_reply()),
+            };
+            if n > PEER_READ_CHUNK || offset + n > size {
+                return Err(bad_reply());
+            }
+
+            let mut left = n;
+            while left != 0 {
+                let want = if left < buf.len() as u64 {
+                    left as usize
+                } else {
+                    buf.len()
+                };
+                conn.read_exact(&mut buf[..want])?;
+                let mut written = 0;
+                while written < want {
+                    written += to.write_at(&buf[written..want], (offset + written as u64) as i64)?;
+                }
+                offset += want as u64;
+                left -= want as u64;
+            }
+
+            if n < PEER_READ_CHUNK {
+                break;
+            }
+        }
+
+        // the peer may have raced with a change to the source
+        return Ok(offset == size);
+    }
+}
+
+// a peer that doesn't make sense is as good as down, which is an
+// EIO if it can't be read from src instead
+fn bad_reply() -> io::Error {
+    error!("bad reply from peer");
+    return io::Error::from_raw_os_error(libc::EIO);
+}
+
+// asks peers for files before reading them from the source
+pub struct PeerBackend {
+    src: Arc<dyn SourceBackend>,
+    peers: Vec<Peer>,
+    spool: Spool,
+}
+
+impl PeerBackend {
+    pub fn new(
+        src: Arc<dyn SourceBackend>,
+        peers: &[String],
+        tmp_dir: &dyn AsRef<Path>,
+    ) -> io::Result<PeerBackend> {
+        let mut addrs = Vec::new();
+        for p in peers {
+            match p.to_socket_addrs()?.next() {
+                Some(addr) => addrs.push(Peer {
+                    addr: addr,
+                    conns: Mutex::new(Vec::new()),
+                    down_until: Mutex::new(None),
+                }),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot resolve {}", p),
+                    ))
+                }
+            }
+        }
+
+        return Ok(PeerBackend {
+            src: src,
+            peers: addrs,
+            spool: Spool::new(tmp_dir, "peer"),
+        });
+    }
+
+    fn open_from_peers(&self, path: &dyn AsRef<Path>) -> error::Result<Option<File>> {
+        let st = self.src.stat(path)?;
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+            return Ok(None);
+        }
+        let chksum = to_hex(file::Handle::src_chksum_at(&*self.src, path)?.as_slice());
+
+        for peer in &self.peers {
+            if peer.is_down() {
+                continue;
+            }
+
+            let mut f = self.spool.create()?;
+            match peer.fetch(path.as_ref(), &chksum, st.st_size as u64, &f) {
+                Ok(true) => {
+                    // make it look like the source, the checksum is
+                    // computed with this
+                    let mtime = Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32);
+                    rlibc::futimens(f.as_raw_fd(), &mtime, &mtime)?;
+                    debug!("<-- peer {} has {:?}", peer.addr, path.as_ref());
+                    return Ok(Some(f));
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    info!("peer {} failed: {}", peer.addr, e);
+                    peer.mark_down();
+                }
+            }
+            f.close()?;
+        }
+
+        return Ok(None);
+    }
+}
+
+impl SourceBackend for PeerBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        if (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY &&
+            (flags & (rlibc::O_CREAT | rlibc::O_TRUNC)) == 0
+        {
+            match self.open_from_peers(path) {
+                Ok(Some(f)) => return Ok(f),
+                Ok(None) => (),
+                Err(e) => {
+                    if !error::is_enoent(&e) {
+                        error!("!peer open {:?} = {}", path.as_ref(), e);
+                    }
+                }
+            }
+        }
+
+        return self.src.open(path, flags, mode);
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        return self.src.stat(path);
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return self.src.statvfs();
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        return self.src.opendir(path);
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        return self.src.mkdir(path, mode);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        return self.src.mknod(path, mode, rdev);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.unlink(path);
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.rmdir(path);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.symlink(target, path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return self.src.readlink(path);
+    }
+
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.link(path, new_path);
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return self.src.rename(path, new_path);
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.utimes(path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        return self.src.chmod(path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.chown(path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.src.get_xattr(path, name);
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.src.set_xattr(path, name, value);
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return self.src.list_xattr(path);
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        return self.src.remove_xattr(path, name);
+    }
+
+    fn open_for_locks(&self, path: &dyn AsRef<Path>) -> io::Result<File> {
+        return self.src.open_for_locks(path);
+    }
+
+    fn access(&self, path: &dyn AsRef<Path>, mask: libc::c_int) -> io::Result<()> {
+        return self.src.access(path, mask);
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.src.hints();
+    }
+}
+
+// who may read from our cache, an address or a network in CIDR
+// notation
+#[derive(Debug, PartialEq)]
+pub struct AllowRule {
+    net: IpAddr,
+    prefix: u32,
+}
+
+impl AllowRule {
+    fn matches(&self, ip: &IpAddr) -> bool {
+        let (net, ip, bits) = match (self.net, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            (IpAddr::V6(net), IpAddr::V4(ip)) => (u128::from(net), u128::from(ip.to_ipv6_mapped()), 128),
+            (IpAddr::V4(net), IpAddr::V6(ip)) => match ip.to_ipv4() {
+                Some(ip) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+                None => return false,
+            },
+        };
+        let shift = bits - cmp::min(self.prefix, bits);
+        if shift >= 128 {
+            return true;
+        }
+        return (net >> shift) == (ip >> shift);
+    }
+}
+
+pub fn parse_allow_list(s: &str) -> Result<Vec<AllowRule>, String> {
+    let mut rules = Vec::new();
+    for r in s.split(',').filter(|r| !r.is_empty()) {
+        let mut parts = r.splitn(2, '/');
+        let net = parts.next().unwrap().parse::<IpAddr>().map_err(|e| {
+            format!("{}: {}", r, e)
+        })?;
+        let bits = if net.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => p.parse::<u32>().map_err(|e| format!("{}: {}", r, e))?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(format!("{}: prefix is too long", r));
+        }
+        rules.push(AllowRule {
+            net: net,
+            prefix: prefix,
+        });
+    }
+    return Ok(rules);
+}
+
+// serves fully cached, unmodified files to other catfs instances
+pub struct PeerServer {
+    cache_dir: RawFd,
+    layout: file::CacheLayout,
+    allow: Vec<AllowRule>,
+    // how fast all peers together can read from us
+    throttle: Option<Throttle>,
+}
+
+impl PeerServer {
+    pub fn new(cache_dir: RawFd, allow: Vec<AllowRule>, bytes_per_sec: u64) -> PeerServer {
+        let layout = file::CacheLayout::load(cache_dir).unwrap_or_else(|e| {
+            error!("!load cache layout = {}", e);
+            Default::default()
+        });
+        return PeerServer {
+            cache_dir: cache_dir,
+            layout: layout,
+            allow: allow,
+            throttle: if bytes_per_sec == 0 {
+                None
+            } else {
+                Some(Throttle::new(bytes_per_sec))
+            },
+        };
+    }
+
+    // listens in the background, returns the address we ended up on
+    pub fn start(self, addr: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        let server = Arc::new(self);
+
+        thread::spawn(move || for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    thread::spawn(move || if let Err(e) = server.serve(stream) {
+                        debug!("!peer = {}", e);
+                    });
+                }
+                Err(e) => error!("!accept = {}", e),
+            }
+        });
+
+        return Ok(local);
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        if !self.allow.iter().any(|r| r.matches(&peer.ip())) {
+            info!("peer {} is not allowed", peer);
+            return Ok(());
+        }
+
+        let mut r = BufReader::new(stream);
+        let mut line = String::new();
+        let mut buf = Vec::new();
+        while r.read_line(&mut line)? != 0 {
+            let n = match line.trim_end().split(' ').collect::<Vec<&str>>().as_slice() {
+                ["READ", chksum, offset, len, path] => {
+                    match (offset.parse::<u64>(), len.parse::<u64>()) {
+                        (Ok(offset), Ok(len)) => {
+                            let len = cmp::min(len, PEER_READ_CHUNK);
+                            buf.resize(len as usize, 0);
+                            self.read(chksum, offset, path, &mut buf)
+                        }
+                        _ => return Err(bad_reply()),
+                    }
+                }
+                _ => return Err(bad_reply()),
+            };
+
+            match n {
+                Some(n) => {
+                    if let Some(ref throttle) = self.throttle {
+                        throttle.wait(n);
+                    }
+                    r.get_mut().write_all(format!("OK {}\n", n).as_bytes())?;
+                    r.get_mut().write_all(&buf[..n])?;
+                }
+                None => r.get_mut().write_all(b"NO\n")?,
+            }
+            line.clear();
+        }
+
+        return Ok(());
+    }
+
+    // returns how much was read, or None if we can't vouch for the
+    // cached file
+    fn read(&self, chksum: &str, offset: u64, path: &str, buf: &mut [u8]) -> Option<usize> {
+        let path = PathBuf::from(OsString::from_vec(percent_decode(path)));
+        if path.components().any(|c| match c {
+            Component::Normal(_) => false,
+            _ => true,
+        })
+        {
+            return None;
+        }
+
+        let mut f = match File::openat(
+            self.cache_dir,
+            &self.layout.cache_path(&path),
+            rlibc::O_RDONLY | libc::O_NOFOLLOW as u32,
+            0,
+        ) {
+            Ok(f) => f,
+            Err(_) => return None,
+        };
+
+        let res = (|| {
+            let st = f.stat().ok()?;
+            if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+                return None;
+            }
+            // this is only set once the whole file is cached and
+            // it's not being written to
+            let pristine = f.get_xattr("user.catfs.src_chksum").ok()??;
+            if to_hex(&pristine) != chksum {
+                return None;
+            }
+
+            // an encrypted cache file stays with whoever has the key
+            if crypt::is_sealed(&f).ok()? {
+                return None;
+            }
+
+            // peers are sent what's in the file, not how it's stored
+            let r = compress::Reader::new(&f).ok()?;
+            let mut n = 0;
+            while n < buf.len() {
+                match r.read_at(&mut buf[n..], (offset + n as u64) as i64) {
+                    Ok(0) => break,
+                    Ok(nread) => n += nread,
+                    Err(_) => return None,
+                }
+            }
+            return Some(n);
+        })();
+
+        if let Err(e) = f.close() {
+            error!("!close {:?} = {}", path, e);
+        }
+        return res;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use super::*;
+
+    #[test]
+    fn read_from_peer() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = Arc::new(LocalBackend::new(&prefix.join("resources")).unwrap());
+        let chksum = to_hex(file::Handle::src_chksum_at(&*src, &"file1").unwrap().as_slice());
+
+        // a peer that has a different copy of file1 with the same size,
+        // so we can tell where it came from
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(stream);
+            let mut line = String::new();
+            while r.read_line(&mut line).unwrap() != 0 {
+                let reply = if line == format!("READ {} 0 {} file1\n", chksum, PEER_READ_CHUNK) {
+                    "OK 6\nPEER1\n"
+                } else {
+                    "NO\n"
+                };
+                r.get_mut().write_all(reply.as_bytes()).unwrap();
+                line.clear();
+            }
+        });
+
+        let peers = PeerBackend::new(src, &[addr], &prefix).unwrap();
+        let mut buf = [0u8; 16];
+
+        let mut f = peers.open(&"file1", rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(f.read_at(&mut buf, 0).unwrap(), 6);
+        assert_eq!(&buf[..6], b"PEER1\n");
+        f.close().unwrap();
+
+        let mut f = peers.open(&"file2", rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(f.read_at(&mut buf, 0).unwrap(), 6);
+        assert_eq!(&buf[..6], b"file2\n");
+        f.close().unwrap();
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn serve_peer() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = Arc::new(LocalBackend::new(&prefix.join("resources")).unwrap());
+        let cache = prefix.join("cache");
+        fs::create_dir_all(cache.join("dir1")).unwrap();
+
+        // only the pristine file should be served
+        for name in ["file1", "dir1/file1"].iter() {
+            let mut f = File::open(
+                &cache.join(name),
+                rlibc::O_WRONLY | rlibc::O_CREAT,
+                0o644,
+            ).unwrap();
+            f.write_at(b"PEER1\n", 0).unwrap();
+            if *name == "file1" {
+                f.set_xattr(
+                    "user.catfs.src_chksum",
+                    file::Handle::src_chksum_at(&*src, name).unwrap().as_slice(),
+                ).unwrap();
+            }
+            f.close().unwrap();
+        }
+
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let allow = parse_allow_list("127.0.0.1").unwrap();
+        let addr = PeerServer::new(cache_dir, allow, 1024 * 1024)
+            .start("127.0.0.1:0")
+            .unwrap();
+
+        let peers = PeerBackend::new(src, &[addr.to_string()], &prefix).unwrap();
+        let mut buf = [0u8; 16];
+
+        let mut f = peers.open(&"file1", rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(f.read_at(&mut buf, 0).unwrap(), 6);
+        assert_eq!(&buf[..6], b"PEER1\n");
+        f.close().unwrap();
+
+        let mut f = peers.open(&"dir1/file1", rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(f.read_at(&mut buf, 0).unwrap(), 11);
+        assert_eq!(&buf[..11], b"dir1/file1\n");
+        f.close().unwrap();
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn allow_list() {
+        let rules = parse_allow_list("10.1.0.0/16,192.168.1.5,::1").unwrap();
+        let allowed = |ip: &str| rules.iter().any(|r| r.matches(&ip.parse().unwrap()));
+        assert!(allowed("10.1.2.3"));
+        assert!(!allowed("10.2.2.3"));
+        assert!(allowed("192.168.1.5"));
+        assert!(!allowed("192.168.1.6"));
+        assert!(allowed("::1"));
+        assert!(allowed("::ffff:10.1.0.1"));
+
+        let everyone = parse_allow_list("0.0.0.0/0,::/0").unwrap();
+        assert!(everyone[1].matches(&"2001:db8::1".parse().unwrap()));
+        assert!(parse_allow_list("10.0.0.0/33").is_err());
+        assert!(parse_allow_list("example.com").is_err());
+    }
+}
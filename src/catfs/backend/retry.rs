@@ -0,0 +1,278 @@
+extern crate libc;
+extern crate time;
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use self::time::Timespec;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use super::{SourceBackend, SourceDir, SourceHints};
+
+pub const DEFAULT_DELAY_MS: u64 = 100;
+// backoff doesn't grow past this, however many retries are allowed
+const MAX_DELAY_SECS: u64 = 10;
+
+// src operations that failed and were tried again, and ones that
+// still failed after all the retries
+static RETRIED: AtomicUsize = AtomicUsize::new(0);
+static GAVE_UP: AtomicUsize = AtomicUsize::new(0);
+
+pub fn retried() -> usize {
+    return RETRIED.load(Ordering::Relaxed);
+}
+
+pub fn gave_up() -> usize {
+    return GAVE_UP.load(Ordering::Relaxed);
+}
+
+// errors a network filesystem returns when a request didn't make it,
+// which may well work if it's sent again
+pub fn is_transient(errno: libc::c_int) -> bool {
+    return match errno {
+        libc::EIO | libc::EAGAIN | libc::ETIMEDOUT | libc::ECONNRESET | libc::ECONNABORTED => true,
+        _ => false,
+    };
+}
+
+pub trait Errno {
+    fn errno(&self) -> Option<libc::c_int>;
+}
+
+impl Errno for io::Error {
+    fn errno(&self) -> Option<libc::c_int> {
+        return self.raw_os_error();
+    }
+}
+
+impl Errno for error::RError<io::Error> {
+    fn errno(&self) -> Option<libc::c_int> {
+        return self.raw_os_error();
+    }
+}
+
+// how many times to try an idempotent src operation again when it
+// fails with a transient error, waiting delay before the first retry
+// and twice as long before each one after that
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Retry {
+    pub fn new(retries: u32, delay: Duration) -> Retry {
+        return Retry {
+            retries: retries,
+            delay: delay,
+        };
+    }
+
+    pub fn run<T, E, F>(&self, mut f: F) -> Result<T, E>
+    where
+        E: Errno + ::std::fmt::Display,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut delay = self.delay;
+        let mut tries = 0;
+        loop {
+            match f() {
+                Err(e) => {
+                    if !e.errno().map(is_transient).unwrap_or(false) {
+                        return Err(e);
+                    }
+                    if tries == self.retries {
+                        if tries != 0 {
+                            GAVE_UP.fetch_add(1, Ordering::Relaxed);
+                            warn!("giving up after {} retries: {}", tries, e);
+                        }
+                        return Err(e);
+                    }
+                    debug!("retrying in {:?}: {}", delay, e);
+                    RETRIED.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(Duration::from_secs(MAX_DELAY_SECS));
+                    tries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// tries src operations that can safely be repeated again when they
+// fail with a transient error. What would change src is passed on
+// once, retrying a rename or a create that did go through could make
+// it fail or do something else
+pub struct RetryBackend {
+    src: Arc<dyn SourceBackend>,
+    retry: Retry,
+}
+
+impl RetryBackend {
+    pub fn new(src: Arc<dyn SourceBackend>) -> RetryBackend {
+        let retry = src.hints().retry;
+        return RetryBackend {
+            src: src,
+            retry: retry,
+        };
+    }
+}
+
+impl SourceBackend for RetryBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<File> {
+        if (flags & (rlibc::O_CREAT | rlibc::O_TRUNC)) != 0 {
+            return self.src.open(path, flags, mode);
+        }
+        return self.retry.run(|| self.src.open(path, flags, mode));
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        return self.retry.run(|| self.src.stat(path));
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return self.retry.run(|| self.src.statvfs());
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        return self.retry.run(|| self.src.opendir(path));
+    }
+
+    fn mkdir(&self, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io::Result<()> {
+        return self.src.mkdir(path, mode);
+    }
+
+    fn mknod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, rdev: u32) -> io::Result<()> {
+        return self.src.mknod(path, mode, rdev);
+    }
+
+    fn unlink(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.unlink(path);
+    }
+
+    fn rmdir(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.rmdir(path);
+    }
+
+    fn symlink(&self, target: &dyn AsRef<Path>, path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.symlink(target, path);
+    }
+
+    fn readlink(&self, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return self.retry.run(|| self.src.readlink(path));
+    }
+
+    fn link(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return self.src.link(path, new_path);
+    }
+
+    fn rename(&self, path: &dyn AsRef<Path>, new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return self.src.rename(path, new_path);
+    }
+
+    fn utimes(
+        &self,
+        path: &dyn AsRef<Path>,
+        atime: &Timespec,
+        mtime: &Timespec,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.utimes(path, atime, mtime, flags);
+    }
+
+    fn chmod(&self, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
+        return self.src.chmod(path, mode, flags);
+    }
+
+    fn chown(
+        &self,
+        path: &dyn AsRef<Path>,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        flags: u32,
+    ) -> io::Result<()> {
+        return self.src.chown(path, uid, gid, flags);
+    }
+
+    fn get_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.retry.run(|| self.src.get_xattr(path, name));
+    }
+
+    fn set_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.src.set_xattr(path, name, value);
+    }
+
+    fn list_xattr(&self, path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return self.retry.run(|| self.src.list_xattr(path));
+    }
+
+    fn remove_xattr(&self, path: &dyn AsRef<Path>, name: &OsStr) -> io::Result<()> {
+        return self.src.remove_xattr(path, name);
+    }
+
+    fn open_for_locks(&self, path: &dyn AsRef<Path>) -> io::Result<File> {
+        return self.retry.run(|| self.src.open_for_locks(path));
+    }
+
+    fn access(&self, path: &dyn AsRef<Path>, mask: libc::c_int) -> io::Result<()> {
+        return self.retry.run(|| self.src.access(path, mask));
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.src.hints();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fails the first n calls with errno
+    fn run(retry: Retry, n: usize, errno: libc::c_int) -> (io::Result<()>, usize) {
+        let mut calls = 0;
+        let res = retry.run(|| {
+            calls += 1;
+            if calls <= n {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            return Ok(());
+        });
+        return (res, calls);
+    }
+
+    #[test]
+    fn retry() {
+        let retry = Retry::new(3, Duration::from_millis(1));
+
+        let before = retried();
+        let (res, calls) = run(retry, 2, libc::EIO);
+        assert!(res.is_ok());
+        assert_eq!(calls, 3);
+        assert!(retried() >= before + 2);
+
+        // still failing after all the retries
+        let before = gave_up();
+        let (res, calls) = run(retry, 4, libc::EAGAIN);
+        assert!(res.is_err());
+        assert_eq!(calls, 4);
+        assert!(gave_up() > before);
+
+        // nothing to gain from trying again
+        let (res, calls) = run(retry, 1, libc::ENOENT);
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        // off by default
+        let (res, calls) = run(Default::default(), 1, libc::EIO);
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+}
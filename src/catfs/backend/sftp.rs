@@ -0,0 +1,706 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+use self::time::Timespec;
+
+use catfs::error;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use super::{erofs, readonly_statvfs, remote_stat, ListedDir, SourceBackend, SourceDir, SourceHints,
+            Spool};
+
+// SFTP version 3, which is what every server speaks:
+// https://tools.ietf.org/html/draft-ietf-secsh-filexfer-02
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FXF_READ: u32 = 0x1;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
+
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+// servers are only required to return 32K per read, to make up for
+// that we keep many reads in flight
+const READ_CHUNK: u32 = 32 * 1024;
+const MAX_READS_IN_FLIGHT: usize = 64;
+
+// the server is talking nonsense, which is just an EIO to whoever is
+// reading
+fn bad_message() -> io::Error {
+    error!("bad sftp message");
+    return io::Error::from_raw_os_error(libc::EIO);
+}
+
+struct Packet {
+    buf: Vec<u8>,
+}
+
+impl Packet {
+    fn new() -> Packet {
+        return Packet { buf: Vec::new() };
+    }
+
+    fn u32(mut self, v: u32) -> Packet {
+        self.buf.extend_from_slice(&[
+            (v >> 24) as u8,
+            (v >> 16) as u8,
+            (v >> 8) as u8,
+            v as u8,
+        ]);
+        return self;
+    }
+
+    fn u64(self, v: u64) -> Packet {
+        return self.u32((v >> 32) as u32).u32(v as u32);
+    }
+
+    fn string(self, s: &[u8]) -> Packet {
+        let mut p = self.u32(s.len() as u32);
+        p.buf.extend_from_slice(s);
+        return p;
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> io::Result<u32> {
+        if self.buf.len() < 4 {
+            return Err(bad_message());
+        }
+        let v = ((self.buf[0] as u32) << 24) | ((self.buf[1] as u32) << 16) |
+            ((self.buf[2] as u32) << 8) | (self.buf[3] as u32);
+        self.buf = &self.buf[4..];
+        return Ok(v);
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let hi = self.u32()? as u64;
+        let lo = self.u32()? as u64;
+        return Ok((hi << 32) | lo);
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        if self.buf.len() < len {
+            return Err(bad_message());
+        }
+        let (s, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        return Ok(s);
+    }
+}
+
+struct Attrs {
+    size: u64,
+    mode: u32,
+    mtime: i64,
+}
+
+impl Attrs {
+    fn parse(r: &mut Reader) -> io::Result<Attrs> {
+        let flags = r.u32()?;
+        let mut attrs = Attrs {
+            size: 0,
+            mode: libc::S_IFREG as u32 | 0o444,
+            mtime: 0,
+        };
+
+        if (flags & SSH_FILEXFER_ATTR_SIZE) != 0 {
+            attrs.size = r.u64()?;
+        }
+        if (flags & SSH_FILEXFER_ATTR_UIDGID) != 0 {
+            r.u32()?;
+            r.u32()?;
+        }
+        if (flags & SSH_FILEXFER_ATTR_PERMISSIONS) != 0 {
+            attrs.mode = r.u32()?;
+        }
+        if (flags & SSH_FILEXFER_ATTR_ACMODTIME) != 0 {
+            r.u32()?;
+            attrs.mtime = r.u32()? as i64;
+        }
+        if (flags & SSH_FILEXFER_ATTR_EXTENDED) != 0 {
+            for _ in 0..r.u32()? {
+                r.string()?;
+                r.string()?;
+            }
+        }
+
+        return Ok(attrs);
+    }
+
+    fn is_dir(&self) -> bool {
+        return (self.mode & libc::S_IFMT as u32) == libc::S_IFDIR as u32;
+    }
+}
+
+fn status_error(payload: &[u8]) -> io::Error {
+    let mut r = Reader { buf: payload };
+    let errno = match r.u32() {
+        Ok(SSH_FX_NO_SUCH_FILE) => libc::ENOENT,
+        Ok(SSH_FX_PERMISSION_DENIED) => libc::EACCES,
+        Ok(SSH_FX_OP_UNSUPPORTED) => libc::ENOTSUP,
+        _ => libc::EIO,
+    };
+    return io::Error::from_raw_os_error(errno);
+}
+
+fn is_eof(payload: &[u8]) -> bool {
+    return Reader { buf: payload }.u32().ok() == Some(SSH_FX_EOF);
+}
+
+// one ssh session running the sftp subsystem
+struct Conn {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    next_id: u32,
+    // set after a transport error, the session is unusable after that
+    broken: bool,
+}
+
+impl Conn {
+    fn connect(host: &str, port: &Option<String>) -> io::Result<Conn> {
+        let mut cmd = Command::new("ssh");
+        cmd.arg("-x").arg("-a").arg("-s");
+        if let Some(ref port) = *port {
+            cmd.arg("-p").arg(port);
+        }
+        cmd.arg(host).arg("sftp");
+        debug!("<-- sftp connect {}", host);
+
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let mut conn = Conn {
+            stdin: BufWriter::new(child.stdin.take().unwrap()),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child: child,
+            next_id: 0,
+            broken: false,
+        };
+
+        // INIT is the only message without a request id
+        let mut init = Packet::new().u32(5).buf;
+        init.push(SSH_FXP_INIT);
+        init.extend_from_slice(&Packet::new().u32(3).buf);
+        conn.stdin.write_all(&init)?;
+        conn.stdin.flush()?;
+
+        let body = conn.read_packet()?;
+        if body.len() < 5 || body[0] != SSH_FXP_VERSION {
+            return Err(bad_message());
+        }
+
+        return Ok(conn);
+    }
+
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.stdout.read_exact(&mut len)?;
+        let len = Reader { buf: &len }.u32()? as usize;
+        if len == 0 || len > 1024 * 1024 {
+            return Err(bad_message());
+        }
+        let mut body = vec![0u8; len];
+        self.stdout.read_exact(&mut body)?;
+        return Ok(body);
+    }
+
+    fn send(&mut self, t: u8, payload: Packet) -> io::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut msg = Packet::new().u32((payload.buf.len() + 5) as u32).buf;
+        msg.push(t);
+        msg.extend_from_slice(&Packet::new().u32(id).buf);
+        msg.extend_from_slice(&payload.buf);
+        if let Err(e) = self.stdin.write_all(&msg) {
+            self.broken = true;
+            return Err(e);
+        }
+        return Ok(id);
+    }
+
+    // returns the id, type and payload of the next response
+    fn recv(&mut self) -> io::Result<(u32, u8, Vec<u8>)> {
+        if let Err(e) = self.stdin.flush() {
+            self.broken = true;
+            return Err(e);
+        }
+
+        match self.read_packet() {
+            Ok(body) => {
+                if body.len() < 5 {
+                    self.broken = true;
+                    return Err(bad_message());
+                }
+                let id = Reader { buf: &body[1..5] }.u32()?;
+                return Ok((id, body[0], body[5..].to_vec()));
+            }
+            Err(e) => {
+                self.broken = true;
+                return Err(e);
+            }
+        }
+    }
+
+    // sends one request and waits for its response, failing unless
+    // the response has the expected type
+    fn call(&mut self, t: u8, payload: Packet, expect: u8) -> io::Result<Vec<u8>> {
+        let id = self.send(t, payload)?;
+        let (rid, rt, res) = self.recv()?;
+        if rid != id {
+            self.broken = true;
+            return Err(bad_message());
+        }
+
+        if rt == expect {
+            return Ok(res);
+        } else if rt == SSH_FXP_STATUS {
+            return Err(status_error(&res));
+        } else {
+            return Err(bad_message());
+        }
+    }
+
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let res = self.call(
+            SSH_FXP_CLOSE,
+            Packet::new().string(handle),
+            SSH_FXP_STATUS,
+        )?;
+        if (Reader { buf: &res }).u32()? != 0 {
+            return Err(status_error(&res));
+        }
+        return Ok(());
+    }
+
+    fn download(&mut self, handle: &[u8], file: &File) -> io::Result<()> {
+        let mut next = 0u64;
+        let mut eof = false;
+        let mut in_flight: HashMap<u32, (u64, u32)> = HashMap::new();
+
+        loop {
+            while !eof && in_flight.len() < MAX_READS_IN_FLIGHT {
+                let id = self.send(
+                    SSH_FXP_READ,
+                    Packet::new().string(handle).u64(next).u32(READ_CHUNK),
+                )?;
+                in_flight.insert(id, (next, READ_CHUNK));
+                next += READ_CHUNK as u64;
+            }
+            if in_flight.is_empty() {
+                return Ok(());
+            }
+
+            let (id, t, res) = self.recv()?;
+            let (offset, len) = in_flight.remove(&id).ok_or_else(bad_message)?;
+            if t == SSH_FXP_DATA {
+                let data = Reader { buf: &res }.string()?;
+                let mut written = 0;
+                while written < data.len() {
+                    written += file.write_at(&data[written..], offset as i64 + written as i64)?;
+                }
+                if (data.len() as u32) < len {
+                    // servers may return less than asked for, ask for
+                    // the rest again
+                    let rest = len - data.len() as u32;
+                    let offset = offset + data.len() as u64;
+                    let id = self.send(
+                        SSH_FXP_READ,
+                        Packet::new().string(handle).u64(offset).u32(rest),
+                    )?;
+                    in_flight.insert(id, (offset, rest));
+                }
+            } else if t == SSH_FXP_STATUS && is_eof(&res) {
+                eof = true;
+            } else {
+                // there's no telling the other reads apart from what
+                // comes next, give up on this session
+                self.broken = true;
+                if t == SSH_FXP_STATUS {
+                    return Err(status_error(&res));
+                } else {
+                    return Err(bad_message());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn is_url(s: &OsStr) -> bool {
+    return s.as_bytes().starts_with(b"sftp://");
+}
+
+// a read-only source on a server that's reachable over ssh. Uses the
+// ssh binary so ~/.ssh/config, keys and agents all work as usual
+pub struct SftpBackend {
+    // [user@]host, as given to ssh
+    host: String,
+    port: Option<String>,
+    // remote directory that is the root of the source, relative
+    // paths are relative to the home directory
+    root: Vec<u8>,
+    // connected on first use, and again after the session breaks
+    conn: Mutex<Option<Conn>>,
+    spool: Spool,
+    hints: SourceHints,
+}
+
+impl SftpBackend {
+    pub fn new(url: &OsStr, tmp_dir: &dyn AsRef<Path>) -> io::Result<SftpBackend> {
+        let invalid = || {
+            error!("expected sftp://[user@]host[:port]/path");
+            return io::Error::from_raw_os_error(libc::EINVAL);
+        };
+        if !is_url(url) {
+            return Err(invalid());
+        }
+        let rest = &url.as_bytes()["sftp://".len()..];
+        let (authority, path) = match rest.iter().position(|c| *c == b'/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, &b""[..]),
+        };
+        let authority = String::from_utf8(authority.to_vec()).map_err(|_| invalid())?;
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                authority[..i].to_owned(),
+                Some(authority[i + 1..].to_owned()),
+            ),
+            None => (authority.clone(), None),
+        };
+        if host.is_empty() || host.starts_with('-') {
+            return Err(invalid());
+        }
+        if let Some(ref port) = port {
+            if port.parse::<u16>().is_err() {
+                return Err(invalid());
+            }
+        }
+
+        // sftp://host/~/dir is relative to the home directory
+        let mut root = path.to_vec();
+        if root.starts_with(b"/~/") {
+            root = root[3..].to_vec();
+        } else if root == b"/~" {
+            root.clear();
+        }
+        while root.len() > 1 && root.ends_with(b"/") {
+            root.pop();
+        }
+        if root.is_empty() {
+            root.push(b'.');
+        }
+
+        return Ok(SftpBackend {
+            host: host,
+            port: port,
+            root: root,
+            conn: Mutex::new(None),
+            spool: Spool::new(tmp_dir, "sftp"),
+            hints: Default::default(),
+        });
+    }
+
+    pub fn set_hints(&mut self, hints: SourceHints) {
+        self.hints = hints;
+    }
+
+    fn remote_path(&self, path: &dyn AsRef<Path>) -> Vec<u8> {
+        let path = path.as_ref().as_os_str().as_bytes();
+        let mut p = self.root.clone();
+        if !path.is_empty() {
+            if !p.ends_with(b"/") {
+                p.push(b'/');
+            }
+            p.extend_from_slice(path);
+        }
+        return p;
+    }
+
+    fn with_conn<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Conn) -> io::Result<T>,
+    {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.is_none() {
+            *conn = Some(Conn::connect(&self.host, &self.port)?);
+        }
+
+        let res = f(conn.as_mut().unwrap());
+        if conn.as_ref().unwrap().broken {
+            error!("sftp session to {} broke, will reconnect", self.host);
+            *conn = None;
+        }
+        return res;
+    }
+
+    fn attrs(&self, path: &dyn AsRef<Path>) -> io::Result<Attrs> {
+        let path = self.remote_path(path);
+        return self.with_conn(|conn| {
+            let res = conn.call(
+                SSH_FXP_STAT,
+                Packet::new().string(&path),
+                SSH_FXP_ATTRS,
+            )?;
+            return Attrs::parse(&mut Reader { buf: &res });
+        });
+    }
+}
+
+impl SourceBackend for SftpBackend {
+    fn open(&self, path: &dyn AsRef<Path>, flags: u32, _mode: libc::mode_t) -> io::Result<File> {
+        if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY ||
+            (flags & (rlibc::O_CREAT | rlibc::O_TRUNC)) != 0
+        {
+            return Err(erofs());
+        }
+
+        let mut file = self.spool.create()?;
+        let remote_path = self.remote_path(path);
+        let res = self.with_conn(|conn| {
+            let res = conn.call(
+                SSH_FXP_OPEN,
+                Packet::new().string(&remote_path).u32(SSH_FXF_READ).u32(0),
+                SSH_FXP_HANDLE,
+            )?;
+            let handle = Reader { buf: &res }.string()?.to_vec();
+
+            let res = conn.call(
+                SSH_FXP_FSTAT,
+                Packet::new().string(&handle),
+                SSH_FXP_ATTRS,
+            ).and_then(|res| Attrs::parse(&mut Reader { buf: &res }))
+                .and_then(|attrs| {
+                    conn.download(&handle, &file)?;
+                    return Ok(attrs);
+                });
+
+            if conn.broken {
+                return res;
+            }
+            conn.close(&handle)?;
+            return res;
+        });
+
+        let res = res.and_then(|attrs| {
+            // the checksum we use to validate the cache includes the
+            // mtime, so make the copy look like the original
+            let mtime = Timespec::new(attrs.mtime, 0);
+            return rlibc::futimens(file.as_raw_fd(), &mtime, &mtime);
+        });
+
+        if let Err(e) = res {
+            file.close()?;
+            return Err(e);
+        }
+        return Ok(file);
+    }
+
+    fn stat(&self, path: &dyn AsRef<Path>) -> io::Result<rlibc::stat64> {
+        let attrs = self.attrs(path)?;
+        return Ok(remote_stat(
+            path.as_ref(),
+            attrs.mode,
+            attrs.size,
+            attrs.mtime,
+        ));
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        return Ok(readonly_statvfs());
+    }
+
+    fn opendir(&self, path: &dyn AsRef<Path>) -> io::Result<Box<dyn SourceDir>> {
+        let path = path.as_ref();
+        let mut dir = ListedDir::new(path);
+        let remote_path = self.remote_path(&path);
+
+        self.with_conn(|conn| {
+            let res = conn.call(
+                SSH_FXP_OPENDIR,
+                Packet::new().string(&remote_path),
+                SSH_FXP_HANDLE,
+            )?;
+            let handle = Reader { buf: &res }.string()?.to_vec();
+
+            let mut res = Ok(());
+            loop {
+                let id = conn.send(SSH_FXP_READDIR, Packet::new().string(&handle))?;
+                let (rid, t, names) = conn.recv()?;
+                if rid != id {
+                    conn.broken = true;
+                    return Err(bad_message());
+                }
+                if t == SSH_FXP_STATUS {
+                    if !is_eof(&names) {
+                        res = Err(status_error(&names));
+                    }
+                    break;
+                } else if t != SSH_FXP_NAME {
+                    return Err(bad_message());
+                }
+
+                let mut r = Reader { buf: &names };
+                for _ in 0..r.u32()? {
+                    let name = r.string()?;
+                    r.string()?;
+                    let attrs = Attrs::parse(&mut r)?;
+                    if name != b"." && name != b".." {
+                        dir.push(path, OsStr::from_bytes(name), attrs.is_dir());
+                    }
+                }
+            }
+
+            conn.close(&handle)?;
+            return res;
+        })?;
+
+        return Ok(Box::new(dir));
+    }
+
+    fn mkdir(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn mknod(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t, _rdev: u32) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn unlink(&self, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn rmdir(&self, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn symlink(&self, _target: &dyn AsRef<Path>, _path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    // nothing here is ever a symlink
+    fn readlink(&self, _path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    fn link(&self, _path: &dyn AsRef<Path>, _new_path: &dyn AsRef<Path>) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn rename(&self, _path: &dyn AsRef<Path>, _new_path: &dyn AsRef<Path>) -> error::Result<()> {
+        return error::propagate(erofs());
+    }
+
+    fn utimes(
+        &self,
+        _path: &dyn AsRef<Path>,
+        _atime: &Timespec,
+        _mtime: &Timespec,
+        _flags: u32,
+    ) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn chmod(&self, _path: &dyn AsRef<Path>, _mode: libc::mode_t, _flags: u32) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn get_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return Ok(None);
+    }
+
+    fn set_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn list_xattr(&self, _path: &dyn AsRef<Path>) -> io::Result<Vec<OsString>> {
+        return Ok(Vec::new());
+    }
+
+    fn remove_xattr(&self, _path: &dyn AsRef<Path>, _name: &OsStr) -> io::Result<()> {
+        return Err(erofs());
+    }
+
+    fn hints(&self) -> SourceHints {
+        return self.hints.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sftp_url() {
+        let src = SftpBackend::new(OsStr::new("sftp://me@host:2222/data/"), &"/tmp").unwrap();
+        assert_eq!(src.host, "me@host");
+        assert_eq!(src.port, Some(String::from("2222")));
+        assert_eq!(src.remote_path(&""), b"/data".to_vec());
+        assert_eq!(src.remote_path(&"a/b"), b"/data/a/b".to_vec());
+
+        let src = SftpBackend::new(OsStr::new("sftp://host/~/data"), &"/tmp").unwrap();
+        assert_eq!(src.port, None);
+        assert_eq!(src.remote_path(&"a"), b"data/a".to_vec());
+
+        let src = SftpBackend::new(OsStr::new("sftp://host"), &"/tmp").unwrap();
+        assert_eq!(src.remote_path(&"a"), b"./a".to_vec());
+
+        assert!(SftpBackend::new(OsStr::new("sftp://host:ssh/"), &"/tmp").is_err());
+        assert!(SftpBackend::new(OsStr::new("sftp://-oProxyCommand=x/"), &"/tmp").is_err());
+    }
+
+    #[test]
+    fn parse_attrs() {
+        let buf = Packet::new()
+            .u32(SSH_FILEXFER_ATTR_SIZE | SSH_FILEXFER_ATTR_PERMISSIONS |
+                SSH_FILEXFER_ATTR_ACMODTIME)
+            .u64(1 << 33)
+            .u32(libc::S_IFDIR as u32 | 0o755)
+            .u32(1)
+            .u32(2)
+            .buf;
+        let attrs = Attrs::parse(&mut Reader { buf: &buf }).unwrap();
+        assert_eq!(attrs.size, 1 << 33);
+        assert!(attrs.is_dir());
+        assert_eq!(attrs.mtime, 2);
+    }
+}
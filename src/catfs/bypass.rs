@@ -0,0 +1,117 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::str::FromStr;
+
+// processes whose IO goes straight to the source, for example a
+// backup that reads everything once and would otherwise evict the
+// working set. A process is matched by its pid, by the name of its
+// executable, or by having an environment variable set, which also
+// catches everything it runs
+#[derive(Default, Debug, PartialEq)]
+pub struct Bypass {
+    pids: Vec<u32>,
+    names: Vec<OsString>,
+    env: Vec<OsString>,
+}
+
+impl FromStr for Bypass {
+    type Err = String;
+
+    // a comma separated list such as "1234,restic,env:CATFS_BYPASS"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bypass: Bypass = Default::default();
+
+        for p in s.split(',') {
+            let p = p.trim();
+            if p.is_empty() {
+                return Err("empty bypass process in ".to_owned() + s);
+            } else if p.starts_with("env:") {
+                bypass.env.push(OsString::from(&p[4..]));
+            } else if let Ok(pid) = p.parse() {
+                bypass.pids.push(pid);
+            } else {
+                bypass.names.push(OsString::from(p));
+            }
+        }
+
+        return Ok(bypass);
+    }
+}
+
+fn exe_name(pid: u32) -> Option<OsString> {
+    if let Ok(exe) = fs::read_link(format!("/proc/{}/exe", pid)) {
+        if let Some(name) = exe.file_name() {
+            return Some(name.to_os_string());
+        }
+    }
+    // exe is only readable by the owner, comm is truncated but isn't
+    if let Ok(mut comm) = fs::read(format!("/proc/{}/comm", pid)) {
+        if comm.last() == Some(&b'\n') {
+            comm.pop();
+        }
+        return Some(OsStr::from_bytes(&comm).to_os_string());
+    }
+    return None;
+}
+
+fn has_env(environ: &[u8], name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    for var in environ.split(|b| *b == 0) {
+        if var.len() > name.len() && var.starts_with(name) && var[name.len()] == b'=' {
+            return true;
+        }
+    }
+    return false;
+}
+
+impl Bypass {
+    pub fn matches(&self, pid: u32) -> bool {
+        // requests the kernel makes on its own
+        if pid == 0 {
+            return false;
+        }
+        if self.pids.contains(&pid) {
+            return true;
+        }
+
+        if !self.names.is_empty() {
+            if let Some(name) = exe_name(pid) {
+                if self.names.contains(&name) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.env.is_empty() {
+            if let Ok(environ) = fs::read(format!("/proc/{}/environ", pid)) {
+                if self.env.iter().any(|name| has_env(&environ, name)) {
+                    return true;
+                }
+            }
+        }
+
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let bypass: Bypass = "1234, restic,env:CATFS_BYPASS".parse().unwrap();
+        assert_eq!(bypass.pids, vec![1234]);
+        assert_eq!(bypass.names, vec![OsString::from("restic")]);
+        assert_eq!(bypass.env, vec![OsString::from("CATFS_BYPASS")]);
+        assert!("1234,".parse::<Bypass>().is_err());
+
+        assert!(has_env(b"A=1\0CATFS_BYPASS=\0", OsStr::new("CATFS_BYPASS")));
+        assert!(!has_env(b"CATFS_BYPASS_NOT=1\0", OsStr::new("CATFS_BYPASS")));
+        assert!(!has_env(b"CATFS_BYPASS\0", OsStr::new("CATFS_BYPASS")));
+
+        assert!(!bypass.matches(0));
+        assert!(bypass.matches(1234));
+    }
+}
@@ -0,0 +1,343 @@
+extern crate libc;
+extern crate lz4_flex;
+extern crate xattr;
+extern crate zstd;
+
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use self::xattr::FileExt;
+
+use catfs::crypt;
+use catfs::rlibc::File;
+
+// which codec a --cache-compress cache file is compressed with, plain
+// cache files don't have it. It's only set once the index is written
+pub const COMPRESS_XATTR: &'static str = "user.catfs.compress";
+
+// how much is compressed at a time, a read decompresses at least this
+pub const CHUNK_SIZE: usize = 128 * 1024;
+
+const MAGIC: &'static [u8; 8] = b"catfsz01";
+// the size before compression, how many chunks there are and MAGIC
+const FOOTER_SIZE: usize = 24;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    // fast enough to not be noticed next to the disk
+    Lz4,
+    // smaller, but takes more CPU to read
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err("unrecognized codec ".to_owned() + s),
+        };
+    }
+}
+
+impl Codec {
+    pub fn to_str(&self) -> &'static str {
+        return match *self {
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        };
+    }
+
+    // what f is compressed with, None if it's plain
+    pub fn of(f: &File) -> io::Result<Option<Codec>> {
+        return match f.get_xattr(COMPRESS_XATTR)? {
+            Some(v) => {
+                match Codec::from_str(&String::from_utf8_lossy(&v)) {
+                    Ok(codec) => Ok(Some(codec)),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                }
+            }
+            None => Ok(None),
+        };
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        return match *self {
+            Codec::Lz4 => Ok(lz4_flex::block::compress(data)),
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+        };
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        return match *self {
+            Codec::Lz4 => {
+                lz4_flex::block::decompress(data, size).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                })
+            }
+            Codec::Zstd => zstd::bulk::decompress(data, size),
+        };
+    }
+}
+
+// a compressed cache file is CHUNK_SIZE chunks compressed one by one
+// and written back to back, then where each of them starts and where
+// the last one ends, then the footer. Page in appends to it and
+// readers go through the same Chunks while it does
+pub struct Chunks {
+    codec: Codec,
+    // the size before compression
+    size: i64,
+    // where each chunk starts in the file, and where the last one ends
+    offsets: Vec<u64>,
+    // reads are usually smaller than a chunk and in order, so the
+    // last one decompressed is kept around
+    last: Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl Chunks {
+    pub fn new(codec: Codec) -> Chunks {
+        return Chunks {
+            codec: codec,
+            size: 0,
+            offsets: vec![0],
+            last: Mutex::new(None),
+        };
+    }
+
+    // the index of f, None if f isn't compressed
+    pub fn load(f: &File) -> io::Result<Option<Chunks>> {
+        let codec = match Codec::of(f)? {
+            Some(codec) => codec,
+            None => return Ok(None),
+        };
+
+        let file_size = f.filesize()?;
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(corrupt("no footer"));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        read_exact_at(f, &mut footer, file_size - FOOTER_SIZE as u64)?;
+        if &footer[16..] != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let size = get_u64(&footer[0..8]);
+        let n = get_u64(&footer[8..16]);
+        let index_size = (n + 1) * 8;
+        if n != (size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64 ||
+            index_size + FOOTER_SIZE as u64 > file_size
+        {
+            return Err(corrupt("bad index size"));
+        }
+
+        let index_start = file_size - FOOTER_SIZE as u64 - index_size;
+        let mut index = vec![0u8; index_size as usize];
+        read_exact_at(f, &mut index, index_start)?;
+        let offsets: Vec<u64> = index.chunks(8).map(get_u64).collect();
+        if offsets[0] != 0 || offsets[n as usize] != index_start ||
+            offsets.windows(2).any(|w| w[0] > w[1])
+        {
+            return Err(corrupt("bad index"));
+        }
+
+        return Ok(Some(Chunks {
+            codec: codec,
+            size: size as i64,
+            offsets: offsets,
+            last: Mutex::new(None),
+        }));
+    }
+
+    pub fn size(&self) -> i64 {
+        return self.size;
+    }
+
+    // compresses data, which is the next chunk, to the end of f. Only
+    // the last chunk can be short
+    pub fn append(&mut self, f: &File, data: &[u8]) -> io::Result<()> {
+        let compressed = self.codec.compress(data)?;
+        let end = *self.offsets.last().unwrap();
+        write_all_at(f, &compressed, end)?;
+        self.offsets.push(end + compressed.len() as u64);
+        self.size += data.len() as i64;
+        return Ok(());
+    }
+
+    // writes the index after the chunks, after this f can be loaded
+    pub fn finish(&self, f: &File) -> io::Result<()> {
+        let end = *self.offsets.last().unwrap();
+        let mut index = Vec::with_capacity(self.offsets.len() * 8 + FOOTER_SIZE);
+        for offset in &self.offsets {
+            put_u64(&mut index, *offset);
+        }
+        put_u64(&mut index, self.size as u64);
+        put_u64(&mut index, (self.offsets.len() - 1) as u64);
+        index.extend_from_slice(MAGIC);
+        write_all_at(f, &index, end)?;
+        f.truncate(end + index.len() as u64)?;
+        return f.set_xattr(COMPRESS_XATTR, self.codec.to_str().as_bytes());
+    }
+
+    pub fn read_at(&self, f: &File, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        let mut nread = 0;
+        while nread < buf.len() {
+            let pos = offset + nread as i64;
+            if pos >= self.size {
+                break;
+            }
+            let i = pos as usize / CHUNK_SIZE;
+            if i + 1 >= self.offsets.len() {
+                // not appended yet
+                break;
+            }
+            let chunk = self.chunk(f, i)?;
+            let skip = pos as usize % CHUNK_SIZE;
+            if skip >= chunk.len() {
+                break;
+            }
+            let n = (buf.len() - nread).min(chunk.len() - skip);
+            buf[nread..nread + n].copy_from_slice(&chunk[skip..skip + n]);
+            nread += n;
+        }
+        return Ok(nread);
+    }
+
+    fn chunk(&self, f: &File, i: usize) -> io::Result<Arc<Vec<u8>>> {
+        if let Some((last, ref data)) = *self.last.lock().unwrap() {
+            if last == i {
+                return Ok(data.clone());
+            }
+        }
+
+        let start = self.offsets[i];
+        let mut compressed = vec![0u8; (self.offsets[i + 1] - start) as usize];
+        read_exact_at(f, &mut compressed, start)?;
+        let size = (self.size as usize - i * CHUNK_SIZE).min(CHUNK_SIZE);
+        let data = Arc::new(self.codec.decompress(&compressed, size)?);
+        if data.len() != size {
+            return Err(corrupt("short chunk"));
+        }
+        *self.last.lock().unwrap() = Some((i, data.clone()));
+        return Ok(data);
+    }
+}
+
+// reads a cache file the same way whether it's compressed or not, for
+// what looks at cache files outside of a Handle
+pub struct Reader<'a> {
+    f: &'a File,
+    chunks: Option<Chunks>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(f: &'a File) -> io::Result<Reader<'a>> {
+        return Ok(Reader {
+            f: f,
+            chunks: Chunks::load(f)?,
+        });
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        return self.chunks.is_some();
+    }
+
+    pub fn size(&self) -> io::Result<i64> {
+        return match self.chunks {
+            Some(ref chunks) => Ok(chunks.size()),
+            None => Ok(crypt::size(self.f)? as i64),
+        };
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        return match self.chunks {
+            Some(ref chunks) => chunks.read_at(self.f, buf, offset),
+            None => self.f.read_at(buf, offset),
+        };
+    }
+}
+
+fn corrupt(what: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, "compressed cache file: ".to_owned() + what);
+}
+
+fn get_u64(b: &[u8]) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&b[..8]);
+    return u64::from_le_bytes(v);
+}
+
+fn put_u64(v: &mut Vec<u8>, n: u64) {
+    v.extend_from_slice(&n.to_le_bytes());
+}
+
+fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        let n = f.read_at(&mut buf[nread..], offset as i64 + nread as i64)?;
+        if n == 0 {
+            return Err(corrupt("truncated"));
+        }
+        nread += n;
+    }
+    return Ok(());
+}
+
+fn write_all_at(f: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    let mut nwritten = 0;
+    while nwritten < buf.len() {
+        nwritten += f.write_at(&buf[nwritten..], offset as i64 + nwritten as i64)?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use catfs;
+    use catfs::rlibc;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let prefix = catfs::tests::copy_resources();
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| (i / 1000) as u8).collect();
+
+        for codec in &[Codec::Lz4, Codec::Zstd] {
+            let path = prefix.join(codec.to_str());
+            let flags = rlibc::O_RDWR | rlibc::O_CREAT;
+            let mut f = File::open(&path, flags, 0o666).unwrap();
+            let mut chunks = Chunks::new(*codec);
+            for chunk in data.chunks(CHUNK_SIZE) {
+                chunks.append(&f, chunk).unwrap();
+            }
+            // readable while it's being written
+            let mut buf = vec![0u8; 10];
+            assert_eq!(chunks.read_at(&f, &mut buf, CHUNK_SIZE as i64 - 5).unwrap(), 10);
+            assert_eq!(&buf[..], &data[CHUNK_SIZE - 5..CHUNK_SIZE + 5]);
+            chunks.finish(&f).unwrap();
+            assert!((f.filesize().unwrap() as usize) < data.len() / 10);
+
+            let r = Reader::new(&f).unwrap();
+            assert!(r.is_compressed());
+            assert_eq!(r.size().unwrap(), data.len() as i64);
+            let mut buf = vec![0u8; data.len() + 10];
+            let mut nread = 0;
+            loop {
+                let n = r.read_at(&mut buf[nread..nread + 4096usize.min(buf.len() - nread)], nread as i64)
+                    .unwrap();
+                if n == 0 {
+                    break;
+                }
+                nread += n;
+            }
+            assert_eq!(&buf[..nread], &data[..]);
+            f.close().unwrap();
+        }
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use catfs::error;
+
+// a client that stops talking shouldn't hold up the others, or unmount
+const CLIENT_TIMEOUT_SECS: u64 = 10;
+
+// a unix socket to poke at a running catfs with, ex: echo stats | nc
+// -U <socket>. Each line is a command and an optional argument, which
+// gets back what the command has to say followed by OK, or ERR and
+// why it failed. Clients are served one at a time
+pub struct ControlServer {
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+    t: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl ControlServer {
+    pub fn new(path: &Path) -> ControlServer {
+        return ControlServer {
+            path: path.to_path_buf(),
+            stopping: Arc::new(AtomicBool::new(false)),
+            t: Mutex::new(None),
+        };
+    }
+
+    // handler is called with each command and its argument, and
+    // writes its reply to the vec
+    pub fn start<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&str, &str, &mut Vec<u8>) -> error::Result<()> + Send + 'static,
+    {
+        // left over from a catfs that didn't get to clean up
+        if let Ok(m) = fs::symlink_metadata(&self.path) {
+            if m.file_type().is_socket() {
+                fs::remove_file(&self.path)?;
+            }
+        }
+        let listener = UnixListener::bind(&self.path)?;
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+
+        let stopping = self.stopping.clone();
+        let t = thread::Builder::new()
+            .name(String::from("control"))
+            .spawn(move || for stream in listener.incoming() {
+                if stopping.load(Ordering::Relaxed) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(stream, &handler) {
+                            debug!("!control = {}", e);
+                        }
+                    }
+                    Err(e) => error!("!accept = {}", e),
+                }
+            })?;
+        *self.t.lock().unwrap() = Some(t);
+        return Ok(());
+    }
+
+    pub fn stop(&self) {
+        if let Some(t) = self.t.lock().unwrap().take() {
+            self.stopping.store(true, Ordering::Relaxed);
+            // wakes up accept
+            let _ = UnixStream::connect(&self.path);
+            let _ = t.join();
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("!unlink {:?} = {}", self.path, e);
+            }
+        }
+    }
+}
+
+fn serve<F>(stream: UnixStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&str, &str, &mut Vec<u8>) -> error::Result<()>,
+{
+    stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+
+    let mut r = BufReader::new(stream);
+    let mut line = String::new();
+    while r.read_line(&mut line)? != 0 {
+        let (cmd, arg) = {
+            let l = line.trim();
+            match l.find(' ') {
+                Some(i) => (&l[..i], l[i + 1..].trim_start()),
+                None => (l, ""),
+            }
+        };
+
+        let mut out = Vec::new();
+        if !cmd.is_empty() {
+            match handler(cmd, arg, &mut out) {
+                Ok(_) => {
+                    debug!("<-- control {} {}", cmd, arg);
+                    out.extend_from_slice(b"OK\n");
+                }
+                Err(e) => {
+                    debug!("<-- !control {} {} = {}", cmd, arg, e);
+                    // without the backtrace
+                    let e: &io::Error = &e;
+                    out.clear();
+                    out.extend_from_slice(format!("ERR {}\n", e).as_bytes());
+                }
+            }
+        }
+        r.get_mut().write_all(&out)?;
+        line.clear();
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    extern crate libc;
+    use std::io::Read;
+    use std::net::Shutdown;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let path = prefix.join("ctl");
+
+        let server = ControlServer::new(&path);
+        server
+            .start(|cmd, arg, out| match cmd {
+                "echo" => {
+                    out.extend_from_slice(arg.as_bytes());
+                    out.push(b'\n');
+                    return Ok(());
+                }
+                _ => return error::propagate(io::Error::from_raw_os_error(libc::EINVAL)),
+            })
+            .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut c = UnixStream::connect(&path).unwrap();
+        c.write_all(b"echo hello  world\n\nfoo\n").unwrap();
+        c.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        c.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("hello  world\nOK\nERR "));
+        assert_eq!(reply.lines().count(), 3);
+
+        server.stop();
+        assert!(!path.exists());
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,520 @@
+extern crate aes_gcm;
+extern crate libc;
+extern crate sha2;
+extern crate xattr;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use self::aes_gcm::{Aes256Gcm, Nonce};
+use self::aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use self::sha2::{Sha256, Digest};
+use self::xattr::FileExt;
+
+use catfs::rlibc::File;
+
+// set on cache files written with --cache-encrypt, to the id of the
+// key they were written with
+pub const ENCRYPT_XATTR: &'static str = "user.catfs.encrypt";
+// the chunks of an encrypted cache file that were never written,
+// encrypted so a chunk can't be zeroed out to pass for one
+const HOLES_XATTR: &'static str = "user.catfs.holes";
+const HOLES_AAD: &'static [u8] = b"catfs holes";
+
+// how much is encrypted at a time. Each chunk is stored as its nonce,
+// then what it encrypts to, then the tag, at CHUNK_SIZE + OVERHEAD
+// apart so a chunk can be found without an index
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+const RAW_CHUNK_SIZE: usize = CHUNK_SIZE + OVERHEAD;
+
+// the key cache files are encrypted with. Names and xattrs are left in
+// the clear, only what's in the files is protected
+#[derive(Clone)]
+pub struct Key {
+    cipher: Arc<Aes256Gcm>,
+    id: Vec<u8>,
+    // what's open, by the dev and ino of the cache file
+    files: Arc<Mutex<HashMap<(u64, u64), Weak<FileState>>>>,
+    // the cache file this is for, see for_file
+    file: Arc<FileState>,
+}
+
+#[derive(Default)]
+struct FileState {
+    // a write may rewrite the chunks around it, which readers and
+    // other writers of the same file can't see half done
+    lock: RwLock<()>,
+    // [start, end) ranges of chunks that were never written, such as
+    // past where the file was extended to, which read as zeros. A
+    // miss is looked up again in HOLES_XATTR in case another catfs
+    // sharing the cache dir made it
+    holes: Mutex<Vec<(u64, u64)>>,
+}
+
+impl Key {
+    // the key file has the 32 byte key, or the 64 hex digits of it
+    pub fn load(path: &Path) -> io::Result<Key> {
+        let v = fs::read(path)?;
+        if v.len() == 32 {
+            return Key::new(&v);
+        }
+        let hex = String::from_utf8_lossy(&v);
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(bad_key());
+        }
+        let mut key = Vec::new();
+        for i in 0..32 {
+            match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(b) => key.push(b),
+                Err(_) => return Err(bad_key()),
+            }
+        }
+        return Key::new(&key);
+    }
+
+    pub fn new(key: &[u8]) -> io::Result<Key> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| bad_key())?;
+        let mut hasher = Sha256::new();
+        hasher.update(b"catfs cache key\0");
+        hasher.update(key);
+        return Ok(Key {
+            cipher: Arc::new(cipher),
+            id: hasher.finalize()[..8].to_vec(),
+            files: Default::default(),
+            file: Default::default(),
+        });
+    }
+
+    // the key for reading and writing f, which shares its lock with
+    // everyone else who has f open, and no one else
+    pub fn for_file(&self, f: &File) -> io::Result<Key> {
+        let st = f.stat()?;
+        let id = (st.st_dev as u64, st.st_ino as u64);
+        let mut files = self.files.lock().unwrap();
+        files.retain(|_, state| state.upgrade().is_some());
+        let file = match files.get(&id).and_then(|state| state.upgrade()) {
+            Some(state) => state,
+            None => {
+                let state = Arc::new(FileState::default());
+                files.insert(id, Arc::downgrade(&state));
+                state
+            }
+        };
+        return Ok(Key {
+            cipher: self.cipher.clone(),
+            id: self.id.clone(),
+            files: self.files.clone(),
+            file: file,
+        });
+    }
+
+    // marks an empty cache file as ours, everything written to it from
+    // now on has to go through us
+    pub fn seal(&self, f: &File) -> io::Result<()> {
+        let _lock = self.file.lock.write().unwrap();
+        self.file.holes.lock().unwrap().clear();
+        remove_holes(f)?;
+        return f.set_xattr(ENCRYPT_XATTR, &self.id);
+    }
+
+    // if f was written with this key
+    pub fn opens(&self, f: &File) -> io::Result<bool> {
+        return Ok(f.get_xattr(ENCRYPT_XATTR)?.as_ref() == Some(&self.id));
+    }
+
+    pub fn read_at(&self, f: &File, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        let _lock = self.file.lock.read().unwrap();
+        let size = size(f)?;
+        let mut nread = 0;
+        while nread < buf.len() {
+            let pos = offset as u64 + nread as u64;
+            if pos >= size {
+                break;
+            }
+            let i = pos / CHUNK_SIZE as u64;
+            let chunk = self.read_chunk(f, i, size)?;
+            let skip = (pos % CHUNK_SIZE as u64) as usize;
+            let n = (buf.len() - nread).min(chunk.len() - skip);
+            buf[nread..nread + n].copy_from_slice(&chunk[skip..skip + n]);
+            nread += n;
+        }
+        return Ok(nread);
+    }
+
+    // the chunks that buf only covers part of are read and written
+    // back whole
+    pub fn write_at(&self, f: &File, buf: &[u8], offset: i64) -> io::Result<usize> {
+        let _lock = self.file.lock.write().unwrap();
+        return self.saving_holes(f, || self.write_chunks(f, buf, offset));
+    }
+
+    fn write_chunks(&self, f: &File, buf: &[u8], offset: i64) -> io::Result<usize> {
+        let offset = offset as u64;
+        let end = offset + buf.len() as u64;
+        let mut size = size(f)?;
+        if end > size {
+            self.resize(f, size, end)?;
+            size = end;
+        }
+
+        let mut pos = offset;
+        while pos < end {
+            let i = pos / CHUNK_SIZE as u64;
+            let start = i * CHUNK_SIZE as u64;
+            let len = chunk_len(i, size);
+            let from = (pos - start) as usize;
+            let to = (end - start).min(len as u64) as usize;
+            let data = &buf[(pos - offset) as usize..(pos - offset) as usize + (to - from)];
+            if from == 0 && to == len {
+                self.write_chunk(f, i, data)?;
+            } else {
+                let mut chunk = self.read_chunk(f, i, size)?;
+                chunk[from..to].copy_from_slice(data);
+                self.write_chunk(f, i, &chunk)?;
+            }
+            pos = start + to as u64;
+        }
+        return Ok(buf.len());
+    }
+
+    pub fn set_size(&self, f: &File, new_size: u64) -> io::Result<()> {
+        let _lock = self.file.lock.write().unwrap();
+        return self.saving_holes(f, || {
+            let old_size = size(f)?;
+            return self.resize(f, old_size, new_size);
+        });
+    }
+
+    // what's appended is marked a hole by resize before it's written
+    // over, so the holes are only saved once it's all done and only
+    // if they changed
+    fn saving_holes<T, F>(&self, f: &File, op: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let before = self.file.holes.lock().unwrap().clone();
+        let res = op();
+        let holes = self.file.holes.lock().unwrap().clone();
+        if holes != before {
+            self.save_holes(f, &holes)?;
+        }
+        return res;
+    }
+
+    // the last chunk that's kept changes length unless it's whole, so
+    // it's encrypted again. What's added after it is a hole
+    fn resize(&self, f: &File, old_size: u64, new_size: u64) -> io::Result<()> {
+        if old_size == new_size {
+            return Ok(());
+        }
+        let keep = old_size.min(new_size);
+        let i = keep / CHUNK_SIZE as u64;
+        let tail = (keep % CHUNK_SIZE as u64) as usize;
+        let last = if tail != 0 {
+            let mut chunk = self.read_chunk(f, i, old_size)?;
+            chunk.truncate(tail);
+            Some(chunk)
+        } else {
+            None
+        };
+
+        f.truncate(raw_size(new_size))?;
+        let nchunks = (new_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+        {
+            let mut holes = self.file.holes.lock().unwrap();
+            holes.retain(|r| r.0 < nchunks);
+            for r in holes.iter_mut() {
+                r.1 = r.1.min(nchunks);
+            }
+            let first = if last.is_some() { i + 1 } else { i };
+            if new_size > old_size && first < nchunks {
+                add_hole(&mut holes, first, nchunks);
+            }
+        }
+        if let Some(mut chunk) = last {
+            chunk.resize(chunk_len(i, new_size), 0);
+            self.write_chunk(f, i, &chunk)?;
+        }
+        return Ok(());
+    }
+
+    // if chunk i was never written, looking in HOLES_XATTR if we
+    // don't know of it
+    fn is_hole(&self, f: &File, i: u64) -> io::Result<bool> {
+        let mut holes = self.file.holes.lock().unwrap();
+        if holes.iter().any(|r| r.0 <= i && i < r.1) {
+            return Ok(true);
+        }
+        *holes = self.load_holes(f)?;
+        return Ok(holes.iter().any(|r| r.0 <= i && i < r.1));
+    }
+
+    fn load_holes(&self, f: &File) -> io::Result<Vec<(u64, u64)>> {
+        let raw = match f.get_xattr(HOLES_XATTR)? {
+            Some(raw) => raw,
+            None => return Ok(Vec::new()),
+        };
+        if raw.len() < NONCE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "can't decrypt cache holes"));
+        }
+        let v = self.cipher
+            .decrypt(
+                Nonce::from_slice(&raw[..NONCE_SIZE]),
+                Payload {
+                    msg: &raw[NONCE_SIZE..],
+                    aad: HOLES_AAD,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "can't decrypt cache holes")
+            })?;
+        let mut holes = Vec::new();
+        for r in v.chunks(16) {
+            if r.len() != 16 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad cache holes"));
+            }
+            let mut start = [0u8; 8];
+            let mut end = [0u8; 8];
+            start.copy_from_slice(&r[..8]);
+            end.copy_from_slice(&r[8..]);
+            holes.push((u64::from_le_bytes(start), u64::from_le_bytes(end)));
+        }
+        return Ok(holes);
+    }
+
+    fn save_holes(&self, f: &File, holes: &[(u64, u64)]) -> io::Result<()> {
+        if holes.is_empty() {
+            return remove_holes(f);
+        }
+        let mut v = Vec::with_capacity(holes.len() * 16);
+        for r in holes {
+            v.extend_from_slice(&r.0.to_le_bytes());
+            v.extend_from_slice(&r.1.to_le_bytes());
+        }
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = self.cipher
+            .encrypt(&nonce, Payload { msg: &v, aad: HOLES_AAD })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "can't encrypt cache holes"))?;
+        let mut raw = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+        raw.extend_from_slice(&nonce);
+        raw.extend_from_slice(&encrypted);
+        return f.set_xattr(HOLES_XATTR, &raw);
+    }
+
+    fn read_chunk(&self, f: &File, i: u64, size: u64) -> io::Result<Vec<u8>> {
+        let len = chunk_len(i, size);
+        let mut raw = vec![0u8; len + OVERHEAD];
+        let mut nread = 0;
+        while nread < raw.len() {
+            let n = f.read_at(&mut raw[nread..], (i * RAW_CHUNK_SIZE as u64) as i64 + nread as i64)?;
+            if n == 0 {
+                break;
+            }
+            nread += n;
+        }
+        // a chunk that's written is never all zeros, and one that
+        // isn't has to be a hole we know of
+        if raw.iter().all(|&b| b == 0) && self.is_hole(f, i)? {
+            return Ok(vec![0u8; len]);
+        }
+        if nread != raw.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted chunk is short"));
+        }
+
+        let aad = i.to_le_bytes();
+        return self.cipher
+            .decrypt(
+                Nonce::from_slice(&raw[..NONCE_SIZE]),
+                Payload {
+                    msg: &raw[NONCE_SIZE..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "can't decrypt cache file")
+            });
+    }
+
+    fn write_chunk(&self, f: &File, i: u64, data: &[u8]) -> io::Result<()> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = i.to_le_bytes();
+        let encrypted = self.cipher
+            .encrypt(&nonce, Payload { msg: data, aad: &aad })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "can't encrypt cache file"))?;
+
+        let mut raw = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+        raw.extend_from_slice(&nonce);
+        raw.extend_from_slice(&encrypted);
+        let offset = (i * RAW_CHUNK_SIZE as u64) as i64;
+        let mut nwritten = 0;
+        while nwritten < raw.len() {
+            nwritten += f.write_at(&raw[nwritten..], offset + nwritten as i64)?;
+        }
+
+        let mut holes = self.file.holes.lock().unwrap();
+        if let Some(pos) = holes.iter().position(|r| r.0 <= i && i < r.1) {
+            let (start, end) = holes.remove(pos);
+            if i + 1 < end {
+                holes.insert(pos, (i + 1, end));
+            }
+            if start < i {
+                holes.insert(pos, (start, i));
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn add_hole(holes: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    if let Some(last) = holes.last_mut() {
+        if last.1 == start {
+            last.1 = end;
+            return;
+        }
+    }
+    holes.push((start, end));
+}
+
+fn remove_holes(f: &File) -> io::Result<()> {
+    if let Err(e) = f.remove_xattr(HOLES_XATTR) {
+        if e.raw_os_error() != Some(libc::ENODATA) && e.raw_os_error() != Some(libc::ENOATTR) {
+            return Err(e);
+        }
+    }
+    return Ok(());
+}
+
+pub fn is_sealed(f: &File) -> io::Result<bool> {
+    return Ok(f.get_xattr(ENCRYPT_XATTR)?.is_some());
+}
+
+// how big what's in f is, which doesn't need the key
+pub fn size(f: &File) -> io::Result<u64> {
+    let raw = f.filesize()?;
+    if !is_sealed(f)? {
+        return Ok(raw);
+    }
+    let rem = raw % RAW_CHUNK_SIZE as u64;
+    return Ok(
+        raw / RAW_CHUNK_SIZE as u64 * CHUNK_SIZE as u64 + rem.saturating_sub(OVERHEAD as u64),
+    );
+}
+
+fn raw_size(size: u64) -> u64 {
+    let rem = size % CHUNK_SIZE as u64;
+    let n = size / CHUNK_SIZE as u64;
+    return n * RAW_CHUNK_SIZE as u64 + if rem == 0 { 0 } else { rem + OVERHEAD as u64 };
+}
+
+fn chunk_len(i: u64, size: u64) -> usize {
+    return (size - i * CHUNK_SIZE as u64).min(CHUNK_SIZE as u64) as usize;
+}
+
+fn bad_key() -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "cache key has to be 32 bytes, or 64 hex digits",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use catfs;
+    use catfs::rlibc;
+    use super::*;
+
+    #[test]
+    fn encrypt_at() {
+        let prefix = catfs::tests::copy_resources();
+        let key = Key::new(&[7u8; 32]).unwrap();
+        let path = prefix.join("sealed");
+        let mut f = File::open(&path, rlibc::O_RDWR | rlibc::O_CREAT, 0o666).unwrap();
+        key.seal(&f).unwrap();
+
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| (i % 251) as u8).collect();
+        assert_eq!(key.write_at(&f, &data, 0).unwrap(), data.len());
+        assert_eq!(size(&f).unwrap(), data.len() as u64);
+        assert_eq!(f.filesize().unwrap(), raw_size(data.len() as u64));
+        let raw = fs::read(&path).unwrap();
+        assert!(raw.windows(100).all(|w| w != &data[CHUNK_SIZE..CHUNK_SIZE + 100]));
+
+        // across a chunk boundary
+        key.write_at(&f, b"catfs", CHUNK_SIZE as i64 - 2).unwrap();
+        let mut buf = vec![0u8; 10];
+        assert_eq!(key.read_at(&f, &mut buf, CHUNK_SIZE as i64 - 5).unwrap(), 10);
+        assert_eq!(&buf[..3], &data[CHUNK_SIZE - 5..CHUNK_SIZE - 2]);
+        assert_eq!(&buf[3..8], b"catfs");
+
+        // what's added past the end reads as zeros
+        key.set_size(&f, 50).unwrap();
+        key.set_size(&f, CHUNK_SIZE as u64 * 3).unwrap();
+        let mut buf = vec![1u8; 100];
+        assert_eq!(key.read_at(&f, &mut buf, 0).unwrap(), 100);
+        assert_eq!(&buf[..50], &data[..50]);
+        assert!(buf[50..].iter().all(|&b| b == 0));
+        assert_eq!(key.read_at(&f, &mut buf, CHUNK_SIZE as i64 * 2).unwrap(), 100);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // and a different key can't read it
+        let other = Key::new(&[8u8; 32]).unwrap();
+        assert!(!other.opens(&f).unwrap());
+        assert!(other.read_at(&f, &mut buf, 0).is_err());
+
+        f.close().unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn zeroed_chunk() {
+        let prefix = catfs::tests::copy_resources();
+        let key = Key::new(&[7u8; 32]).unwrap();
+        let path = prefix.join("sealed");
+        let mut f = File::open(&path, rlibc::O_RDWR | rlibc::O_CREAT, 0o666).unwrap();
+        let fkey = key.for_file(&f).unwrap();
+        fkey.seal(&f).unwrap();
+
+        // a write past the end leaves holes before it
+        let data = vec![1u8; CHUNK_SIZE];
+        fkey.write_at(&f, &data, CHUNK_SIZE as i64 * 2).unwrap();
+        assert!(f.get_xattr(HOLES_XATTR).unwrap().is_some());
+
+        // which another open of it knows about
+        let other = key.for_file(&f).unwrap();
+        assert!(Arc::ptr_eq(&other.file, &fkey.file));
+        let mut buf = vec![1u8; 100];
+        assert_eq!(other.read_at(&f, &mut buf, CHUNK_SIZE as i64).unwrap(), 100);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // as does a key that has to read them from the file
+        let again = Key::new(&[7u8; 32]).unwrap().for_file(&f).unwrap();
+        assert_eq!(again.read_at(&f, &mut buf, 0).unwrap(), 100);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // filling them in leaves nothing to record
+        fkey.write_at(&f, &data, 0).unwrap();
+        fkey.write_at(&f, &data, CHUNK_SIZE as i64).unwrap();
+        assert!(f.get_xattr(HOLES_XATTR).unwrap().is_none());
+
+        // and a written chunk that's zeroed out doesn't pass for one
+        f.write_at(&vec![0u8; RAW_CHUNK_SIZE], RAW_CHUNK_SIZE as i64).unwrap();
+        let e = fkey.read_at(&f, &mut buf, CHUNK_SIZE as i64).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // other files don't share the lock
+        let mut g = File::open(&prefix.join("sealed2"), rlibc::O_RDWR | rlibc::O_CREAT, 0o666)
+            .unwrap();
+        assert!(!Arc::ptr_eq(&key.for_file(&g).unwrap().file, &fkey.file));
+
+        g.close().unwrap();
+        f.close().unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+extern crate libc;
+extern crate sha2;
+extern crate xattr;
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use self::sha2::{Sha256, Digest};
+use self::xattr::FileExt;
+
+use catfs::error;
+use catfs::file;
+use catfs::file::CacheLayout;
+use catfs::rlibc;
+use catfs::rlibc::File;
+
+// the sha256 of what's in a cache file, set once it's paged in and
+// removed before it's written to
+pub const CONTENT_XATTR: &'static str = "user.catfs.content";
+
+// smaller files aren't worth reading again to hash
+pub const MIN_SIZE: u64 = 1024 * 1024;
+// how many contents are remembered, the oldest are forgotten first
+const MAX_ENTRIES: usize = 64 * 1024;
+const READ_SIZE: usize = 1024 * 1024;
+
+// which cache file has each content that was paged in, so the next
+// cache file with the same content can share its blocks instead of
+// taking up space of its own
+pub struct Index {
+    cache_dir: RawFd,
+    layout: CacheLayout,
+    // cleared the first time the cache filesystem can't reflink
+    supported: AtomicBool,
+    paths: Mutex<Paths>,
+}
+
+#[derive(Default)]
+struct Paths {
+    by_hash: HashMap<Vec<u8>, PathBuf>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl Paths {
+    fn insert(&mut self, hash: Vec<u8>, cache_path: PathBuf) {
+        if self.by_hash.insert(hash.clone(), cache_path).is_none() {
+            self.order.push_back(hash);
+            if self.order.len() > MAX_ENTRIES {
+                let oldest = self.order.pop_front().unwrap();
+                self.by_hash.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Index {
+    pub fn new(cache_dir: RawFd, layout: CacheLayout) -> Index {
+        return Index {
+            cache_dir: cache_dir,
+            layout: layout,
+            supported: AtomicBool::new(true),
+            paths: Default::default(),
+        };
+    }
+
+    pub fn is_supported(&self) -> bool {
+        return self.supported.load(Ordering::Relaxed);
+    }
+
+    // f is the cache file of path, which was just paged in and has
+    // hash. If another cache file was paged in with the same content,
+    // f is made to share its blocks. Returns how many bytes that saved
+    pub fn add(&self, path: &dyn AsRef<Path>, f: &File, hash: &[u8]) -> error::Result<u64> {
+        let cache_path = self.layout.cache_path(path);
+        let size = f.filesize()?;
+        let other = self.paths.lock().unwrap().by_hash.get(hash).cloned();
+
+        let mut saved = 0;
+        if let Some(other) = other {
+            if other != cache_path {
+                match self.clone_from(&other, hash, size, f) {
+                    Ok(true) => {
+                        debug!("{:?} shares its blocks with {:?}", path.as_ref(), other);
+                        saved = size;
+                    }
+                    Ok(false) => (),
+                    Err(e) => {
+                        if !is_unsupported(&e) {
+                            return Err(error::RError::from(e));
+                        }
+                        debug!("cache dir can't reflink: {}", e);
+                        self.supported.store(false, Ordering::Relaxed);
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+
+        f.set_xattr(CONTENT_XATTR, hash)?;
+        // a write that started in the meantime took away the checksum
+        // first, and this has to go too
+        if !file::was_pristine(f) {
+            f.remove_xattr(CONTENT_XATTR)?;
+            return Ok(saved);
+        }
+        if saved == 0 {
+            // other is gone or isn't what it was, f takes its place
+            self.paths.lock().unwrap().insert(hash.to_vec(), cache_path);
+        }
+        return Ok(saved);
+    }
+
+    // false if the cache file at other doesn't have hash anymore
+    fn clone_from(&self, other: &Path, hash: &[u8], size: u64, f: &File) -> io::Result<bool> {
+        let mut of = match File::openat(self.cache_dir, &other, rlibc::O_RDONLY, 0) {
+            Ok(of) => of,
+            Err(e) => {
+                if error::is_enoent(&e) {
+                    return Ok(false);
+                }
+                return Err(e);
+            }
+        };
+        let res = (|| -> io::Result<bool> {
+            let has_hash = |of: &File| -> io::Result<bool> {
+                return Ok(
+                    of.get_xattr(CONTENT_XATTR)?.as_ref().map(|v| &v[..]) == Some(hash) &&
+                        of.filesize()? == size && file::was_pristine(of),
+                );
+            };
+            if !has_hash(&of)? {
+                return Ok(false);
+            }
+            // a write to other takes away the hash before it changes
+            // anything, so if it's still there after other is cloned,
+            // what was cloned has it too. f only gets it after that
+            let mut tmp = rlibc::tmpfile(self.cache_dir)?;
+            let res = (|| -> io::Result<bool> {
+                rlibc::ficlone(tmp.as_raw_fd(), of.as_raw_fd())?;
+                if !has_hash(&of)? {
+                    return Ok(false);
+                }
+                rlibc::ficlone(f.as_raw_fd(), tmp.as_raw_fd())?;
+                return Ok(true);
+            })();
+            let _ = tmp.close();
+            return res;
+        })();
+        let _ = of.close();
+        return res;
+    }
+}
+
+// hashes all of f
+pub fn hash(f: &File) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_SIZE];
+    let mut offset = 0;
+    loop {
+        let nread = f.read_at(&mut buf, offset)?;
+        if nread == 0 {
+            break;
+        }
+        hasher.update(&buf[..nread]);
+        offset += nread as i64;
+    }
+    return Ok(hasher.finalize().to_vec());
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    return match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) | Some(libc::EXDEV) |
+        Some(libc::ENOSYS) => true,
+        _ => false,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn dedupe_same_content() {
+        let prefix = catfs::tests::copy_resources();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let data: Vec<u8> = (0..MIN_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let index = Index::new(cache_dir, CacheLayout::Path);
+
+        let mut files = Vec::new();
+        for name in &["file1", "file2"] {
+            fs::write(cache.join(name), &data).unwrap();
+            let f = File::openat(cache_dir, &name, rlibc::O_RDWR, 0).unwrap();
+            f.set_xattr("user.catfs.src_chksum", b"42").unwrap();
+            files.push(f);
+        }
+
+        let h = hash(&files[0]).unwrap();
+        assert_eq!(h, hash(&files[1]).unwrap());
+        assert_eq!(index.add(&"file1", &files[0], &h).unwrap(), 0);
+        assert_eq!(files[0].get_xattr(CONTENT_XATTR).unwrap(), Some(h.clone()));
+        // only shared if the cache dir can reflink
+        let saved = index.add(&"file2", &files[1], &h).unwrap();
+        assert!(saved == data.len() as u64 || !index.is_supported());
+        assert_eq!(fs::read(cache.join("file2")).unwrap(), data);
+
+        for mut f in files {
+            f.close().unwrap();
+        }
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -3,29 +3,22 @@ extern crate libc;
 use std::fs;
 use std::io;
 use std::os::unix::io::RawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use catfs::backend::{LocalDir, SourceBackend, SourceDir};
+use catfs::dircache::DirCache;
 use catfs::error;
 use catfs::rlibc;
+use catfs::whiteout::Whiteouts;
 
 pub struct Handle {
-    dh: *mut libc::DIR,
+    dh: Box<dyn SourceDir>,
     offset: i64,
     entry: rlibc::Dirent,
     entry_valid: bool,
-}
-
-// no-op to workaround the fact that we send the entire CatFS at start
-// time, but we never send anything. Could have used Unique but that
-// bounds us to rust nightly
-unsafe impl Send for Handle {}
-
-impl Drop for Handle {
-    fn drop(&mut self) {
-        if let Err(e) = rlibc::closedir(self.dh) {
-            error!("!closedir {:?} = {}", self.dh, e);
-        }
-    }
+    path: PathBuf,
+    whiteouts: Option<Arc<Whiteouts>>,
 }
 
 #[allow(dead_code)]
@@ -34,17 +27,23 @@ pub fn openpath(path: &dyn AsRef<Path>) -> io::Result<RawFd> {
 }
 
 impl Handle {
-    pub fn openat(dir: RawFd, path: &dyn AsRef<Path>) -> error::Result<Handle> {
-        let fd = if path.as_ref() == Path::new("") {
-            rlibc::openat(dir, &".", rlibc::O_RDONLY, 0)?
-        } else {
-            rlibc::openat(dir, &path, rlibc::O_RDONLY, 0)?
+    pub fn openat(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        whiteouts: Option<Arc<Whiteouts>>,
+        dir_cache: Option<&DirCache>,
+    ) -> error::Result<Handle> {
+        let dh = match dir_cache {
+            Some(dir_cache) => dir_cache.opendir(src, path.as_ref())?,
+            None => src.opendir(path)?,
         };
         return Ok(Handle {
-            dh: rlibc::fdopendir(fd)?,
+            dh: dh,
             offset: 0,
             entry: Default::default(),
             entry_valid: false,
+            path: path.as_ref().to_path_buf(),
+            whiteouts: whiteouts,
         });
     }
 
@@ -52,21 +51,18 @@ impl Handle {
     pub fn open(path: &dyn AsRef<Path>) -> error::Result<Handle> {
         let dh = rlibc::opendir(&path)?;
         return Ok(Handle {
-            dh: dh,
+            dh: Box::new(LocalDir::new(dh)),
             offset: 0,
             entry: Default::default(),
             entry_valid: false,
+            path: PathBuf::new(),
+            whiteouts: None,
         });
     }
 
     pub fn seekdir(&mut self, offset: i64) {
         if offset != self.offset {
-            debug!(
-                "seeking {} to {}",
-                unsafe { libc::telldir(self.dh) },
-                offset
-            );
-            rlibc::seekdir(self.dh, offset);
+            self.dh.seekdir(offset);
             self.offset = offset;
             self.entry_valid = false;
         }
@@ -86,11 +82,19 @@ impl Handle {
         if self.entry_valid {
             return Ok(Some(self.entry.clone()));
         } else {
-            match rlibc::readdir(self.dh)? {
-                Some(entry) => {
-                    return Ok(Some(entry));
+            loop {
+                match self.dh.readdir()? {
+                    Some(entry) => {
+                        if let Some(ref whiteouts) = self.whiteouts {
+                            if whiteouts.contains(&self.path.join(entry.name())) {
+                                self.offset = entry.off();
+                                continue;
+                            }
+                        }
+                        return Ok(Some(entry));
+                    }
+                    None => return Ok(None),
                 }
-                None => return Ok(None),
             }
         }
     }
@@ -100,14 +104,19 @@ impl Handle {
         rlibc::mkdir(path, mode)
     }
 
-    pub fn rmdirat(src_dir: RawFd, cache_dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<()> {
-        if let Err(e) = rlibc::unlinkat(cache_dir, path, libc::AT_REMOVEDIR as u32) {
+    pub fn rmdirat(
+        src: &dyn SourceBackend,
+        cache_dir: RawFd,
+        cache_path: &dyn AsRef<Path>,
+        path: &dyn AsRef<Path>,
+    ) -> io::Result<()> {
+        if let Err(e) = rlibc::unlinkat(cache_dir, cache_path, libc::AT_REMOVEDIR as u32) {
             if !error::is_enoent(&e) {
                 return Err(e);
             }
         }
 
-        return rlibc::unlinkat(src_dir, path, libc::AT_REMOVEDIR as u32);
+        return src.rmdir(path);
     }
 
     #[allow(dead_code)]
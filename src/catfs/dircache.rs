@@ -0,0 +1,161 @@
+extern crate fuse;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use catfs::backend::{SourceBackend, SourceDir};
+use catfs::rlibc;
+
+type Entries = Arc<Vec<(OsString, fuse::FileType, u64)>>;
+
+struct Listings {
+    dirs: HashMap<PathBuf, (Instant, Entries)>,
+    // bumped on every change, a listing that was read while
+    // something changed may already be stale
+    changes: u64,
+}
+
+// directory listings kept in memory for a while, so listing a
+// directory again doesn't go to the source. Changing a directory
+// through the mount drops its listing, changes made directly to the
+// source show up once the listing expires
+pub struct DirCache {
+    ttl: Duration,
+    listings: Mutex<Listings>,
+}
+
+impl DirCache {
+    pub fn new(ttl: Duration) -> DirCache {
+        return DirCache {
+            ttl: ttl,
+            listings: Mutex::new(Listings {
+                dirs: HashMap::new(),
+                changes: 0,
+            }),
+        };
+    }
+
+    // the cached listing of path, read from src if there isn't one
+    pub fn opendir(&self, src: &dyn SourceBackend, path: &Path) -> io::Result<Box<dyn SourceDir>> {
+        let changes = {
+            let mut listings = self.listings.lock().unwrap();
+            if let Some(&(time, ref entries)) = listings.dirs.get(path) {
+                if time.elapsed() < self.ttl {
+                    return Ok(Box::new(CachedDir::new(entries.clone())));
+                }
+            }
+            listings.dirs.remove(path);
+            listings.changes
+        };
+
+        let mut dh = src.opendir(&path)?;
+        let mut entries = Vec::new();
+        while let Some(en) = dh.readdir()? {
+            entries.push((en.name(), en.kind(), en.ino()));
+        }
+        let entries = Arc::new(entries);
+
+        let mut listings = self.listings.lock().unwrap();
+        if listings.changes == changes {
+            listings.dirs.insert(
+                path.to_path_buf(),
+                (Instant::now(), entries.clone()),
+            );
+        }
+        return Ok(Box::new(CachedDir::new(entries)));
+    }
+
+    // something in this directory changed
+    pub fn invalidate(&self, dir: &Path) {
+        let mut listings = self.listings.lock().unwrap();
+        listings.changes += 1;
+        listings.dirs.remove(dir);
+    }
+
+    // this directory was renamed or removed, along with everything
+    // under it
+    pub fn invalidate_tree(&self, dir: &Path) {
+        let mut listings = self.listings.lock().unwrap();
+        listings.changes += 1;
+        listings.dirs.retain(|path, _| !path.starts_with(dir));
+    }
+}
+
+// offsets are positions in the listing, since the source's offsets
+// may not be good once its dir is closed
+struct CachedDir {
+    entries: Entries,
+    pos: usize,
+}
+
+impl CachedDir {
+    fn new(entries: Entries) -> CachedDir {
+        return CachedDir {
+            entries: entries,
+            pos: 0,
+        };
+    }
+}
+
+impl SourceDir for CachedDir {
+    fn seekdir(&mut self, offset: i64) {
+        self.pos = offset as usize;
+    }
+
+    fn readdir(&mut self) -> io::Result<Option<rlibc::Dirent>> {
+        if self.pos >= self.entries.len() {
+            return Ok(None);
+        }
+
+        let (ref name, kind, ino) = self.entries[self.pos];
+        self.pos += 1;
+        return Ok(Some(rlibc::Dirent::new(ino, self.pos as i64, kind, name)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use super::*;
+
+    fn names(dh: &mut Box<dyn SourceDir>) -> Vec<OsString> {
+        let mut names = Vec::new();
+        while let Some(en) = dh.readdir().unwrap() {
+            names.push(en.name());
+        }
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn listings() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = DirCache::new(Duration::from_secs(3600));
+
+        let before = names(&mut cache.opendir(&src, Path::new("dir1")).unwrap());
+        fs::write(resources.join("dir1/new"), b"").unwrap();
+        // still the cached listing
+        assert_eq!(names(&mut cache.opendir(&src, Path::new("dir1")).unwrap()), before);
+
+        cache.invalidate(Path::new("dir1"));
+        let after = names(&mut cache.opendir(&src, Path::new("dir1")).unwrap());
+        assert_eq!(after.len(), before.len() + 1);
+        assert!(after.contains(&OsString::from("new")));
+
+        fs::remove_file(resources.join("dir1/new")).unwrap();
+        cache.invalidate_tree(Path::new(""));
+        assert_eq!(names(&mut cache.opendir(&src, Path::new("dir1")).unwrap()), before);
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
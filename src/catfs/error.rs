@@ -33,12 +33,17 @@ pub fn propagate<T>(e: io::Error) -> Result<T> {
 
 pub fn errno(e: &RError<io::Error>) -> libc::c_int {
     if RError::expected(e) {
-        return e.e.raw_os_error().unwrap();
+        return os_errno(&e.e);
     } else {
         return libc::EIO;
     }
 }
 
+// errors that didn't come from the OS, such as a cache file that
+// can't be decoded or a bad reply from a remote source, are EIO
+pub fn os_errno(e: &io::Error) -> libc::c_int {
+    return e.raw_os_error().unwrap_or(libc::EIO);
+}
 
 impl<E> RError<E> {
     pub fn propagate(e: E) -> RError<E> {
@@ -80,7 +85,7 @@ impl<E> RError<E> {
 
 impl RError<io::Error> {
     pub fn errno(&self) -> i32 {
-        return self.e.raw_os_error().unwrap();
+        return os_errno(&self.e);
     }
 }
 
@@ -106,7 +111,7 @@ impl<E> Deref for RError<E> {
 impl Clone for RError<io::Error> {
     fn clone(&self) -> Self {
         RError {
-            e: io::Error::from_raw_os_error(self.e.raw_os_error().unwrap()),
+            e: io::Error::from_raw_os_error(os_errno(&self.e)),
             bt: Default::default(),
         }
     }
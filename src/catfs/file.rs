@@ -5,41 +5,624 @@ extern crate sha2;
 extern crate threadpool;
 extern crate xattr;
 
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::io;
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+#[cfg(not(target_os = "macos"))]
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::os::unix::io::RawFd;
 use std::path::{Component, Path, PathBuf};
-use std::sync::{Arc, Condvar, Mutex};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use self::generic_array::GenericArray;
 use self::generic_array::typenum::U64;
-use self::sha2::{Sha512, Digest};
+use self::sha2::{Sha256, Sha512, Digest};
 use self::threadpool::ThreadPool;
 use self::xattr::FileExt;
 
+use catfs::backend::{SourceBackend, SourceHints};
+use catfs::compress;
+use catfs::crypt;
+use catfs::dedupe;
 use catfs::error;
 use catfs::error::RError;
 use catfs::rlibc;
 use catfs::rlibc::File;
+use catfs::throttle::PageInLimit;
+use catfs::tier;
 
 type CvData<T> = Arc<(Mutex<T>, Condvar)>;
 
+// how much we copy from src at a time when paging in
+pub const DEFAULT_READAHEAD: usize = 128 * 1024;
+// it's allocated for every page in
+pub const MAX_READAHEAD: usize = 64 * 1024 * 1024;
+// page in jumps ahead to where a reader is waiting, copying this much
+// at most before going back to fill in what it skipped
+const MAX_READAHEAD_WINDOW: i64 = 16 * 1024 * 1024;
+// files up to this size are paged in with the rest of their directory
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+// a partially filled cache file is copied from src a block at a time,
+// bigger files get bigger blocks to keep the bitmap of which blocks
+// are there small enough for an xattr
+const MIN_BLOCK_SIZE: i64 = 64 * 1024;
+const MAX_BITMAP_BYTES: i64 = 2048;
+// what O_DIRECT reads and writes have to be aligned to
+const DIRECT_ALIGN: usize = 4096;
+
+// xattrs that fuse filesystems backed by object stores use to say
+// which version of an object a file is
+const VERSION_XATTRS: [&'static str; 4] = [
+    // goofys
+    "s3.etag",
+    // gcsfuse, bumped every time the object is written
+    "user.gcsfuse.generation",
+    // rclone mount, the hashes of the remote object
+    "user.rclone.md5",
+    "user.rclone.sha1",
+];
+
+// which layout the cache dir uses, set on the cache dir itself so
+// everything that shares it agrees
+const LAYOUT_XATTR: &'static str = "user.catfs.layout";
+// the source path of a cache file that's not named after it
+pub const PATH_XATTR: &'static str = "user.catfs.path";
+// the blocks of a partially filled cache file that are there
+const BLOCKS_XATTR: &'static str = "user.catfs.blocks";
+// how many times a cache file was opened, for the lfu eviction policy
+const HITS_XATTR: &'static str = "user.catfs.hits";
+
+// how files in the cache dir are named
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheLayout {
+    // the same as in the source
+    Path,
+    // a hash of the source path, so paths close to PATH_MAX and deep
+    // trees don't carry over into the cache dir
+    Hashed,
+    // same as Hashed but under aa/bb/ from the start of the hash, so
+    // no directory gets too big to scan or unlink from
+    Fanout,
+}
+
+impl Default for CacheLayout {
+    fn default() -> CacheLayout {
+        return CacheLayout::Path;
+    }
+}
+
+impl FromStr for CacheLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "path" => Ok(CacheLayout::Path),
+            "hashed" => Ok(CacheLayout::Hashed),
+            "fanout" => Ok(CacheLayout::Fanout),
+            _ => Err("unrecognized cache layout ".to_owned() + s),
+        };
+    }
+}
+
+impl CacheLayout {
+    pub fn to_str(&self) -> &'static str {
+        return match *self {
+            CacheLayout::Path => "path",
+            CacheLayout::Hashed => "hashed",
+            CacheLayout::Fanout => "fanout",
+        };
+    }
+
+    // the layout cache_dir was last used with
+    pub fn load(cache_dir: RawFd) -> error::Result<CacheLayout> {
+        let mut dir = File::openat(cache_dir, &".", rlibc::O_RDONLY, 0)?;
+        let res = dir.get_xattr(LAYOUT_XATTR);
+        dir.close()?;
+
+        match res {
+            Ok(Some(v)) => {
+                return match CacheLayout::from_str(&String::from_utf8_lossy(&v)) {
+                    Ok(layout) => Ok(layout),
+                    Err(e) => {
+                        error!("{}", e);
+                        error::propagate(io::Error::from_raw_os_error(libc::EINVAL))
+                    }
+                };
+            }
+            Ok(None) => return Ok(Default::default()),
+            Err(e) => {
+                if e.raw_os_error() == Some(libc::ENOTSUP) {
+                    return Ok(Default::default());
+                }
+                return Err(RError::from(e));
+            }
+        }
+    }
+
+    pub fn save(&self, cache_dir: RawFd) -> error::Result<()> {
+        let mut dir = File::openat(cache_dir, &".", rlibc::O_RDONLY, 0)?;
+        let res = dir.set_xattr(LAYOUT_XATTR, self.to_str().as_bytes());
+        dir.close()?;
+        return Ok(res?);
+    }
+
+    pub fn cache_path(&self, path: &dyn AsRef<Path>) -> PathBuf {
+        match *self {
+            CacheLayout::Path => return path.as_ref().to_path_buf(),
+            CacheLayout::Hashed | CacheLayout::Fanout => {
+                let mut hasher = Sha256::default();
+                hasher.update(path.as_ref().as_os_str().as_bytes());
+                let name: String = hasher
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                if *self == CacheLayout::Fanout {
+                    return Path::new(&name[0..2]).join(&name[2..4]).join(&name);
+                }
+                return PathBuf::from(name);
+            }
+        }
+    }
+
+    // the source path of a file in the cache dir
+    pub fn src_path<F: FileExt>(
+        &self,
+        cache_path: &dyn AsRef<Path>,
+        f: &F,
+    ) -> io::Result<Option<PathBuf>> {
+        match *self {
+            CacheLayout::Path => return Ok(Some(cache_path.as_ref().to_path_buf())),
+            CacheLayout::Hashed | CacheLayout::Fanout => {
+                return Ok(f.get_xattr(PATH_XATTR)?.map(|v| {
+                    PathBuf::from(OsStr::from_bytes(&v))
+                }));
+            }
+        }
+    }
+
+    // opens the cache file of path, creating the parents and
+    // recording where it's from if this may create it
+    pub fn openat(
+        &self,
+        cache_dir: RawFd,
+        perms: CachePerms,
+        path: &dyn AsRef<Path>,
+        flags: u32,
+        mode: libc::mode_t,
+    ) -> io::Result<File> {
+        let cache_path = self.cache_path(path);
+        if (flags & rlibc::O_CREAT) == 0 {
+            return File::openat(cache_dir, &cache_path, flags, mode);
+        }
+
+        if let Some(parent) = cache_path.parent() {
+            mkdirat_all(cache_dir, &parent, perms.dir_mode())?;
+        }
+        let mut f = File::openat(cache_dir, &cache_path, flags, perms.file_mode(mode))?;
+        if *self != CacheLayout::Path {
+            if let Err(e) = f.set_xattr(PATH_XATTR, path.as_ref().as_os_str().as_bytes()) {
+                let _ = f.close();
+                return Err(e);
+            }
+        }
+        return Ok(f);
+    }
+
+    // replaces the cache copy of the symlink at path, creating the
+    // parents. Symlinks can't have xattrs, so unlike files this
+    // doesn't record where it's from
+    pub fn symlinkat(
+        &self,
+        cache_dir: RawFd,
+        perms: CachePerms,
+        target: &dyn AsRef<Path>,
+        path: &dyn AsRef<Path>,
+    ) -> io::Result<()> {
+        let cache_path = self.cache_path(path);
+        if let Some(parent) = cache_path.parent() {
+            mkdirat_all(cache_dir, &parent, perms.dir_mode())?;
+        }
+        maybe_unlinkat(cache_dir, &cache_path)?;
+        return rlibc::symlinkat(target, cache_dir, &cache_path);
+    }
+
+    // makes the cache copy of path, if there's one, also the cache
+    // copy of new_path. Only a path layout can share it, other
+    // layouts record in the cache file where it's from, so new_path
+    // is paged in on its own
+    pub fn linkat(
+        &self,
+        cache_dir: RawFd,
+        perms: CachePerms,
+        path: &dyn AsRef<Path>,
+        new_path: &dyn AsRef<Path>,
+    ) -> io::Result<()> {
+        let new_cache_path = self.cache_path(new_path);
+        maybe_unlinkat(cache_dir, &new_cache_path)?;
+        if *self != CacheLayout::Path || !rlibc::existat(cache_dir, path).unwrap_or(false) {
+            return Ok(());
+        }
+        if let Some(parent) = new_cache_path.parent() {
+            mkdirat_all(cache_dir, &parent, perms.dir_mode())?;
+        }
+        return rlibc::linkat(cache_dir, path, &new_cache_path);
+    }
+}
+
+// modes of what catfs creates in the cache dir. By default cache
+// files get the mode of the file they are caching and directories
+// get 0777, less the process umask
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CachePerms {
+    // used for every cache file instead, directories get it with x
+    // wherever there's r
+    pub mode: Option<libc::mode_t>,
+    // taken away from any mode
+    pub umask: libc::mode_t,
+    // cache files belong to whoever owns the file in src instead of
+    // us, so the evicter can tell whose they are. Only root can
+    pub src_owner: bool,
+}
+
+impl CachePerms {
+    pub fn file_mode(&self, mode: libc::mode_t) -> libc::mode_t {
+        return self.mode.unwrap_or(mode) & !self.umask;
+    }
+
+    pub fn dir_mode(&self) -> libc::mode_t {
+        let mode = match self.mode {
+            Some(m) => m | ((m & 0o444) >> 2),
+            None => 0o777,
+        };
+        return mode & !self.umask;
+    }
+}
+
+// the modes shown through the mount and given to what's created in
+// src, for sources whose modes aren't useful. By default they are
+// whatever src has
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct MountPerms {
+    // shown for everything that's not a directory or a symlink
+    pub file_mode: Option<libc::mode_t>,
+    pub dir_mode: Option<libc::mode_t>,
+    // taken away from what's shown and what's created
+    pub umask: libc::mode_t,
+}
+
+impl MountPerms {
+    // the permission bits of st_mode as they are shown
+    pub fn shown(&self, st_mode: libc::mode_t) -> libc::mode_t {
+        let perm = st_mode & !libc::S_IFMT;
+        let perm = match st_mode & libc::S_IFMT {
+            libc::S_IFDIR => self.dir_mode.unwrap_or(perm),
+            libc::S_IFLNK => return perm,
+            _ => self.file_mode.unwrap_or(perm),
+        };
+        return perm & !self.umask;
+    }
+
+    // the type bits of mode are kept
+    pub fn create_mode(&self, dir: bool, mode: libc::mode_t) -> libc::mode_t {
+        let perm = if dir { self.dir_mode } else { self.file_mode };
+        let perm = perm.unwrap_or(mode & !libc::S_IFMT) & !self.umask;
+        return (mode & libc::S_IFMT) | perm;
+    }
+}
+
+// what flush does when src changed after it was opened, and we have
+// changes that have yet to be copied back over it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    // ESTALE, the changes are dropped the next time it's opened
+    Fail,
+    // replace what's in src, which is what happens without checking
+    Overwrite,
+    // put the changes next to it in src as <name>.catfs-conflict-<time>
+    KeepBoth,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        return ConflictPolicy::Fail;
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            _ => Err("unrecognized conflict policy ".to_owned() + s),
+        };
+    }
+}
+
+// how much of a file opening it for read brings into the cache
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheFill {
+    // all of it, in the background
+    Full,
+    // only the blocks that are read, which are kept in a sparse
+    // cache file
+    Partial,
+}
+
+impl Default for CacheFill {
+    fn default() -> CacheFill {
+        return CacheFill::Full;
+    }
+}
+
+// what a mount opens every file with, on top of the flags of each open
+#[derive(Clone)]
+pub struct OpenOptions {
+    // how much to copy from src at a time
+    pub readahead: usize,
+    // other catfs may be using the same cache dir
+    pub shared_cache: bool,
+    pub fill: CacheFill,
+    pub small: SmallPageIns,
+    pub stats: Arc<CacheStats>,
+    pub page_in_limit: Arc<PageInLimit>,
+    // where evicted cache files may still be, see --cache-l2
+    pub l2: Option<RawFd>,
+    pub compress: Option<compress::Codec>,
+    pub key: Option<crypt::Key>,
+    pub dedupe: Option<Arc<dedupe::Index>>,
+    pub disable_splice: bool,
+    // src can't be written to, what's written stays in the cache
+    // until upload
+    pub src_readonly: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        return OpenOptions {
+            readahead: DEFAULT_READAHEAD,
+            shared_cache: false,
+            fill: Default::default(),
+            small: Default::default(),
+            stats: Default::default(),
+            page_in_limit: Default::default(),
+            l2: None,
+            compress: None,
+            key: None,
+            dedupe: None,
+            disable_splice: false,
+            src_readonly: false,
+        };
+    }
+}
+
+impl FromStr for CacheFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => return Ok(CacheFill::Full),
+            "partial" => return Ok(CacheFill::Partial),
+            _ => return Err(format!("unknown cache fill: {}", s)),
+        }
+    }
+}
+
+// which blocks of a partially filled cache file have been copied
+// from src. It's kept in the cache file along with the checksum of
+// the src it was copied from, so later opens can carry on filling
+#[derive(Debug)]
+struct Blocks {
+    size: i64,
+    block_size: i64,
+    bitmap: Vec<u8>,
+    version: Vec<u8>,
+    // how many more blocks to copy when src has to be read anyway
+    readahead: i64,
+}
+
+impl Blocks {
+    fn new(size: i64, version: Vec<u8>) -> Blocks {
+        let mut block_size = MIN_BLOCK_SIZE;
+        while (size + block_size * 8 - 1) / (block_size * 8) > MAX_BITMAP_BYTES {
+            block_size *= 2;
+        }
+        let count = (size + block_size - 1) / block_size;
+        return Blocks {
+            size: size,
+            block_size: block_size,
+            bitmap: vec![0u8; ((count + 7) / 8) as usize],
+            version: version,
+            readahead: 0,
+        };
+    }
+
+    // None if v is not for this version of src
+    fn decode(v: &[u8], size: i64, version: &[u8]) -> Option<Blocks> {
+        let n = version.len();
+        if v.len() < n + 8 || &v[..n] != version {
+            return None;
+        }
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&v[n..n + 8]);
+        let mut blocks = Blocks::new(size, version.to_vec());
+        if i64::from_le_bytes(b) != blocks.block_size || v.len() - n - 8 != blocks.bitmap.len() {
+            return None;
+        }
+        blocks.bitmap.copy_from_slice(&v[n + 8..]);
+        return Some(blocks);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = self.version.clone();
+        v.extend_from_slice(&self.block_size.to_le_bytes());
+        v.extend_from_slice(&self.bitmap);
+        return v;
+    }
+
+    fn count(&self) -> i64 {
+        return (self.size + self.block_size - 1) / self.block_size;
+    }
+
+    fn has(&self, i: i64) -> bool {
+        return self.bitmap[(i / 8) as usize] & (1 << (i % 8)) != 0;
+    }
+
+    fn is_full(&self) -> bool {
+        return (0..self.count()).all(|i| self.has(i));
+    }
+
+    // the byte ranges that are missing between blocks first and
+    // last, neighbours merged so each is one read from src
+    fn missing(&self, first: i64, last: i64) -> Vec<(i64, i64)> {
+        let mut ranges: Vec<(i64, i64)> = Vec::new();
+        for i in first..(last + 1) {
+            if self.has(i) {
+                continue;
+            }
+            let start = i * self.block_size;
+            let end = self.size.min(start + self.block_size);
+            match ranges.last_mut() {
+                Some(ref mut r) if r.1 == start => {
+                    r.1 = end;
+                    continue;
+                }
+                _ => (),
+            }
+            ranges.push((start, end));
+        }
+        return ranges;
+    }
+
+    // [start, end) was copied, which may have stopped short of a
+    // whole block
+    fn add(&mut self, start: i64, end: i64) {
+        let mut i = start / self.block_size;
+        while i < self.count() && self.size.min((i + 1) * self.block_size) <= end {
+            self.bitmap[(i / 8) as usize] |= 1 << (i % 8);
+            i += 1;
+        }
+    }
+}
+
+fn conflict_path(path: &Path) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut name = path.file_name().unwrap_or(OsStr::new("")).to_os_string();
+    name.push(format!(".catfs-conflict-{}", now.as_secs()));
+    return path.with_file_name(name);
+}
+
 #[derive(Default)]
 struct PageInInfo {
+    // everything before this is in the cache file
     offset: i64,
+    // what was copied past offset, sorted and not touching each other
+    copied: Vec<(i64, i64)>,
+    // the size of src when page in started
+    size: Option<i64>,
+    // the cache file is read with O_DIRECT, so what's copied
+    // shouldn't stay in the page cache
+    drop_cache: bool,
+    // the first offset a reader is waiting for
+    want: Option<i64>,
     dirty: bool,
     eof: bool,
+    // src changed while it was paged in, so what's cached may be
+    // part old and part new
+    src_moved: bool,
     err: Option<RError<io::Error>>,
 }
 
+impl PageInInfo {
+    fn add(&mut self, start: i64, end: i64) {
+        if start <= self.offset {
+            self.offset = self.offset.max(end);
+        } else {
+            let i = self.copied.iter().position(|r| r.1 >= start).unwrap_or(
+                self.copied.len(),
+            );
+            let mut r = (start, end);
+            while i < self.copied.len() && self.copied[i].0 <= r.1 {
+                let next = self.copied.remove(i);
+                r = (r.0.min(next.0), r.1.max(next.1));
+            }
+            self.copied.insert(i, r);
+        }
+
+        while !self.copied.is_empty() && self.copied[0].0 <= self.offset {
+            let r = self.copied.remove(0);
+            self.offset = self.offset.max(r.1);
+        }
+    }
+
+    fn has(&self, start: i64, end: i64) -> bool {
+        let start = start.max(self.offset);
+        return start >= end || self.copied.iter().any(|r| r.0 <= start && end <= r.1);
+    }
+
+    // the first offset from pos that's not copied yet
+    fn next_missing(&self, pos: i64) -> i64 {
+        let mut pos = pos.max(self.offset);
+        for r in &self.copied {
+            if r.0 <= pos && pos < r.1 {
+                pos = r.1;
+            }
+        }
+        return pos;
+    }
+
+    // where the hole at pos ends, None if it goes on to eof
+    fn hole_end(&self, pos: i64) -> Option<i64> {
+        return self.copied.iter().map(|r| r.0).find(|&start| start > pos);
+    }
+}
+
 pub struct Handle {
     src_file: File,
     cache_file: File,
     dirty: bool,
     write_through_failed: bool,
+    // what's written is only copied to src by upload
+    write_back: bool,
     has_page_in_thread: bool,
     page_in_res: CvData<PageInInfo>,
+    hints: SourceHints,
+    // there's no cache file, everything goes to src_file
+    bypass: bool,
+    // checksum of src_file when we last knew what was in it, to tell
+    // if someone else changed it before we copy back
+    src_version: Option<Vec<u8>>,
+    // what's been read into a partially filled cache file, None once
+    // all of it is there
+    partial: Option<Blocks>,
+    // how much to copy back to src at a time
+    copy_size: usize,
+    // copies to and from src go through a buffer instead of splice
+    // or copy_file_range
+    disable_splice: bool,
+    // the cache file opened with O_DIRECT for --cache-odirect, reads
+    // and writes through the mount use this when it's valid
+    direct_file: File,
+    stats: Arc<CacheStats>,
+    page_in_limit: Arc<PageInLimit>,
+    // the index of a --cache-compress cache file, which page in
+    // appends to
+    compressed: Option<Arc<RwLock<compress::Chunks>>>,
+    // what goes in the cache file is encrypted with this, see
+    // --cache-encrypt
+    key: Option<crypt::Key>,
+    // cache files that are paged in share their blocks with ones of
+    // the same content, see --cache-dedupe
+    dedupe: Option<Arc<dedupe::Index>>,
 }
 
 // no-op to workaround the fact that we send the entire CatFS at start
@@ -47,10 +630,155 @@ pub struct Handle {
 // bounds us to rust nightly
 unsafe impl Send for Handle {}
 
+// what the cache of a mount has been doing, for its stats file
+#[derive(Default)]
+pub struct CacheStats {
+    // opens that found a valid cache file, and those that didn't
+    pub hits: AtomicUsize,
+    pub misses: AtomicUsize,
+    pub bytes_read: AtomicUsize,
+    pub bytes_paged_in: AtomicUsize,
+    // hits that were moved back from --cache-l2
+    pub l2_hits: AtomicUsize,
+    // what --cache-dedupe didn't have to store again
+    pub bytes_deduped: AtomicUsize,
+}
+
+impl CacheStats {
+    fn paged_in(&self, n: usize) {
+        self.bytes_paged_in.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+// small files waiting to be paged in, by directory. Each directory
+// gets one task that goes through everything queued for it with one
+// buffer, instead of a task and a pipe per file, which is most of the
+// cost when the files are tiny
+#[derive(Clone, Default)]
+pub struct SmallPageIns {
+    queued: Arc<Mutex<HashMap<PathBuf, VecDeque<(Handle, PathBuf)>>>>,
+}
+
+impl SmallPageIns {
+    fn add(&self, h: Handle, path: PathBuf, tp: &Mutex<ThreadPool>) {
+        let limit = h.page_in_limit.clone();
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut queued = self.queued.lock().unwrap();
+        if let Some(files) = queued.get_mut(&dir) {
+            // the task for this directory will get to it
+            files.push_back((h, path));
+            return;
+        }
+
+        let mut files = VecDeque::new();
+        files.push_back((h, path));
+        queued.insert(dir.clone(), files);
+        let batch = self.clone();
+        tp.lock().unwrap().execute(move || limit.run(move || batch.run(&dir)));
+    }
+
+    fn run(&self, dir: &Path) {
+        let mut buf = vec![0u8; SMALL_FILE_SIZE as usize];
+        let mut npaged = 0;
+        loop {
+            let (mut h, path) = {
+                let mut queued = self.queued.lock().unwrap();
+                match queued.get_mut(dir).and_then(|files| files.pop_front()) {
+                    Some(f) => f,
+                    None => {
+                        queued.remove(dir);
+                        break;
+                    }
+                }
+            };
+
+            let res = h.copy_small(&mut buf);
+            h.page_in_done(&path, res);
+            npaged += 1;
+        }
+        debug!("paged in {} small files in {:?}", npaged, dir);
+    }
+}
+
+// the whole blocks that [offset, offset + len) is in
+fn direct_range(offset: i64, len: usize) -> (i64, i64) {
+    let align = DIRECT_ALIGN as i64;
+    let start = offset - offset % align;
+    let end = (offset + len as i64 + align - 1) / align * align;
+    return (start, end);
+}
+
+// a buffer that O_DIRECT can read into and write from
+struct AlignedBuf {
+    v: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let v = vec![0u8; len + DIRECT_ALIGN];
+        let start = (DIRECT_ALIGN - (v.as_ptr() as usize) % DIRECT_ALIGN) % DIRECT_ALIGN;
+        return AlignedBuf {
+            v: v,
+            start: start,
+            len: len,
+        };
+    }
+
+    fn len(&self) -> usize {
+        return self.len;
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        return &self.v[self.start..self.start + self.len];
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        return &mut self.v[self.start..self.start + self.len];
+    }
+}
+
 fn make_rdwr(f: &mut u32) {
     *f = (*f & !rlibc::O_ACCMODE) | rlibc::O_RDWR;
 }
 
+// how to open src for flags, a read-only src is only read from and
+// upload opens it again for write
+fn src_flags(flags: u32, opts: &OpenOptions) -> u32 {
+    if opts.src_readonly {
+        return (flags & !(rlibc::O_ACCMODE | rlibc::O_TRUNC | rlibc::O_CREAT)) | rlibc::O_RDONLY;
+    }
+    return flags;
+}
+
+// how many times a cache file was opened while it was valid
+pub fn hits(f: &File) -> u64 {
+    if let Ok(Some(v)) = f.get_xattr(HITS_XATTR) {
+        if let Ok(s) = String::from_utf8(v) {
+            return s.parse().unwrap_or(0);
+        }
+    }
+    return 0;
+}
+
+// whether all of a cache file was paged in, or it was flushed to src.
+// It may have changed in src since
+pub fn was_pristine(f: &File) -> bool {
+    match f.get_xattr("user.catfs.src_chksum") {
+        Ok(v) => return v.is_some(),
+        Err(_) => return false,
+    }
+}
+
+// whether a cache file is filled by block, see CacheFill::Partial
+pub fn is_filled_by_block(f: &File) -> bool {
+    match f.get_xattr(BLOCKS_XATTR) {
+        Ok(v) => return v.is_some(),
+        Err(_) => return false,
+    }
+}
+
 fn maybe_unlinkat(dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<()> {
     if let Err(e) = rlibc::unlinkat(dir, path, 0) {
         if !error::is_enoent(&e) {
@@ -80,11 +808,15 @@ pub fn mkdirat_all(dir: RawFd, path: &dyn AsRef<Path>, mode: libc::mode_t) -> io
 
 impl Handle {
     pub fn create(
-        src_dir: RawFd,
+        src: &dyn SourceBackend,
         cache_dir: RawFd,
+        layout: CacheLayout,
+        perms: CachePerms,
         path: &dyn AsRef<Path>,
         flags: u32,
         mode: libc::mode_t,
+        key: Option<crypt::Key>,
+        disable_splice: bool,
     ) -> error::Result<Handle> {
         // need to read the cache file for writeback
         let mut cache_flags = flags;
@@ -93,35 +825,65 @@ impl Handle {
         }
         //debug!("create {:b} {:b} {:#o}", flags, cache_flags, mode);
 
-        if let Some(parent) = path.as_ref().parent() {
-            mkdirat_all(cache_dir, &parent, 0o777)?;
-        }
-
-        let src_file = File::openat(src_dir, path, flags, mode)?;
+        let src_file = src.open(path, flags, mode)?;
         // we are able to create the src file, then the cache file
         // shouldn't be here, but it could be because of bug/crash,
         // so unlink it first
-        maybe_unlinkat(cache_dir, path)?;
+        maybe_unlinkat(cache_dir, &layout.cache_path(path))?;
+        let cache_file = layout.openat(cache_dir, perms, path, cache_flags, mode)?;
+        let key = Handle::key_for(key, &cache_file)?;
 
-        return Ok(Handle {
+        let mut handle = Handle {
             src_file: src_file,
-            cache_file: File::openat(cache_dir, path, cache_flags, mode)?,
+            cache_file: cache_file,
             dirty: true,
-            write_through_failed: false,
+            write_through_failed: src.hints().sequential_writes,
+            write_back: false,
             has_page_in_thread: false,
             page_in_res: Arc::new((Default::default(), Condvar::new())),
-        });
+            hints: src.hints(),
+            bypass: false,
+            src_version: None,
+            partial: None,
+            copy_size: DEFAULT_READAHEAD,
+            disable_splice: disable_splice,
+            direct_file: Default::default(),
+            stats: Default::default(),
+            page_in_limit: Default::default(),
+            compressed: None,
+            key: key,
+            dedupe: None,
+        };
+        handle.prepare_cache_file()?;
+        handle.remember_src_version();
+        return Ok(handle);
     }
 
     pub fn open(
-        src_dir: RawFd,
+        src: &dyn SourceBackend,
         cache_dir: RawFd,
+        layout: CacheLayout,
+        perms: CachePerms,
         path: &dyn AsRef<Path>,
         flags: u32,
         cache_valid_if_present: bool,
-        disable_splice: bool,
         tp: &Mutex<ThreadPool>,
+        opts: &OpenOptions,
     ) -> error::Result<Handle> {
+        if (flags & rlibc::O_ACCMODE) == rlibc::O_WRONLY && (flags & rlibc::O_TRUNC) != 0 {
+            return Handle::open_truncated(
+                src,
+                cache_dir,
+                layout,
+                perms,
+                path,
+                flags,
+                src_flags(flags, opts),
+                opts.key.clone(),
+                opts.disable_splice,
+            );
+        }
+
         // even if file is open for write only, I still need to be
         // able to read the src for read-modify-write
         let mut flags = flags;
@@ -129,8 +891,20 @@ impl Handle {
             make_rdwr(&mut flags);
         }
 
-        let valid =
-            Handle::validate_cache(src_dir, cache_dir, &path, cache_valid_if_present, false)?;
+        let mut valid =
+            Handle::validate_cache(src, cache_dir, layout, &path, cache_valid_if_present, false)?;
+        if !valid && (flags & rlibc::O_TRUNC) == 0 {
+            if let Some(l2) = opts.l2 {
+                valid = Handle::promote(src, cache_dir, l2, layout, perms, &path)?;
+                if valid {
+                    opts.stats.l2_hits.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        let writable = (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY;
+        if Handle::drop_unusable(cache_dir, layout, &path, writable, &opts.key)? {
+            valid = false;
+        }
         debug!(
             "{:?} {} a valid cache file",
             path.as_ref(),
@@ -139,10 +913,6 @@ impl Handle {
         let mut cache_flags = flags;
 
         if !valid {
-            // mkdir the parents
-            if let Some(parent) = path.as_ref().parent() {
-                mkdirat_all(cache_dir, &parent, 0o777)?;
-            }
             // need to cache this file so need to open it for write
             cache_flags |= rlibc::O_CREAT;
             if (cache_flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY {
@@ -150,63 +920,238 @@ impl Handle {
             }
         }
 
+        // only for reads, what's written is copied back whole. An
+        // immutable src is checked by size alone, which a sparse file
+        // can have without being complete
+        let partial = !valid && opts.fill == CacheFill::Partial &&
+            (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY && !src.hints().immutable;
+
+        let cache_file = layout.openat(cache_dir, perms, path, cache_flags, 0o666)?;
+        if partial && opts.shared_cache {
+            // this is never done, don't hold up others sharing the
+            // cache dir, but keep them from deleting it if we can
+            rlibc::try_flock(cache_file.as_raw_fd(), libc::LOCK_EX)?;
+        } else if !valid && opts.shared_cache && (flags & rlibc::O_TRUNC) == 0 {
+            valid = Handle::wait_for_page_in(src, path, &cache_file)?;
+        }
+
         let src_file = if valid && (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY {
             Default::default()
         } else {
-            File::openat(src_dir, path, flags, 0o666)?
+            src.open(path, src_flags(flags, opts), 0o666)?
         };
+        let key = Handle::key_for(opts.key.clone(), &cache_file)?;
 
         let mut handle = Handle {
             src_file: src_file,
-            cache_file: File::openat(cache_dir, path, cache_flags, 0o666)?,
+            cache_file: cache_file,
             dirty: false,
-            write_through_failed: false,
+            write_through_failed: src.hints().sequential_writes,
+            write_back: false,
             has_page_in_thread: false,
             page_in_res: Arc::new((Default::default(), Condvar::new())),
+            hints: src.hints(),
+            bypass: false,
+            src_version: None,
+            partial: None,
+            copy_size: opts.readahead,
+            disable_splice: opts.disable_splice,
+            direct_file: Default::default(),
+            stats: opts.stats.clone(),
+            page_in_limit: opts.page_in_limit.clone(),
+            compressed: None,
+            key: key,
+            dedupe: opts.dedupe.clone(),
         };
+        if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+            handle.remember_src_version();
+        }
+        if opts.src_readonly && writable && (flags & rlibc::O_TRUNC) != 0 {
+            // src wasn't truncated, upload has to do it
+            handle.dirty = true;
+            handle.set_pristine(false)?;
+        }
+        if !valid && perms.src_owner {
+            handle.set_cache_owner(path);
+        }
+        if valid {
+            handle.compressed = compress::Chunks::load(&handle.cache_file)?.map(|c| {
+                Arc::new(RwLock::new(c))
+            });
+        } else {
+            handle.prepare_cache_file()?;
+        }
+        if valid {
+            handle.count_hit();
+            opts.stats.hits.fetch_add(1, Ordering::Relaxed);
+        } else if (flags & rlibc::O_TRUNC) == 0 {
+            opts.stats.misses.fetch_add(1, Ordering::Relaxed);
+        }
 
-        if !valid && (flags & rlibc::O_TRUNC) == 0 {
+        if partial {
+            handle.start_partial(path, opts.readahead)?;
+        } else if !valid && (flags & rlibc::O_TRUNC) == 0 {
             debug!("read ahead {:?}", path.as_ref());
+            let small_file = handle.src_file.filesize()? <= SMALL_FILE_SIZE;
+            // what's written to has to stay plain, and small files
+            // aren't worth it
+            if let Some(codec) = opts.compress {
+                if (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY && !small_file {
+                    handle.compressed = Some(Arc::new(RwLock::new(compress::Chunks::new(codec))));
+                }
+            }
             handle.has_page_in_thread = true;
             let mut h = handle.clone();
             let path = path.as_ref().to_path_buf();
-            tp.lock().unwrap().execute(move || {
-                if let Err(e) = h.copy(true, disable_splice) {
-                    let mut is_cancel = false;
-
-                    {
-                        let page_in_res = h.page_in_res.0.lock().unwrap();
-                        if let Some(ref e2) = page_in_res.err {
-                            if e2.raw_os_error().unwrap() == libc::ECANCELED {
-                                is_cancel = true;
-                            }
-                        }
-                    }
-
-                    if !is_cancel {
-                        error!("read ahead {:?} failed: {}", path, e);
-                        h.notify_offset(Err(e), false).unwrap();
-                    } else {
-                        debug!("read ahead {:?} canceled", path);
-                    }
-                }
-                // the files are always closed in the main IO path, consume
-                // the fds to prevent closing
-                h.src_file.into_raw();
-                h.cache_file.into_raw();
-            });
+            if small_file {
+                opts.small.add(h, path, tp);
+            } else {
+                let limit = handle.page_in_limit.clone();
+                let (disable_splice, readahead) = (opts.disable_splice, opts.readahead);
+                tp.lock().unwrap().execute(move || {
+                    limit.run(move || {
+                        let res = if h.compressed.is_some() {
+                            h.page_in_compressed()
+                        } else {
+                            h.page_in(disable_splice, readahead)
+                        };
+                        h.page_in_done(&path, res);
+                    })
+                });
+            }
         }
 
         return Ok(handle);
     }
 
+    // the old content is about to be thrown away, so there's nothing
+    // to validate or page in and the src never has to be read. Like
+    // create, this is dirty so flush marks it pristine
+    fn open_truncated(
+        src: &dyn SourceBackend,
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        perms: CachePerms,
+        path: &dyn AsRef<Path>,
+        flags: u32,
+        src_flags: u32,
+        key: Option<crypt::Key>,
+        disable_splice: bool,
+    ) -> error::Result<Handle> {
+        // need to read the cache file for writeback
+        let mut cache_flags = flags | rlibc::O_CREAT;
+        make_rdwr(&mut cache_flags);
+
+        let src_file = src.open(path, src_flags, 0o666)?;
+        let cache_file = layout.openat(cache_dir, perms, path, cache_flags, 0o666)?;
+        let key = Handle::key_for(key, &cache_file)?;
+        let mut handle = Handle {
+            src_file: src_file,
+            cache_file: cache_file,
+            dirty: true,
+            write_through_failed: src.hints().sequential_writes,
+            write_back: false,
+            has_page_in_thread: false,
+            page_in_res: Arc::new((Default::default(), Condvar::new())),
+            hints: src.hints(),
+            bypass: false,
+            src_version: None,
+            partial: None,
+            copy_size: DEFAULT_READAHEAD,
+            disable_splice: disable_splice,
+            direct_file: Default::default(),
+            stats: Default::default(),
+            page_in_limit: Default::default(),
+            compressed: None,
+            key: key,
+            dedupe: None,
+        };
+        handle.remember_src_version();
+        // in case we don't get to flush
+        handle.set_pristine(false)?;
+        handle.prepare_cache_file()?;
+        debug!("{:?} is truncated, not reading it", path.as_ref());
+        return Ok(handle);
+    }
+
+    // for processes that shouldn't touch the cache. If this can
+    // change src, the cache file is removed so it won't be used stale
+    pub fn open_bypass(
+        src: &dyn SourceBackend,
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        path: &dyn AsRef<Path>,
+        flags: u32,
+        mode: libc::mode_t,
+    ) -> error::Result<Handle> {
+        let src_file = src.open(path, flags, mode)?;
+        if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+            if let Err(e) = maybe_unlinkat(cache_dir, &layout.cache_path(path)) {
+                // the cache dir may be failing, which is why we are
+                // here. What's left is checked against src before
+                // it's used again
+                warn!("!unlink cache of {:?} = {}", path.as_ref(), e);
+            }
+        }
+
+        return Ok(Handle {
+            src_file: src_file,
+            cache_file: Default::default(),
+            dirty: false,
+            write_through_failed: false,
+            write_back: false,
+            has_page_in_thread: false,
+            page_in_res: Arc::new((Default::default(), Condvar::new())),
+            hints: src.hints(),
+            bypass: true,
+            src_version: None,
+            partial: None,
+            copy_size: DEFAULT_READAHEAD,
+            disable_splice: false,
+            direct_file: Default::default(),
+            stats: Default::default(),
+            page_in_limit: Default::default(),
+            compressed: None,
+            key: None,
+            dedupe: None,
+        });
+    }
+
+    // another catfs sharing the cache dir may be paging this file
+    // in, wait for it and use what it cached if that's still
+    // current. Otherwise we hold the lock until our page in is done
+    fn wait_for_page_in(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        cache_file: &File,
+    ) -> error::Result<bool> {
+        rlibc::flock(cache_file.as_raw_fd(), libc::LOCK_EX)?;
+        if Handle::is_valid(src, path, cache_file)? {
+            rlibc::flock(cache_file.as_raw_fd(), libc::LOCK_UN)?;
+            debug!("{:?} was cached by someone else", path.as_ref());
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
     // see validate_cache.sh on how to replicate this
-    pub fn src_str_to_checksum(f: &File) -> error::Result<OsString> {
+    fn checksum_str<F>(get_xattr: F, st: &rlibc::stat64, hints: &SourceHints) -> error::Result<OsString>
+    where
+        F: Fn(&OsStr) -> io::Result<Option<Vec<u8>>>,
+    {
+        let v = &hints.validation;
         let mut s = OsString::new();
-        for x in ["s3.etag"].iter() {
-            match f.get_xattr(&x) {
+        let mut has_version = false;
+        let xattrs: Vec<&str> = match v.version_xattrs {
+            _ if !v.version => Vec::new(),
+            Some(ref xattrs) => xattrs.iter().map(|x| x.as_str()).collect(),
+            None => VERSION_XATTRS.to_vec(),
+        };
+        for x in xattrs {
+            match get_xattr(OsStr::new(x)) {
                 Ok(v) => {
                     if let Some(v) = v {
+                        has_version = true;
                         s.push(x);
                         s.push(OsStr::new("="));
                         s.push("0x");
@@ -225,36 +1170,69 @@ impl Handle {
             }
         }
 
+        // goofys reports a new mtime for the same object once it lists
+        // it again, don't throw away the cache because of that
+        if v.mtime && !(hints.etag_versions && has_version) {
+            s.push(format!("{}\n", st.st_mtime));
+        }
+        if v.size {
+            s.push(format!("{}\n", st.st_size));
+        }
+        return Ok(s);
+    }
+
+    pub fn src_str_to_checksum(f: &File, hints: &SourceHints) -> error::Result<OsString> {
         let st = f.stat()?;
-        s.push(format!("{}\n", st.st_mtime));
-        s.push(format!("{}\n", st.st_size));
-        return Ok(s);
+        return Handle::checksum_str(|name| f.get_xattr(name), &st, hints);
     }
 
-    fn src_chksum(f: &File) -> error::Result<GenericArray<u8, U64>> {
-        let s = Handle::src_str_to_checksum(f)?;
+    fn hash_checksum_str(s: OsString) -> GenericArray<u8, U64> {
         //debug!("checksum is {:?}", s);
         let mut hasher = Sha512::default();
         hasher.update(s.as_bytes());
-        return Ok(hasher.finalize());
+        return hasher.finalize();
+    }
+
+    fn src_chksum(f: &File, hints: &SourceHints) -> error::Result<GenericArray<u8, U64>> {
+        return Ok(Handle::hash_checksum_str(
+            Handle::src_str_to_checksum(f, hints)?,
+        ));
+    }
+
+    // same as src_chksum but without opening the file, which for
+    // remote sources can mean fetching it
+    pub fn src_chksum_at(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+    ) -> error::Result<GenericArray<u8, U64>> {
+        let st = src.stat(path)?;
+        let s = Handle::checksum_str(
+            |name| src.get_xattr(path, name),
+            &st,
+            &src.hints(),
+        )?;
+        return Ok(Handle::hash_checksum_str(s));
     }
 
     pub fn make_pristine(
-        src_dir: RawFd,
+        src: &dyn SourceBackend,
         cache_dir: RawFd,
+        layout: CacheLayout,
         path: &dyn AsRef<Path>,
     ) -> error::Result<()> {
-        match File::openat(cache_dir, path, rlibc::O_WRONLY, 0) {
+        if src.hints().immutable {
+            return Ok(());
+        }
+        match layout.openat(cache_dir, Default::default(), path, rlibc::O_WRONLY, 0) {
             Err(e) => {
-                return Err(RError::from(e));
+                // a compressed cache file that was truncated is gone
+                error::try_enoent(e)?;
             }
             Ok(mut cache) => {
-                let mut src = File::openat(src_dir, path, rlibc::O_RDONLY, 0)?;
                 cache.set_xattr(
                     "user.catfs.src_chksum",
-                    Handle::src_chksum(&src)?.as_slice(),
+                    Handle::src_chksum_at(src, path)?.as_slice(),
                 )?;
-                src.close()?;
                 cache.close()?;
             }
         }
@@ -262,27 +1240,170 @@ impl Handle {
         return Ok(());
     }
 
+    pub fn is_bypass(&self) -> bool {
+        return self.bypass;
+    }
+
+    pub fn is_partial(&self) -> bool {
+        return self.partial.is_some();
+    }
+
+    // waits until all of the file is in the cache
+    pub fn wait_for_cache(&mut self) -> error::Result<()> {
+        if let Some(size) = self.partial.as_ref().map(|blocks| blocks.size) {
+            self.fill_blocks(0, size as usize)?;
+        }
+        if self.has_page_in_thread {
+            self.wait_for_offset(0, i64::max_value(), false)?;
+        }
+        return Ok(());
+    }
+
+    // picks up the blocks an earlier open left in the cache file if
+    // src is still the same, otherwise starts over with a sparse file
+    // the size of src
+    fn start_partial(&mut self, path: &dyn AsRef<Path>, readahead: usize) -> error::Result<()> {
+        let size = self.src_file.filesize()? as i64;
+        let version = Handle::src_chksum(&self.src_file, &self.hints)?.to_vec();
+        let old = match self.cache_file.get_xattr(BLOCKS_XATTR)? {
+            Some(v) => Blocks::decode(&v, size, &version),
+            None => None,
+        };
+        let mut blocks = match old {
+            Some(blocks) => blocks,
+            None => {
+                self.cache_truncate(0)?;
+                self.cache_truncate(size as u64)?;
+                Blocks::new(size, version)
+            }
+        };
+        blocks.readahead = readahead as i64 / blocks.block_size;
+        debug!(
+            "partially caching {:?}, {} blocks of {}",
+            path.as_ref(),
+            blocks.count(),
+            blocks.block_size
+        );
+
+        if blocks.is_full() {
+            return self.set_pristine(true);
+        }
+        self.partial = Some(blocks);
+        return Ok(());
+    }
+
+    // copies the blocks that are about to be read into a partially
+    // filled cache file if they are not there yet
+    fn fill_blocks(&mut self, offset: i64, len: usize) -> error::Result<()> {
+        let missing = {
+            let blocks = self.partial.as_ref().unwrap();
+            let end = blocks.size.min(offset + len as i64);
+            if offset >= end {
+                return Ok(());
+            }
+            let first = offset / blocks.block_size;
+            let last = (end - 1) / blocks.block_size;
+            if blocks.missing(first, last).is_empty() {
+                return Ok(());
+            }
+            // src is read anyway, bring in what's likely read next
+            blocks.missing(first, (last + blocks.readahead).min(blocks.count() - 1))
+        };
+
+        let mut buf = vec![0u8; (missing[0].1 - missing[0].0).min(1024 * 1024) as usize];
+        for (start, end) in missing {
+            let mut offset = start;
+            while offset < end {
+                let want = (end - offset).min(buf.len() as i64) as usize;
+                let src_file = &self.src_file;
+                let n = self.hints.retry.run(|| src_file.read_at(&mut buf[..want], offset))?;
+                if n == 0 {
+                    break;
+                }
+                self.cache_write_at(&buf[..n], offset)?;
+                offset += n as i64;
+                self.stats.paged_in(n);
+            }
+            self.partial.as_mut().unwrap().add(start, offset);
+        }
+
+        let (full, v) = {
+            let blocks = self.partial.as_ref().unwrap();
+            (blocks.is_full(), blocks.encode())
+        };
+        if full {
+            // everything is here now, so it's as good as a full page in
+            self.partial = None;
+            return self.set_pristine(true);
+        }
+        if let Err(e) = self.cache_file.set_xattr(BLOCKS_XATTR, &v) {
+            // the next open starts over
+            debug!("!set {} = {}", BLOCKS_XATTR, e);
+        }
+        return Ok(());
+    }
+
     pub fn set_pristine(&self, pristine: bool) -> error::Result<()> {
+        if self.hints.immutable || self.bypass {
+            return Ok(());
+        }
+        // either the blocks are all there or what's there is not
+        // from src anymore
+        self.remove_cache_xattr(BLOCKS_XATTR)?;
         if pristine {
             self.cache_file.set_xattr(
                 "user.catfs.src_chksum",
-                Handle::src_chksum(&self.src_file)?
+                Handle::src_chksum(&self.src_file, &self.hints)?
                     .as_slice(),
             )?;
         } else {
-            if let Err(e) = self.cache_file.remove_xattr("user.catfs.src_chksum") {
-                let my_errno = e.raw_os_error().unwrap();
-                if my_errno != libc::ENODATA && my_errno != libc::ENOATTR {
-                    return Err(RError::from(e));
-                }
+            self.remove_cache_xattr("user.catfs.src_chksum")?;
+            // nothing that's written to is compressed
+            self.remove_cache_xattr(compress::COMPRESS_XATTR)?;
+            // or can be shared
+            self.remove_cache_xattr(dedupe::CONTENT_XATTR)?;
+        }
+        return Ok(());
+    }
+
+    // good enough if two opens race, so failing to count is fine too
+    fn count_hit(&self) {
+        let n = hits(&self.cache_file) + 1;
+        if let Err(e) = self.cache_file.set_xattr(HITS_XATTR, n.to_string().as_bytes()) {
+            debug!("!count_hit = {}", e);
+        }
+    }
+
+    fn remove_cache_xattr(&self, name: &str) -> error::Result<()> {
+        if let Err(e) = self.cache_file.remove_xattr(name) {
+            let my_errno = e.raw_os_error().unwrap();
+            if my_errno != libc::ENODATA && my_errno != libc::ENOATTR {
+                return Err(RError::from(e));
             }
         }
         return Ok(());
     }
 
-    fn is_pristine(src_file: &File, cache_file: &File) -> error::Result<bool> {
+    // a partially filled cache file that's still for what's in src
+    pub fn has_blocks(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        cache_file: &File,
+    ) -> error::Result<bool> {
+        if let Some(v) = cache_file.get_xattr(BLOCKS_XATTR)? {
+            let version = Handle::src_chksum_at(src, path)?;
+            return Ok(v.starts_with(version.as_slice()));
+        }
+        return Ok(false);
+    }
+
+    fn is_pristine(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        cache_file: &File,
+    ) -> error::Result<bool> {
         if let Some(v) = cache_file.get_xattr("user.catfs.src_chksum")? {
-            let expected = Handle::src_chksum(src_file)?;
+            let expected = Handle::src_chksum_at(src, path)?;
             if v == expected.as_slice() {
                 return Ok(true);
             } else {
@@ -295,38 +1416,199 @@ impl Handle {
         return Ok(false);
     }
 
-    pub fn unlink(src_dir: RawFd, cache_dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<()> {
-        maybe_unlinkat(cache_dir, path)?;
-        return rlibc::unlinkat(src_dir, path, 0);
+    // an immutable source never changes what it has, so the cache
+    // file is good once all of it is paged in
+    fn is_valid(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        cache_file: &File,
+    ) -> error::Result<bool> {
+        // what's checked against src is the size before compression,
+        // and a compressed file with a broken index is no good at all
+        let size = match compress::Reader::new(cache_file).and_then(|r| r.size()) {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("{:?} has a bad cache file: {}", path.as_ref(), e);
+                return Ok(false);
+            }
+        };
+        if src.hints().immutable {
+            let st = src.stat(path)?;
+            return Ok(size == st.st_size);
+        }
+        return Handle::is_pristine(src, path, cache_file);
+    }
+
+    pub fn unlink(
+        src: &dyn SourceBackend,
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        path: &dyn AsRef<Path>,
+    ) -> io::Result<()> {
+        maybe_unlinkat(cache_dir, &layout.cache_path(path))?;
+        return src.unlink(path);
+    }
+
+    // a valid cache file in l2 is moved back to the cache dir, which
+    // is still cheaper than paging it in from src again
+    fn promote(
+        src: &dyn SourceBackend,
+        cache_dir: RawFd,
+        l2: RawFd,
+        layout: CacheLayout,
+        perms: CachePerms,
+        path: &dyn AsRef<Path>,
+    ) -> error::Result<bool> {
+        if !Handle::validate_cache(src, l2, layout, path, false, false)? {
+            return Ok(false);
+        }
+        let cache_path = layout.cache_path(path);
+        if let Err(e) = tier::move_at(l2, &cache_path, cache_dir, &cache_path, perms.dir_mode()) {
+            warn!("!promote {:?} = {}", path.as_ref(), e);
+            return Ok(false);
+        }
+        debug!("{:?} promoted from l2", path.as_ref());
+        return Ok(true);
+    }
+
+    // removes a cache file we can't use and returns true. A compressed
+    // cache file can't be written to in place, it's paged in again
+    // plain, which is fine since it was never written to. One that's
+    // encrypted with another key, or is and shouldn't be, is as good
+    // as gone
+    fn drop_unusable(
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        path: &dyn AsRef<Path>,
+        writable: bool,
+        key: &Option<crypt::Key>,
+    ) -> error::Result<bool> {
+        let cache_path = layout.cache_path(path);
+        let mut cache_file = match File::openat(cache_dir, &cache_path, rlibc::O_RDONLY, 0) {
+            Ok(f) => f,
+            Err(e) => {
+                error::try_enoent(e)?;
+                return Ok(false);
+            }
+        };
+        let res = (|| -> io::Result<Option<&'static str>> {
+            if writable && compress::Codec::of(&cache_file)?.is_some() {
+                return Ok(Some("compressed"));
+            }
+            let usable = match *key {
+                Some(ref key) => key.opens(&cache_file)?,
+                None => !crypt::is_sealed(&cache_file)?,
+            };
+            return Ok(if usable { None } else { Some("encrypted differently") });
+        })();
+        cache_file.close()?;
+        let why = match res? {
+            Some(why) => why,
+            None => return Ok(false),
+        };
+        debug!("{:?} is {}, caching it again", path.as_ref(), why);
+        maybe_unlinkat(cache_dir, &cache_path)?;
+        return Ok(true);
+    }
+
+    // the key has to lock what it's encrypting, see Key::for_file
+    fn key_for(key: Option<crypt::Key>, cache_file: &File) -> io::Result<Option<crypt::Key>> {
+        return match key {
+            Some(key) => Ok(Some(key.for_file(cache_file)?)),
+            None => Ok(None),
+        };
+    }
+
+    // a cache file that's about to be filled is made plain, or
+    // encrypted with our key if we have one
+    fn prepare_cache_file(&self) -> error::Result<()> {
+        self.remove_cache_xattr(compress::COMPRESS_XATTR)?;
+        self.remove_cache_xattr(dedupe::CONTENT_XATTR)?;
+        match self.key {
+            Some(ref key) => {
+                if !key.opens(&self.cache_file)? {
+                    self.cache_file.truncate(0)?;
+                    key.seal(&self.cache_file)?;
+                }
+            }
+            None => {
+                if crypt::is_sealed(&self.cache_file)? {
+                    self.cache_file.truncate(0)?;
+                    self.remove_cache_xattr(crypt::ENCRYPT_XATTR)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn cache_read_at(&self, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        return match self.key {
+            Some(ref key) => key.read_at(&self.cache_file, buf, offset),
+            None => self.cache_file.read_at(buf, offset),
+        };
+    }
+
+    fn cache_write_at(&self, buf: &[u8], offset: i64) -> io::Result<usize> {
+        return match self.key {
+            Some(ref key) => key.write_at(&self.cache_file, buf, offset),
+            None => self.cache_file.write_at(buf, offset),
+        };
+    }
+
+    // the size of what's in the cache file, which is smaller than the
+    // file when it's encrypted
+    fn cache_size(&self) -> io::Result<u64> {
+        return match self.key {
+            Some(_) => crypt::size(&self.cache_file),
+            None => self.cache_file.filesize(),
+        };
+    }
+
+    fn cache_truncate(&self, size: u64) -> io::Result<()> {
+        return match self.key {
+            Some(ref key) => key.set_size(&self.cache_file, size),
+            None => self.cache_file.truncate(size),
+        };
     }
 
     pub fn validate_cache(
-        src_dir: RawFd,
+        src: &dyn SourceBackend,
         cache_dir: RawFd,
+        layout: CacheLayout,
         path: &dyn AsRef<Path>,
         cache_valid_if_present: bool,
         check_only: bool,
     ) -> error::Result<bool> {
-        match File::openat(src_dir, path, rlibc::O_RDONLY, 0) {
-            Ok(mut src_file) => {
-                match File::openat(cache_dir, path, rlibc::O_RDONLY, 0) {
+        let cache_path = layout.cache_path(path);
+        match src.stat(path) {
+            Ok(_) => {
+                match File::openat(cache_dir, &cache_path, rlibc::O_RDONLY, 0) {
                     Ok(mut cache_file) => {
                         let valid: bool;
-                        if cache_valid_if_present || Handle::is_pristine(&src_file, &cache_file)? {
+                        if cache_valid_if_present ||
+                            Handle::is_valid(src, path, &cache_file)?
+                        {
                             valid = true;
                         } else {
                             valid = false;
-                            if !check_only {
-                                error!("{:?} is not a valid cache file, deleting", path.as_ref());
-                                rlibc::unlinkat(cache_dir, path, 0)?;
+                            if !check_only && !Handle::has_blocks(src, path, &cache_file)? {
+                                if rlibc::try_flock(cache_file.as_raw_fd(), libc::LOCK_EX)? {
+                                    error!(
+                                        "{:?} is not a valid cache file, deleting",
+                                        path.as_ref()
+                                    );
+                                    rlibc::unlinkat(cache_dir, &cache_path, 0)?;
+                                } else {
+                                    // another catfs sharing the cache
+                                    // dir is paging this in
+                                    debug!("{:?} is being cached", path.as_ref());
+                                }
                             }
                         }
-                        src_file.close()?;
                         cache_file.close()?;
                         return Ok(valid);
                     }
                     Err(e) => {
-                        src_file.close()?;
                         if error::try_enoent(e)? {
                             return Ok(false);
                         }
@@ -337,7 +1619,7 @@ impl Handle {
                 if error::try_enoent(e)? {
                     // the source file doesn't exist, the cache file shouldn't either
                     if !check_only {
-                        maybe_unlinkat(cache_dir, path)?;
+                        maybe_unlinkat(cache_dir, &cache_path)?;
                     }
                 }
             }
@@ -347,18 +1629,47 @@ impl Handle {
     }
 
     pub fn read(&mut self, offset: i64, buf: &mut [u8]) -> error::Result<usize> {
+        if self.partial.is_some() {
+            self.fill_blocks(offset, buf.len())?;
+        }
+        if self.has_page_in_thread {
+            self.wait_for_offset(offset, offset + (buf.len() as i64), false)?;
+        }
+
+        return self.read_shared(offset, buf);
+    }
+
+    // reads that don't need to change the handle, so they can run
+    // alongside each other. A partially filled cache file needs
+    // read() instead
+    pub fn read_shared(&self, offset: i64, buf: &mut [u8]) -> error::Result<usize> {
         let nwant = buf.len();
         let mut bytes_read: usize = 0;
 
         if self.has_page_in_thread {
-            self.wait_for_offset(offset + (buf.len() as i64), false)?;
+            self.wait_for_page_in(offset, offset + (nwant as i64), false)?;
+        }
+
+        if let Some(ref chunks) = self.compressed {
+            return Ok(chunks.read().unwrap().read_at(&self.cache_file, buf, offset)?);
+        }
+
+        if self.direct_file.valid() {
+            return Ok(self.read_direct(offset, buf)?);
         }
 
+        // only src is worth trying again
+        let retry = if self.bypass {
+            self.hints.retry
+        } else {
+            Default::default()
+        };
         while bytes_read < nwant {
-            match self.cache_file.read_at(
-                &mut buf[bytes_read..],
-                offset + (bytes_read as i64),
-            ) {
+            match retry.run(|| if self.bypass {
+                self.src_file.read_at(&mut buf[bytes_read..], offset + (bytes_read as i64))
+            } else {
+                self.cache_read_at(&mut buf[bytes_read..], offset + (bytes_read as i64))
+            }) {
                 Ok(nread) => {
                     if nread == 0 {
                         return Ok(bytes_read);
@@ -382,6 +1693,9 @@ impl Handle {
         // pristiness comes from size as well so this automatically
         // invalidates the cache file if it's used again
         self.src_file.set_size(size)?;
+        if self.bypass {
+            return Ok(());
+        }
 
         // wait for the background thread to finish so we won't have
         // more bytes being concurrently written to cache_file
@@ -389,20 +1703,51 @@ impl Handle {
             self.wait_for_eof()?;
         }
 
-        self.cache_file.set_size(size)?;
+        match self.key {
+            Some(ref key) => key.set_size(&self.cache_file, size)?,
+            None => self.cache_file.set_size(size)?,
+        }
         // caller is responsible for setting this to pristine if necessary
         return Ok(());
     }
 
     pub fn chmod(&self, mode: libc::mode_t) -> io::Result<()> {
+        if self.hints.no_attrs {
+            return Ok(());
+        }
         self.src_file.chmod(mode)?;
         return Ok(());
     }
 
+    // see CachePerms::src_owner
+    fn set_cache_owner(&self, path: &dyn AsRef<Path>) {
+        let res = self.src_file.stat().and_then(
+            |st| self.cache_file.chown(st.st_uid, st.st_gid),
+        );
+        if let Err(e) = res {
+            warn!("!chown cache file of {:?} = {}", path.as_ref(), e);
+        }
+    }
+
+    pub fn chown(&self, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+        if self.hints.no_attrs {
+            return Ok(());
+        }
+        return self.src_file.chown(uid, gid);
+    }
+
     pub fn write(&mut self, offset: i64, buf: &[u8]) -> error::Result<usize> {
         let nwant = buf.len();
         let mut bytes_written: usize = 0;
 
+        if self.bypass {
+            let nwritten = self.src_file.write_at(buf, offset)?;
+            if nwritten != 0 {
+                self.dirty = true;
+            }
+            return Ok(nwritten);
+        }
+
         if !self.dirty {
             // assumes that the metadata will hit the disk before the
             // incoming data will, and not flushing
@@ -410,7 +1755,14 @@ impl Handle {
         }
 
         if self.has_page_in_thread {
-            self.wait_for_offset(offset + (buf.len() as i64), true)?;
+            if self.direct_file.valid() {
+                // the whole blocks are rewritten, so what's around
+                // this has to be there too
+                let (start, end) = direct_range(offset, buf.len());
+                self.wait_for_offset(start, end, true)?;
+            } else {
+                self.wait_for_offset(offset, offset + (buf.len() as i64), true)?;
+            }
         }
 
         while bytes_written < nwant {
@@ -433,10 +1785,12 @@ impl Handle {
 
             }
 
-            match self.cache_file.write_at(
-                &buf[bytes_written..],
-                offset + (bytes_written as i64),
-            ) {
+            let res = if self.direct_file.valid() {
+                self.write_direct(&buf[bytes_written..], offset + (bytes_written as i64))
+            } else {
+                self.cache_write_at(&buf[bytes_written..], offset + (bytes_written as i64))
+            };
+            match res {
                 Ok(nwritten) => {
                     bytes_written += nwritten;
                 }
@@ -460,15 +1814,217 @@ impl Handle {
         return Ok(bytes_written);
     }
 
-    pub fn flush(&mut self) -> error::Result<bool> {
+    // the cache is full and nothing more can be evicted, so from now
+    // on this only goes to src, like open_bypass. Returns false if
+    // src doesn't have everything written so far
+    pub fn stop_caching(
+        &mut self,
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        path: &dyn AsRef<Path>,
+    ) -> error::Result<bool> {
+        if self.bypass {
+            return Ok(true);
+        }
+        if self.write_through_failed || self.partial.is_some() {
+            return Ok(false);
+        }
+        if self.has_page_in_thread {
+            // which is most likely out of space too
+            if let Err(e) = self.wait_for_eof() {
+                debug!("!page in {:?} = {}", path.as_ref(), e);
+            }
+        }
+
+        maybe_unlinkat(cache_dir, &layout.cache_path(path))?;
+        // the page in thread may still be unlocking it, so the fd is
+        // only closed with the handle
+        self.cache_file.truncate(0)?;
+        self.bypass = true;
+        return Ok(true);
+    }
+
+    // whether src has everything written through this handle, so it
+    // can be swapped for one that only goes to src
+    pub fn src_is_current(&self) -> bool {
+        return !self.dirty || !self.write_through_failed;
+    }
+
+    pub fn set_write_back(&mut self) {
+        if !self.bypass {
+            self.write_back = true;
+            self.write_through_failed = true;
+        }
+    }
+
+    // reads and writes through the mount go to the cache file with
+    // O_DIRECT, so it doesn't take up the page cache as well
+    #[cfg(not(target_os = "macos"))]
+    pub fn set_direct(
+        &mut self,
+        cache_dir: RawFd,
+        layout: CacheLayout,
+        path: &dyn AsRef<Path>,
+    ) -> error::Result<()> {
+        // O_DIRECT would go around the index or the key
+        if self.bypass || self.compressed.is_some() || self.key.is_some() {
+            return Ok(());
+        }
+
+        let flags = self.cache_file.get_flags()? & rlibc::O_ACCMODE;
+        match layout.openat(cache_dir, Default::default(), path, flags | rlibc::O_DIRECT, 0) {
+            Ok(f) => self.direct_file = f,
+            Err(e) => {
+                if e.raw_os_error() == Some(libc::EINVAL) {
+                    debug!("{:?} can't be opened with O_DIRECT", path.as_ref());
+                    return Ok(());
+                }
+                return Err(RError::from(e));
+            }
+        }
+        self.page_in_res.0.lock().unwrap().drop_cache = true;
+        return Ok(());
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_direct(
+        &mut self,
+        _cache_dir: RawFd,
+        _layout: CacheLayout,
+        _path: &dyn AsRef<Path>,
+    ) -> error::Result<()> {
+        return Ok(());
+    }
+
+    // reads the blocks around [offset, offset + len) into an aligned
+    // buffer, the result is short at eof
+    fn read_blocks(&self, offset: i64, len: usize) -> io::Result<(AlignedBuf, usize)> {
+        let (start, end) = direct_range(offset, len);
+        let mut abuf = AlignedBuf::new((end - start) as usize);
+        let mut nread = 0;
+        while nread < abuf.len() {
+            let n = self.direct_file.read_at(
+                &mut abuf.as_mut_slice()[nread..],
+                start + nread as i64,
+            )?;
+            nread += n;
+            if n == 0 || n % DIRECT_ALIGN != 0 {
+                break;
+            }
+        }
+        return Ok((abuf, nread));
+    }
+
+    fn read_direct(&self, offset: i64, buf: &mut [u8]) -> io::Result<usize> {
+        let (abuf, nread) = self.read_blocks(offset, buf.len())?;
+        let skip = (offset % DIRECT_ALIGN as i64) as usize;
+        if nread <= skip {
+            return Ok(0);
+        }
+        let n = buf.len().min(nread - skip);
+        buf[..n].copy_from_slice(&abuf.as_slice()[skip..skip + n]);
+        return Ok(n);
+    }
+
+    // O_DIRECT only writes whole blocks, so what's around buf is
+    // read first and written back along with it
+    fn write_direct(&self, buf: &[u8], offset: i64) -> io::Result<usize> {
+        let skip = (offset % DIRECT_ALIGN as i64) as usize;
+        let (start, end) = direct_range(offset, buf.len());
+        let size = self.cache_file.filesize()? as i64;
+
+        let mut abuf = if skip == 0 && (buf.len() % DIRECT_ALIGN) == 0 {
+            AlignedBuf::new(buf.len())
+        } else {
+            self.read_blocks(offset, buf.len())?.0
+        };
+        abuf.as_mut_slice()[skip..skip + buf.len()].copy_from_slice(buf);
+
+        let mut nwritten = 0;
+        while nwritten < abuf.len() {
+            nwritten += self.direct_file.write_at(
+                &abuf.as_slice()[nwritten..],
+                start + nwritten as i64,
+            )?;
+        }
+        // the last block may have gone past the end
+        let new_size = offset + buf.len() as i64;
+        if end > size && new_size < end {
+            self.cache_file.truncate(size.max(new_size) as u64)?;
+        }
+        return Ok(buf.len());
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        return self.dirty;
+    }
+
+    // whether there's something for upload to do
+    pub fn needs_upload(&self) -> bool {
+        return self.write_back && self.dirty;
+    }
+
+    // copies a file written with write back to src
+    pub fn upload(
+        &mut self,
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        on_conflict: ConflictPolicy,
+    ) -> error::Result<bool> {
+        if !self.needs_upload() {
+            return Ok(false);
+        }
+        if self.src_file.valid() &&
+            (self.src_file.get_flags()? & rlibc::O_ACCMODE) == rlibc::O_RDONLY
+        {
+            // src was read-only when this was opened, this fails
+            // with EROFS if it still is
+            let _unused = self.page_in_res.0.lock().unwrap();
+            let f = src.open(path, rlibc::O_RDWR, 0)?;
+            let mut old = mem::replace(&mut self.src_file, f);
+            old.close()?;
+        }
+        self.write_back = false;
+        let res = self.flush(src, path, on_conflict);
+        self.write_back = true;
+        return res;
+    }
+
+    pub fn flush(
+        &mut self,
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        on_conflict: ConflictPolicy,
+    ) -> error::Result<bool> {
         let mut flushed_to_src = false;
+        if self.needs_upload() {
+            self.cache_file.flush()?;
+            return Ok(false);
+        }
+        if self.bypass {
+            if self.dirty {
+                if let Err(e) = self.src_file.flush() {
+                    error!("!flush(src) = {}", e);
+                    return Err(RError::propagate(e));
+                }
+                self.dirty = false;
+                flushed_to_src = true;
+            }
+            return Ok(flushed_to_src);
+        }
         if self.dirty {
             if self.write_through_failed {
                 if self.has_page_in_thread {
                     self.wait_for_eof()?;
                 }
 
-                self.copy(false, false)?;
+                if !self.resolve_conflict(src, path, on_conflict)? {
+                    // our changes went somewhere else
+                    self.cache_file.flush()?;
+                    self.dirty = false;
+                    return Ok(false);
+                }
+                self.copy(false, self.disable_splice, self.copy_size)?;
             } else {
                 self.set_pristine(true)?;
             }
@@ -485,24 +2041,110 @@ impl Handle {
                 // would validate and thus we would never read from
                 // src).
 
-                // we only have the fd and there's no funlink, so we
-                // will just unset the xattr
+                // we only have the fd and there's no funlink, so we
+                // will just unset the xattr
+                self.set_pristine(false)?;
+
+                return Err(RError::propagate(e));
+            }
+            self.dirty = false;
+            flushed_to_src = true;
+            if self.write_through_failed {
+                // so our own changes aren't taken as a conflict
+                self.remember_src_version();
+            }
+        } else {
+            if self.has_page_in_thread {
+                // tell it to cancel, it may be waiting out the
+                // bandwidth limit
+                let mut page_in_res = self.page_in_res.0.lock().unwrap();
+                page_in_res.err = Some(RError::propagate(
+                    io::Error::from_raw_os_error(libc::ECANCELED),
+                ));
+                self.page_in_res.1.notify_all();
+            }
+        }
+        return Ok(flushed_to_src);
+    }
+
+    fn remember_src_version(&mut self) {
+        self.src_version = match Handle::src_chksum(&self.src_file, &self.hints) {
+            Ok(chksum) => Some(chksum.to_vec()),
+            Err(e) => {
+                debug!("unable to checksum src: {}", e);
+                None
+            }
+        };
+    }
+
+    // whether to go on and copy back over src_file
+    fn resolve_conflict(
+        &mut self,
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        on_conflict: ConflictPolicy,
+    ) -> error::Result<bool> {
+        let expected = match self.src_version {
+            Some(ref v) => v.clone(),
+            None => return Ok(true),
+        };
+        if Handle::src_chksum(&self.src_file, &self.hints)?.as_slice() == &expected[..] {
+            return Ok(true);
+        }
+
+        let path = path.as_ref();
+        if self.page_in_res.0.lock().unwrap().src_moved {
+            // what we didn't write isn't from any one version of src,
+            // so it can't be copied back anywhere
+            error!("{:?} changed in the source while it was paged in", path);
+            return Err(RError::propagate(io::Error::from_raw_os_error(libc::ESTALE)));
+        }
+        match on_conflict {
+            ConflictPolicy::Overwrite => {
+                warn!("{:?} changed in the source, overwriting it", path);
+                return Ok(true);
+            }
+            ConflictPolicy::Fail => {
+                error!("{:?} changed in the source, not overwriting it", path);
+                return Err(RError::propagate(io::Error::from_raw_os_error(libc::ESTALE)));
+            }
+            ConflictPolicy::KeepBoth => {
+                let new_path = conflict_path(path);
+                let mode = self.src_file.stat()?.st_mode & 0o7777;
+                let mut f = src.open(
+                    &new_path,
+                    rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_EXCL,
+                    mode as libc::mode_t,
+                )?;
+                mem::swap(&mut self.src_file, &mut f);
+                let res = self.copy(false, self.disable_splice, self.copy_size);
+                mem::swap(&mut self.src_file, &mut f);
+                let res = res.and_then(|_| Ok(f.flush()?));
+                f.close()?;
+                res?;
+
+                // what's cached is no longer what's in src
                 self.set_pristine(false)?;
-
-                return Err(RError::propagate(e));
+                warn!("{:?} changed in the source, kept ours as {:?}", path, new_path);
+                return Ok(false);
             }
-            self.dirty = false;
-            flushed_to_src = true;
-        } else {
-            if self.has_page_in_thread {
-                // tell it to cancel
-                let mut page_in_res = self.page_in_res.0.lock().unwrap();
-                page_in_res.err = Some(RError::propagate(
-                    io::Error::from_raw_os_error(libc::ECANCELED),
-                ));
+        }
+    }
+
+    // called by page in once it has read everything
+    fn check_src_version(&self) {
+        let moved = match self.src_version {
+            Some(ref expected) => {
+                match Handle::src_chksum(&self.src_file, &self.hints) {
+                    Ok(chksum) => chksum.as_slice() != &expected[..],
+                    Err(_) => true,
+                }
             }
+            None => false,
+        };
+        if moved {
+            self.page_in_res.0.lock().unwrap().src_moved = true;
         }
-        return Ok(flushed_to_src);
     }
 
     fn wait_for_eof(&mut self) -> error::Result<()> {
@@ -517,7 +2159,16 @@ impl Handle {
         }
     }
 
-    fn wait_for_offset(&mut self, offset: i64, set_dirty: bool) -> error::Result<()> {
+    fn wait_for_offset(&mut self, start: i64, end: i64, set_dirty: bool) -> error::Result<()> {
+        if self.wait_for_page_in(start, end, set_dirty)? {
+            self.has_page_in_thread = false;
+        }
+        return Ok(());
+    }
+
+    // waits until [start, end) is in the cache file, returns true if
+    // page in is done
+    fn wait_for_page_in(&self, start: i64, end: i64, set_dirty: bool) -> error::Result<bool> {
         let &(ref lock, ref cvar) = &*self.page_in_res;
 
         let mut page_in_res = lock.lock().unwrap();
@@ -527,20 +2178,47 @@ impl Handle {
         }
         loop {
             if page_in_res.eof {
-                self.has_page_in_thread = false;
-                return Ok(());
+                return Ok(true);
             }
 
-            if page_in_res.offset >= offset {
-                return Ok(());
+            // what's past the end of src has to wait for eof, in
+            // case src grew since
+            let end = match page_in_res.size {
+                Some(size) if start < size => end.min(size),
+                _ => end,
+            };
+            if page_in_res.has(start, end) {
+                return Ok(false);
             } else if let Some(e) = page_in_res.err.clone() {
                 return Err(e);
             } else {
+                let past_eof = page_in_res.size.map_or(false, |size| start >= size);
+                if !past_eof {
+                    // ask page in to get here sooner, it may be
+                    // waiting out the bandwidth limit
+                    let missing = page_in_res.next_missing(start);
+                    page_in_res.want = Some(page_in_res.want.map_or(missing, |w| w.min(missing)));
+                    cvar.notify_all();
+                }
                 page_in_res = cvar.wait(page_in_res).unwrap();
             }
         }
     }
 
+    // [start, end) was copied by page in, returns false if a reader
+    // is waiting for something outside of [end, until)
+    fn notify_range(&self, start: i64, end: i64, until: i64) -> error::Result<bool> {
+        let &(ref lock, ref cvar) = &*self.page_in_res;
+
+        let mut page_in_res = lock.lock().unwrap();
+        if let Some(e) = page_in_res.err.clone() {
+            return Err(e);
+        }
+        page_in_res.add(start, end);
+        cvar.notify_all();
+        return Ok(page_in_res.want.map_or(true, |w| w >= end && w < until));
+    }
+
     fn notify_offset(&self, res: error::Result<i64>, eof: bool) -> error::Result<()> {
         let &(ref lock, ref cvar) = &*self.page_in_res;
 
@@ -565,7 +2243,7 @@ impl Handle {
 
     pub fn reopen_src(
         &mut self,
-        dir: RawFd,
+        src: &dyn SourceBackend,
         path: &dyn AsRef<Path>,
         create: bool,
     ) -> error::Result<()> {
@@ -596,19 +2274,97 @@ impl Handle {
             }
         }
 
-        self.src_file = File::openat(dir, path, flags, mode)?;
+        self.src_file = src.open(path, flags, mode)?;
+        // from here on we may copy back over it
+        self.src_version = Handle::src_chksum(&self.src_file, &self.hints)
+            .ok()
+            .map(|chksum| chksum.to_vec());
+        return Ok(());
+    }
+
+    // runs on the page in thread once it's done with this handle
+    fn page_in_done(&mut self, path: &Path, res: error::Result<()>) {
+        if let Err(e) = res {
+            let mut is_cancel = false;
+
+            {
+                let page_in_res = self.page_in_res.0.lock().unwrap();
+                if let Some(ref e2) = page_in_res.err {
+                    if e2.raw_os_error().unwrap() == libc::ECANCELED {
+                        is_cancel = true;
+                    }
+                }
+            }
+
+            if !is_cancel {
+                error!("read ahead {:?} failed: {}", path, e);
+                self.notify_offset(Err(e), false).unwrap();
+            } else {
+                debug!("read ahead {:?} canceled", path);
+            }
+        } else if let Err(e) = self.dedupe(path) {
+            warn!("!dedupe {:?} = {}", path, e);
+        }
+        // let other catfs sharing the cache dir use this file
+        if let Err(e) = rlibc::flock(self.cache_file.as_raw_fd(), libc::LOCK_UN) {
+            error!("!unlock {:?} = {}", path, e);
+        }
+        // the files are always closed in the main IO path, consume
+        // the fds to prevent closing
+        self.src_file.into_raw();
+        self.cache_file.into_raw();
+    }
+
+    // makes the cache file share its blocks with another one that has
+    // the same content. Only what's paged in in full and untouched
+    fn dedupe(&self, path: &Path) -> error::Result<()> {
+        let index = match self.dedupe {
+            Some(ref index) if index.is_supported() => index,
+            _ => return Ok(()),
+        };
+        if self.compressed.is_some() || self.key.is_some() ||
+            self.cache_file.filesize()? < dedupe::MIN_SIZE
+        {
+            return Ok(());
+        }
+
+        let hash = dedupe::hash(&self.cache_file)?;
+        // writers on this handle wait for this, so the cache file
+        // doesn't change under the clone
+        let page_in_res = self.page_in_res.0.lock().unwrap();
+        if !page_in_res.eof || page_in_res.dirty || !was_pristine(&self.cache_file) {
+            return Ok(());
+        }
+        let saved = index.add(&path, &self.cache_file, &hash)?;
+        drop(page_in_res);
+        self.stats.bytes_deduped.fetch_add(saved as usize, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    // pages in a small file with a buffer that's shared with the rest
+    // of the batch
+    fn copy_small(&self, buf: &mut [u8]) -> error::Result<()> {
+        let size = self.src_file.filesize()?;
+        if size < self.cache_size()? {
+            self.cache_truncate(size)?;
+        }
+
+        let offset = self.copy_user_buf(buf)?;
+        self.stats.paged_in(offset as usize);
+        thread::sleep(self.page_in_limit.paged_in(offset as usize));
+        self.check_src_version();
+        self.notify_offset(Ok(offset), true)?;
         return Ok(());
     }
 
-    fn copy_user(&self, rh: &File, wh: &File) -> error::Result<i64> {
-        let mut buf = [0u8; 32 * 1024];
+    fn copy_user_buf(&self, buf: &mut [u8]) -> error::Result<i64> {
         let mut offset = 0;
         loop {
-            let nread = rh.read_at(&mut buf, offset)?;
+            let nread = self.src_file.read_at(buf, offset)?;
             if nread == 0 {
                 break;
             }
-            wh.write_at(&buf[..nread], offset)?;
+            self.cache_write_at(&buf[..nread], offset)?;
             offset += nread as i64;
 
             self.notify_offset(Ok(offset), false)?;
@@ -617,85 +2373,397 @@ impl Handle {
         return Ok(offset);
     }
 
-    #[cfg(not(target_os = "macos"))]
-    fn copy_splice(&self, rh: &File, wh: &File) -> error::Result<i64> {
-        let (pin, pout) = rlibc::pipe()?;
-        let pin = fd::FileDesc::new(pin, /*close_on_drop=*/ true);
-        let pout = fd::FileDesc::new(pout, /*close_on_drop=*/ true);
+    fn copy(&self, to_cache: bool, disable_splice: bool, chunk: usize) -> error::Result<()> {
+        let rh: &File;
+        let wh: &File;
+        if to_cache {
+            rh = &self.src_file;
+            wh = &self.cache_file;
+        } else {
+            rh = &self.cache_file;
+            wh = &self.src_file;
+        }
+
+        if to_cache {
+            let size = rh.filesize()?;
+            if size < self.cache_size()? {
+                self.cache_truncate(size)?;
+            }
+        } else {
+            let size = self.cache_size()?;
+            if size < wh.filesize()? {
+                wh.truncate(size)?;
+            }
+        }
 
+        let mut copier = self.copier(to_cache, disable_splice)?;
         let mut offset = 0;
         loop {
-            let nread = rlibc::splice(rh.as_raw_fd(), offset, pout.as_raw_fd(), -1, 128 * 1024)?;
-            if nread == 0 {
+            let n = copier.copy(rh, wh, offset, chunk)?;
+            if n == 0 {
                 break;
             }
+            offset += n as i64;
+
+            self.notify_offset(Ok(offset), false)?;
+        }
+
+        self.notify_offset(Ok(offset), true)?;
+        return Ok(());
+    }
+
+    // copies src to the cache file from the start, but when a reader
+    // waits for something further in, it jumps there and copies a
+    // window after it. The window grows while the reader keeps
+    // catching up with it, once it's done page in goes back for what
+    // it skipped
+    fn page_in(&self, disable_splice: bool, chunk: usize) -> error::Result<()> {
+        let size = self.src_file.filesize()?;
+        if size < self.cache_size()? {
+            self.cache_truncate(size)?;
+        }
+        self.page_in_res.0.lock().unwrap().size = Some(size as i64);
+
+        let mut copier = self.copier(true, disable_splice)?;
+        let mut jumped = false;
+        let mut window = chunk as i64;
+        let mut window_start = 0;
+        let mut window_end = 0;
+        let mut pos = 0;
+
+        loop {
+            let until = {
+                let mut page_in_res = self.page_in_res.0.lock().unwrap();
+                if let Some(e) = page_in_res.err.clone() {
+                    return Err(e);
+                }
 
-            let mut written = 0;
-            while written < nread {
-                let nxfer = rlibc::splice(pin.as_raw_fd(), -1, wh.as_raw_fd(), offset, 128 * 1024)?;
+                match page_in_res.want.take() {
+                    Some(w) if !page_in_res.has(w, w + 1) => {
+                        if jumped && w >= window_start && w <= window_end + window {
+                            // the reader is keeping up, read further ahead
+                            window = (window * 2).min(MAX_READAHEAD_WINDOW);
+                        } else {
+                            window = chunk as i64;
+                        }
+                        jumped = true;
+                        window_start = w;
+                        pos = page_in_res.next_missing(w);
+                        window_end = pos + window;
+                    }
+                    _ => {
+                        pos = page_in_res.next_missing(pos);
+                        if jumped && pos >= window_end {
+                            jumped = false;
+                            pos = page_in_res.next_missing(0);
+                        }
+                    }
+                }
 
-                written += nxfer;
-                offset += nxfer as i64;
+                let hole_end = page_in_res.hole_end(pos).unwrap_or(i64::max_value());
+                if jumped {
+                    hole_end.min(window_end)
+                } else {
+                    hole_end
+                }
+            };
 
-                self.notify_offset(Ok(offset), false)?;
+            let (end, eof) = self.copy_range(&mut copier, chunk, pos, until, jumped)?;
+            pos = end;
+            if eof {
+                if !jumped {
+                    break;
+                }
+                // src is shorter than it was, nothing more to read ahead
+                window_end = end;
             }
         }
 
-        if let Err(e) = rlibc::close(pin.into_raw_fd()) {
-            rlibc::close(pout.into_raw_fd())?;
-            return Err(RError::from(e));
-        } else {
-            rlibc::close(pout.into_raw_fd())?;
+        self.check_src_version();
+        self.notify_offset(Ok(pos), true)?;
+        return Ok(());
+    }
+
+    // pages in to a compressed cache file. Chunks are appended in
+    // order, so unlike page_in this can't jump ahead for a reader
+    fn page_in_compressed(&self) -> error::Result<()> {
+        let chunks = self.compressed.as_ref().unwrap();
+        let size = self.src_file.filesize()?;
+        self.cache_file.truncate(0)?;
+        self.page_in_res.0.lock().unwrap().size = Some(size as i64);
+
+        let mut buf = vec![0u8; compress::CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let mut n = 0;
+            while n < buf.len() {
+                let nread = self.hints.retry.run(|| {
+                    self.src_file.read_at(&mut buf[n..], offset + n as i64)
+                })?;
+                if nread == 0 {
+                    break;
+                }
+                n += nread;
+            }
+            if n == 0 {
+                break;
+            }
+            chunks.write().unwrap().append(&self.cache_file, &buf[..n])?;
+            self.stats.paged_in(n);
+            offset += n as i64;
+            self.notify_offset(Ok(offset), false)?;
+            if n < buf.len() {
+                break;
+            }
+
+            {
+                // readers waiting for what's there now are done waiting
+                let mut page_in_res = self.page_in_res.0.lock().unwrap();
+                if page_in_res.want.map_or(false, |w| w < offset) {
+                    page_in_res.want = None;
+                }
+            }
+            self.throttle(n);
         }
 
-        return Ok(offset);
+        chunks.read().unwrap().finish(&self.cache_file)?;
+        self.notify_offset(Ok(offset), true)?;
+        return Ok(());
     }
 
-    #[cfg(target_os = "macos")]
-    fn copy_splice(&self, rh: &File, wh: &File) -> error::Result<i64> {
-        self.copy_user(rh, wh)
+    // copies [start, until) to the cache file unless a reader wants
+    // something else first, returns where it stopped and if that's
+    // eof. What a reader is waiting for isn't held back by the
+    // bandwidth limit
+    fn copy_range(
+        &self,
+        copier: &mut Copier,
+        chunk: usize,
+        start: i64,
+        until: i64,
+        for_reader: bool,
+    ) -> error::Result<(i64, bool)> {
+        let mut offset = start;
+        while offset < until {
+            let len = (until - offset).min(chunk as i64) as usize;
+            let n = self.hints.retry.run(|| {
+                copier.copy(&self.src_file, &self.cache_file, offset, len)
+            })?;
+            if n == 0 {
+                return Ok((offset, true));
+            }
+            self.stats.paged_in(n);
+            if self.page_in_res.0.lock().unwrap().drop_cache {
+                self.cache_file.drop_cache(offset, n as i64)?;
+            }
+            let more = self.notify_range(offset, offset + n as i64, until)?;
+            offset += n as i64;
+            if !more {
+                break;
+            }
+            if !for_reader && !self.throttle(n) {
+                break;
+            }
+        }
+        return Ok((offset, false));
     }
 
-    fn copy(&self, to_cache: bool, disable_splice: bool) -> error::Result<()> {
-        let rh: &File;
-        let wh: &File;
-        if to_cache {
-            rh = &self.src_file;
-            wh = &self.cache_file;
+    // encrypts what goes to the cache file and decrypts what comes
+    // from it if there's a key, which the kernel can't do for us
+    fn copier(&self, to_cache: bool, disable_splice: bool) -> error::Result<Copier> {
+        let mut copier = Copier::new(disable_splice || self.key.is_some())?;
+        copier.seal = self.key.clone().map(|key| if to_cache {
+            Seal::Encrypt(key)
         } else {
-            rh = &self.cache_file;
-            wh = &self.src_file;
+            Seal::Decrypt(key)
+        });
+        return Ok(copier);
+    }
+
+    // waits out --max-pagein-bandwidth for the n bytes just copied,
+    // returns false if a reader started waiting in the meantime
+    fn throttle(&self, n: usize) -> bool {
+        let delay = self.page_in_limit.paged_in(n);
+        if delay == Duration::from_secs(0) {
+            return true;
         }
 
-        let size = rh.filesize()?;
-        if size < wh.filesize()? {
-            wh.truncate(size)?;
+        let until = Instant::now() + delay;
+        let &(ref lock, ref cvar) = &*self.page_in_res;
+        let mut page_in_res = lock.lock().unwrap();
+        loop {
+            if page_in_res.want.is_some() || page_in_res.err.is_some() {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= until {
+                return true;
+            }
+            page_in_res = cvar.wait_timeout(page_in_res, until - now).unwrap().0;
         }
+    }
+}
+
+// copies between two files at the same offsets. copy_file_range
+// has the kernel do it without going through a pipe, splice is
+// next best, and reading into a buffer works with anything. With
+// the io_uring backend the buffer is read and written in batches
+// instead of splicing
+struct Copier {
+    copy_file_range: bool,
+    pipe: Option<(fd::FileDesc, fd::FileDesc)>,
+    buf: Vec<u8>,
+    seal: Option<Seal>,
+}
 
-        let offset: i64;
+// which way an encrypted cache file is being copied
+enum Seal {
+    Encrypt(crypt::Key),
+    Decrypt(crypt::Key),
+}
 
-        if disable_splice {
-            offset = self.copy_user(rh, wh)?;
+impl Copier {
+    #[cfg(not(target_os = "macos"))]
+    fn new(disable_splice: bool) -> error::Result<Copier> {
+        let pipe = if disable_splice || rlibc::io_backend() == rlibc::IoBackend::Uring {
+            None
         } else {
-            match self.copy_splice(rh, wh) {
+            let (pin, pout) = rlibc::pipe()?;
+            Some((
+                fd::FileDesc::new(pin, /*close_on_drop=*/ true),
+                fd::FileDesc::new(pout, /*close_on_drop=*/ true),
+            ))
+        };
+        return Ok(Copier {
+            copy_file_range: !disable_splice,
+            pipe: pipe,
+            buf: Vec::new(),
+            seal: None,
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    fn new(_disable_splice: bool) -> error::Result<Copier> {
+        return Ok(Copier {
+            copy_file_range: false,
+            pipe: None,
+            buf: Vec::new(),
+            seal: None,
+        });
+    }
+
+    // copies up to len bytes at offset, returns 0 at eof
+    fn copy(&mut self, rh: &File, wh: &File, offset: i64, len: usize) -> error::Result<usize> {
+        if let Some(ref seal) = self.seal {
+            if self.buf.len() < len {
+                self.buf.resize(len, 0);
+            }
+            let nread = match *seal {
+                Seal::Encrypt(_) => rh.read_at(&mut self.buf[..len], offset)?,
+                Seal::Decrypt(ref key) => key.read_at(rh, &mut self.buf[..len], offset)?,
+            };
+            if nread != 0 {
+                match *seal {
+                    Seal::Encrypt(ref key) => key.write_at(wh, &self.buf[..nread], offset)?,
+                    Seal::Decrypt(_) => wh.write_at(&self.buf[..nread], offset)?,
+                };
+            }
+            return Ok(nread);
+        }
+
+        if self.copy_file_range {
+            match rlibc::copy_file_range(rh.as_raw_fd(), offset, wh.as_raw_fd(), offset, len) {
+                Ok(n) => return Ok(n),
                 Err(e) => {
-                    if e.raw_os_error().unwrap() == libc::EINVAL {
-                        offset = self.copy_user(rh, wh)?;
-                    } else {
+                    match e.raw_os_error() {
+                        // not between these files, try the next way
+                        Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::ENOSYS) |
+                        Some(libc::EOPNOTSUPP) | Some(libc::EBADF) => {
+                            self.copy_file_range = false;
+                        }
+                        _ => return Err(RError::from(e)),
+                    }
+                }
+            }
+        }
+
+        if let Some((pin, pout)) = self.pipe.take() {
+            match Copier::splice(rh, wh, &pin, &pout, offset, len) {
+                Ok(n) => {
+                    self.pipe = Some((pin, pout));
+                    return Ok(n);
+                }
+                Err(e) => {
+                    if e.raw_os_error() != Some(libc::EINVAL) {
                         return Err(e);
                     }
                 }
-                Ok(off) => offset = off,
             }
         }
 
-        self.notify_offset(Ok(offset), true)?;
-        return Ok(());
+        if self.buf.len() < len {
+            self.buf.resize(len, 0);
+        }
+        if rlibc::io_backend() == rlibc::IoBackend::Uring {
+            return Ok(rlibc::copy_at_batched(
+                rh.as_raw_fd(),
+                wh.as_raw_fd(),
+                offset,
+                &mut self.buf[..len],
+            )?);
+        }
+        let nread = rh.read_at(&mut self.buf[..len], offset)?;
+        if nread != 0 {
+            wh.write_at(&self.buf[..nread], offset)?;
+        }
+        return Ok(nread);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn splice(
+        rh: &File,
+        wh: &File,
+        pin: &fd::FileDesc,
+        pout: &fd::FileDesc,
+        offset: i64,
+        len: usize,
+    ) -> error::Result<usize> {
+        let nread = rlibc::splice(rh.as_raw_fd(), offset, pout.as_raw_fd(), -1, len)?;
+
+        let mut written = 0;
+        while written < nread {
+            written += rlibc::splice(
+                pin.as_raw_fd(),
+                -1,
+                wh.as_raw_fd(),
+                offset + written as i64,
+                nread - written,
+            )?;
+        }
+        return Ok(nread);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn splice(
+        _rh: &File,
+        _wh: &File,
+        _pin: &fd::FileDesc,
+        _pout: &fd::FileDesc,
+        _offset: i64,
+        _len: usize,
+    ) -> error::Result<usize> {
+        return Err(RError::from(io::Error::from_raw_os_error(libc::EINVAL)));
     }
 }
 
 impl Drop for Handle {
     fn drop(&mut self) {
+        if self.direct_file.valid() {
+            if let Err(e) = self.direct_file.close() {
+                error!("!close(direct) = {}", RError::from(e));
+            }
+        }
+
         if self.cache_file.valid() {
             if let Err(e) = self.cache_file.close() {
                 error!("!close(cache) = {}", RError::from(e));
@@ -717,8 +2785,826 @@ impl Clone for Handle {
             cache_file: File::with_fd(self.cache_file.as_raw_fd()),
             dirty: self.dirty,
             write_through_failed: self.write_through_failed,
+            write_back: self.write_back,
             has_page_in_thread: false,
             page_in_res: self.page_in_res.clone(),
+            hints: self.hints.clone(),
+            bypass: self.bypass,
+            src_version: self.src_version.clone(),
+            partial: None,
+            copy_size: self.copy_size,
+            disable_splice: self.disable_splice,
+            direct_file: Default::default(),
+            stats: self.stats.clone(),
+            page_in_limit: self.page_in_limit.clone(),
+            compressed: self.compressed.clone(),
+            key: self.key.clone(),
+            dedupe: self.dedupe.clone(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use std::sync::mpsc;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use catfs::health;
+    use super::*;
+
+    #[test]
+    fn hashed_layout() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+
+        assert_eq!(CacheLayout::load(cache_dir).unwrap(), CacheLayout::Path);
+        CacheLayout::Hashed.save(cache_dir).unwrap();
+        let layout = CacheLayout::load(cache_dir).unwrap();
+        assert_eq!(layout, CacheLayout::Hashed);
+
+        let path = Path::new("dir1/file1");
+        let mut f = layout
+            .openat(cache_dir, Default::default(), &path, rlibc::O_WRONLY | rlibc::O_CREAT, 0o666)
+            .unwrap();
+        f.write_at(b"dir1/file1\n", 0).unwrap();
+        f.close().unwrap();
+        Handle::make_pristine(&src, cache_dir, layout, &path).unwrap();
+
+        // no directories in the cache, and the path can be found again
+        let cache_path = layout.cache_path(&path);
+        assert_eq!(cache_path.components().count(), 1);
+        assert!(cache.join(&cache_path).is_file());
+        assert!(!cache.join("dir1").exists());
+        let f = fs::File::open(cache.join(&cache_path)).unwrap();
+        assert_eq!(layout.src_path(&cache_path, &f).unwrap().unwrap(), path);
+
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &path, false, true).unwrap());
+        assert!(!Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+
+        let fanout_path = CacheLayout::Fanout.cache_path(&path);
+        assert_eq!(fanout_path.file_name(), cache_path.file_name());
+        assert_eq!(fanout_path.components().count(), 3);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+    #[test]
+    fn immutable_src() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let mut src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        src.set_hints(SourceHints {
+            immutable: true,
+            ..Default::default()
+        });
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+
+        // a partial page in isn't valid
+        fs::write(cache.join("file1"), b"file").unwrap();
+        assert!(!Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+
+        // a complete one is, without any checksum
+        fs::write(cache.join("file1"), b"file1\n").unwrap();
+        Handle::make_pristine(&src, cache_dir, layout, &"file1").unwrap();
+        let f = fs::File::open(cache.join("file1")).unwrap();
+        assert!(f.get_xattr("user.catfs.src_chksum").unwrap().is_none());
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn open_truncated() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+
+        fs::write(cache.join("file1"), b"file1\n").unwrap();
+        Handle::make_pristine(&src, cache_dir, layout, &"file1").unwrap();
+
+        let mut f = Handle::open(
+            &src,
+            cache_dir,
+            layout,
+            Default::default(),
+            &"file1",
+            rlibc::O_WRONLY | rlibc::O_TRUNC,
+            false,
+            &tp,
+            &OpenOptions {
+                readahead: 4096,
+                ..Default::default()
+            },
+        ).unwrap();
+        assert!(!f.has_page_in_thread);
+        // the old checksum is gone until it's flushed
+        assert!(!Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+        assert_eq!(f.write(0, b"new\n").unwrap(), 4);
+        assert!(f.flush(&src, &"file1", Default::default()).unwrap());
+        drop(f);
+
+        assert_eq!(fs::read(prefix.join("resources/file1")).unwrap(), b"new\n");
+        assert_eq!(fs::read(cache.join("file1")).unwrap(), b"new\n");
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn open_bypass() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+
+        let mut f = Handle::open_bypass(&src, cache_dir, layout, &"file1", rlibc::O_RDONLY, 0)
+            .unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(f.read(0, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"file1\n");
+        assert!(!f.flush(&src, &"file1", Default::default()).unwrap());
+        drop(f);
+        assert!(!cache.join("file1").exists());
+
+        // writing makes what was cached stale
+        fs::write(cache.join("file1"), b"file1\n").unwrap();
+        let mut f = Handle::open_bypass(&src, cache_dir, layout, &"file1", rlibc::O_WRONLY, 0)
+            .unwrap();
+        assert!(!cache.join("file1").exists());
+        assert_eq!(f.write(0, b"new").unwrap(), 3);
+        assert!(f.flush(&src, &"file1", Default::default()).unwrap());
+        drop(f);
+        assert_eq!(fs::read(prefix.join("resources/file1")).unwrap(), b"newe1\n");
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn mount_perms() {
+        let perms = MountPerms {
+            file_mode: Some(0o644),
+            dir_mode: None,
+            umask: 0o022,
+        };
+        assert_eq!(perms.shown(libc::S_IFREG | 0o600), 0o644);
+        assert_eq!(perms.shown(libc::S_IFDIR | 0o777), 0o755);
+        assert_eq!(perms.shown(libc::S_IFLNK | 0o777), 0o777);
+        assert_eq!(perms.create_mode(false, libc::S_IFREG | 0o777), libc::S_IFREG | 0o644);
+        assert_eq!(perms.create_mode(true, 0o777), 0o755);
+    }
+
+    #[test]
+    fn cache_perms() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+
+        let perms = CachePerms {
+            mode: Some(0o640),
+            umask: 0o027,
+            ..Default::default()
+        };
+        assert_eq!(perms.file_mode(0o666), 0o640);
+        assert_eq!(perms.dir_mode(), 0o750);
+        assert_eq!(CachePerms::default().file_mode(0o644), 0o644);
+
+        let mut f = CacheLayout::Path
+            .openat(cache_dir, perms, &"dir1/file1", rlibc::O_WRONLY | rlibc::O_CREAT, 0o666)
+            .unwrap();
+        f.close().unwrap();
+        let mode = |p: &str| fs::metadata(cache.join(p)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("dir1"), 0o750);
+        assert_eq!(mode("dir1/file1"), 0o640);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn flush_conflict() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let mut src = LocalBackend::new(&resources).unwrap();
+        // so changes are copied back on flush
+        src.set_hints(SourceHints {
+            sequential_writes: true,
+            ..Default::default()
+        });
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+
+        let mut f = Handle::open(
+            &src,
+            cache_dir,
+            layout,
+            Default::default(),
+            &"file1",
+            rlibc::O_RDWR,
+            false,
+            &tp,
+            &OpenOptions {
+                readahead: 4096,
+                ..Default::default()
+            },
+        ).unwrap();
+        assert_eq!(f.write(0, b"ours").unwrap(), 4);
+        // the page in mustn't see what's written next
+        let mut buf = [0u8; 64];
+        assert_eq!(f.read(0, &mut buf).unwrap(), 6);
+        fs::write(resources.join("file1"), b"theirs\n").unwrap();
+
+        let e = f.flush(&src, &"file1", ConflictPolicy::Fail).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ESTALE));
+        assert!(!f.flush(&src, &"file1", ConflictPolicy::KeepBoth).unwrap());
+        drop(f);
+
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"theirs\n");
+        let mut kept = Vec::new();
+        for entry in fs::read_dir(&resources).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if name.starts_with("file1.catfs-conflict-") {
+                kept.push(name);
+            }
+        }
+        assert_eq!(kept.len(), 1);
+        assert_eq!(fs::read(resources.join(&kept[0])).unwrap(), b"ours1\n");
+        assert!(!Handle::validate_cache(&src, cache_dir, layout, &"file1", false, true).unwrap());
+
+        // src changes before the page in gets to it, what's cached
+        // isn't what we started from so it can't be kept either
+        let (tx, rx) = mpsc::channel::<()>();
+        tp.lock().unwrap().execute(move || {
+            let _ = rx.recv();
+        });
+        let mut f = Handle::open(
+            &src,
+            cache_dir,
+            layout,
+            Default::default(),
+            &"file2",
+            rlibc::O_RDWR,
+            false,
+            &tp,
+            &OpenOptions {
+                readahead: 4096,
+                ..Default::default()
+            },
+        ).unwrap();
+        fs::write(resources.join("file2"), b"theirs\n").unwrap();
+        tx.send(()).unwrap();
+        assert_eq!(f.write(0, b"ours").unwrap(), 4);
+        let e = f.flush(&src, &"file2", ConflictPolicy::KeepBoth).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ESTALE));
+        drop(f);
+        assert_eq!(fs::read(resources.join("file2")).unwrap(), b"theirs\n");
+        for entry in fs::read_dir(&resources).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(!name.starts_with("file2.catfs-conflict-"));
+        }
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn stop_caching() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+        let open = || {
+            Handle::open(
+                &src,
+                cache_dir,
+                layout,
+                Default::default(),
+                &"file1",
+                rlibc::O_RDWR,
+                false,
+                &tp,
+                &OpenOptions {
+                    readahead: 4096,
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+
+        let mut f = open();
+        assert!(f.stop_caching(cache_dir, layout, &"file1").unwrap());
+        assert!(!cache.join("file1").exists());
+        assert_eq!(f.write(0, b"new").unwrap(), 3);
+        assert!(f.flush(&src, &"file1", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"newe1\n");
+        drop(f);
+
+        // src doesn't have what's only in the cache
+        let mut f = open();
+        f.set_write_back();
+        assert!(!f.stop_caching(cache_dir, layout, &"file1").unwrap());
+        drop(f);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn write_back() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let tp = Mutex::new(ThreadPool::new(1));
+
+        let mut f = Handle::open(
+            &src,
+            cache_dir,
+            CacheLayout::Path,
+            Default::default(),
+            &"file1",
+            rlibc::O_RDWR,
+            false,
+            &tp,
+            &OpenOptions {
+                readahead: 4096,
+                ..Default::default()
+            },
+        ).unwrap();
+        f.set_write_back();
+        assert!(!f.upload(&src, &"file1", Default::default()).unwrap());
+        assert_eq!(f.write(0, b"ours").unwrap(), 4);
+        assert!(f.needs_upload());
+        assert!(!f.flush(&src, &"file1", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"file1\n");
+        assert_eq!(fs::read(cache.join("file1")).unwrap(), b"ours1\n");
+
+        assert!(f.upload(&src, &"file1", Default::default()).unwrap());
+        assert!(!f.needs_upload());
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"ours1\n");
+        drop(f);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn write_back_readonly_src() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let tp = Mutex::new(ThreadPool::new(1));
+        let opts = OpenOptions {
+            readahead: 4096,
+            src_readonly: true,
+            ..Default::default()
+        };
+        let open = |path: &str, flags: u32| {
+            let mut f = Handle::open(
+                &src,
+                cache_dir,
+                CacheLayout::Path,
+                Default::default(),
+                &path,
+                flags,
+                false,
+                &tp,
+                &opts,
+            ).unwrap();
+            f.set_write_back();
+            f
+        };
+
+        let mut f = open("file1", rlibc::O_RDWR);
+        assert_eq!(f.src_file.get_flags().unwrap() & rlibc::O_ACCMODE, rlibc::O_RDONLY);
+        assert_eq!(f.write(0, b"ours").unwrap(), 4);
+        assert!(!f.flush(&src, &"file1", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"file1\n");
+        assert_eq!(fs::read(cache.join("file1")).unwrap(), b"ours1\n");
+        // src is writable again by the time it's uploaded
+        assert!(f.upload(&src, &"file1", Default::default()).unwrap());
+        assert_eq!(f.src_file.get_flags().unwrap() & rlibc::O_ACCMODE, rlibc::O_RDWR);
+        assert_eq!(fs::read(resources.join("file1")).unwrap(), b"ours1\n");
+        drop(f);
+
+        // truncating only goes as far as the cache
+        let mut f = open("file2", rlibc::O_RDWR | rlibc::O_TRUNC);
+        assert_eq!(fs::read(resources.join("file2")).unwrap(), b"file2\n");
+        assert!(f.needs_upload());
+        assert!(f.upload(&src, &"file2", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file2")).unwrap(), b"");
+        drop(f);
+
+        let mut f = open("file3", rlibc::O_WRONLY | rlibc::O_TRUNC);
+        assert_eq!(f.write(0, b"new").unwrap(), 3);
+        assert!(!f.flush(&src, &"file3", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file3")).unwrap().len(), 6);
+        assert!(f.upload(&src, &"file3", Default::default()).unwrap());
+        assert_eq!(fs::read(resources.join("file3")).unwrap(), b"new");
+        drop(f);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn blocks() {
+        let mut b = Blocks::new(300000, vec![1; 64]);
+        assert_eq!(b.block_size, MIN_BLOCK_SIZE);
+        assert_eq!(b.count(), 5);
+        assert_eq!(b.missing(0, 4), vec![(0, 300000)]);
+        b.add(65536, 131072);
+        b.add(0, 1000);
+        assert!(b.has(1));
+        assert!(!b.has(0));
+        b.add(262144, 300000);
+        assert_eq!(b.missing(0, 4), vec![(0, 65536), (131072, 262144)]);
+        assert!(b.missing(1, 1).is_empty());
+        assert!(!b.is_full());
+
+        let d = Blocks::decode(&b.encode(), 300000, &[1; 64]).unwrap();
+        assert_eq!(d.bitmap, b.bitmap);
+        assert!(Blocks::decode(&b.encode(), 300000, &[2; 64]).is_none());
+        assert!(Blocks::decode(&b.encode(), 3000000, &[1; 64]).is_none());
+
+        b.add(0, 300000);
+        assert!(b.is_full());
+
+        let big = Blocks::new(1 << 40, Vec::new());
+        assert!(big.bitmap.len() as i64 <= MAX_BITMAP_BYTES);
+        assert_eq!(big.count() * big.block_size, 1 << 40);
+    }
+
+    #[test]
+    fn page_in_ranges() {
+        let mut info: PageInInfo = Default::default();
+        info.add(0, 100);
+        info.add(500, 600);
+        info.add(300, 400);
+        info.add(550, 700);
+        assert_eq!(info.offset, 100);
+        assert_eq!(info.copied, vec![(300, 400), (500, 700)]);
+        assert!(info.has(50, 100));
+        assert!(info.has(520, 700));
+        assert!(!info.has(350, 550));
+        assert_eq!(info.next_missing(0), 100);
+        assert_eq!(info.next_missing(300), 400);
+        assert_eq!(info.hole_end(100), Some(300));
+        assert_eq!(info.hole_end(700), None);
+
+        // backfilling joins them up
+        info.add(100, 300);
+        assert_eq!(info.offset, 400);
+        info.add(400, 500);
+        assert_eq!(info.offset, 700);
+        assert!(info.copied.is_empty());
+    }
+
+    #[test]
+    fn partial_fill() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+        fs::write(resources.join("big"), &data).unwrap();
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+        let open = |readahead| {
+            Handle::open(
+                &src,
+                cache_dir,
+                layout,
+                Default::default(),
+                &"big",
+                rlibc::O_RDONLY,
+                false,
+                &tp,
+                &OpenOptions {
+                    readahead,
+                    fill: CacheFill::Partial,
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+
+        let mut f = open(4096);
+        assert!(!f.has_page_in_thread);
+        assert!(f.is_partial());
+
+        let mut buf = [0u8; 100];
+        assert_eq!(f.read(70000, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &data[70000..70100]);
+        let cached = fs::read(cache.join("big")).unwrap();
+        assert_eq!(cached.len(), data.len());
+        assert_eq!(&cached[65536..131072], &data[65536..131072]);
+        assert!(f.partial.as_ref().unwrap().missing(0, 4).len() == 2);
+        drop(f);
+
+        // not valid, but not thrown away either
+        assert!(!Handle::validate_cache(&src, cache_dir, layout, &"big", false, false).unwrap());
+        assert!(cache.join("big").exists());
+
+        // picks up where the last open left off, and reads ahead
+        let mut f = open(1024 * 1024);
+        assert!(f.partial.as_ref().unwrap().has(1));
+        assert!(!f.partial.as_ref().unwrap().has(0));
+        assert_eq!(f.read(0, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &data[..100]);
+        assert!(!f.is_partial());
+        drop(f);
+
+        assert_eq!(fs::read(cache.join("big")).unwrap(), data);
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &"big", false, true).unwrap());
+        let cached = fs::File::open(cache.join("big")).unwrap();
+        assert!(cached.get_xattr(BLOCKS_XATTR).unwrap().is_none());
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn compressed_cache() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let data: Vec<u8> = (0..300000).map(|i| b"catfs\n"[i % 6]).collect();
+        fs::write(resources.join("big"), &data).unwrap();
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+        let open = |flags| {
+            Handle::open(
+                &src,
+                cache_dir,
+                layout,
+                Default::default(),
+                &"big",
+                flags,
+                false,
+                &tp,
+                &OpenOptions {
+                    readahead: 4096,
+                    compress: Some(compress::Codec::Lz4),
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+
+        let mut f = open(rlibc::O_RDONLY);
+        assert!(f.compressed.is_some());
+        let mut buf = [0u8; 100];
+        assert_eq!(f.read(200000, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &data[200000..200100]);
+        f.wait_for_cache().unwrap();
+        drop(f);
+        assert!(fs::metadata(cache.join("big")).unwrap().len() < data.len() as u64 / 10);
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &"big", false, true).unwrap());
+
+        let mut f = open(rlibc::O_RDONLY);
+        assert!(!f.has_page_in_thread);
+        assert!(f.compressed.is_some());
+        assert_eq!(f.read(299950, &mut buf).unwrap(), 50);
+        assert_eq!(&buf[..50], &data[299950..]);
+        drop(f);
+
+        // cached again plain to be written to
+        let mut f = open(rlibc::O_RDWR);
+        assert!(f.compressed.is_none());
+        f.wait_for_cache().unwrap();
+        drop(f);
+        assert_eq!(fs::read(cache.join("big")).unwrap(), data);
+        let cached = fs::File::open(cache.join("big")).unwrap();
+        assert!(cached.get_xattr(compress::COMPRESS_XATTR).unwrap().is_none());
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn encrypted_cache() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let mut data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+        fs::write(resources.join("big"), &data).unwrap();
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+        let key = crypt::Key::new(&[7u8; 32]).unwrap();
+        let open = |flags, key| {
+            Handle::open(
+                &src,
+                cache_dir,
+                layout,
+                Default::default(),
+                &"big",
+                flags,
+                false,
+                &tp,
+                &OpenOptions {
+                    readahead: 4096,
+                    key,
+                    ..Default::default()
+                },
+            ).unwrap()
         };
+
+        let mut f = open(rlibc::O_RDONLY, Some(key.clone()));
+        let mut buf = [0u8; 100];
+        assert_eq!(f.read(200000, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &data[200000..200100]);
+        f.wait_for_cache().unwrap();
+        drop(f);
+        let raw = fs::read(cache.join("big")).unwrap();
+        assert!(raw.len() > data.len());
+        assert!(raw.windows(100).all(|w| w != &data[200000..200100]));
+        assert!(Handle::validate_cache(&src, cache_dir, layout, &"big", false, true).unwrap());
+
+        let mut f = open(rlibc::O_RDWR, Some(key.clone()));
+        assert!(!f.has_page_in_thread);
+        assert_eq!(f.write(100000, b"catfs").unwrap(), 5);
+        assert_eq!(f.read(99998, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(&buf[2..7], b"catfs");
+        f.flush(&src, &"big", Default::default()).unwrap();
+        drop(f);
+        data[100000..100005].copy_from_slice(b"catfs");
+        assert_eq!(fs::read(resources.join("big")).unwrap(), data);
+
+        // what doesn't decrypt is an EIO like any other bad cache
+        // read, so the read can go to src instead
+        let mut raw = fs::read(cache.join("big")).unwrap();
+        raw[100] ^= 1;
+        fs::write(cache.join("big"), &raw).unwrap();
+        let mut f = open(rlibc::O_RDONLY, Some(key.clone()));
+        let e = f.read(0, &mut buf).unwrap_err();
+        assert_eq!(e.errno(), libc::EIO);
+        assert!(health::is_cache_error(e.errno()));
+        drop(f);
+
+        // without the key, it's cached again plain
+        let mut f = open(rlibc::O_RDONLY, None);
+        assert!(f.has_page_in_thread);
+        f.wait_for_cache().unwrap();
+        drop(f);
+        assert_eq!(fs::read(cache.join("big")).unwrap(), data);
+        let cached = fs::File::open(cache.join("big")).unwrap();
+        assert!(cached.get_xattr(crypt::ENCRYPT_XATTR).unwrap().is_none());
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn throttled_page_in() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(resources.join("big"), &data).unwrap();
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let tp = Mutex::new(ThreadPool::new(1));
+        // caching all of it takes 16 seconds
+        let limit = Arc::new(PageInLimit::new(64 * 1024, 0));
+        let mut f = Handle::open(
+            &src,
+            cache_dir,
+            CacheLayout::Path,
+            Default::default(),
+            &"big",
+            rlibc::O_RDONLY,
+            false,
+            &tp,
+            &OpenOptions {
+                readahead: 4096,
+                page_in_limit: limit.clone(),
+                ..Default::default()
+            },
+        ).unwrap();
+        assert!(f.has_page_in_thread);
+
+        // the read doesn't wait for the limit
+        let start = Instant::now();
+        let mut buf = [0u8; 100];
+        assert_eq!(f.read(1000000, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..], &data[1000000..1000100]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // cancels page in
+        assert!(!f.flush(&src, &"big", Default::default()).unwrap());
+        drop(f);
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn small_page_ins() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        fs::create_dir_all(resources.join("small")).unwrap();
+        for i in 0..10 {
+            fs::write(resources.join(format!("small/{}", i)), format!("small{}\n", i)).unwrap();
+        }
+        let src = LocalBackend::new(&resources).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(cache.join("small")).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let layout = CacheLayout::Path;
+        let tp = Mutex::new(ThreadPool::new(1));
+        let small: SmallPageIns = Default::default();
+
+        let mut files = Vec::new();
+        for i in 0..10 {
+            let f = Handle::open(
+                &src,
+                cache_dir,
+                layout,
+                Default::default(),
+                &format!("small/{}", i),
+                rlibc::O_RDONLY,
+                false,
+                &tp,
+                &OpenOptions {
+                    readahead: 4096,
+                    small: small.clone(),
+                    ..Default::default()
+                },
+            ).unwrap();
+            assert!(f.has_page_in_thread);
+            files.push(f);
+        }
+
+        for (i, f) in files.iter_mut().enumerate() {
+            let mut buf = [0u8; 64];
+            let expected = format!("small{}\n", i);
+            assert_eq!(f.read(0, &mut buf).unwrap(), expected.len());
+            assert_eq!(&buf[..expected.len()], expected.as_bytes());
+        }
+        tp.lock().unwrap().join();
+        assert!(small.queued.lock().unwrap().is_empty());
+        drop(files);
+
+        for i in 0..10 {
+            let path = format!("small/{}", i);
+            assert_eq!(
+                fs::read(cache.join(&path)).unwrap(),
+                format!("small{}\n", i).as_bytes()
+            );
+            assert!(Handle::validate_cache(&src, cache_dir, layout, &path, false, true).unwrap());
+        }
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
     }
 }
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use catfs::pin::Pins;
+
+// which files are cached, by their path in the source. The globs work
+// like --pin, ex: "**/*.parquet" or "scratch" for everything under
+// scratch. The rest go straight to the source
+#[derive(Default, Debug, PartialEq)]
+pub struct CacheFilter {
+    include: Pins,
+    exclude: Pins,
+}
+
+impl CacheFilter {
+    // each is a list of comma separated globs, without includes
+    // everything that's not excluded is cached
+    pub fn new(include: &[String], exclude: &[String]) -> Result<CacheFilter, String> {
+        let parse = |globs: &[String]| if globs.is_empty() {
+            Ok(Default::default())
+        } else {
+            globs.join(",").parse::<Pins>()
+        };
+
+        return Ok(CacheFilter {
+            include: parse(include)?,
+            exclude: parse(exclude)?,
+        });
+    }
+
+    pub fn is_cached(&self, path: &Path) -> bool {
+        if !self.include.is_empty() && !self.include.matches(path) {
+            return false;
+        }
+        return !self.exclude.matches(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn is_cached() {
+        let all = CacheFilter::default();
+        assert!(all.is_cached(Path::new("a/b")));
+
+        let f = CacheFilter::new(
+            &[String::from("**/*.parquet"), String::from("*.idx")],
+            &[String::from("scratch")],
+        ).unwrap();
+        assert!(f.is_cached(Path::new("a.idx")));
+        assert!(f.is_cached(Path::new("data/2017/a.parquet")));
+        assert!(!f.is_cached(Path::new("data/a.idx")));
+        assert!(!f.is_cached(Path::new("scratch/out.parquet")));
+
+        let f = CacheFilter::new(&[], &[String::from("**/*.tmp")]).unwrap();
+        assert!(f.is_cached(Path::new("a/b")));
+        assert!(!f.is_cached(Path::new("a/b.tmp")));
+
+        assert!(CacheFilter::new(&[String::from("")], &[]).is_err());
+    }
+}
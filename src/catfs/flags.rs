@@ -64,6 +64,173 @@ impl FromStr for DiskSpace {
     }
 }
 
+// mount-level settings that can be changed on a live mount
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Setting {
+    ReadOnly(bool),
+    FreeSpace(DiskSpace),
+    Ttl(u64),
+}
+
+// parses a comma separated list such as "ro,free=10G,ttl=5"
+pub fn parse_settings(s: &str) -> Result<Vec<Setting>, DiskSpaceParseError> {
+    let mut settings = Vec::new();
+
+    for opt in s.split(',') {
+        let mut kv = opt.splitn(2, '=');
+        let k = kv.next().unwrap().trim();
+        let v = kv.next();
+
+        let setting = match (k, v) {
+            ("ro", None) => Setting::ReadOnly(true),
+            ("rw", None) => Setting::ReadOnly(false),
+            ("free", Some(v)) => Setting::FreeSpace(v.parse()?),
+            ("ttl", Some(v)) => Setting::Ttl(v.parse()?),
+            _ => return Err(DiskSpaceParseError("unrecognized setting ".to_owned() + opt)),
+        };
+        settings.push(setting);
+    }
+
+    return Ok(settings);
+}
+
+// what to do when the mounted catfs receives a signal
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+pub enum SignalAction {
+    Ignore,
+    // unmount, a repeated signal forces the unmount
+    Unmount,
+    ForceUnmount,
+    // log the internal state and keep running
+    Dump,
+    // log the internal state then unmount
+    DumpAndUnmount,
+    // check for something to evict right away
+    Evict,
+    // log how long each kind of request has been taking
+    Latency,
+}
+
+impl FromStr for SignalAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "ignore" => Ok(SignalAction::Ignore),
+            "unmount" => Ok(SignalAction::Unmount),
+            "force-unmount" => Ok(SignalAction::ForceUnmount),
+            "dump" => Ok(SignalAction::Dump),
+            "dump-unmount" => Ok(SignalAction::DumpAndUnmount),
+            "evict" => Ok(SignalAction::Evict),
+            "latency" => Ok(SignalAction::Latency),
+            _ => Err("unrecognized signal action ".to_owned() + s),
+        };
+    }
+}
+
+pub const DEFAULT_SIGNAL_ACTIONS: &'static str =
+    "INT=unmount,TERM=unmount,QUIT=dump-unmount,USR1=evict,USR2=latency";
+
+// parses a comma separated list such as "INT=unmount,USR1=dump",
+// signal names are returned upper cased and without the SIG prefix
+pub fn parse_signal_actions(s: &str) -> Result<Vec<(String, SignalAction)>, String> {
+    let mut actions = Vec::new();
+
+    for opt in s.split(',') {
+        let mut kv = opt.splitn(2, '=');
+        let sig = kv.next().unwrap().trim().to_uppercase();
+        let sig = if sig.starts_with("SIG") {
+            sig[3..].to_owned()
+        } else {
+            sig
+        };
+        let action = match kv.next() {
+            Some(v) => v.trim().parse()?,
+            None => return Err("missing action for ".to_owned() + opt),
+        };
+        if sig.is_empty() {
+            return Err("missing signal in ".to_owned() + opt);
+        }
+        actions.push((sig, action));
+    }
+
+    return Ok(actions);
+}
+
+// defaults that suit a type of source, explicitly passed flags
+// take precedence
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Profile {
+    pub ttl: u64,
+    pub readahead: DiskSpace,
+    pub validate: String,
+    pub evict_request_weight: DiskSpace,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            // objects rarely change behind our back and each request
+            // is expensive. An object's mtime is when it was uploaded,
+            // which the etag already tells
+            "s3" | "goofys" => Ok(Profile {
+                ttl: 60,
+                readahead: DiskSpace::Bytes(1024 * 1024),
+                validate: String::from("etag,size"),
+                evict_request_weight: DiskSpace::Bytes(1024 * 1024),
+            }),
+            // roughly what the nfs client caches attributes for. There
+            // are no etags, and looking for them is a round trip
+            "nfs" => Ok(Profile {
+                ttl: 3,
+                readahead: DiskSpace::Bytes(256 * 1024),
+                validate: String::from("mtime,size"),
+                evict_request_weight: DiskSpace::Bytes(256 * 1024),
+            }),
+            // fetching a file again costs no more than its size
+            "local" => Ok(Profile {
+                ttl: 0,
+                readahead: DiskSpace::Bytes(128 * 1024),
+                validate: String::from("etag,mtime,size"),
+                evict_request_weight: DiskSpace::Bytes(0),
+            }),
+            _ => Err("unrecognized profile ".to_owned() + s),
+        };
+    }
+}
+
+impl Profile {
+    // is_set tells if a flag was given on the command line
+    pub fn apply(&self, flags: &mut FlagStorage, is_set: &dyn Fn(&str) -> bool) {
+        if !is_set("ttl") {
+            flags.ttl = self.ttl;
+        }
+        if !is_set("readahead") {
+            flags.readahead = self.readahead.clone();
+        }
+        if !is_set("validate") {
+            flags.validate = self.validate.clone();
+        }
+        if !is_set("evict-request-weight") {
+            flags.evict_request_weight = self.evict_request_weight.clone();
+        }
+    }
+}
+
+// parses permission bits in octal, such as "0600" or "077"
+pub fn parse_mode(s: &str) -> Result<libc::mode_t, String> {
+    match libc::mode_t::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => return Ok(mode),
+        _ => return Err("invalid mode ".to_owned() + s),
+    }
+}
+
 #[derive(Default)]
 pub struct FlagStorage {
     pub cat_from: OsString,
@@ -71,9 +238,82 @@ pub struct FlagStorage {
     pub mount_point: OsString,
     pub mount_options: Vec<OsString>,
     pub foreground: bool,
+    pub log_format: String,
+    pub log_file: OsString,
+    pub log_file_size: DiskSpace,
+    pub log_file_count: u64,
+    pub threads: u64,
     pub free_space: DiskSpace,
+    pub max_cache_size: DiskSpace,
+    pub eviction_policy: String,
+    pub evict_scan_interval: u64,
+    pub evict_low_watermark: DiskSpace,
+    pub evict_hot_percent: u64,
+    pub evict_request_weight: DiskSpace,
     pub uid: libc::uid_t,
     pub gid: libc::gid_t,
+    pub allow_other: bool,
+    pub allow_root: bool,
+    pub permissions: String,
+    pub nonempty: bool,
+    pub ttl: u64,
+    pub dir_cache_ttl: u64,
+    pub cache_valid_ttl: String,
+    pub signals: String,
+    pub groups: String,
+    pub map_uid: Vec<String>,
+    pub map_gid: Vec<String>,
+    pub squash_uid: String,
+    pub squash_gid: String,
+    pub readahead: DiskSpace,
+    pub max_pagein_bandwidth: DiskSpace,
+    pub max_concurrent_pagein: u64,
+    pub profile: String,
+    pub mirror_to: OsString,
+    pub control_socket: OsString,
+    pub stats_file: bool,
+    pub watch_source: bool,
+    pub peers: String,
+    pub serve_peers: String,
+    pub peer_allow: String,
+    pub peer_bandwidth: DiskSpace,
+    pub shared_cache: bool,
+    pub cache_odirect: bool,
+    pub no_splice: bool,
+    pub cache_l2: OsString,
+    pub cache_prefix: OsString,
+    pub cache_quota: DiskSpace,
+    pub quota_per_uid: DiskSpace,
+    pub whiteout: bool,
+    pub cow: bool,
+    pub fetch_cmd: OsString,
+    pub src_retries: u64,
+    pub src_retry_delay: u64,
+    pub virtiofs: OsString,
+    pub cache_layout: String,
+    pub immutable_src: bool,
+    pub validate: String,
+    pub validate_xattrs: String,
+    pub bypass: String,
+    pub max_cacheable_size: DiskSpace,
+    pub no_cache: bool,
+    pub cache_include: Vec<String>,
+    pub cache_exclude: Vec<String>,
+    pub idle_handle_warn: u64,
+    pub cache_mode: String,
+    pub cache_umask: String,
+    pub file_mode: String,
+    pub dir_mode: String,
+    pub umask: String,
+    pub on_conflict: String,
+    pub cache_fill: String,
+    pub cache_compress: String,
+    pub cache_encrypt: OsString,
+    pub cache_dedupe: bool,
+    pub statfs: String,
+    pub io_backend: String,
+    pub write_back: Option<String>,
+    pub pin: String,
 }
 
 #[cfg(test)]
@@ -93,6 +333,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_settings_list() {
+        assert_eq!(
+            parse_settings("ro,free=10G,ttl=5").unwrap(),
+            vec![
+                Setting::ReadOnly(true),
+                Setting::FreeSpace(DiskSpace::Bytes(10 * 1024 * 1024 * 1024)),
+                Setting::Ttl(5),
+            ]
+        );
+        assert_eq!(parse_settings("rw").unwrap(), vec![Setting::ReadOnly(false)]);
+        assert!(parse_settings("ro=1").is_err());
+        assert!(parse_settings("ttl=x").is_err());
+        assert!(parse_settings("cat").is_err());
+    }
+
+    #[test]
+    fn parse_signal_action_list() {
+        assert_eq!(
+            parse_signal_actions(DEFAULT_SIGNAL_ACTIONS).unwrap(),
+            vec![
+                ("INT".to_owned(), SignalAction::Unmount),
+                ("TERM".to_owned(), SignalAction::Unmount),
+                ("QUIT".to_owned(), SignalAction::DumpAndUnmount),
+                ("USR1".to_owned(), SignalAction::Evict),
+                ("USR2".to_owned(), SignalAction::Latency),
+            ]
+        );
+        assert_eq!(
+            parse_signal_actions("sigusr1=dump").unwrap(),
+            vec![("USR1".to_owned(), SignalAction::Dump)]
+        );
+        assert!(parse_signal_actions("INT").is_err());
+        assert!(parse_signal_actions("INT=cat").is_err());
+        assert!(parse_signal_actions("=dump").is_err());
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!(parse_mode("0600").unwrap(), 0o600);
+        assert_eq!(parse_mode("77").unwrap(), 0o77);
+        assert!(parse_mode("4755").is_err());
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn profile_defaults() {
+        let mut flags: FlagStorage = Default::default();
+        flags.ttl = 5;
+
+        let profile: Profile = "s3".parse().unwrap();
+        profile.apply(&mut flags, &|name| name == "ttl");
+        assert_eq!(flags.ttl, 5);
+        assert_eq!(flags.readahead, DiskSpace::Bytes(1024 * 1024));
+        assert_eq!(flags.validate, "etag,size");
+        assert_eq!(flags.evict_request_weight, DiskSpace::Bytes(1024 * 1024));
+
+        flags.validate = String::from("size");
+        let profile: Profile = "nfs".parse().unwrap();
+        profile.apply(&mut flags, &|name| name == "validate");
+        assert_eq!(flags.validate, "size");
+        assert_eq!(flags.evict_request_weight, DiskSpace::Bytes(256 * 1024));
+
+        profile.apply(&mut flags, &|_| false);
+        assert_eq!(flags.ttl, 60);
+        assert!("cat".parse::<Profile>().is_err());
+    }
+
     #[test]
     #[should_panic]
     fn parse_negative() {
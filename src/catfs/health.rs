@@ -0,0 +1,146 @@
+extern crate libc;
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use catfs::rlibc;
+use catfs::rlibc::File;
+
+// cache errors in a row before files are served from src instead
+pub const MAX_ERRORS: usize = 3;
+// how often a failing cache dir is tried again
+const PROBE_SECS: u64 = 30;
+const PROBE_FILE: &'static str = ".catfs-probe";
+
+// errors that say the cache dir itself is in trouble, rather than
+// the file we were after
+pub fn is_cache_error(errno: libc::c_int) -> bool {
+    return match errno {
+        libc::EIO | libc::ENODEV | libc::ENXIO | libc::ENOTCONN | libc::ESTALE | libc::EROFS => true,
+        _ => false,
+    };
+}
+
+// keeps track of whether the cache dir is usable. When it's not (the
+// disk went away or keeps returning EIO), catfs degrades to reading
+// and writing src directly, and the cache dir is tried every so often
+// to see if it's back
+pub struct CacheHealth {
+    dir: RawFd,
+    errors: AtomicUsize,
+    // when the cache was last tried, while it's failing
+    failing: Mutex<Option<Instant>>,
+}
+
+impl CacheHealth {
+    pub fn new(dir: RawFd) -> CacheHealth {
+        return CacheHealth {
+            dir: dir,
+            errors: AtomicUsize::new(0),
+            failing: Mutex::new(None),
+        };
+    }
+
+    pub fn ok(&self) {
+        self.errors.store(0, Ordering::Relaxed);
+    }
+
+    // an operation on the cache failed with errno. It may well have
+    // been src's fault, so the cache is only given up on if it can't
+    // be written to either
+    pub fn failed(&self, errno: libc::c_int) {
+        if !is_cache_error(errno) {
+            return;
+        }
+        let n = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if n >= MAX_ERRORS {
+            if let Err(e) = self.probe() {
+                self.degrade(&e);
+            }
+        }
+    }
+
+    pub fn degrade(&self, why: &dyn ::std::fmt::Display) {
+        let mut failing = self.failing.lock().unwrap();
+        if failing.is_none() {
+            error!(
+                "cache dir is failing ({}), serving files from the source directly until it's back",
+                why
+            );
+            *failing = Some(Instant::now());
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        let mut failing = self.failing.lock().unwrap();
+        let tried = match *failing {
+            Some(tried) => tried,
+            None => return false,
+        };
+        if tried.elapsed() < Duration::from_secs(PROBE_SECS) {
+            return true;
+        }
+        match self.probe() {
+            Ok(_) => {
+                warn!("cache dir is back, caching again");
+                self.errors.store(0, Ordering::Relaxed);
+                *failing = None;
+                return false;
+            }
+            Err(e) => {
+                debug!("cache dir is still failing: {}", e);
+                *failing = Some(Instant::now());
+                return true;
+            }
+        }
+    }
+
+    fn probe(&self) -> io::Result<()> {
+        let mut f = File::openat(
+            self.dir,
+            &PROBE_FILE,
+            rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC,
+            0o600,
+        )?;
+        let res = f.write_at(b"catfs", 0);
+        f.close()?;
+        res?;
+        return rlibc::unlinkat(self.dir, &PROBE_FILE, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn degrade() {
+        let prefix = catfs::tests::copy_resources();
+        let dir = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+        let health = CacheHealth::new(dir);
+
+        // not the cache's fault
+        for _ in 0..MAX_ERRORS {
+            health.failed(libc::ENOENT);
+        }
+        // the cache can still be written to
+        for _ in 0..MAX_ERRORS {
+            health.failed(libc::EIO);
+        }
+        assert!(!health.is_degraded());
+        assert!(!prefix.join(PROBE_FILE).exists());
+
+        health.degrade(&"testing");
+        assert!(health.is_degraded());
+        *health.failing.lock().unwrap() = Some(Instant::now() - Duration::from_secs(PROBE_SECS));
+        assert!(!health.is_degraded());
+
+        rlibc::close(dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
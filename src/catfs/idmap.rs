@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+// how the owners of files in src are shown through the mount, for
+// sources whose uids and gids mean nothing here, such as NFS exports
+// and container images. Owners given to chown and to what's created
+// are mapped back before they go to src
+#[derive(Clone, Default)]
+pub struct IdMap {
+    uids: Arc<Ids>,
+    gids: Arc<Ids>,
+}
+
+#[derive(Default)]
+struct Ids {
+    // from the id in src to the id shown, the first one wins
+    map: Vec<(u32, u32)>,
+    // shown for every id that's not in map
+    squash: Option<u32>,
+}
+
+impl Ids {
+    fn to_mount(&self, id: u32) -> u32 {
+        for &(from, to) in &self.map {
+            if from == id {
+                return to;
+            }
+        }
+        return self.squash.unwrap_or(id);
+    }
+
+    // squashed ids can't be told apart anymore, those go to src as
+    // they are
+    fn to_src(&self, id: u32) -> u32 {
+        if id == u32::MAX {
+            // chown leaves this one alone
+            return id;
+        }
+        for &(from, to) in &self.map {
+            if to == id {
+                return from;
+            }
+        }
+        return id;
+    }
+}
+
+impl IdMap {
+    pub fn new(
+        uids: Vec<(u32, u32)>,
+        squash_uid: Option<u32>,
+        gids: Vec<(u32, u32)>,
+        squash_gid: Option<u32>,
+    ) -> IdMap {
+        return IdMap {
+            uids: Arc::new(Ids {
+                map: uids,
+                squash: squash_uid,
+            }),
+            gids: Arc::new(Ids {
+                map: gids,
+                squash: squash_gid,
+            }),
+        };
+    }
+
+    pub fn uid(&self, uid: u32) -> u32 {
+        return self.uids.to_mount(uid);
+    }
+
+    pub fn gid(&self, gid: u32) -> u32 {
+        return self.gids.to_mount(gid);
+    }
+
+    pub fn src_uid(&self, uid: u32) -> u32 {
+        return self.uids.to_src(uid);
+    }
+
+    pub fn src_gid(&self, gid: u32) -> u32 {
+        return self.gids.to_src(gid);
+    }
+}
+
+// parses "a:b", a in src is shown as b
+pub fn parse_pair(s: &str) -> Result<(u32, u32), String> {
+    let mut ids = s.splitn(2, ':');
+    let from = ids.next().unwrap().parse::<u32>();
+    let to = ids.next().map(|id| id.parse::<u32>());
+    return match (from, to) {
+        (Ok(from), Some(Ok(to))) => Ok((from, to)),
+        _ => Err("expected <id in source>:<id to show>, not ".to_owned() + s),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_ids() {
+        assert_eq!(parse_pair("1000:0").unwrap(), (1000, 0));
+        assert!(parse_pair("1000").is_err());
+        assert!(parse_pair("a:0").is_err());
+        assert!(parse_pair("1000:").is_err());
+
+        let ids = IdMap::new(vec![(1000, 500), (1001, 500)], Some(65534), vec![(0, 100)], None);
+        assert_eq!(ids.uid(1000), 500);
+        assert_eq!(ids.uid(1001), 500);
+        assert_eq!(ids.uid(0), 65534);
+        assert_eq!(ids.src_uid(500), 1000);
+        assert_eq!(ids.src_uid(65534), 65534);
+        assert_eq!(ids.src_uid(u32::MAX), u32::MAX);
+        assert_eq!(ids.gid(0), 100);
+        assert_eq!(ids.gid(5), 5);
+        assert_eq!(ids.src_gid(100), 0);
+
+        let ids: IdMap = Default::default();
+        assert_eq!(ids.uid(1000), 1000);
+        assert_eq!(ids.src_gid(1000), 1000);
+    }
+}
@@ -2,34 +2,52 @@ extern crate fuse;
 extern crate libc;
 extern crate threadpool;
 extern crate time;
+extern crate xattr;
 
 use self::threadpool::ThreadPool;
 use self::time::{Duration, Timespec};
+use self::xattr::FileExt;
 
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use catfs::backend::SourceBackend;
+use catfs::compress;
+use catfs::crypt;
 use catfs::dir;
+use catfs::dircache::DirCache;
 use catfs::error;
 use catfs::file;
+use catfs::idmap::IdMap;
 use catfs::rlibc;
 use catfs::rlibc::File;
+use catfs::whiteout::Whiteouts;
 
 #[derive(Clone)]
 pub struct Inode {
-    src_dir: RawFd,
+    src: Arc<dyn SourceBackend>,
     cache_dir: RawFd,
+    layout: file::CacheLayout,
+    perms: file::CachePerms,
+    ids: IdMap,
+    mount_perms: file::MountPerms,
 
     name: OsString,
     path: PathBuf,
 
     attr: fuse::FileAttr,
+    // the kernel hands this out in nfs file handles along with the
+    // ino, so it has to change when an ino starts to mean another file
+    generation: u64,
     time: Timespec,
     cache_valid_if_present: bool,
+    // when the cache file was last checked against src
+    validated: Option<Timespec>,
     flush_failed: bool,
 
     refcnt: u64,
@@ -50,30 +68,89 @@ fn to_filetype(t: libc::mode_t) -> fuse::FileType {
 
 impl Inode {
     pub fn new(
-        src_dir: RawFd,
+        src: Arc<dyn SourceBackend>,
         cache_dir: RawFd,
+        layout: file::CacheLayout,
+        perms: file::CachePerms,
+        ids: IdMap,
+        mount_perms: file::MountPerms,
         name: OsString,
         path: PathBuf,
         attr: fuse::FileAttr,
     ) -> Inode {
         return Inode {
-            src_dir: src_dir,
+            src: src,
             cache_dir: cache_dir,
+            layout: layout,
+            perms: perms,
+            ids: ids,
+            mount_perms: mount_perms,
             name: name,
             path: path,
             attr: attr,
+            generation: 0,
             time: time::get_time(),
             cache_valid_if_present: false,
+            validated: None,
             flush_failed: false,
             refcnt: 1,
         };
     }
 
+    pub fn set_cache_layout(&mut self, layout: file::CacheLayout) {
+        self.layout = layout;
+    }
+
+    pub fn set_cache_perms(&mut self, perms: file::CachePerms) {
+        self.perms = perms;
+    }
+
+    // what's already looked up keeps the owners it had until refresh
+    pub fn set_id_map(&mut self, ids: IdMap) {
+        self.ids = ids;
+    }
+
+    // and the modes
+    pub fn set_mount_perms(&mut self, mount_perms: file::MountPerms) {
+        self.mount_perms = mount_perms;
+    }
+
     pub fn take(&mut self, other: Inode) {
         self.attr = other.attr;
         self.time = other.time;
     }
 
+    // the same file showed up under another name, either a hard link
+    // or it was renamed in the source behind our back
+    pub fn relink(&mut self, other: Inode) {
+        self.name = other.name;
+        self.path = other.path;
+        self.attr = other.attr;
+        self.time = other.time;
+    }
+
+    // for hard links, which of the file's names to use
+    pub fn use_path(&mut self, path: &Path) {
+        if let Some(name) = path.file_name() {
+            self.name = name.to_os_string();
+        }
+        self.path = path.to_path_buf();
+    }
+
+    // one of the file's names went away
+    pub fn unlinked(&mut self) {
+        if self.attr.nlink > 1 {
+            self.attr.nlink -= 1;
+        }
+    }
+
+    // whether other, which was just looked up from the same path, is
+    // still the file we have. The source may have replaced it with
+    // another one, in which case our ino is stale
+    pub fn is_same_file(&self, other: &Inode) -> bool {
+        return self.attr.ino == other.attr.ino && self.attr.kind == other.attr.kind;
+    }
+
     pub fn not_expired(&self, ttl: &Duration) -> bool {
         (time::get_time() - self.time) > *ttl
     }
@@ -100,14 +177,27 @@ impl Inode {
         return self.attr.ino;
     }
 
+    pub fn get_generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
     pub fn extend(&mut self, offset: u64) {
         if self.attr.size < offset {
             self.attr.size = offset;
         }
     }
 
-    pub fn lookup_path(dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<fuse::FileAttr> {
-        let st = rlibc::fstatat(dir, path)?;
+    pub fn lookup_path(
+        src: &dyn SourceBackend,
+        path: &dyn AsRef<Path>,
+        ids: &IdMap,
+        mount_perms: file::MountPerms,
+    ) -> io::Result<fuse::FileAttr> {
+        let st = src.stat(path)?;
         let attr = fuse::FileAttr {
             ino: st.st_ino,
             size: st.st_size as u64,
@@ -129,10 +219,10 @@ impl Inode {
                 nsec: st.st_ctime_nsec as i32,
             },
             kind: to_filetype(st.st_mode),
-            perm: (st.st_mode & !libc::S_IFMT) as u16,
+            perm: mount_perms.shown(st.st_mode) as u16,
             nlink: st.st_nlink as u32,
-            uid: st.st_uid,
-            gid: st.st_gid,
+            uid: ids.uid(st.st_uid),
+            gid: ids.gid(st.st_gid),
             rdev: st.st_rdev as u32,
             flags: 0,
         };
@@ -146,8 +236,17 @@ impl Inode {
     }
 
     pub fn refresh(&mut self) -> error::Result<()> {
-        match Inode::lookup_path(self.src_dir, &self.path) {
-            Ok(attr) => self.attr = attr,
+        match Inode::lookup_path(&*self.src, &self.path, &self.ids, self.mount_perms) {
+            Ok(mut attr) => {
+                if self.attr.ino == fuse::FUSE_ROOT_ID {
+                    attr.ino = fuse::FUSE_ROOT_ID;
+                } else if attr.ino != self.attr.ino || attr.kind != self.attr.kind {
+                    // the path is now another file, which needs a
+                    // new lookup to get its own ino
+                    return error::propagate(io::Error::from_raw_os_error(libc::ESTALE));
+                }
+                self.attr = attr;
+            }
             Err(e) => {
                 if error::is_enoent(&e) {
                     return Err(error::RError::propagate(e));
@@ -160,6 +259,11 @@ impl Inode {
         return Ok(());
     }
 
+    // the cache is checked again the next time it's opened
+    pub fn invalidate(&mut self) {
+        self.cache_valid_if_present = false;
+    }
+
     pub fn flush_failed(&mut self) {
         // we know that flush failed, demand more from the pristineness
         self.cache_valid_if_present = false;
@@ -172,11 +276,15 @@ impl Inode {
 
     pub fn lookup(&self, name: &OsStr) -> error::Result<Inode> {
         let path = self.get_child_name(name);
-        match Inode::lookup_path(self.src_dir, &path) {
+        match Inode::lookup_path(&*self.src, &path, &self.ids, self.mount_perms) {
             Ok(attr) => {
                 return Ok(Inode::new(
-                    self.src_dir,
+                    self.src.clone(),
                     self.cache_dir,
+                    self.layout,
+                    self.perms,
+                    self.ids.clone(),
+                    self.mount_perms,
                     name.to_os_string(),
                     path,
                     attr,
@@ -186,49 +294,127 @@ impl Inode {
         }
     }
 
-    pub fn create(&self, name: &OsStr, mode: libc::mode_t) -> error::Result<(Inode, file::Handle)> {
+    // without excl this replaces what's in src, for when the old file
+    // had been whited out. With bypass the file is only created in src
+    pub fn create(
+        &self,
+        name: &OsStr,
+        mode: libc::mode_t,
+        excl: bool,
+        bypass: bool,
+        key: Option<crypt::Key>,
+        disable_splice: bool,
+    ) -> error::Result<(Inode, file::Handle)> {
         let path = self.get_child_name(name);
+        let mode = self.mount_perms.create_mode(false, mode);
 
-        let flags = rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_EXCL;
+        let flags = if excl {
+            rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_EXCL
+        } else {
+            rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC
+        };
 
-        let wh = file::Handle::create(self.src_dir, self.cache_dir, &path, flags, mode)?;
+        let wh = if bypass {
+            file::Handle::open_bypass(&*self.src, self.cache_dir, self.layout, &path, flags, mode)?
+        } else {
+            file::Handle::create(
+                &*self.src,
+                self.cache_dir,
+                self.layout,
+                self.perms,
+                &path,
+                flags,
+                mode,
+                key,
+                disable_splice,
+            )?
+        };
 
-        let attr = Inode::lookup_path(self.src_dir, &path)?;
+        let attr = Inode::lookup_path(&*self.src, &path, &self.ids, self.mount_perms)?;
         let mut inode = Inode::new(
-            self.src_dir,
+            self.src.clone(),
             self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
             name.to_os_string(),
             path,
             attr,
         );
         // we just created this file, it's gotta be valid
-        inode.cache_valid_if_present = true;
+        inode.cache_valid_if_present = !bypass;
 
         return Ok((inode, wh));
     }
 
-    pub fn open(&mut self, flags: u32, tp: &Mutex<ThreadPool>) -> error::Result<file::Handle> {
+    // without valid_ttl the cache file is trusted from the first open
+    // until something tells us src has changed
+    pub fn open(
+        &mut self,
+        flags: u32,
+        valid_ttl: Option<Duration>,
+        tp: &Mutex<ThreadPool>,
+        opts: &file::OpenOptions,
+    ) -> error::Result<file::Handle> {
+        if let (Some(ttl), Some(validated)) = (valid_ttl, self.validated) {
+            if time::get_time() >= validated + ttl {
+                self.cache_valid_if_present = false;
+            }
+        }
+
+        let mut opts = opts.clone();
+        opts.disable_splice |= self.flush_failed;
         let f = file::Handle::open(
-            self.src_dir,
+            &*self.src,
             self.cache_dir,
+            self.layout,
+            self.perms,
             &self.path,
             flags,
             self.cache_valid_if_present,
-            self.flush_failed,
             tp,
+            &opts,
         )?;
         // Handle::open deletes the cache file if it was invalid, so
-        // at this point it must be valid, even after we start writing to it
-        self.cache_valid_if_present = true;
+        // at this point it must be valid, even after we start writing to
+        // it. That's not true of a partially filled one
+        if !f.is_partial() {
+            if !self.cache_valid_if_present || self.validated.is_none() {
+                self.validated = Some(time::get_time());
+            }
+            self.cache_valid_if_present = true;
+        }
+        return Ok(f);
+    }
+
+    pub fn open_bypass(&mut self, flags: u32) -> error::Result<file::Handle> {
+        let f = file::Handle::open_bypass(
+            &*self.src,
+            self.cache_dir,
+            self.layout,
+            &self.path,
+            flags,
+            0o666,
+        )?;
+        if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+            // src may change under what's cached
+            self.cache_valid_if_present = false;
+        }
         return Ok(f);
     }
 
     pub fn reopen_src(&self, file: &mut file::Handle) -> error::Result<()> {
-        file.reopen_src(self.src_dir, &self.path, self.cache_valid_if_present)
+        file.reopen_src(&*self.src, &self.path, self.cache_valid_if_present)
     }
 
     pub fn unlink(&self, name: &OsStr) -> io::Result<()> {
-        return file::Handle::unlink(self.src_dir, self.cache_dir, &self.get_child_name(name));
+        return file::Handle::unlink(
+            &*self.src,
+            self.cache_dir,
+            self.layout,
+            &self.get_child_name(name),
+        );
     }
 
     pub fn rename(&mut self, new_name: &OsStr, new_path: &dyn AsRef<Path>) -> error::Result<()> {
@@ -237,32 +423,71 @@ impl Inode {
         // rename src first because if it's a directory, underlining
         // filesystem may reject if it's non-empty, where as if it's
         // the cache it may not contain anything or may even not exist
-        rlibc::renameat(self.src_dir, &self.path, new_path)?;
+        self.src.rename(&self.path, new_path)?;
         // source is renamed and now rename what's in the
         // cache. If things fail here we are inconsistent. XXX
         // delete cache path (could be a dir) if we failed to
-        // rename it
-        if rlibc::existat(self.cache_dir, &self.path)? {
-            if let Some(parent) = new_path.as_ref().parent() {
-                file::mkdirat_all(self.cache_dir, &parent, 0o777)?;
-            }
-            rlibc::renameat(self.cache_dir, &self.path, new_path)?;
-        }
+        // rename it. Directories don't exist in a hashed cache, what
+        // was cached under them is moved by moved() if we know of it,
+        // or left for the evicter
+        self.rename_cache(new_path.as_ref())?;
 
         self.name = new_name.to_os_string();
         self.path = new_path.as_ref().to_path_buf();
         return Ok(());
     }
 
+    // a directory above us was renamed, which already moved our cache
+    // file unless the cache is hashed
+    pub fn moved(&mut self, new_path: &Path) -> error::Result<()> {
+        if self.layout != file::CacheLayout::Path && self.attr.kind != fuse::FileType::Directory {
+            self.rename_cache(new_path)?;
+        }
+        self.use_path(new_path);
+        return Ok(());
+    }
+
+    fn rename_cache(&self, new_path: &Path) -> error::Result<()> {
+        let cache_path = self.layout.cache_path(&self.path);
+        if rlibc::existat(self.cache_dir, &cache_path)? {
+            let new_cache_path = self.layout.cache_path(new_path);
+            if let Some(parent) = new_cache_path.parent() {
+                file::mkdirat_all(self.cache_dir, &parent, self.perms.dir_mode())?;
+            }
+            rlibc::renameat(self.cache_dir, &cache_path, &new_cache_path)?;
+            // symlinks don't say where they are from
+            if self.layout != file::CacheLayout::Path && self.attr.kind != fuse::FileType::Symlink {
+                let mut f = File::openat(self.cache_dir, &new_cache_path, rlibc::O_RDONLY, 0)?;
+                let res = f.set_xattr(file::PATH_XATTR, new_path.as_os_str().as_bytes());
+                f.close()?;
+                res?;
+            }
+        }
+        return Ok(());
+    }
+
     pub fn truncate(&mut self, size: u64) -> error::Result<()> {
-        let mut f = File::openat(self.src_dir, &self.path, rlibc::O_WRONLY, 0)?;
+        let mut f = self.src.open(&self.path, rlibc::O_WRONLY, 0)?;
         f.set_size(size)?;
         f.close()?;
 
-        match File::openat(self.cache_dir, &self.path, rlibc::O_WRONLY, 0) {
+        match self.layout.openat(self.cache_dir, self.perms, &self.path, rlibc::O_WRONLY, 0) {
             Ok(mut f) => {
-                f.set_size(size)?;
+                let encoded = compress::Codec::of(&f).and_then(|codec| {
+                    Ok(codec.is_some() || crypt::is_sealed(&f)?)
+                });
+                let res = match encoded {
+                    Ok(false) => f.set_size(size),
+                    Ok(true) => Ok(()),
+                    Err(e) => Err(error::RError::from(e)),
+                };
                 f.close()?;
+                res?;
+                if let Ok(true) = encoded {
+                    // a compressed or encrypted cache file can't be
+                    // cut short in place, it's paged in again instead
+                    rlibc::unlinkat(self.cache_dir, &self.layout.cache_path(&self.path), 0)?;
+                }
             }
             Err(e) => {
                 error::try_enoent(e)?;
@@ -273,23 +498,66 @@ impl Inode {
     }
 
     pub fn utimes(&self, atime: &Timespec, mtime: &Timespec, flags: u32) -> io::Result<()> {
-        rlibc::utimensat(self.src_dir, &self.path, atime, mtime, flags)
+        self.src.utimes(&self.path, atime, mtime, flags)
     }
 
     pub fn chmod(&self, mode: libc::mode_t, flags: u32) -> io::Result<()> {
-        rlibc::fchmodat(self.src_dir, &self.path, mode, flags)?;
+        self.src.chmod(&self.path, mode, flags)?;
+        return Ok(());
+    }
+
+    // uid and gid are as shown through the mount
+    pub fn chown(&mut self, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+        let src_uid = self.ids.src_uid(uid);
+        let src_gid = self.ids.src_gid(gid);
+        self.src.chown(&self.path, src_uid, src_gid, libc::AT_SYMLINK_NOFOLLOW as u32)?;
+        if self.perms.src_owner {
+            let cache_path = self.layout.cache_path(&self.path);
+            let flags = libc::AT_SYMLINK_NOFOLLOW as u32;
+            if let Err(e) = rlibc::fchownat(self.cache_dir, &cache_path, src_uid, src_gid, flags) {
+                if !error::is_enoent(&e) {
+                    warn!("!chown cache file of {:?} = {}", self.path, e);
+                }
+            }
+        }
+        if uid != u32::MAX {
+            self.attr.uid = uid;
+        }
+        if gid != u32::MAX {
+            self.attr.gid = gid;
+        }
         return Ok(());
     }
 
+    pub fn get_xattr(&self, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        return self.src.get_xattr(&self.path, name);
+    }
+
+    pub fn set_xattr(&self, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        return self.src.set_xattr(&self.path, name, value);
+    }
+
+    pub fn list_xattr(&self) -> io::Result<Vec<OsString>> {
+        return self.src.list_xattr(&self.path);
+    }
+
+    pub fn remove_xattr(&self, name: &OsStr) -> io::Result<()> {
+        return self.src.remove_xattr(&self.path, name);
+    }
+
     pub fn mkdir(&self, name: &OsStr, mode: libc::mode_t) -> error::Result<Inode> {
         let path = self.get_child_name(name);
 
-        rlibc::mkdirat(self.src_dir, &path, mode)?;
+        self.src.mkdir(&path, self.mount_perms.create_mode(true, mode))?;
 
-        let attr = Inode::lookup_path(self.src_dir, &path)?;
+        let attr = Inode::lookup_path(&*self.src, &path, &self.ids, self.mount_perms)?;
         let inode = Inode::new(
-            self.src_dir,
+            self.src.clone(),
             self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
             name.to_os_string(),
             path,
             attr,
@@ -298,12 +566,189 @@ impl Inode {
         return Ok(inode);
     }
 
+    // fifos and device nodes only exist in src, there's nothing to
+    // cache. replace is as in symlink
+    pub fn mknod(
+        &self,
+        name: &OsStr,
+        mode: libc::mode_t,
+        rdev: u32,
+        replace: bool,
+    ) -> error::Result<Inode> {
+        let path = self.get_child_name(name);
+
+        if replace {
+            if let Err(e) = self.src.unlink(&path) {
+                if !error::is_enoent(&e) {
+                    return Err(e.into());
+                }
+            }
+        }
+        self.src.mknod(&path, self.mount_perms.create_mode(false, mode), rdev)?;
+
+        let attr = Inode::lookup_path(&*self.src, &path, &self.ids, self.mount_perms)?;
+        let inode = Inode::new(
+            self.src.clone(),
+            self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
+            name.to_os_string(),
+            path,
+            attr,
+        );
+
+        return Ok(inode);
+    }
+
+    // replace is for when src still has what was whited out there
+    pub fn symlink(&self, name: &OsStr, target: &Path, replace: bool) -> error::Result<Inode> {
+        let path = self.get_child_name(name);
+
+        if replace {
+            if let Err(e) = self.src.unlink(&path) {
+                if !error::is_enoent(&e) {
+                    return Err(e.into());
+                }
+            }
+        }
+        self.src.symlink(&target, &path)?;
+        if let Err(e) = self.layout.symlinkat(self.cache_dir, self.perms, &target, &path) {
+            // readlink will go to src instead
+            error!("!symlink {:?} in cache = {}", path, e);
+        }
+
+        let attr = Inode::lookup_path(&*self.src, &path, &self.ids, self.mount_perms)?;
+        let inode = Inode::new(
+            self.src.clone(),
+            self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
+            name.to_os_string(),
+            path,
+            attr,
+        );
+
+        return Ok(inode);
+    }
+
+    // new_path becomes another name for this file, replace is as in
+    // symlink
+    pub fn link(
+        &self,
+        new_name: &OsStr,
+        new_path: &dyn AsRef<Path>,
+        replace: bool,
+    ) -> error::Result<Inode> {
+        if replace {
+            if let Err(e) = self.src.unlink(new_path) {
+                if !error::is_enoent(&e) {
+                    return Err(e.into());
+                }
+            }
+        }
+        self.src.link(&self.path, new_path)?;
+        if let Err(e) = self.layout.linkat(self.cache_dir, self.perms, &self.path, new_path) {
+            // the cache copy is checked against src before it's used
+            error!("!link {:?} in cache = {}", new_path.as_ref(), e);
+        }
+
+        // nlink changed
+        let attr = Inode::lookup_path(&*self.src, new_path, &self.ids, self.mount_perms)?;
+        let inode = Inode::new(
+            self.src.clone(),
+            self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
+            new_name.to_os_string(),
+            new_path.as_ref().to_path_buf(),
+            attr,
+        );
+
+        return Ok(inode);
+    }
+
+    // the cache copy is used if it was made after the symlink in src
+    // last changed, symlinks are only ever replaced and not changed
+    pub fn readlink(&self) -> error::Result<PathBuf> {
+        let cache_path = self.layout.cache_path(&self.path);
+        if let Ok(st) = rlibc::fstatat(self.cache_dir, &cache_path) {
+            let mtime = Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32);
+            if (st.st_mode & libc::S_IFMT) == libc::S_IFLNK && mtime >= self.attr.mtime {
+                if let Ok(target) = rlibc::readlinkat(self.cache_dir, &cache_path) {
+                    return Ok(target);
+                }
+            }
+        }
+
+        let target = self.src.readlink(&self.path)?;
+        if let Err(e) = self.layout.symlinkat(self.cache_dir, self.perms, &target, &self.path) {
+            debug!("!symlink {:?} in cache = {}", self.path, e);
+        }
+        return Ok(target);
+    }
+
     pub fn rmdir(&self, name: &OsStr) -> io::Result<()> {
-        return dir::Handle::rmdirat(self.src_dir, self.cache_dir, &self.get_child_name(name));
+        return dir::Handle::rmdirat(
+            &*self.src,
+            self.cache_dir,
+            &self.layout.cache_path(&self.get_child_name(name)),
+            &self.get_child_name(name),
+        );
+    }
+
+    // unlink or rmdir that leaves src alone and only records a
+    // whiteout. A directory is empty if everything in it is whited out
+    pub fn whiteout(&self, name: &OsStr, whiteouts: &Whiteouts) -> io::Result<()> {
+        let path = self.get_child_name(name);
+        let st = self.src.stat(&path)?;
+
+        let mut flags = 0;
+        if (st.st_mode & libc::S_IFMT) == libc::S_IFDIR {
+            let mut dh = self.src.opendir(&path)?;
+            while let Some(en) = dh.readdir()? {
+                let name = en.name();
+                if name != OsStr::new(".") && name != OsStr::new("..") &&
+                    !whiteouts.contains(&path.join(&name))
+                {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+                }
+            }
+            flags = libc::AT_REMOVEDIR as u32;
+        }
+
+        if let Err(e) = rlibc::unlinkat(self.cache_dir, &self.layout.cache_path(&path), flags) {
+            if !error::is_enoent(&e) {
+                return Err(e);
+            }
+        }
+        return whiteouts.add(&path);
     }
 
-    pub fn opendir(&self) -> error::Result<dir::Handle> {
-        return dir::Handle::openat(self.src_dir, &self.path);
+    // src has a directory where there was a whiteout, hide what's
+    // still in it so it looks like a new directory
+    pub fn whiteout_children(&self, whiteouts: &Whiteouts) -> io::Result<()> {
+        let mut dh = self.src.opendir(&self.path)?;
+        while let Some(en) = dh.readdir()? {
+            let name = en.name();
+            if name != OsStr::new(".") && name != OsStr::new("..") {
+                whiteouts.add(&self.path.join(&name))?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn opendir(
+        &self,
+        whiteouts: Option<Arc<Whiteouts>>,
+        dir_cache: Option<&DirCache>,
+    ) -> error::Result<dir::Handle> {
+        return dir::Handle::openat(&*self.src, &self.path, whiteouts, dir_cache);
     }
 
     pub fn use_ino(&mut self, ino: u64) {
@@ -315,6 +760,11 @@ impl Inode {
         return self.refcnt;
     }
 
+    // other had the same ino, the kernel will forget its lookups too
+    pub fn take_refs(&mut self, other: &Inode) {
+        self.refcnt += other.refcnt;
+    }
+
     pub fn get_refcnt(&self) -> u64 {
         return self.refcnt;
     }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct OpLatency {
+    pub count: u64,
+    // how long requests waited for a thread
+    pub queued: Duration,
+    pub max_queued: Duration,
+    // how long they took once a thread got to them
+    pub served: Duration,
+    pub max_served: Duration,
+}
+
+fn micros(d: Duration) -> u64 {
+    return d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1000) as u64;
+}
+
+impl OpLatency {
+    fn add(&mut self, queued: Duration, served: Duration) {
+        self.count += 1;
+        self.queued += queued;
+        self.served += served;
+        if queued > self.max_queued {
+            self.max_queued = queued;
+        }
+        if served > self.max_served {
+            self.max_served = served;
+        }
+    }
+
+    fn avg(d: Duration, count: u64) -> u64 {
+        return if count == 0 { 0 } else { micros(d) / count };
+    }
+}
+
+// queue wait and service time of each kind of fuse request, to tell
+// if catfs is short of threads or the source is slow
+#[derive(Default)]
+pub struct Latencies {
+    ops: Mutex<HashMap<&'static str, OpLatency>>,
+}
+
+impl Latencies {
+    pub fn add(&self, op: &'static str, queued: Duration, served: Duration) {
+        let mut ops = self.ops.lock().unwrap();
+        ops.entry(op).or_insert_with(Default::default).add(queued, served);
+    }
+
+    pub fn get(&self) -> Vec<(&'static str, OpLatency)> {
+        let ops = self.ops.lock().unwrap();
+        let mut v: Vec<(&'static str, OpLatency)> = ops.iter().map(|(k, v)| (*k, *v)).collect();
+        v.sort_by_key(|&(op, _)| op);
+        return v;
+    }
+
+    // one line per op, ex: "lookup 12 queued avg 5us max 9us served avg 30us max 81us"
+    pub fn lines(&self) -> Vec<String> {
+        return self.get()
+            .iter()
+            .map(|&(op, ref l)| {
+                format!(
+                    "{} {} queued avg {}us max {}us served avg {}us max {}us",
+                    op,
+                    l.count,
+                    OpLatency::avg(l.queued, l.count),
+                    micros(l.max_queued),
+                    OpLatency::avg(l.served, l.count),
+                    micros(l.max_served)
+                )
+            })
+            .collect();
+    }
+
+    pub fn dump(&self) {
+        for line in self.lines() {
+            info!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies() {
+        let l: Latencies = Default::default();
+        let ms = Duration::from_millis;
+        l.add("read", ms(1), ms(10));
+        l.add("lookup", ms(0), ms(2));
+        l.add("read", ms(3), ms(20));
+
+        let ops = l.get();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].0, "lookup");
+        assert_eq!(
+            ops[1],
+            (
+                "read",
+                OpLatency {
+                    count: 2,
+                    queued: ms(4),
+                    max_queued: ms(3),
+                    served: ms(30),
+                    max_served: ms(20),
+                },
+            )
+        );
+        assert_eq!(
+            l.lines()[1],
+            "read 2 queued avg 2000us max 3000us served avg 15000us max 20000us"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+extern crate libc;
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use catfs::backend::SourceBackend;
+use catfs::rlibc;
+
+// a src file that locks are taken on, closing it drops them
+struct LockFd(RawFd);
+
+impl Drop for LockFd {
+    fn drop(&mut self) {
+        if let Err(e) = rlibc::close(self.0) {
+            error!("!close({}) = {}", self.0, e);
+        }
+    }
+}
+
+// posix locks taken through the mount, which are held on the src
+// files so others using the source see them. Each lock owner gets its
+// own fd, since locks on different fds conflict like the locks of
+// different processes do
+#[derive(Default)]
+pub struct Locks {
+    fds: Mutex<HashMap<(u64, u64), Arc<LockFd>>>,
+}
+
+impl Locks {
+    fn get(
+        &self,
+        src: &dyn SourceBackend,
+        ino: u64,
+        owner: u64,
+        path: &Path,
+    ) -> io::Result<Arc<LockFd>> {
+        let mut fds = self.fds.lock().unwrap();
+        if let Some(fd) = fds.get(&(ino, owner)) {
+            return Ok(fd.clone());
+        }
+        let fd = Arc::new(LockFd(src.open_for_locks(&path)?.into_raw()));
+        fds.insert((ino, owner), fd.clone());
+        return Ok(fd);
+    }
+
+    // the type and range of a lock that's in the way, F_UNLCK if none
+    pub fn getlk(
+        &self,
+        src: &dyn SourceBackend,
+        ino: u64,
+        owner: u64,
+        path: &Path,
+        typ: libc::c_int,
+        start: u64,
+        end: u64,
+    ) -> io::Result<(libc::c_int, u64, u64)> {
+        let fd = self.get(src, ino, owner, path)?;
+        return rlibc::getlk(fd.0, typ, start, end);
+    }
+
+    pub fn setlk(
+        &self,
+        src: &dyn SourceBackend,
+        ino: u64,
+        owner: u64,
+        path: &Path,
+        typ: libc::c_int,
+        start: u64,
+        end: u64,
+        wait: bool,
+    ) -> io::Result<()> {
+        if typ == libc::F_UNLCK as libc::c_int && !self.fds.lock().unwrap().contains_key(&(ino, owner)) {
+            return Ok(());
+        }
+        // the map isn't locked while waiting, so the lock owner that's
+        // in the way can let go
+        let fd = self.get(src, ino, owner, path)?;
+        return rlibc::setlk(fd.0, typ, start, end, wait);
+    }
+
+    // closing any fd of a file drops the locks a process holds on it
+    pub fn release(&self, ino: u64, owner: u64) {
+        self.fds.lock().unwrap().remove(&(ino, owner));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use super::*;
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn owners() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let path = Path::new("file1");
+        let locks: Locks = Default::default();
+        let end = i64::MAX as u64;
+
+        locks.setlk(&src, 2, 1, path, libc::F_WRLCK, 0, 9, false).unwrap();
+        // the same owner can take it again
+        locks.setlk(&src, 2, 1, path, libc::F_RDLCK, 0, 9, false).unwrap();
+        let (typ, start, e) = locks.getlk(&src, 2, 2, path, libc::F_WRLCK, 5, end).unwrap();
+        assert_eq!((typ, start, e), (libc::F_RDLCK, 0, 9));
+        let e = locks.setlk(&src, 2, 2, path, libc::F_WRLCK, 0, end, false).unwrap_err();
+        assert!(e.raw_os_error() == Some(libc::EAGAIN) || e.raw_os_error() == Some(libc::EACCES));
+        locks.setlk(&src, 2, 2, path, libc::F_RDLCK, 5, end, false).unwrap();
+
+        locks.release(2, 1);
+        locks.release(2, 2);
+        let (typ, _, _) = locks.getlk(&src, 2, 3, path, libc::F_WRLCK, 0, end).unwrap();
+        assert_eq!(typ, libc::F_UNLCK);
+        // unlocking what was never locked doesn't open anything
+        locks.setlk(&src, 2, 4, path, libc::F_UNLCK, 0, end, false).unwrap();
+        assert_eq!(locks.fds.lock().unwrap().len(), 1);
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+
+use self::time::Timespec;
+
+use catfs::backend::SourceBackend;
+use catfs::error::RError;
+use catfs::file;
+use catfs::rlibc;
+use catfs::rlibc::File;
+
+// copies files that were written through the mount to another
+// directory. This happens in the background so a slow mirror doesn't
+// slow down writes
+pub struct Mirror {
+    queue: Mutex<mpsc::Sender<PathBuf>>,
+}
+
+impl Mirror {
+    pub fn new(src: Arc<dyn SourceBackend>, dir: &dyn AsRef<Path>) -> Mirror {
+        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let dir = dir.as_ref().to_path_buf();
+
+        thread::spawn(move || {
+            while let Ok(path) = rx.recv() {
+                // a file flushed many times only needs to be copied once
+                let mut paths = vec![path];
+                let mut seen = HashSet::new();
+                while let Ok(path) = rx.try_recv() {
+                    paths.push(path);
+                }
+                paths.retain(|p| seen.insert(p.clone()));
+
+                for path in paths {
+                    match copy(&*src, &dir, &path) {
+                        Ok(_) => debug!("<-- mirror {:?}", path),
+                        Err(e) => error!("!mirror {:?} = {}", path, RError::from(e)),
+                    }
+                }
+            }
+        });
+
+        return Mirror { queue: Mutex::new(tx) };
+    }
+
+    pub fn add(&self, path: &Path) {
+        if let Err(e) = self.queue.lock().unwrap().send(path.to_path_buf()) {
+            error!("!mirror {:?} = {}", path, e);
+        }
+    }
+}
+
+// copies from the source and not the cache, the cache may have been
+// evicted by the time we get to it
+fn copy(src: &dyn SourceBackend, dir: &Path, path: &Path) -> io::Result<()> {
+    let mut from = src.open(&path, rlibc::O_RDONLY, 0)?;
+    let res = copy_file(&from, dir, path);
+    from.close()?;
+    return res;
+}
+
+fn copy_file(from: &File, dir: &Path, path: &Path) -> io::Result<()> {
+    let st = from.stat()?;
+    let to_path = dir.join(path);
+    if let Some(parent) = to_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // write somewhere else first so the mirror never has half a file
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or(path.as_os_str()));
+    tmp_name.push(".catfs-mirror");
+    let tmp_path = to_path.with_file_name(tmp_name);
+
+    let mut to = File::open(
+        &tmp_path,
+        rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC,
+        (st.st_mode & 0o7777) as u32,
+    )?;
+
+    let res = (|| {
+        let mut buf = vec![0u8; file::DEFAULT_READAHEAD];
+        let mut offset = 0;
+        loop {
+            let nread = from.read_at(&mut buf, offset)?;
+            if nread == 0 {
+                break;
+            }
+            let mut nwritten = 0;
+            while nwritten < nread {
+                nwritten += to.write_at(&buf[nwritten..nread], offset + nwritten as i64)?;
+            }
+            offset += nread as i64;
+        }
+
+        let mtime = Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32);
+        rlibc::futimens(to.as_raw_fd(), &mtime, &mtime)?;
+        return Ok(());
+    })();
+
+    to.close()?;
+    match res {
+        Ok(_) => return fs::rename(&tmp_path, &to_path),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use std::io::Read;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use super::*;
+
+    #[test]
+    fn copy_to_mirror() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let mirror = prefix.join("mirror");
+
+        copy(&src, &mirror, Path::new("dir1/file1")).unwrap();
+
+        let mut contents = String::new();
+        fs::File::open(mirror.join("dir1/file1"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "dir1/file1\n");
+        assert!(!mirror.join("dir1/.file1.catfs-mirror").exists());
+        assert_eq!(
+            fs::metadata(mirror.join("dir1/file1"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            fs::metadata(prefix.join("resources/dir1/file1"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
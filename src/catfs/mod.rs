@@ -4,7 +4,7 @@ extern crate threadpool;
 extern crate time;
 
 use self::fuse::{ReplyEntry, ReplyAttr, ReplyOpen, ReplyEmpty, ReplyDirectory, ReplyData,
-                 ReplyWrite, ReplyCreate, ReplyStatfs};
+                 ReplyWrite, ReplyCreate, ReplyStatfs, ReplyXattr, ReplyLock};
 
 use self::time::{Duration, Timespec};
 
@@ -12,31 +12,190 @@ use std::cmp;
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::path::{Path, PathBuf};
+use std::time::{Duration as StdDuration, Instant};
 
 use self::threadpool::ThreadPool;
 
+#[macro_use]
+pub mod oplog;
+pub mod backend;
+pub mod bypass;
+pub mod compress;
+pub mod crypt;
+pub mod dedupe;
 pub mod error;
 pub mod file;
+pub mod filter;
 pub mod flags;
+pub mod idmap;
+pub mod mount;
+pub mod pin;
 pub mod rlibc;
 pub mod tests;
+pub mod throttle;
+pub mod tier;
+pub mod whiteout;
 
+mod control;
 mod dir;
+mod dircache;
+mod health;
 mod inode;
+mod latency;
+mod lock;
+mod mirror;
 mod substr;
-
+mod watch;
+mod writeback;
+
+use self::backend::{LocalBackend, SourceBackend};
+use self::bypass::Bypass;
+use self::control::ControlServer;
+use self::dircache::DirCache;
+use self::filter::CacheFilter;
+use self::health::CacheHealth;
+use self::idmap::IdMap;
 use self::inode::Inode;
-use self::flags::DiskSpace;
-use super::evicter::Evicter;
+use self::latency::Latencies;
+use self::lock::Locks;
+use self::mirror::Mirror;
+use self::pin::{Pins, PIN_XATTR};
+use self::throttle::PageInLimit;
+use self::watch::SourceWatcher;
+use self::whiteout::Whiteouts;
+use self::writeback::WriteBack;
+use self::flags::{DiskSpace, Setting};
+use super::evicter::EvicterHandle;
+
+// setting this xattr on the root of the mount changes mount-level
+// settings, ex: setfattr -n user.catfs.remount -v ro,free=10G <mnt>
+const REMOUNT_XATTR: &'static str = "user.catfs.remount";
+// xattrs catfs keeps for itself, which are hidden from the mount
+const CATFS_XATTR_PREFIX: &'static [u8] = b"user.catfs.";
+// how long a write that ran out of cache space waits for the evicter
+const ENOSPC_EVICT_SECS: u64 = 5;
+// with --stats-file, a file at the root of the mount that's made up
+// on the fly and never exists in src or the cache
+const STATS_FILE: &'static str = ".catfs_stats";
+const STATS_INO: u64 = ::std::u64::MAX;
+// fuse's FOPEN_DIRECT_IO, so reads aren't cut short at a stale size
+const FOPEN_DIRECT_IO: u32 = 1;
+
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: libc::c_int = libc::ENODATA;
+#[cfg(target_os = "macos")]
+const ENOATTR: libc::c_int = libc::ENOATTR;
+
+fn is_catfs_xattr(name: &OsStr) -> bool {
+    return name.as_bytes().starts_with(CATFS_XATTR_PREFIX);
+}
+
+// what the mode bits allow uid/gid to do. Only the primary group is
+// known, so access through supplementary groups is refused
+fn mode_allows(attr: &fuse::FileAttr, uid: u32, gid: u32, mask: libc::c_int) -> bool {
+    let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    if uid == 0 {
+        // root may execute anything that anyone can, and search any dir
+        return mask & (libc::X_OK as u16) == 0 || attr.kind == fuse::FileType::Directory ||
+            attr.perm & 0o111 != 0;
+    }
+
+    let perm = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    return perm & mask == mask;
+}
+
+// changing owners needs root, and owners only come from src. Without
+// either, say it's not supported rather than that it's not allowed
+fn chown_errno(e: &io::Error) -> libc::c_int {
+    return match e.raw_os_error() {
+        Some(libc::ENOSYS) => libc::ENOTSUP,
+        Some(libc::EPERM) if unsafe { libc::geteuid() } != 0 => libc::ENOTSUP,
+        Some(errno) => errno,
+        None => libc::EIO,
+    };
+}
+
+// path, which is under from, as it is under to
+fn rebase(path: &Path, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) if !rest.as_os_str().is_empty() => return to.join(rest),
+        _ => return to.to_path_buf(),
+    }
+}
+
+// which filesystem's size and free space statfs reports
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatfsFrom {
+    Cache,
+    Source,
+    // whichever has less, so what fits is sure to fit in both
+    Min,
+}
+
+impl Default for StatfsFrom {
+    fn default() -> StatfsFrom {
+        return StatfsFrom::Cache;
+    }
+}
+
+impl ::std::str::FromStr for StatfsFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cache" => return Ok(StatfsFrom::Cache),
+            "source" => return Ok(StatfsFrom::Source),
+            "min" => return Ok(StatfsFrom::Min),
+            _ => return Err(format!("unknown statfs: {}", s)),
+        }
+    }
+}
+
+// the smaller of each count in a and b, in a's fragment size
+fn min_statvfs(a: &rlibc::statvfs64, b: &rlibc::statvfs64) -> rlibc::statvfs64 {
+    let mut st = *a;
+    let frsize = a.f_frsize as u64;
+    let in_a = |blocks: u64| if frsize == 0 {
+        0
+    } else {
+        blocks.saturating_mul(b.f_frsize as u64) / frsize
+    };
+    st.f_blocks = cmp::min(a.f_blocks as u64, in_a(b.f_blocks as u64)) as _;
+    st.f_bfree = cmp::min(a.f_bfree as u64, in_a(b.f_bfree as u64)) as _;
+    st.f_bavail = cmp::min(a.f_bavail as u64, in_a(b.f_bavail as u64)) as _;
+    st.f_files = cmp::min(a.f_files, b.f_files);
+    st.f_ffree = cmp::min(a.f_ffree, b.f_ffree);
+    st.f_namemax = cmp::min(a.f_namemax, b.f_namemax);
+    return st;
+}
+
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
 
 #[derive(Default)]
 struct InodeStore {
     inodes: HashMap<u64, Arc<RwLock<Inode>>>,
     inodes_cache: HashMap<PathBuf, u64>,
+    next_generation: u64,
 }
 
 impl InodeStore {
@@ -44,7 +203,7 @@ impl InodeStore {
         return self.inodes.get(&ino).unwrap().clone();
     }
 
-    fn get_mut_by_path(&mut self, path: &Path) -> Option<Arc<RwLock<Inode>>> {
+    fn get_by_path(&self, path: &Path) -> Option<Arc<RwLock<Inode>>> {
         let ino: u64;
 
         if let Some(ino_ref) = self.inodes_cache.get(path) {
@@ -53,18 +212,69 @@ impl InodeStore {
             return None;
         }
 
-        return Some(self.get(ino));
+        // another name of a hard link that's forgotten
+        return self.inodes.get(&ino).cloned();
+    }
+
+    // another name for the file besides path, if we know of one
+    fn other_path(&self, ino: u64, path: &Path) -> Option<PathBuf> {
+        for (p, i) in self.inodes_cache.iter() {
+            if *i == ino && p != path {
+                return Some(p.clone());
+            }
+        }
+        return None;
+    }
+
+    // path was renamed to new_path. Returns what was under it, with
+    // the old and new paths
+    fn rename(&mut self, path: &Path, new_path: &Path) -> Vec<(u64, PathBuf, PathBuf)> {
+        let under: Vec<PathBuf> = self.inodes_cache
+            .keys()
+            .filter(|p| p.starts_with(path) && p.as_path() != path)
+            .cloned()
+            .collect();
+        if let Some(ino) = self.inodes_cache.remove(path) {
+            self.inodes_cache.insert(new_path.to_path_buf(), ino);
+        }
+
+        let mut moved = Vec::new();
+        for old in under {
+            let ino = self.inodes_cache.remove(&old).unwrap();
+            let new = rebase(&old, path, new_path);
+            self.inodes_cache.insert(new.clone(), ino);
+            moved.push((ino, old, new));
+        }
+        return moved;
     }
 
     fn remove_ino(&mut self, ino: u64) {
         let inode = self.inodes.remove(&ino).unwrap();
         let inode = inode.read().unwrap();
-        self.inodes_cache.remove(inode.get_path());
+        if inode.get_attr().nlink > 1 && inode.get_kind() != fuse::FileType::Directory {
+            self.inodes_cache.retain(|_, i| *i != ino);
+            return;
+        }
+        // the path may be another file by now
+        if self.inodes_cache.get(inode.get_path()) == Some(&ino) {
+            self.inodes_cache.remove(inode.get_path());
+        }
     }
 }
 
+// what an open handle is and how long it has been around, a client
+// that leaks handles pins cache files and fds forever
+struct HandleInfo {
+    path: PathBuf,
+    opened: Instant,
+    last_io: Instant,
+    // already warned about being idle
+    warned: bool,
+}
+
 struct HandleStore<T> {
     handles: HashMap<u64, T>,
+    info: HashMap<u64, HandleInfo>,
     next_id: u64,
 }
 
@@ -72,33 +282,268 @@ impl<T> Default for HandleStore<T> {
     fn default() -> HandleStore<T> {
         return HandleStore {
             handles: Default::default(),
+            info: Default::default(),
             next_id: 1,
         };
     }
 }
 
+impl<T> HandleStore<T> {
+    fn insert(&mut self, handle: T, path: &Path) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        let now = Instant::now();
+        self.info.insert(
+            id,
+            HandleInfo {
+                path: path.to_path_buf(),
+                opened: now,
+                last_io: now,
+                warned: false,
+            },
+        );
+        return id;
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.handles.remove(&id);
+        self.info.remove(&id);
+    }
+
+    // path was renamed to new_path
+    fn rename(&mut self, path: &Path, new_path: &Path) {
+        for info in self.info.values_mut() {
+            if info.path.starts_with(path) {
+                info.path = rebase(&info.path, path, new_path);
+            }
+        }
+    }
+
+    fn touch(&mut self, id: u64) {
+        if let Some(info) = self.info.get_mut(&id) {
+            info.last_io = Instant::now();
+            info.warned = false;
+        }
+    }
+
+    // how long the handle that has gone the longest without IO has
+    // been idle
+    fn max_idle(&self) -> StdDuration {
+        let now = Instant::now();
+        return self.info
+            .values()
+            .map(|info| now.duration_since(info.last_io))
+            .max()
+            .unwrap_or(StdDuration::from_secs(0));
+    }
+
+    fn warn_idle(&mut self, kind: &str, max_idle: StdDuration) {
+        let now = Instant::now();
+        for (id, info) in self.info.iter_mut() {
+            let idle = now.duration_since(info.last_io);
+            if !info.warned && idle >= max_idle {
+                warn!(
+                    "open {} {} {:?} has been idle for {}s (opened {}s ago), leaked?",
+                    kind,
+                    id,
+                    info.path,
+                    idle.as_secs(),
+                    now.duration_since(info.opened).as_secs()
+                );
+                info.warned = true;
+            }
+        }
+    }
+
+    fn dump(&self, kind: &str) {
+        let now = Instant::now();
+        for (id, info) in &self.info {
+            info!(
+                "{} {} {:?} opened {}s ago, idle {}s",
+                kind,
+                id,
+                info.path,
+                now.duration_since(info.opened).as_secs(),
+                now.duration_since(info.last_io).as_secs()
+            );
+        }
+    }
+}
+
 pub struct CatFS {
     from: PathBuf,
     cache: PathBuf,
-    src_dir: RawFd,
+    src: Arc<dyn SourceBackend>,
     cache_dir: RawFd,
 
-    ttl: Duration,
-    store: Mutex<InodeStore>,
-    dh_store: Mutex<HandleStore<dir::Handle>>,
-    fh_store: Mutex<HandleStore<Arc<Mutex<file::Handle>>>>,
+    ttl: RwLock<Duration>,
+    cache_valid_ttl: Option<Duration>,
+    src_readonly: AtomicBool,
+    evicter: Option<EvicterHandle>,
+    mirror: Option<Mirror>,
+    whiteouts: Option<Arc<Whiteouts>>,
+    dir_cache: Option<DirCache>,
+    bypass: Option<Bypass>,
+    no_cache: bool,
+    health: CacheHealth,
+    max_cacheable_size: u64,
+    filter: CacheFilter,
+    readahead: usize,
+    shared_cache: bool,
+    cache_odirect: bool,
+    // second cache dir, see set_cache_l2
+    cache_l2: Option<RawFd>,
+    cache_compress: Option<compress::Codec>,
+    cache_key: Option<crypt::Key>,
+    cache_dedupe: Option<Arc<dedupe::Index>>,
+    disable_splice: bool,
+    layout: file::CacheLayout,
+    perms: file::CachePerms,
+    ids: IdMap,
+    mount_perms: file::MountPerms,
+    on_conflict: file::ConflictPolicy,
+    fill: file::CacheFill,
+    statfs_from: StatfsFrom,
+    write_back: Option<WriteBack>,
+    pins: Pins,
+    locks: Locks,
+    control: Option<ControlServer>,
+    watcher: Option<SourceWatcher>,
+    store: Arc<RwLock<InodeStore>>,
+    dh_store: Arc<Mutex<HandleStore<dir::Handle>>>,
+    fh_store: Arc<Mutex<HandleStore<Arc<RwLock<file::Handle>>>>>,
     tp: Mutex<ThreadPool>,
+    small_page_ins: file::SmallPageIns,
+    page_in_limit: Arc<PageInLimit>,
+    stats: Arc<file::CacheStats>,
+    stats_file: bool,
+    latencies: Arc<Latencies>,
+    // set once the kernel has sent us init
+    ready: Arc<(Mutex<bool>, Condvar)>,
+}
+
+// logs what a CatFS is holding on to, usable after the CatFS itself
+// has been handed off to the fuse session
+#[derive(Clone)]
+pub struct StateDumper {
+    from: PathBuf,
+    cache: PathBuf,
+    store: Arc<RwLock<InodeStore>>,
+    dh_store: Arc<Mutex<HandleStore<dir::Handle>>>,
+    fh_store: Arc<Mutex<HandleStore<Arc<RwLock<file::Handle>>>>>,
+    latencies: Arc<Latencies>,
+}
+
+// counts of what a CatFS is holding on to. The idle times are of the
+// open dir/file that has gone the longest without IO
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Stats {
+    pub inodes: usize,
+    pub open_dirs: usize,
+    pub open_files: usize,
+    pub dir_idle_secs: u64,
+    pub file_idle_secs: u64,
+}
+
+impl StateDumper {
+    #[allow(dead_code)]
+    pub fn stats(&self) -> Stats {
+        let (open_dirs, dir_idle) = {
+            let dh_store = self.dh_store.lock().unwrap();
+            (dh_store.handles.len(), dh_store.max_idle())
+        };
+        let (open_files, file_idle) = {
+            let fh_store = self.fh_store.lock().unwrap();
+            (fh_store.handles.len(), fh_store.max_idle())
+        };
+        return Stats {
+            inodes: self.store.read().unwrap().inodes.len(),
+            open_dirs: open_dirs,
+            open_files: open_files,
+            dir_idle_secs: dir_idle.as_secs(),
+            file_idle_secs: file_idle.as_secs(),
+        };
+    }
+
+    // warns once about each handle that has had no IO for max_idle
+    pub fn warn_idle(&self, max_idle: StdDuration) {
+        self.dh_store.lock().unwrap().warn_idle("dir", max_idle);
+        self.fh_store.lock().unwrap().warn_idle("file", max_idle);
+    }
+
+    pub fn dump(&self) {
+        info!("catfs {:?} {:?}", self.from, self.cache);
+        {
+            let store = self.store.read().unwrap();
+            info!(
+                "{} inodes, {} cached paths",
+                store.inodes.len(),
+                store.inodes_cache.len()
+            );
+            for (ino, inode) in &store.inodes {
+                // don't block the dump on an inode that's in use
+                if let Ok(inode) = inode.try_read() {
+                    info!(
+                        "ino 0x{:016x} {:?} refcnt {}",
+                        ino,
+                        inode.get_path(),
+                        inode.get_refcnt()
+                    );
+                } else {
+                    info!("ino 0x{:016x} busy", ino);
+                }
+            }
+        }
+        let dh_store = self.dh_store.lock().unwrap();
+        let fh_store = self.fh_store.lock().unwrap();
+        info!(
+            "{} open dirs, {} open files",
+            dh_store.handles.len(),
+            fh_store.handles.len()
+        );
+        dh_store.dump("dir");
+        fh_store.dump("file");
+    }
+
+    pub fn dump_latencies(&self) {
+        info!("catfs {:?} request latencies", self.from);
+        self.latencies.dump();
+    }
 }
 
 impl Drop for CatFS {
     fn drop(&mut self) {
-        self.tp.lock().unwrap().join();
-        if let Err(e) = rlibc::close(self.src_dir) {
-            error!("!close({}) = {}", self.src_dir, error::RError::from(e));
+        if let Some(ref control) = self.control {
+            control.stop();
+        }
+        if let Some(ref watcher) = self.watcher {
+            watcher.stop();
         }
+        if let Some(ref write_back) = self.write_back {
+            write_back.stop();
+        }
+        self.tp.lock().unwrap().join();
         if let Err(e) = rlibc::close(self.cache_dir) {
             error!("!close({}) = {}", self.cache_dir, error::RError::from(e));
         }
+        if let Some(l2) = self.cache_l2 {
+            if let Err(e) = rlibc::close(l2) {
+                error!("!close({}) = {}", l2, error::RError::from(e));
+            }
+        }
+    }
+}
+
+// open flags to serve an open from a read-only src, None if the open
+// needs to modify the file
+fn readonly_flags(flags: u32) -> Option<u32> {
+    if (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY {
+        return Some(flags);
+    } else if (flags & rlibc::O_TRUNC) != 0 {
+        return None;
+    } else {
+        return Some((flags & !rlibc::O_ACCMODE) | rlibc::O_RDONLY);
     }
 }
 
@@ -110,25 +555,82 @@ pub fn make_self<T>(s: &mut T) -> &'static T {
 }
 
 impl CatFS {
+    #[allow(dead_code)]
     pub fn new(from: &dyn AsRef<Path>, to: &dyn AsRef<Path>) -> error::Result<CatFS> {
-        let src_dir = rlibc::open(from, rlibc::O_RDONLY, 0)?;
+        let src = LocalBackend::new(from)?;
+        return CatFS::with_backend(Arc::new(src), from, to);
+    }
+
+    // from only names the source in logs
+    pub fn with_backend(
+        src: Arc<dyn SourceBackend>,
+        from: &dyn AsRef<Path>,
+        to: &dyn AsRef<Path>,
+    ) -> error::Result<CatFS> {
         let cache_dir = rlibc::open(to, rlibc::O_RDONLY, 0)?;
 
         let mut catfs = CatFS {
             from: from.as_ref().to_path_buf(),
             cache: to.as_ref().to_path_buf(),
-            src_dir: src_dir,
+            src: src,
             cache_dir: cache_dir,
-            ttl: Duration::zero(),
-            store: Mutex::new(Default::default()),
-            dh_store: Mutex::new(Default::default()),
-            fh_store: Mutex::new(Default::default()),
+            ttl: RwLock::new(Duration::zero()),
+            cache_valid_ttl: None,
+            src_readonly: AtomicBool::new(false),
+            evicter: None,
+            mirror: None,
+            whiteouts: None,
+            dir_cache: None,
+            bypass: None,
+            no_cache: false,
+            health: CacheHealth::new(cache_dir),
+            max_cacheable_size: 0,
+            filter: Default::default(),
+            readahead: file::DEFAULT_READAHEAD,
+            shared_cache: false,
+            cache_odirect: false,
+            cache_l2: None,
+            cache_compress: None,
+            cache_key: None,
+            cache_dedupe: None,
+            disable_splice: false,
+            layout: file::CacheLayout::load(cache_dir)?,
+            perms: Default::default(),
+            ids: Default::default(),
+            mount_perms: Default::default(),
+            on_conflict: Default::default(),
+            fill: Default::default(),
+            statfs_from: Default::default(),
+            write_back: None,
+            pins: Default::default(),
+            locks: Default::default(),
+            control: None,
+            watcher: None,
+            store: Arc::new(RwLock::new(InodeStore {
+                // inos come from the source and survive a restart, so
+                // don't hand out generations from the last run again
+                next_generation: time::get_time().sec as u64,
+                ..Default::default()
+            })),
+            dh_store: Arc::new(Mutex::new(Default::default())),
+            fh_store: Arc::new(Mutex::new(Default::default())),
             tp: Mutex::new(ThreadPool::new(5)),
+            small_page_ins: Default::default(),
+            page_in_limit: Default::default(),
+            stats: Default::default(),
+            stats_file: false,
+            latencies: Default::default(),
+            ready: Arc::new((Mutex::new(false), Condvar::new())),
         };
 
         catfs.make_root()?;
         debug!("catfs {:?} {:?}", catfs.from, catfs.cache);
 
+        let st = catfs.src.statvfs()?;
+        if (st.f_flag & libc::ST_RDONLY) != 0 {
+            catfs.src_failed(libc::EROFS);
+        }
+
         return Ok(catfs);
     }
 
@@ -136,727 +638,2969 @@ impl CatFS {
         return Ok(rlibc::openat(self.cache_dir, &".", rlibc::O_RDONLY, 0)?);
     }
 
+    pub fn latencies(&self) -> &Latencies {
+        return &self.latencies;
+    }
+
+    pub fn state_dumper(&self) -> StateDumper {
+        return StateDumper {
+            from: self.from.clone(),
+            cache: self.cache.clone(),
+            store: self.store.clone(),
+            dh_store: self.dh_store.clone(),
+            fh_store: self.fh_store.clone(),
+            latencies: self.latencies.clone(),
+        };
+    }
+
     fn make_root(&mut self) -> error::Result<()> {
-        let root_attr = Inode::lookup_path(self.src_dir, &self.from)?;
+        let root_attr = Inode::lookup_path(&*self.src, &"", &self.ids, self.mount_perms)?;
 
         let mut inode = Inode::new(
-            self.src_dir,
+            self.src.clone(),
             self.cache_dir,
+            self.layout,
+            self.perms,
+            self.ids.clone(),
+            self.mount_perms,
             OsString::new(),
             PathBuf::new(),
             root_attr,
         );
         inode.use_ino(fuse::FUSE_ROOT_ID);
 
-        self.insert_inode(inode);
+        self.insert_inode(inode, true);
 
         return Ok(());
     }
 
-    fn insert_inode(&mut self, inode: Inode) {
-        let mut store = self.store.lock().unwrap();
-        let ino: u64;
-        {
-            let attr = inode.get_attr();
-            ino = attr.ino;
-            store.inodes_cache.insert(
-                inode.get_path().to_path_buf(),
-                attr.ino,
-            );
+    // returns the attr and generation to reply with. The kernel, and
+    // nfsd through it, knows a file by its ino and generation, so the
+    // same file looked up under another path keeps both. A file we
+    // just created that reuses an ino we still have gets a new
+    // generation, so handles to the old file become ESTALE instead of
+    // pointing at the new one
+    fn insert_inode(&mut self, mut inode: Inode, created: bool) -> (fuse::FileAttr, u64) {
+        let mut store = self.store.write().unwrap();
+        let ino = inode.get_ino();
+        let path = inode.get_path().to_path_buf();
+
+        if let Some(old) = store.inodes.get(&ino).cloned() {
+            let mut old = old.write().unwrap();
+            if store.inodes_cache.get(old.get_path()) == Some(&ino) {
+                store.inodes_cache.remove(old.get_path());
+            }
+            store.inodes_cache.insert(path, ino);
+
+            if !created && old.get_kind() == inode.get_kind() {
+                old.relink(inode);
+                old.inc_ref();
+                return (*old.get_attr(), old.get_generation());
+            }
+            inode.take_refs(&old);
+        } else {
+            store.inodes_cache.insert(path, ino);
         }
+
+        store.next_generation += 1;
+        inode.set_generation(store.next_generation);
+        let res = (*inode.get_attr(), inode.get_generation());
         store.inodes.insert(ino, Arc::new(RwLock::new(inode)));
+        return res;
     }
 
     fn get_inode(&self, ino: u64) -> Arc<RwLock<Inode>> {
-        let store = self.store.lock().unwrap();
+        let store = self.store.read().unwrap();
         return store.get(ino);
     }
 
+    // path was renamed to new_path, along with everything under it if
+    // it's a directory
     fn replace_path(&mut self, path: &Path, new_path: PathBuf) {
-        let mut store = self.store.lock().unwrap();
-        if let Some(ino) = store.inodes_cache.remove(path) {
-            store.inodes_cache.insert(new_path, ino);
+        let moved: Vec<(Arc<RwLock<Inode>>, PathBuf, PathBuf)> = {
+            let mut store = self.store.write().unwrap();
+            let moved = store.rename(path, &new_path);
+            moved
+                .into_iter()
+                .filter_map(|(ino, old, new)| {
+                    store.inodes.get(&ino).map(|inode| (inode.clone(), old, new))
+                })
+                .collect()
+        };
+        self.fh_store.lock().unwrap().rename(path, &new_path);
+        self.dh_store.lock().unwrap().rename(path, &new_path);
+
+        // the store lock isn't held here, others take an inode's lock
+        // before the store's
+        for (inode, old, new) in moved {
+            let mut inode = inode.write().unwrap();
+            // a hard link may be known by a name outside of path
+            if inode.get_path() != old {
+                continue;
+            }
+            if let Err(e) = inode.moved(&new) {
+                error!("!rename {:?} -> {:?} = {}", old, new, e);
+            }
         }
     }
 
     fn remove_path(&mut self, path: &Path) {
-        let mut store = self.store.lock().unwrap();
+        let mut store = self.store.write().unwrap();
         store.inodes_cache.remove(path);
     }
 
-    fn ttl_now(&self) -> time::Timespec {
-        return time::get_time() + self.ttl;
-    }
+    // a hard link may still be around under another name, which it's
+    // then known by
+    fn unlinked(&mut self, path: &Path) {
+        let mut store = self.store.write().unwrap();
+        let ino = match store.inodes_cache.remove(path) {
+            Some(ino) => ino,
+            None => return,
+        };
+        let inode = match store.inodes.get(&ino) {
+            Some(inode) => inode.clone(),
+            None => return,
+        };
 
-    pub fn statfs(&mut self, _ino: u64, reply: ReplyStatfs) {
-        match rlibc::fstatvfs(self.cache_dir) {
-            Ok(st) => {
-                reply.statfs(
-                    st.f_blocks as u64,
-                    st.f_bfree as u64,
-                    st.f_bavail as u64,
-                    st.f_files as u64,
-                    st.f_ffree as u64,
-                    st.f_bsize as u32,
-                    st.f_namemax as u32,
-                    st.f_frsize as u32,
-                )
+        let mut inode = inode.write().unwrap();
+        if inode.get_attr().nlink > 1 && inode.get_kind() != fuse::FileType::Directory {
+            if inode.get_path() == path {
+                if let Some(other) = store.other_path(ino, path) {
+                    inode.use_path(&other);
+                }
             }
-            Err(e) => reply.error(e.raw_os_error().unwrap()),
+            inode.unlinked();
         }
     }
 
-    pub fn lookup(&mut self, parent: u64, name: OsString, reply: ReplyEntry) {
-        let parent_inode: Arc<RwLock<Inode>>;
-        let mut old_inode: Option<Arc<RwLock<Inode>>> = None;
-        let path: PathBuf;
-        {
-            let store = self.store.lock().unwrap();
-            parent_inode = store.get(parent);
+    // path was added to or removed from its directory
+    fn dir_changed(&self, path: &Path) {
+        if let Some(ref dir_cache) = self.dir_cache {
+            dir_cache.invalidate(path.parent().unwrap_or(path));
         }
+    }
 
+    // what we create is ours, when running as root hand it to
+    // whoever asked for it
+    fn set_owner(&self, inode: &mut Inode, uid: u32, gid: u32) {
+        if unsafe { libc::geteuid() } != 0 ||
+            (inode.get_attr().uid == uid && inode.get_attr().gid == gid)
         {
-            let parent_inode = parent_inode.read().unwrap();
-            path = parent_inode.get_child_name(&name);
+            return;
         }
 
-        {
-            let mut i: Option<Arc<RwLock<Inode>>>;
-
-            {
-                let mut store = self.store.lock().unwrap();
-                i = store.get_mut_by_path(&path);
+        if let Err(e) = inode.chown(uid, gid) {
+            if e.raw_os_error() != Some(libc::ENOSYS) {
+                warn!("!chown {:?} {}:{} = {}", inode.get_path(), uid, gid, e);
             }
+        }
+    }
 
-            if let Some(ref mut i) = i {
-                old_inode = Some(i.clone());
-                let mut inode = i.write().unwrap();
-                let refcnt = inode.inc_ref();
+    pub fn is_src_readonly(&self) -> bool {
+        return self.src_readonly.load(Ordering::Relaxed);
+    }
 
-                if inode.not_expired(&self.ttl) {
-                    reply.entry(&self.ttl_now(), inode.get_attr(), 0);
-                    debug!(
-                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {}",
-                        inode.get_path(),
-                        inode.get_ino(),
-                        inode.get_kind(),
-                        refcnt
-                    );
-                    return;
-                } else {
-                    debug!(
-                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {} expired",
-                        inode.get_path(),
-                        inode.get_ino(),
-                        inode.get_kind(),
-                        refcnt
-                    );
-                }
+    // a read-only src is not fatal, we can still serve reads from
+    // it, so remember that and fail writes early from now on. With
+    // write back, writes still go to the cache and it's the upload
+    // that fails
+    fn src_failed(&self, errno: libc::c_int) {
+        if errno == libc::EROFS && !self.src_readonly.swap(true, Ordering::Relaxed) {
+            if self.write_back.is_some() {
+                warn!("{:?} is read-only, writes are kept in the cache", self.from);
+            } else {
+                warn!("{:?} is read-only, serving it read-only", self.from);
             }
         }
+    }
 
-        let parent_inode = parent_inode.read().unwrap();
-        match parent_inode.lookup(&name) {
-            Ok(new_inode) => {
-                if let Some(inode) = old_inode {
-                    let mut inode = inode.write().unwrap();
-                    inode.take(new_inode);
-                    reply.entry(&self.ttl_now(), &inode.get_attr(), 0);
-                    debug!(
-                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {}",
-                        inode.get_path(),
-                        inode.get_ino(),
-                        inode.get_kind(),
-                        inode.get_refcnt(),
-                    );
-                } else {
-                    debug!(
-                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt *1",
-                        new_inode.get_path(),
-                        new_inode.get_ino(),
-                        new_inode.get_kind()
-                    );
-                    let attr = *new_inode.get_attr();
-                    self.insert_inode(new_inode);
+    pub fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.write().unwrap() = ttl;
+    }
 
-                    reply.entry(&self.ttl_now(), &attr, 0);
-                }
-            }
-            Err(e) => {
-                if let Some(inode) = old_inode {
-                    let mut inode = inode.write().unwrap();
-                    let stale = inode.deref(1);
-                    if stale {
-                        let mut store = self.store.lock().unwrap();
-                        store.remove_ino(inode.get_attr().ino);
-                        debug!("<-- expired 0x{:016x}", inode.get_attr().ino);
-                    }
-                }
-                debug!("<-- !lookup {:?} = {}", path, e);
-                reply.error(error::errno(&e));
-            }
-        }
+    fn ttl(&self) -> Duration {
+        return *self.ttl.read().unwrap();
     }
 
-    pub fn getattr(&mut self, ino: u64, reply: ReplyAttr) {
-        let inode: Arc<RwLock<Inode>>;
+    // a cache file that was checked against src is used without
+    // checking again for this long, instead of for as long as its
+    // inode is around
+    pub fn set_cache_valid_ttl(&mut self, ttl: Duration) {
+        self.cache_valid_ttl = Some(ttl);
+    }
 
-        {
-            let store = self.store.lock().unwrap();
-            inode = store.get(ino);
-        }
+    fn ttl_now(&self) -> time::Timespec {
+        return time::get_time() + self.ttl();
+    }
 
-        {
-            let inode = inode.read().unwrap();
-            if !inode.was_flush_failed() {
-                reply.attr(&self.ttl_now(), inode.get_attr());
-                debug!(
-                    "<-- getattr {} {:?} {} bytes",
-                    ino,
-                    inode.get_path(),
-                    inode.get_attr().size
-                );
-                return;
-            }
-        }
+    // keep directory listings for this long instead of listing the
+    // source dir every time
+    pub fn set_dir_cache_ttl(&mut self, ttl: StdDuration) {
+        self.dir_cache = Some(DirCache::new(ttl));
+    }
 
-        let mut inode = inode.write().unwrap();
-        if let Err(e) = inode.refresh() {
-            debug!("<-- !getattr {:?} = {}", inode.get_path(), e);
-            reply.error(error::errno(&e));
-            return;
+    pub fn set_readahead(&mut self, readahead: usize) {
+        self.readahead = cmp::min(readahead, file::MAX_READAHEAD);
+    }
+
+    // other catfs may be using the same cache dir
+    pub fn set_shared_cache(&mut self, shared: bool) {
+        self.shared_cache = shared;
+    }
+
+    // keeps what's read and written through the mount out of the page
+    // cache, where it would be cached twice
+    pub fn set_cache_odirect(&mut self) {
+        self.cache_odirect = true;
+    }
+
+    // a bigger and slower cache dir behind the cache dir. Cache files
+    // the evicter would delete are moved there instead, and files that
+    // aren't in the cache dir are looked for there before src
+    pub fn set_cache_l2(&mut self, l2: &Path) -> error::Result<()> {
+        let dir = rlibc::open(&l2, rlibc::O_RDONLY, 0)?;
+        if let Err(e) = self.layout.save(dir) {
+            let _ = rlibc::close(dir);
+            return Err(e);
         }
-        reply.attr(&self.ttl_now(), inode.get_attr());
-        debug!(
-            "<-- getattr {} {:?} {} bytes",
-            ino,
-            inode.get_path(),
-            inode.get_attr().size
-        );
-        return;
+        self.cache_l2 = Some(dir);
+        return Ok(());
     }
 
-    pub fn setattr(
-        &mut self,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<Timespec>,
-        mtime: Option<Timespec>,
-        fh: Option<u64>,
-        crtime: Option<Timespec>,
-        chgtime: Option<Timespec>,
-        bkuptime: Option<Timespec>,
+    // files that are only read are kept compressed in the cache dir,
+    // which takes CPU on every read instead of only on page in
+    pub fn set_cache_compress(&mut self, codec: compress::Codec) {
+        self.cache_compress = Some(codec);
+    }
+
+    // what's in cache files is encrypted with key, their names and
+    // xattrs aren't
+    pub fn set_cache_key(&mut self, key: crypt::Key) {
+        self.cache_key = Some(key);
+    }
+
+    // cache files with the same content share their blocks, if the
+    // cache dir can reflink. Goes by the layout set before this
+    pub fn set_cache_dedupe(&mut self) {
+        self.cache_dedupe = Some(Arc::new(dedupe::Index::new(self.cache_dir, self.layout)));
+    }
+
+    // for filesystems where splice or copy_file_range misbehave, every
+    // copy goes through a buffer in userspace
+    pub fn set_disable_splice(&mut self) {
+        self.disable_splice = true;
+    }
+
+    pub fn get_cache_l2(&self) -> error::Result<Option<RawFd>> {
+        return match self.cache_l2 {
+            Some(l2) => Ok(Some(rlibc::openat(l2, &".", rlibc::O_RDONLY, 0)?)),
+            None => Ok(None),
+        };
+    }
+
+    // how fast and how many files at once are paged in from src in
+    // the background, 0 is no limit
+    pub fn set_page_in_limit(&mut self, bytes_per_sec: u64, max_running: usize) {
+        self.page_in_limit = Arc::new(PageInLimit::new(bytes_per_sec, max_running));
+    }
+
+    pub fn set_stats_file(&mut self) {
+        self.stats_file = true;
+    }
+
+    fn is_stats_file(&self, ino: u64) -> bool {
+        return self.stats_file && ino == STATS_INO;
+    }
+
+    fn is_stats_name(&self, parent: u64, name: &OsStr) -> bool {
+        return self.stats_file && parent == fuse::FUSE_ROOT_ID && name == OsStr::new(STATS_FILE);
+    }
+
+    fn stats_file_content(&self) -> Vec<u8> {
+        let evictions = match self.evicter {
+            Some(ref evicter) => evicter.evicted(),
+            None => 0,
+        };
+        let cache_size = match self.evicter.as_ref().and_then(|e| e.cache_size()) {
+            Some(size) => size,
+            // the evicter only adds up the cache when it has a max size,
+            // otherwise go by what's used on the cache fs
+            None => match rlibc::fstatvfs(self.cache_dir) {
+                Ok(st) => (st.f_blocks - st.f_bfree) as u64 * st.f_frsize as u64,
+                Err(_) => 0,
+            },
+        };
+        return format!(
+            "hits {}\nmisses {}\nbytes_from_cache {}\nbytes_paged_in {}\nevictions {}\ncache_size {}\n\
+             l2_hits {}\nbytes_deduped {}\n",
+            self.stats.hits.load(Ordering::Relaxed),
+            self.stats.misses.load(Ordering::Relaxed),
+            self.stats.bytes_read.load(Ordering::Relaxed),
+            self.stats.bytes_paged_in.load(Ordering::Relaxed),
+            evictions,
+            cache_size,
+            self.stats.l2_hits.load(Ordering::Relaxed),
+            self.stats.bytes_deduped.load(Ordering::Relaxed)
+        ).into_bytes();
+    }
+
+    fn stats_file_attr(&self) -> fuse::FileAttr {
+        let root = self.get_inode(fuse::FUSE_ROOT_ID);
+        let root = root.read().unwrap();
+        let root = root.get_attr();
+        let now = time::get_time();
+        return fuse::FileAttr {
+            ino: STATS_INO,
+            size: self.stats_file_content().len() as u64,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: fuse::FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: root.uid,
+            gid: root.gid,
+            rdev: 0,
+            flags: 0,
+        };
+    }
+
+    fn set_direct(&self, file: &mut file::Handle, path: &Path) {
+        if self.cache_odirect {
+            if let Err(e) = file.set_direct(self.cache_dir, self.layout, &path) {
+                warn!("can't use O_DIRECT for {:?}: {}", path, e);
+            }
+        }
+    }
+
+    // switching layouts leaves what was cached before to the evicter
+    pub fn set_cache_layout(&mut self, layout: file::CacheLayout) -> error::Result<()> {
+        if layout != self.layout {
+            info!("{:?} was using the {} cache layout", self.cache, self.layout.to_str());
+        }
+        layout.save(self.cache_dir)?;
+        if let Some(l2) = self.cache_l2 {
+            layout.save(l2)?;
+        }
+        self.layout = layout;
+        self.get_inode(fuse::FUSE_ROOT_ID).write().unwrap().set_cache_layout(layout);
+        return Ok(());
+    }
+
+    // files opened by these processes are read from and written to
+    // the source without going through the cache
+    pub fn set_bypass(&mut self, bypass: Bypass) {
+        self.bypass = Some(bypass);
+    }
+
+    // nothing is cached, files are read from and written to the
+    // source directly
+    pub fn set_no_cache(&mut self) {
+        self.no_cache = true;
+    }
+
+    // files bigger than this are read from and written to the source
+    // directly, 0 to cache everything
+    pub fn set_max_cacheable_size(&mut self, size: u64) {
+        self.max_cacheable_size = size;
+    }
+
+    // files the filter leaves out are read from and written to the
+    // source directly
+    pub fn set_cache_filter(&mut self, filter: CacheFilter) {
+        self.filter = filter;
+    }
+
+    fn open_options(&self) -> file::OpenOptions {
+        return file::OpenOptions {
+            readahead: self.readahead,
+            shared_cache: self.shared_cache,
+            fill: self.fill,
+            small: self.small_page_ins.clone(),
+            stats: self.stats.clone(),
+            page_in_limit: self.page_in_limit.clone(),
+            l2: self.cache_l2,
+            compress: self.cache_compress,
+            key: self.cache_key.clone(),
+            dedupe: self.cache_dedupe.clone(),
+            disable_splice: self.disable_splice,
+            src_readonly: self.is_src_readonly() && self.write_back.is_some(),
+        };
+    }
+
+    fn can_bypass(&self, flags: u32) -> bool {
+        // random writes only work through the cache there
+        return (flags & rlibc::O_ACCMODE) == rlibc::O_RDONLY || !self.src.hints().sequential_writes;
+    }
+
+    fn is_bypass(&self, pid: u32, flags: u32) -> bool {
+        match self.bypass {
+            Some(ref b) => return self.can_bypass(flags) && b.matches(pid),
+            None => return false,
+        }
+    }
+
+    // whether the cache dir is failing and files should be read from
+    // and written to the source directly for now
+    fn cache_degraded(&self) -> bool {
+        if let Some(ref evicter) = self.evicter {
+            if evicter.errors() >= health::MAX_ERRORS as u64 {
+                self.health.degrade(&"the evicter keeps failing");
+            }
+        }
+        return self.health.is_degraded();
+    }
+
+    // whether this file is never cached, no matter who opens it
+    fn skips_cache(&self, path: &Path, size: u64, flags: u32) -> bool {
+        if !self.can_bypass(flags) {
+            return false;
+        }
+        if self.no_cache {
+            return true;
+        }
+        if self.pins.matches(path) {
+            return false;
+        }
+        if !self.filter.is_cached(path) {
+            return true;
+        }
+        return self.max_cacheable_size != 0 && (flags & rlibc::O_TRUNC) == 0 &&
+            size > self.max_cacheable_size;
+    }
+
+    // also applies to whiteouts, so set_whiteout comes after this
+    pub fn set_cache_perms(&mut self, perms: file::CachePerms) {
+        self.perms = perms;
+        self.get_inode(fuse::FUSE_ROOT_ID).write().unwrap().set_cache_perms(perms);
+    }
+
+    // who owns what in src as shown through the mount, see IdMap
+    pub fn set_id_map(&mut self, ids: IdMap) -> error::Result<()> {
+        self.ids = ids.clone();
+        let root = self.get_inode(fuse::FUSE_ROOT_ID);
+        let mut root = root.write().unwrap();
+        root.set_id_map(ids);
+        return root.refresh();
+    }
+
+    // the modes shown through the mount, whatever src has
+    pub fn set_mount_perms(&mut self, mount_perms: file::MountPerms) -> error::Result<()> {
+        self.mount_perms = mount_perms;
+        let root = self.get_inode(fuse::FUSE_ROOT_ID);
+        let mut root = root.write().unwrap();
+        root.set_mount_perms(mount_perms);
+        return root.refresh();
+    }
+
+    // when a file changed in src while we had changes to copy back
+    pub fn set_conflict_policy(&mut self, on_conflict: file::ConflictPolicy) {
+        self.on_conflict = on_conflict;
+    }
+
+    // whether opening a file for read caches all of it or only what's
+    // read
+    pub fn set_cache_fill(&mut self, fill: file::CacheFill) {
+        self.fill = fill;
+    }
+
+    pub fn set_statfs_from(&mut self, from: StatfsFrom) {
+        self.statfs_from = from;
+    }
+
+    // writes only go to the cache, and files are copied to the source
+    // this long after they are closed
+    pub fn set_write_back(&mut self, delay: StdDuration) {
+        self.write_back = Some(WriteBack::new(delay));
+    }
+
+    // files that are cached once we are mounted, the evicter is
+    // told about them separately
+    pub fn set_pins(&mut self, pins: Pins) {
+        self.pins = pins;
+    }
+
+    // listens for commands on this unix socket once we are mounted
+    pub fn set_control_socket(&mut self, path: &dyn AsRef<Path>) {
+        self.control = Some(ControlServer::new(path.as_ref()));
+    }
+
+    // drop what's changed in src from the cache as soon as it changes,
+    // for when src is also written to by others
+    pub fn set_watch_source(&mut self) {
+        self.watcher = Some(SourceWatcher::new(&self.from));
+    }
+
+    pub fn set_evicter(&mut self, evicter: EvicterHandle) {
+        self.evicter = Some(evicter);
+    }
+
+    // files flushed to the source will also be copied to this directory
+    pub fn set_mirror(&mut self, dir: &dyn AsRef<Path>) {
+        self.mirror = Some(Mirror::new(self.src.clone(), dir));
+    }
+
+    // unlink and rmdir only record a whiteout in the cache and leave
+    // the source alone, which also works if the source is read-only
+    pub fn set_whiteout(&mut self) -> error::Result<()> {
+        self.whiteouts = Some(Arc::new(Whiteouts::new(&self.cache, self.perms)?));
+        return Ok(());
+    }
+
+    fn is_whited_out(&self, path: &Path) -> bool {
+        match self.whiteouts {
+            Some(ref w) => return w.contains(&path),
+            None => return false,
+        }
+    }
+
+    // the path is being created again, returns whether it was whited out
+    fn remove_whiteout(&self, path: &Path) -> error::Result<bool> {
+        match self.whiteouts {
+            Some(ref w) => return Ok(w.remove(&path)?),
+            None => return Ok(false),
+        }
+    }
+
+    pub fn apply_settings(&self, settings: &[Setting]) {
+        for s in settings {
+            info!("changing setting {:?}", s);
+            match *s {
+                Setting::ReadOnly(ro) => self.src_readonly.store(ro, Ordering::Relaxed),
+                Setting::FreeSpace(ref free) => {
+                    if let Some(ref evicter) = self.evicter {
+                        evicter.set_free(free);
+                    }
+                }
+                Setting::Ttl(secs) => self.set_ttl(Duration::seconds(secs as i64)),
+            }
+        }
+    }
+
+    pub fn setxattr(
+        &mut self,
+        ino: u64,
+        name: OsString,
+        value: Vec<u8>,
+        flags: u32,
+        _position: u32,
+        uid: u32,
+        reply: ReplyEmpty,
+    ) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if name == OsStr::new(PIN_XATTR) {
+            self.pin(ino, true, reply);
+            return;
+        }
+        if ino != fuse::FUSE_ROOT_ID || name != OsStr::new(REMOUNT_XATTR) {
+            self.set_src_xattr(ino, name, value, flags, reply);
+            return;
+        }
+
+        // only whoever is running catfs gets to change its settings
+        if uid != 0 && uid != unsafe { libc::geteuid() } {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let value = String::from_utf8_lossy(&value);
+        match flags::parse_settings(&value) {
+            Ok(settings) => {
+                self.apply_settings(&settings);
+                reply.ok();
+            }
+            Err(e) => {
+                oplog!(
+                    Error,
+                    "setxattr",
+                    { ino: ino, errno: libc::EINVAL },
+                    "<-- !setxattr {} = {}",
+                    value,
+                    e.to_str()
+                );
+                reply.error(libc::EINVAL);
+            }
+        }
+    }
+
+    fn set_src_xattr(&mut self, ino: u64, name: OsString, value: Vec<u8>, flags: u32, reply: ReplyEmpty) {
+        if is_catfs_xattr(&name) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
+        // the sources take no flags, so this isn't atomic
+        let res = inode.get_xattr(&name).and_then(|old| {
+            if old.is_some() && (flags & libc::XATTR_CREATE as u32) != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            if old.is_none() && (flags & libc::XATTR_REPLACE as u32) != 0 {
+                return Err(io::Error::from_raw_os_error(ENOATTR));
+            }
+            return inode.set_xattr(&name, &value);
+        });
+        match res {
+            Ok(_) => {
+                oplog!(
+                    "setxattr",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- setxattr {:?} {:?}",
+                    inode.get_path(),
+                    name
+                );
+                reply.ok();
+            }
+            Err(e) => {
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "setxattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !setxattr {:?} {:?} = {}",
+                    inode.get_path(),
+                    name,
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn getxattr(&mut self, ino: u64, name: OsString, size: u32, reply: ReplyXattr) {
+        if self.is_stats_file(ino) {
+            reply.error(ENOATTR);
+            return;
+        }
+        if name == OsStr::new(PIN_XATTR) {
+            match self.is_pinned(ino) {
+                Ok(true) => reply_xattr(b"1", size, reply),
+                Ok(false) => reply.error(ENOATTR),
+                Err(e) => reply.error(error::errno(&e)),
+            }
+            return;
+        }
+        if is_catfs_xattr(&name) {
+            reply.error(ENOATTR);
+            return;
+        }
+
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
+        match inode.get_xattr(&name) {
+            Ok(Some(value)) => {
+                oplog!(
+                    "getxattr",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- getxattr {:?} {:?}",
+                    inode.get_path(),
+                    name
+                );
+                reply_xattr(&value, size, reply);
+            }
+            Ok(None) => reply.error(ENOATTR),
+            Err(e) => {
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "getxattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !getxattr {:?} {:?} = {}",
+                    inode.get_path(),
+                    name,
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn listxattr(&mut self, ino: u64, size: u32, reply: ReplyXattr) {
+        if self.is_stats_file(ino) {
+            reply_xattr(b"", size, reply);
+            return;
+        }
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
+        match inode.list_xattr() {
+            Ok(names) => {
+                let mut value = Vec::new();
+                for name in names.iter().filter(|n| !is_catfs_xattr(n)) {
+                    value.extend_from_slice(name.as_bytes());
+                    value.push(0);
+                }
+                oplog!(
+                    "listxattr",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- listxattr {:?}",
+                    inode.get_path()
+                );
+                reply_xattr(&value, size, reply);
+            }
+            Err(e) => {
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "listxattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !listxattr {:?} = {}",
+                    inode.get_path(),
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn removexattr(&mut self, ino: u64, name: OsString, reply: ReplyEmpty) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if name == OsStr::new(PIN_XATTR) {
+            self.pin(ino, false, reply);
+            return;
+        }
+        if is_catfs_xattr(&name) {
+            reply.error(ENOATTR);
+            return;
+        }
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
+        match inode.remove_xattr(&name) {
+            Ok(_) => {
+                oplog!(
+                    "removexattr",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- removexattr {:?} {:?}",
+                    inode.get_path(),
+                    name
+                );
+                reply.ok();
+            }
+            Err(e) => {
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "removexattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !removexattr {:?} {:?} = {}",
+                    inode.get_path(),
+                    name,
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    // pinned files are cached in full and the evicter leaves them
+    // alone. This is kept in the cache file, so it's gone once the
+    // file changes in src
+    fn pin(&self, ino: u64, pinned: bool, reply: ReplyEmpty) {
+        let path = {
+            let inode = self.get_inode(ino);
+            let inode = inode.read().unwrap();
+            if inode.get_kind() != fuse::FileType::RegularFile {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            inode.get_path().to_path_buf()
+        };
+
+        let res = (|| -> error::Result<()> {
+            if pinned {
+                self.cache_whole(&path)?;
+            }
+            let flags = rlibc::O_RDONLY;
+            let mut f = match self.layout.openat(self.cache_dir, self.perms, &path, flags, 0) {
+                Ok(f) => f,
+                Err(e) => {
+                    if !pinned && error::is_enoent(&e) {
+                        return Ok(());
+                    }
+                    return Err(error::RError::from(e));
+                }
+            };
+            let res = pin::set_pinned(&f, pinned);
+            f.close()?;
+            return Ok(res?);
+        })();
+        match res {
+            Ok(_) => {
+                let op = if pinned { "pin" } else { "unpin" };
+                oplog!(op, { path: &path, ino: ino }, "<-- {} {:?}", op, path);
+                reply.ok();
+            }
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(
+                    Error,
+                    "pin",
+                    { path: &path, ino: ino, errno: errno },
+                    "<-- !pin {:?} = {}",
+                    path,
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    fn is_pinned(&self, ino: u64) -> error::Result<bool> {
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
+        let path = inode.get_path();
+        let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+        match self.layout.openat(self.cache_dir, self.perms, &path, flags, 0) {
+            Ok(mut f) => {
+                let pinned = pin::is_pinned(&f);
+                f.close()?;
+                return Ok(pinned);
+            }
+            Err(e) => {
+                if error::is_enoent(&e) || e.raw_os_error() == Some(libc::ELOOP) {
+                    return Ok(false);
+                }
+                return Err(error::RError::from(e));
+            }
+        }
+    }
+
+    // pages in all of path. If it's been looked up this goes through
+    // its inode, which knows if what's in the cache is newer than src
+    fn cache_whole(&self, path: &Path) -> error::Result<()> {
+        let inode = self.store.read().unwrap().get_by_path(&path);
+        let mut opts = self.open_options();
+        opts.fill = file::CacheFill::Full;
+        let mut f = match inode {
+            Some(inode) => {
+                inode.write().unwrap().open(
+                    rlibc::O_RDONLY,
+                    self.cache_valid_ttl,
+                    &self.tp,
+                    &opts,
+                )?
+            }
+            None => {
+                file::Handle::open(
+                    &*self.src,
+                    self.cache_dir,
+                    self.layout,
+                    self.perms,
+                    &path,
+                    rlibc::O_RDONLY,
+                    false,
+                    &self.tp,
+                    &opts,
+                )?
+            }
+        };
+        return f.wait_for_cache();
+    }
+
+    // caches the files under dir that are pinned, returns how many
+    fn cache_pinned(&self, dir: &Path) -> usize {
+        let mut d = match self.src.opendir(&dir) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("!opendir {:?} = {}", dir, e);
+                return 0;
+            }
+        };
+
+        let mut n = 0;
+        loop {
+            let entry = match d.readdir() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("!readdir {:?} = {}", dir, e);
+                    break;
+                }
+            };
+            let name = entry.name();
+            if name == OsStr::new(".") || name == OsStr::new("..") {
+                continue;
+            }
+            let path = dir.join(&name);
+            if self.is_whited_out(&path) {
+                continue;
+            }
+
+            match entry.kind() {
+                fuse::FileType::Directory => {
+                    if self.pins.may_match_under(&path) {
+                        n += self.cache_pinned(&path);
+                    }
+                }
+                fuse::FileType::RegularFile => {
+                    if self.pins.matches(&path) {
+                        match self.cache_whole(&path) {
+                            Ok(_) => n += 1,
+                            Err(e) => error!("!cache {:?} = {}", path, e),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        return n;
+    }
+
+    pub fn init(&mut self) {
+        oplog!("init", {}, "<-- init");
+        let s = make_self(self);
+        if !s.pins.is_empty() {
+            s.tp.lock().unwrap().execute(move || {
+                let n = s.cache_pinned(Path::new(""));
+                info!("cached {} pinned files", n);
+            });
+        }
+        if let Some(ref write_back) = s.write_back {
+            write_back.start(move |ino, file, path| {
+                s.tp.lock().unwrap().execute(move || {
+                    let _ = s.upload(ino, file, path);
+                });
+            });
+        }
+        // only now that we are running as whoever the socket is for
+        if let Some(ref control) = s.control {
+            if let Err(e) = control.start(move |cmd, arg, out| s.control(cmd, arg, out)) {
+                error!("!control {:?} = {}", s.cache, e);
+            }
+        }
+        if let Some(ref watcher) = s.watcher {
+            if let Err(e) = watcher.start(move |path| s.src_changed(path)) {
+                error!("!watch {:?} = {}", s.from, e);
+            }
+        }
+        let &(ref lock, ref cv) = &*self.ready;
+        *lock.lock().unwrap() = true;
+        cv.notify_all();
+    }
+
+    // path was changed in src by someone else, or anything under src
+    // if it's empty. Files that are open or being uploaded are only
+    // checked again the next time they are opened
+    fn src_changed(&self, path: &Path) {
+        if let Some(ref dir_cache) = self.dir_cache {
+            dir_cache.invalidate(path.parent().unwrap_or(path));
+            dir_cache.invalidate_tree(path);
+        }
+
+        let inodes: Vec<Arc<RwLock<Inode>>> = {
+            let store = self.store.read().unwrap();
+            store
+                .inodes_cache
+                .iter()
+                .filter(|&(p, _)| p.starts_with(path))
+                .filter_map(|(_, ino)| store.inodes.get(ino).cloned())
+                .collect()
+        };
+        for inode in inodes {
+            let mut inode = inode.write().unwrap();
+            if self.is_uploading(inode.get_ino()) {
+                continue;
+            }
+            inode.invalidate();
+            // it may be gone, or another file by now
+            let _ = inode.refresh();
+        }
+
+        if path.as_os_str().is_empty() {
+            return;
+        }
+        match rlibc::fstatat(self.cache_dir, &self.layout.cache_path(&path)) {
+            Ok(st) => {
+                if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+        let busy = {
+            let fh_store = self.fh_store.lock().unwrap();
+            fh_store.info.values().any(|i| i.path == path)
+        };
+        let uploading = match self.store.read().unwrap().inodes_cache.get(path) {
+            Some(ino) => self.is_uploading(*ino),
+            None => false,
+        };
+        if busy || uploading {
+            return;
+        }
+        // deletes the cache file unless it still matches src
+        if let Err(e) = file::Handle::validate_cache(
+            &*self.src,
+            self.cache_dir,
+            self.layout,
+            &path,
+            false,
+            false,
+        )
+        {
+            debug!("!validate_cache {:?} = {}", path, e);
+        }
+    }
+
+    // a command from the control socket
+    fn control(&self, cmd: &str, arg: &str, out: &mut Vec<u8>) -> error::Result<()> {
+        let path = Path::new(arg.trim_matches('/'));
+        match cmd {
+            "stats" => {
+                let stats = self.state_dumper().stats();
+                let pending = match self.write_back {
+                    Some(ref write_back) => write_back.pending().len(),
+                    None => 0,
+                };
+                // the cache dir may be what's failing
+                let free = match rlibc::fstatvfs(self.cache_dir) {
+                    Ok(st) => st.f_bavail as u64 * st.f_frsize as u64,
+                    Err(_) => 0,
+                };
+                let s = format!(
+                    "inodes {}\nopen_dirs {}\nopen_files {}\npending_uploads {}\n\
+                     cache_free_bytes {}\ncache_degraded {}\nsrc_retries {}\n\
+                     src_retry_failures {}\n",
+                    stats.inodes,
+                    stats.open_dirs,
+                    stats.open_files,
+                    pending,
+                    free,
+                    self.cache_degraded() as u8,
+                    backend::retry::retried(),
+                    backend::retry::gave_up()
+                );
+                out.extend_from_slice(s.as_bytes());
+            }
+            "latency" => {
+                for line in self.latencies.lines() {
+                    out.extend_from_slice(line.as_bytes());
+                    out.push(b'\n');
+                }
+            }
+            "evict" | "invalidate" => {
+                if path.as_os_str().is_empty() {
+                    return error::propagate(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                let inode = self.store.read().unwrap().get_by_path(&path);
+                if let Some(ref inode) = inode {
+                    // the cache may have the only copy of what's written
+                    let ino = inode.read().unwrap().get_ino();
+                    if self.is_uploading(ino) {
+                        return error::propagate(io::Error::from_raw_os_error(libc::EBUSY));
+                    }
+                }
+
+                if cmd == "evict" {
+                    let opened = {
+                        let fh_store = self.fh_store.lock().unwrap();
+                        fh_store.info.values().any(|i| i.path == path)
+                    };
+                    if opened {
+                        return error::propagate(io::Error::from_raw_os_error(libc::EBUSY));
+                    }
+                    if let Err(e) = rlibc::unlinkat(
+                        self.cache_dir,
+                        &self.layout.cache_path(&path),
+                        0,
+                    )
+                    {
+                        error::try_enoent(e)?;
+                    }
+                    if let Some(inode) = inode {
+                        inode.write().unwrap().invalidate();
+                    }
+                } else {
+                    if let Some(inode) = inode {
+                        let mut inode = inode.write().unwrap();
+                        inode.invalidate();
+                        inode.refresh()?;
+                    }
+                    file::Handle::validate_cache(
+                        &*self.src,
+                        self.cache_dir,
+                        self.layout,
+                        &path,
+                        false,
+                        false,
+                    )?;
+                }
+            }
+            "flush-all" => {
+                let files: Vec<(PathBuf, Arc<RwLock<file::Handle>>)> = {
+                    let fh_store = self.fh_store.lock().unwrap();
+                    fh_store
+                        .handles
+                        .iter()
+                        .filter_map(|(fh, f)| {
+                            fh_store.info.get(fh).map(|i| (i.path.clone(), f.clone()))
+                        })
+                        .collect()
+                };
+
+                let mut n = 0;
+                for (path, file) in files {
+                    if !file.read().unwrap().is_dirty() {
+                        continue;
+                    }
+                    let ino = self.store.read().unwrap().inodes_cache.get(&path).cloned();
+                    if let Some(ino) = ino {
+                        self.flush_file(ino, &file)?;
+                        n += 1;
+                    }
+                }
+                let mut uploaded = 0;
+                if let Some(ref write_back) = self.write_back {
+                    for ino in write_back.pending() {
+                        self.finish_upload(ino)?;
+                        uploaded += 1;
+                    }
+                }
+                let s = format!("flushed {}\nuploaded {}\n", n, uploaded);
+                out.extend_from_slice(s.as_bytes());
+            }
+            "evict-now" => {
+                match self.evicter {
+                    Some(ref evicter) => evicter.evict_now(),
+                    None => return error::propagate(io::Error::from_raw_os_error(libc::ENOSYS)),
+                }
+            }
+            "set-free" => {
+                match arg.parse::<DiskSpace>() {
+                    Ok(free) => self.apply_settings(&[Setting::FreeSpace(free)]),
+                    Err(_) => {
+                        return error::propagate(io::Error::from_raw_os_error(libc::EINVAL));
+                    }
+                }
+            }
+            _ => return error::propagate(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+        return Ok(());
+    }
+
+    fn statvfs(&self) -> io::Result<rlibc::statvfs64> {
+        match self.statfs_from {
+            StatfsFrom::Cache => return rlibc::fstatvfs(self.cache_dir),
+            StatfsFrom::Source => return self.src.statvfs(),
+            StatfsFrom::Min => {
+                let st = rlibc::fstatvfs(self.cache_dir)?;
+                return Ok(min_statvfs(&st, &self.src.statvfs()?));
+            }
+        }
+    }
+
+    pub fn statfs(&mut self, _ino: u64, reply: ReplyStatfs) {
+        match self.statvfs() {
+            Ok(st) => {
+                reply.statfs(
+                    st.f_blocks as u64,
+                    st.f_bfree as u64,
+                    st.f_bavail as u64,
+                    st.f_files as u64,
+                    st.f_ffree as u64,
+                    st.f_bsize as u32,
+                    st.f_namemax as u32,
+                    st.f_frsize as u32,
+                )
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: OsString, reply: ReplyEntry) {
+        if self.is_stats_name(parent, &name) {
+            reply.entry(&self.ttl_now(), &self.stats_file_attr(), 0);
+            return;
+        }
+        let parent_inode: Arc<RwLock<Inode>>;
+        let mut old_inode: Option<Arc<RwLock<Inode>>> = None;
+        let path: PathBuf;
+        {
+            let store = self.store.read().unwrap();
+            parent_inode = store.get(parent);
+        }
+
+        {
+            let parent_inode = parent_inode.read().unwrap();
+            path = parent_inode.get_child_name(&name);
+        }
+
+        if self.is_whited_out(&path) {
+            oplog!(
+                "lookup",
+                { path: &path, errno: libc::ENOENT },
+                "<-- !lookup {:?} = whiteout",
+                path
+            );
+            reply.error(libc::ENOENT);
+            return;
+        }
+
+        {
+            let mut i: Option<Arc<RwLock<Inode>>>;
+
+            {
+                let store = self.store.read().unwrap();
+                i = store.get_by_path(&path);
+            }
+
+            if let Some(ref mut i) = i {
+                old_inode = Some(i.clone());
+                let mut inode = i.write().unwrap();
+                let refcnt = inode.inc_ref();
+
+                if inode.not_expired(&self.ttl()) {
+                    reply.entry(
+                        &self.ttl_now(),
+                        inode.get_attr(),
+                        inode.get_generation(),
+                    );
+                    oplog!(
+                        "lookup",
+                        { path: inode.get_path(), ino: inode.get_ino() },
+                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {}",
+                        inode.get_path(),
+                        inode.get_ino(),
+                        inode.get_kind(),
+                        refcnt
+                    );
+                    return;
+                } else {
+                    oplog!(
+                        "lookup",
+                        { path: inode.get_path(), ino: inode.get_ino() },
+                        "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {} expired",
+                        inode.get_path(),
+                        inode.get_ino(),
+                        inode.get_kind(),
+                        refcnt
+                    );
+                }
+            }
+        }
+
+        let parent_inode = parent_inode.read().unwrap();
+        match parent_inode.lookup(&name) {
+            Ok(new_inode) => {
+                if let Some(inode) = old_inode {
+                    let mut inode = inode.write().unwrap();
+                    if inode.is_same_file(&new_inode) {
+                        // src doesn't have our writes yet
+                        if !self.is_uploading(inode.get_ino()) {
+                            inode.take(new_inode);
+                        }
+                        reply.entry(
+                            &self.ttl_now(),
+                            &inode.get_attr(),
+                            inode.get_generation(),
+                        );
+                        oplog!(
+                            "lookup",
+                            { path: inode.get_path(), ino: inode.get_ino() },
+                            "<-- lookup {:?} = 0x{:016x}, {:?} refcnt {}",
+                            inode.get_path(),
+                            inode.get_ino(),
+                            inode.get_kind(),
+                            inode.get_refcnt(),
+                        );
+                        return;
+                    }
+
+                    // the source replaced it with another file, which
+                    // gets its own ino. The old one stays around until
+                    // the kernel forgets it
+                    oplog!(
+                        "lookup",
+                        { path: &path, ino: inode.get_ino() },
+                        "<-- replaced 0x{:016x}",
+                        inode.get_ino()
+                    );
+                    if inode.deref(1) {
+                        let mut store = self.store.write().unwrap();
+                        store.remove_ino(inode.get_ino());
+                    }
+                }
+
+                oplog!(
+                    "lookup",
+                    { path: new_inode.get_path(), ino: new_inode.get_ino() },
+                    "<-- lookup {:?} = 0x{:016x}, {:?} refcnt *1",
+                    new_inode.get_path(),
+                    new_inode.get_ino(),
+                    new_inode.get_kind()
+                );
+                let (attr, generation) = self.insert_inode(new_inode, false);
+                reply.entry(&self.ttl_now(), &attr, generation);
+            }
+            Err(e) => {
+                if let Some(inode) = old_inode {
+                    let mut inode = inode.write().unwrap();
+                    let stale = inode.deref(1);
+                    if stale {
+                        let mut store = self.store.write().unwrap();
+                        store.remove_ino(inode.get_attr().ino);
+                        oplog!(
+                            "lookup",
+                            { path: &path, ino: inode.get_attr().ino },
+                            "<-- expired 0x{:016x}",
+                            inode.get_attr().ino
+                        );
+                    }
+                }
+                let errno = error::errno(&e);
+                oplog!("lookup", { path: &path, errno: errno }, "<-- !lookup {:?} = {}", path, e);
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn getattr(&mut self, ino: u64, reply: ReplyAttr) {
+        if self.is_stats_file(ino) {
+            reply.attr(&self.ttl_now(), &self.stats_file_attr());
+            return;
+        }
+        let inode: Arc<RwLock<Inode>>;
+
+        {
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+        }
+
+        {
+            let inode = inode.read().unwrap();
+            if !inode.was_flush_failed() {
+                reply.attr(&self.ttl_now(), inode.get_attr());
+                oplog!(
+                    "getattr",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- getattr {} {:?} {} bytes",
+                    ino,
+                    inode.get_path(),
+                    inode.get_attr().size
+                );
+                return;
+            }
+        }
+
+        let mut inode = inode.write().unwrap();
+        if let Err(e) = inode.refresh() {
+            let errno = error::errno(&e);
+            oplog!(
+                "getattr",
+                { path: inode.get_path(), ino: ino, errno: errno },
+                "<-- !getattr {:?} = {}",
+                inode.get_path(),
+                e
+            );
+            reply.error(errno);
+            return;
+        }
+        reply.attr(&self.ttl_now(), inode.get_attr());
+        oplog!(
+            "getattr",
+            { path: inode.get_path(), ino: ino },
+            "<-- getattr {} {:?} {} bytes",
+            ino,
+            inode.get_path(),
+            inode.get_attr().size
+        );
+        return;
+    }
+
+    pub fn setattr(
+        &mut self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
+        crtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        bkuptime: Option<Timespec>,
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        if uid.is_some() || gid.is_some() {
-            // need to think about how to support this as metadata is
-            // only coming from src and catfs may not be running as root
-            reply.error(libc::ENOTSUP);
+        if self.is_stats_file(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() {
+            // don't know how to change these
+            reply.error(libc::ENOTSUP);
+            return;
+        }
+
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inode_ref: Arc<RwLock<Inode>>;
+        let mut inode: RwLockWriteGuard<Inode>;
+        let was_valid: error::Result<bool>;
+
+        let file_ref: Arc<RwLock<file::Handle>>;
+        let mut file: Option<RwLockWriteGuard<file::Handle>>;
+        if let Some(fh) = fh {
+            let mut fh_store = self.fh_store.lock().unwrap();
+            fh_store.touch(fh);
+            file_ref = fh_store.handles.get(&fh).unwrap().clone();
+            file = Some(file_ref.write().unwrap());
+            // if we had the file open, then we know that it's valid
+            was_valid = Ok(true);
+            inode_ref = self.get_inode(ino);
+            inode = inode_ref.write().unwrap();
+        } else {
+            file = None;
+            inode_ref = self.get_inode(ino);
+            inode = inode_ref.write().unwrap();
+            // if we change the size or mtime then we need to restore the
+            // checksum xattr. XXX make this thing atomic
+            was_valid = file::Handle::validate_cache(
+                &*self.src,
+                self.cache_dir,
+                self.layout,
+                &inode.get_path(),
+                file.is_some(),
+                true,
+            );
+
+            if let Err(e) = was_valid {
+                let errno = error::os_errno(&e);
+                oplog!(Error, "setattr", { ino: ino, errno: errno }, "<-- !setattr {:16x} = {}", ino, e);
+                reply.error(errno);
+                return;
+            }
+        }
+
+        // before chmod, since chown clears the setuid bits
+        if uid.is_some() || gid.is_some() {
+            let uid = uid.unwrap_or(u32::MAX);
+            let gid = gid.unwrap_or(u32::MAX);
+            let res = if let Some(ref file) = file {
+                file.chown(self.ids.src_uid(uid), self.ids.src_gid(gid))
+            } else {
+                inode.chown(uid, gid)
+            };
+            if let Err(e) = res {
+                let errno = chown_errno(&e);
+                oplog!(
+                    Error,
+                    "setattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !setattr {:?} {}:{} = {}",
+                    inode.get_path(),
+                    uid,
+                    gid,
+                    e
+                );
+                reply.error(errno);
+                return;
+            }
+        }
+
+        if let Some(mode) = mode {
+            if let Some(ref file) = file {
+                if let Err(e) = file.chmod(mode as libc::mode_t) {
+                    let errno = error::os_errno(&e);
+                    oplog!(Error, "setattr", { ino: ino, errno: errno }, "<-- !setattr {:16x} = {}", ino, e);
+                    reply.error(errno);
+                    return;
+                }
+            } else {
+                if let Err(e) = inode.chmod(mode as libc::mode_t, flags.unwrap_or(0)) {
+                    let errno = error::os_errno(&e);
+                    oplog!(
+                        Error,
+                        "setattr",
+                        { path: inode.get_path(), ino: ino, errno: errno },
+                        "<-- !setattr {:?} = {}",
+                        inode.get_path(),
+                        e
+                    );
+                    reply.error(errno);
+                    return;
+                }
+            }
+        }
+
+        if let Some(size) = size {
+            if let Some(ref mut file) = file {
+                if let Err(e) = file.truncate(size) {
+                    let errno = error::os_errno(&e);
+                    oplog!(Error, "setattr", { ino: ino, errno: errno }, "<-- !setattr {:16x} = {}", ino, e);
+                    reply.error(errno);
+                    return;
+                }
+            } else {
+                if let Err(e) = inode.truncate(size) {
+                    let errno = error::os_errno(&e);
+                    oplog!(
+                        Error,
+                        "setattr",
+                        { path: inode.get_path(), ino: ino, errno: errno },
+                        "<-- !setattr {:?} = {}",
+                        inode.get_path(),
+                        e
+                    );
+                    reply.error(errno);
+                    return;
+                }
+            }
+        }
+
+        if mtime.is_some() || atime.is_some() {
+            let old_attr = inode.get_attr();
+
+            if let Err(e) = inode.utimes(
+                &atime.unwrap_or(old_attr.atime),
+                &mtime.unwrap_or(old_attr.mtime),
+                flags.unwrap_or(0),
+            )
+            {
+                let errno = error::os_errno(&e);
+                oplog!(
+                    Error,
+                    "setattr",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !setattr {:?} = {}",
+                    inode.get_path(),
+                    e
+                );
+                reply.error(errno);
+                return;
+            }
+        }
+
+        // still need to restore the checksum even if a file handle is
+        // supplied, because we may never flush that file handle
+        if was_valid.unwrap() {
+            if let Some(ref file) = file {
+                if let Err(e) = file.set_pristine(true) {
+                    let errno = error::os_errno(&e);
+                    oplog!(
+                        Error,
+                        "setattr",
+                        { path: inode.get_path(), ino: ino, errno: errno },
+                        "<-- !setattr {:?} = {}",
+                        inode.get_path(),
+                        e
+                    );
+                    reply.error(errno);
+                    return;
+                }
+            } else {
+                if let Err(e) = file::Handle::make_pristine(
+                    &*self.src,
+                    self.cache_dir,
+                    self.layout,
+                    &inode.get_path(),
+                )
+                {
+                    let errno = error::os_errno(&e);
+                    oplog!(
+                        Error,
+                        "setattr",
+                        { path: inode.get_path(), ino: ino, errno: errno },
+                        "<-- !setattr {:?} = {}",
+                        inode.get_path(),
+                        e
+                    );
+                    reply.error(errno);
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = inode.refresh() {
+            let errno = error::os_errno(&e);
+            oplog!(
+                Error,
+                "setattr",
+                { path: inode.get_path(), ino: ino, errno: errno },
+                "<-- !setattr {:?} = {}",
+                inode.get_path(),
+                e
+            );
+            reply.error(errno);
+        } else {
+            oplog!(
+                "setattr",
+                { path: inode.get_path(), ino: ino },
+                "<-- setattr {:?} 0x{:016x} 0x{:?}",
+                inode.get_path(),
+                ino,
+                fh
+            );
+            reply.attr(&self.ttl_now(), inode.get_attr());
+        }
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if self.is_stats_file(ino) {
+            return;
+        }
+        let inode: Arc<RwLock<Inode>>;
+        let stale: bool;
+        {
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+        }
+
+        {
+            let mut inode = inode.write().unwrap();
+            stale = inode.deref(nlookup);
+        }
+
+        if stale {
+            oplog!("forget", { ino: ino }, "<-- forgot 0x{:016x}", ino);
+            let mut store = self.store.write().unwrap();
+            store.remove_ino(ino);
+        }
+    }
+
+    pub fn opendir(&mut self, ino: u64, flags: u32, reply: ReplyOpen) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        let inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+        }
+
+        let inode = inode.read().unwrap();
+        match inode.opendir(self.whiteouts.clone(), self.dir_cache.as_ref()) {
+            Ok(dir) => {
+                let dh = self.dh_store.lock().unwrap().insert(dir, inode.get_path());
+                reply.opened(dh, flags);
+                oplog!(
+                    "opendir",
+                    { path: inode.get_path(), ino: ino, fh: dh },
+                    "<-- opendir {:?} = {}",
+                    inode.get_path(),
+                    dh
+                );
+            }
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(
+                    Error,
+                    "opendir",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !opendir {:?} = {}",
+                    inode.get_path(),
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn readdir(&mut self, _ino: u64, dh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let mut dh_store = self.dh_store.lock().unwrap();
+        dh_store.touch(dh);
+        let dir = dh_store.handles.get_mut(&dh).unwrap();
+        dir.seekdir(offset);
+        loop {
+            match dir.readdir() {
+                Ok(res) => {
+                    match res {
+                        Some(entry) => {
+                            if reply.add(entry.ino(), entry.off(), entry.kind(), entry.name()) {
+                                dir.push(entry);
+                                break;
+                            } else {
+                                dir.consumed(&entry);
+                            }
+                            oplog!(
+                                "readdir",
+                                { fh: dh },
+                                "<-- readdir {} = {:?} {}",
+                                dh,
+                                entry.name(),
+                                entry.off()
+                            );
+                        }
+                        None => {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let errno = error::os_errno(&e);
+                    oplog!(Error, "readdir", { fh: dh, errno: errno }, "<-- !readdir {} = {}", dh, e);
+                    reply.error(errno);
+                    return;
+                }
+            }
+        }
+
+        reply.ok();
+    }
+
+    pub fn releasedir(&mut self, _ino: u64, dh: u64, _flags: u32, reply: ReplyEmpty) {
+        let mut dh_store = self.dh_store.lock().unwrap();
+        // the handle will be destroyed and closed
+        dh_store.remove(dh);
+        reply.ok();
+    }
+
+    pub fn open(&mut self, ino: u64, flags: u32, pid: u32, reply: ReplyOpen) {
+        if self.is_stats_file(ino) {
+            if (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+                reply.error(libc::EACCES);
+            } else {
+                // the size changes as it's read
+                reply.opened(0, FOPEN_DIRECT_IO);
+            }
+            return;
+        }
+        // the cache file isn't valid until it's in src
+        if let Err(e) = self.finish_upload(ino) {
+            reply.error(error::errno(&e));
+            return;
+        }
+
+        let inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+        }
+
+        let mut flags = flags;
+        if self.is_src_readonly() && self.write_back.is_none() {
+            match readonly_flags(flags) {
+                Some(f) => flags = f,
+                None => {
+                    reply.error(libc::EROFS);
+                    return;
+                }
+            }
+        }
+
+        let mut bypass = {
+            let inode = inode.read().unwrap();
+            self.is_bypass(pid, flags) ||
+                self.skips_cache(inode.get_path(), inode.get_attr().size, flags) ||
+                (self.can_bypass(flags) && self.cache_degraded())
+        };
+        // src may turn out to be read-only in between
+        let open = |inode: &mut Inode, flags: u32, bypass: bool| if bypass {
+            inode.open_bypass(flags)
+        } else {
+            inode.open(flags, self.cache_valid_ttl, &self.tp, &self.open_options())
+        };
+
+        let mut inode = inode.write().unwrap();
+        let mut res = open(&mut inode, flags, bypass);
+        let mut erofs = false;
+        if let Err(ref e) = res {
+            erofs = e.errno() == libc::EROFS;
+        }
+        if erofs {
+            self.src_failed(libc::EROFS);
+            if self.write_back.is_some() && !bypass {
+                // writes can go to the cache
+                res = open(&mut inode, flags, bypass);
+            } else if let Some(f) = readonly_flags(flags) {
+                // the app may only want to read, let it
+                flags = f;
+                res = open(&mut inode, flags, bypass);
+            }
+        }
+        if !bypass {
+            let cache_failed = match res {
+                Ok(_) => {
+                    self.health.ok();
+                    false
+                }
+                Err(ref e) => health::is_cache_error(e.errno()) && self.can_bypass(flags),
+            };
+            // it may have been the cache's fault, src can tell
+            if cache_failed {
+                let direct = open(&mut inode, flags, true);
+                if direct.is_ok() {
+                    if let Err(ref e) = res {
+                        self.health.failed(e.errno());
+                    }
+                    bypass = true;
+                    res = direct;
+                }
+            }
+        }
+        if bypass && (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+            // the cache file may be left behind if the cache is
+            // failing, check it against src before it's used again
+            inode.invalidate();
+        }
+
+        match res {
+            Ok(mut file) => {
+                if self.write_back.is_some() && (flags & rlibc::O_ACCMODE) != rlibc::O_RDONLY {
+                    file.set_write_back();
+                }
+                self.set_direct(&mut file, inode.get_path());
+                let fh = self.fh_store.lock().unwrap().insert(
+                    Arc::new(RwLock::new(file)),
+                    inode.get_path(),
+                );
+                reply.opened(fh, flags);
+                oplog!(
+                    "open",
+                    { path: inode.get_path(), ino: ino, fh: fh },
+                    "<-- open {:?} = {}{}",
+                    inode.get_path(),
+                    fh,
+                    if bypass { " bypassing cache" } else { "" },
+                );
+            }
+            Err(e) => {
+                let errno = error::errno(&e);
+                reply.error(errno);
+                oplog!(
+                    Error,
+                    "open",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !open {:?} = {}",
+                    inode.get_path(),
+                    e
+                );
+            }
+        }
+    }
+
+    // the cache failed under an open file, which reads and writes src
+    // directly from now on if src has all of it. Returns whether it
+    // does
+    fn fall_back_to_src(&self, ino: u64, file: &mut file::Handle, errno: libc::c_int) -> bool {
+        if file.is_bypass() || !health::is_cache_error(errno) || !file.src_is_current() {
+            return false;
+        }
+        self.health.failed(errno);
+
+        let inode = self.get_inode(ino);
+        let mut inode = inode.write().unwrap();
+        // we don't know what it was opened for, the kernel does
+        let res = match inode.open_bypass(rlibc::O_RDWR) {
+            Ok(direct) => Ok(direct),
+            Err(_) => inode.open_bypass(rlibc::O_RDONLY),
+        };
+        match res {
+            Ok(direct) => {
+                warn!("cache of {:?} failed, using the source directly", inode.get_path());
+                *file = direct;
+                inode.invalidate();
+                return true;
+            }
+            Err(e) => {
+                debug!("!open_bypass {:?} = {}", inode.get_path(), e);
+                return false;
+            }
+        }
+    }
+
+    pub fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if self.is_stats_file(ino) {
+            let s = self.stats_file_content();
+            let start = cmp::min(offset as usize, s.len());
+            let end = cmp::min(start + size as usize, s.len());
+            reply.data(&s[start..end]);
+            return;
+        }
+        let file: Arc<RwLock<file::Handle>>;
+        {
+            let mut fh_store = self.fh_store.lock().unwrap();
+            fh_store.touch(fh);
+            file = fh_store.handles.get(&fh).unwrap().clone();
+        }
+        // TODO spawn a thread
+        let mut buf: Vec<u8> = vec![0; size as usize];
+        let (res, bypass) = {
+            // reads can share the handle unless they have to fill
+            // in a partially cached file
+            let f = file.read().unwrap();
+            if f.is_partial() {
+                (None, f.is_bypass())
+            } else {
+                (Some(f.read_shared(offset, &mut buf)), f.is_bypass())
+            }
+        };
+        let mut res = match res {
+            Some(res) => res,
+            None => file.write().unwrap().read(offset, &mut buf),
+        };
+        let mut bypass = bypass;
+        let fell_back = match res {
+            Err(ref e) => self.fall_back_to_src(ino, &mut file.write().unwrap(), e.errno()),
+            Ok(_) => false,
+        };
+        if fell_back {
+            bypass = true;
+            res = file.write().unwrap().read(offset, &mut buf);
+        }
+        match res {
+            Ok(nread) => {
+                if !bypass {
+                    self.stats.bytes_read.fetch_add(nread, Ordering::Relaxed);
+                }
+                reply.data(&buf[..nread]);
+            }
+            Err(e) => {
+                let errno = error::os_errno(&e);
+                oplog!("read", { fh: fh, errno: errno }, "<-- !read {} = {}", fh, e);
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: OsString,
+        mode: u32,
+        flags: u32,
+        pid: u32,
+        uid: u32,
+        gid: u32,
+        reply: ReplyCreate,
+    ) {
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::EEXIST);
+            return;
+        }
+        let parent_inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            parent_inode = store.get(parent);
+        }
+
+        // even with write back, a new file is looked up in src
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let parent_inode = parent_inode.read().unwrap();
+        let excl = match self.remove_whiteout(&parent_inode.get_child_name(&name)) {
+            Ok(was_whiteout) => !was_whiteout,
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(
+                    Error,
+                    "create",
+                    { path: parent_inode.get_child_name(&name), errno: errno },
+                    "<-- !create {:?} = {}",
+                    parent_inode.get_child_name(&name),
+                    e
+                );
+                reply.error(errno);
+                return;
+            }
+        };
+        let bypass = self.is_bypass(pid, rlibc::O_WRONLY) ||
+            self.skips_cache(&parent_inode.get_child_name(&name), 0, rlibc::O_WRONLY) ||
+            (self.can_bypass(rlibc::O_WRONLY) && self.cache_degraded());
+        match parent_inode.create(
+            &name,
+            mode as libc::mode_t,
+            excl,
+            bypass,
+            self.cache_key.clone(),
+            self.disable_splice,
+        ) {
+            Ok((mut inode, mut file)) => {
+                self.set_owner(&mut inode, uid, gid);
+                self.dir_changed(inode.get_path());
+                if self.write_back.is_some() {
+                    file.set_write_back();
+                }
+                self.set_direct(&mut file, inode.get_path());
+                let fh = self.fh_store.lock().unwrap().insert(
+                    Arc::new(RwLock::new(file)),
+                    inode.get_path(),
+                );
+
+                oplog!(
+                    "create",
+                    { path: inode.get_path(), ino: inode.get_ino(), fh: fh },
+                    "<-- create {:?} = {}",
+                    inode.get_path(),
+                    fh
+                );
+                let (attr, generation) = self.insert_inode(inode, true);
+                reply.created(&self.ttl_now(), &attr, generation, fh, flags);
+            }
+            Err(e) => {
+                let errno = error::os_errno(&e);
+                oplog!(
+                    Error,
+                    "create",
+                    { path: parent_inode.get_child_name(&name), errno: errno },
+                    "<-- !create {:?} = {}",
+                    parent_inode.get_child_name(&name),
+                    e
+                );
+                self.src_failed(e.errno());
+                reply.error(errno);
+            }
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: Vec<u8>,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if self.is_src_readonly() && self.write_back.is_none() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let nwritten: usize;
+        {
+            let file: Arc<RwLock<file::Handle>>;
+            {
+                let mut fh_store = self.fh_store.lock().unwrap();
+                fh_store.touch(fh);
+                file = fh_store.handles.get(&fh).unwrap().clone();
+            }
+            let mut file = file.write().unwrap();
+            let mut kicked = false;
+            // TODO spawn a thread
+            loop {
+                match file.write(offset, &data) {
+                    Ok(nbytes) => {
+                        nwritten = nbytes;
+                        break;
+                    }
+                    Err(e) => {
+                        if e.errno() == libc::ENOTSUP {
+                            debug!("write rejected, reopening for sequential write");
+                            // the src filesystem rejected our write,
+                            // maybe because this is random
+                            // write. reopen the src and try again
+                            let inode: Arc<RwLock<Inode>>;
+
+                            {
+                                let store = self.store.read().unwrap();
+                                inode = store.get(ino);
+                            }
+                            let inode = inode.read().unwrap();
+
+                            if let Err(e2) = inode.reopen_src(&mut file) {
+                                reply.error(error::os_errno(&e2));
+                                return;
+                            }
+                        } else if e.errno() == libc::ENOSPC {
+                            debug!(
+                                "write(0x{:016x}, 0x{:016x}, {}) = ENOSPC",
+                                ino,
+                                fh,
+                                data.len()
+                            );
+                            if !kicked {
+                                kicked = true;
+                                if let Some(ref evicter) = self.evicter {
+                                    // whose file it is, if that's over its
+                                    // quota only its files are evicted
+                                    let uid = self.get_inode(ino).read().unwrap().get_attr().uid;
+                                    let uid = self.ids.src_uid(uid);
+                                    let wait = StdDuration::from_secs(ENOSPC_EVICT_SECS);
+                                    if evicter.kick_uid(uid, wait) {
+                                        continue;
+                                    }
+                                }
+                            }
+
+                            // nothing more can be evicted, skip the cache
+                            let path = self.get_inode(ino).read().unwrap().get_path().to_path_buf();
+                            match file.stop_caching(self.cache_dir, self.layout, &path) {
+                                Ok(true) => warn!("cache is full, not caching {:?}", path),
+                                Ok(false) => {
+                                    oplog!(
+                                        Error,
+                                        "write",
+                                        { path: &path, ino: ino, fh: fh, errno: libc::ENOSPC },
+                                        "<-- !write {:?} = ENOSPC",
+                                        path
+                                    );
+                                    reply.error(libc::ENOSPC);
+                                    return;
+                                }
+                                Err(e2) => {
+                                    let errno = error::errno(&e2);
+                                    oplog!(
+                                        Error,
+                                        "write",
+                                        { path: &path, ino: ino, fh: fh, errno: errno },
+                                        "<-- !write {:?} = {}",
+                                        path,
+                                        e2
+                                    );
+                                    reply.error(errno);
+                                    return;
+                                }
+                            }
+                        } else if self.fall_back_to_src(ino, &mut file, e.errno()) {
+                            continue;
+                        } else {
+                            let errno = error::os_errno(&e);
+                            oplog!(
+                                Error,
+                                "write",
+                                { ino: ino, fh: fh, errno: errno },
+                                "<-- !write 0x{:016x} {:?} @ {} = {}",
+                                fh,
+                                OsStr::from_bytes(&data[..cmp::min(32, data.len())]),
+                                offset,
+                                e
+                            );
+                            reply.error(errno);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        let inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+        }
+        let mut inode = inode.write().unwrap();
+        inode.extend((offset as u64) + (nwritten as u64));
+        reply.written(nwritten as u32);
+    }
+
+    pub fn flush(&mut self, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        if self.is_stats_file(ino) {
+            reply.ok();
             return;
         }
+        // closing a file drops the posix locks its process had on it
+        self.locks.release(ino, lock_owner);
+
+        let s = make_self(self);
+        self.tp.lock().unwrap().execute(move || {
+            let file_ref: Arc<RwLock<file::Handle>>;
+            {
+                let fh_store = s.fh_store.lock().unwrap();
+                file_ref = fh_store.handles.get(&fh).unwrap().clone();
+            }
+
+            match s.flush_file(ino, &file_ref) {
+                Ok(_) => {
+                    oplog!(
+                        "flush",
+                        { ino: ino, fh: fh },
+                        "<-- flush ino: {:016x} fh: {}",
+                        ino,
+                        fh
+                    );
+                    reply.ok();
+                }
+                Err(e) => {
+                    let errno = error::errno(&e);
+                    oplog!(
+                        Error,
+                        "flush",
+                        { ino: ino, fh: fh, errno: errno },
+                        "<-- !flush {:016x} = {}",
+                        fh,
+                        e
+                    );
+                    reply.error(errno);
+                }
+            }
+        });
+    }
+
+    fn flush_file(&self, ino: u64, file_ref: &Arc<RwLock<file::Handle>>) -> error::Result<()> {
+        let flushed_to_src: bool;
+        let inode = self.get_inode(ino);
+        {
+            // first flush locally
+            let path = inode.read().unwrap().get_path().to_path_buf();
+            let mut file = file_ref.write().unwrap();
+            match file.flush(&*self.src, &path, self.on_conflict) {
+                Ok(b) => {
+                    flushed_to_src = b;
+                    if file.needs_upload() {
+                        if let Some(ref write_back) = self.write_back {
+                            write_back.add(ino, file_ref.clone(), path);
+                        }
+                    }
+                }
+                Err(e) => {
+                    let mut inode = inode.write().unwrap();
+                    inode.flush_failed();
+                    return Err(e);
+                }
+            }
+        }
 
-        if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() {
-            // don't know how to change these
-            reply.error(libc::ENOTSUP);
+        let mut inode = inode.write().unwrap();
+        inode.flushed();
+        if flushed_to_src {
+            // refresh attr with the original file so it will be consistent with lookup
+            inode.refresh()?;
+            if let Some(ref mirror) = self.mirror {
+                mirror.add(inode.get_path());
+            }
+            oplog!(
+                "flush",
+                { path: inode.get_path(), ino: ino },
+                "<-- flush {:?}",
+                inode.get_path()
+            );
+        }
+        return Ok(());
+    }
+
+    // uploads what's still only in the cache because of write back
+    pub fn fsync(&mut self, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        if self.is_stats_file(ino) {
+            reply.ok();
+            return;
+        }
+        if self.write_back.is_none() {
+            // writes already went to src, and the kernel stops asking
+            reply.error(libc::ENOSYS);
             return;
         }
 
-        let inode_ref: Arc<RwLock<Inode>>;
-        let mut inode: RwLockWriteGuard<Inode>;
-        let was_valid: error::Result<bool>;
+        let file: Arc<RwLock<file::Handle>>;
+        {
+            let mut fh_store = self.fh_store.lock().unwrap();
+            fh_store.touch(fh);
+            file = fh_store.handles.get(&fh).unwrap().clone();
+        }
+        let path = self.get_inode(ino).read().unwrap().get_path().to_path_buf();
+        let res = self.finish_upload(ino).and_then(|_| self.upload(ino, file, path));
+        match res {
+            Ok(_) => {
+                oplog!("fsync", { ino: ino, fh: fh }, "<-- fsync {:016x}", fh);
+                reply.ok();
+            }
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(
+                    Error,
+                    "fsync",
+                    { ino: ino, fh: fh, errno: errno },
+                    "<-- !fsync {:016x} = {}",
+                    fh,
+                    e
+                );
+                reply.error(errno);
+            }
+        }
+    }
 
-        let file_ref: Arc<Mutex<file::Handle>>;
-        let mut file: Option<MutexGuard<file::Handle>>;
-        if let Some(fh) = fh {
-            let fh_store = self.fh_store.lock().unwrap();
-            file_ref = fh_store.handles.get(&fh).unwrap().clone();
-            file = Some(file_ref.lock().unwrap());
-            // if we had the file open, then we know that it's valid
-            was_valid = Ok(true);
-            inode_ref = self.get_inode(ino);
-            inode = inode_ref.write().unwrap();
-        } else {
-            file = None;
-            inode_ref = self.get_inode(ino);
-            inode = inode_ref.write().unwrap();
-            // if we change the size or mtime then we need to restore the
-            // checksum xattr. XXX make this thing atomic
-            was_valid = file::Handle::validate_cache(
-                self.src_dir,
-                self.cache_dir,
-                &inode.get_path(),
-                file.is_some(),
-                true,
-            );
+    // copies a file written with write back to the source
+    fn upload(
+        &self,
+        ino: u64,
+        file: Arc<RwLock<file::Handle>>,
+        path: PathBuf,
+    ) -> error::Result<()> {
+        let inode = self.store.read().unwrap().inodes.get(&ino).cloned();
+        // it may have been renamed since it was queued
+        let path = match inode {
+            Some(ref inode) => inode.read().unwrap().get_path().to_path_buf(),
+            None => path,
+        };
 
-            if let Err(e) = was_valid {
-                error!("<-- !setattr {:16x} = {}", ino, e);
-                reply.error(e.raw_os_error().unwrap());
-                return;
+        let res = {
+            let mut f = file.write().unwrap();
+            let res = f.upload(&*self.src, &path, self.on_conflict);
+            if let Some(ref write_back) = self.write_back {
+                write_back.done(ino, &file);
             }
-        }
+            res
+        };
 
-        if let Some(mode) = mode {
-            if let Some(ref file) = file {
-                if let Err(e) = file.chmod(mode as libc::mode_t) {
-                    error!("<-- !setattr {:16x} = {}", ino, e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
+        match res {
+            Ok(flushed_to_src) => {
+                if let Some(inode) = inode {
+                    let mut inode = inode.write().unwrap();
+                    if flushed_to_src {
+                        if let Err(e) = inode.refresh() {
+                            debug!("!refresh {:?} = {}", path, e);
+                        }
+                    }
                 }
-            } else {
-                if let Err(e) = inode.chmod(mode as libc::mode_t, flags.unwrap_or(0)) {
-                    error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
+                if flushed_to_src {
+                    if let Some(ref mirror) = self.mirror {
+                        mirror.add(&path);
+                    }
+                }
+                oplog!("upload", { path: &path, ino: ino }, "<-- upload {:?}", path);
+                return Ok(());
+            }
+            Err(e) => {
+                if let Some(inode) = inode {
+                    inode.write().unwrap().flush_failed();
                 }
+                oplog!(
+                    Error,
+                    "upload",
+                    { path: &path, ino: ino, errno: error::errno(&e) },
+                    "<-- !upload {:?} = {}",
+                    path,
+                    e
+                );
+                return Err(e);
             }
         }
+    }
 
-        if let Some(size) = size {
-            if let Some(ref mut file) = file {
-                if let Err(e) = file.truncate(size) {
-                    error!("<-- !setattr {:16x} = {}", ino, e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
-                }
-            } else {
-                if let Err(e) = inode.truncate(size) {
-                    error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
-                }
+    // uploads now what's waiting to be uploaded later
+    fn finish_upload(&self, ino: u64) -> error::Result<()> {
+        if let Some(ref write_back) = self.write_back {
+            if let Some((file, path)) = write_back.take(ino) {
+                return self.upload(ino, file, path);
             }
         }
+        return Ok(());
+    }
 
-        if mtime.is_some() || atime.is_some() {
-            let old_attr = inode.get_attr();
+    fn is_uploading(&self, ino: u64) -> bool {
+        return match self.write_back {
+            Some(ref write_back) => write_back.is_pending(ino),
+            None => false,
+        };
+    }
 
-            if let Err(e) = inode.utimes(
-                &atime.unwrap_or(old_attr.atime),
-                &mtime.unwrap_or(old_attr.mtime),
-                flags.unwrap_or(0),
-            )
-            {
-                error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-                reply.error(e.raw_os_error().unwrap());
-                return;
+    pub fn release(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if self.is_stats_file(ino) {
+            reply.ok();
+            return;
+        }
+        let mut fh_store = self.fh_store.lock().unwrap();
+        // the handle will be destroyed and closed
+        fh_store.remove(fh);
+        reply.ok();
+    }
+
+    // only asked when the mount isn't using default_permissions. The
+    // source knows best when the caller is us, otherwise go by the
+    // mode bits
+    pub fn access(&mut self, ino: u64, mask: u32, uid: u32, gid: u32, reply: ReplyEmpty) {
+        if self.is_stats_file(ino) {
+            if mask as libc::c_int & libc::W_OK != 0 {
+                reply.error(libc::EACCES);
+            } else {
+                reply.ok();
             }
+            return;
         }
+        let mask = mask as libc::c_int;
+        let inode = self.get_inode(ino);
+        let inode = inode.read().unwrap();
 
-        // still need to restore the checksum even if a file handle is
-        // supplied, because we may never flush that file handle
-        if was_valid.unwrap() {
-            if let Some(ref file) = file {
-                if let Err(e) = file.set_pristine(true) {
-                    error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-                    reply.error(e.raw_os_error().unwrap());
+        // with write back, files can still be written to the cache
+        let writable = self.write_back.is_some() && inode.get_kind() != fuse::FileType::Directory;
+        if mask & libc::W_OK != 0 && self.is_src_readonly() && !writable {
+            oplog!(
+                "access",
+                { path: inode.get_path(), ino: ino, errno: libc::EROFS },
+                "<-- !access {:?} {} = EROFS",
+                inode.get_path(),
+                mask
+            );
+            reply.error(libc::EROFS);
+            return;
+        }
+        if mask == libc::F_OK {
+            reply.ok();
+            return;
+        }
+
+        if uid == unsafe { libc::geteuid() } && gid == unsafe { libc::getegid() } {
+            match self.src.access(&inode.get_path(), mask) {
+                Ok(_) => {
+                    oplog!(
+                        "access",
+                        { path: inode.get_path(), ino: ino },
+                        "<-- access {:?} {}",
+                        inode.get_path(),
+                        mask
+                    );
+                    reply.ok();
                     return;
                 }
-            } else {
-                if let Err(e) = file::Handle::make_pristine(
-                    self.src_dir,
-                    self.cache_dir,
-                    &inode.get_path(),
-                )
-                {
-                    error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
+                Err(e) => {
+                    if e.raw_os_error() != Some(libc::ENOSYS) {
+                        let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                        oplog!(
+                            "access",
+                            { path: inode.get_path(), ino: ino, errno: errno },
+                            "<-- !access {:?} {} = {}",
+                            inode.get_path(),
+                            mask,
+                            e
+                        );
+                        reply.error(errno);
+                        return;
+                    }
                 }
             }
         }
 
-        if let Err(e) = inode.refresh() {
-            error!("<-- !setattr {:?} = {}", inode.get_path(), e);
-            reply.error(e.raw_os_error().unwrap());
+        if mode_allows(inode.get_attr(), uid, gid, mask) {
+            oplog!(
+                "access",
+                { path: inode.get_path(), ino: ino },
+                "<-- access {:?} {} {}:{}",
+                inode.get_path(),
+                mask,
+                uid,
+                gid
+            );
+            reply.ok();
         } else {
-            debug!(
-                "<-- setattr {:?} 0x{:016x} 0x{:?}",
+            oplog!(
+                "access",
+                { path: inode.get_path(), ino: ino, errno: libc::EACCES },
+                "<-- !access {:?} {} {}:{} = EACCES",
                 inode.get_path(),
-                ino,
-                fh
+                mask,
+                uid,
+                gid
             );
-            reply.attr(&self.ttl_now(), inode.get_attr());
+            reply.error(libc::EACCES);
         }
     }
 
-    pub fn forget(&mut self, ino: u64, nlookup: u64) {
-        let inode: Arc<RwLock<Inode>>;
-        let stale: bool;
-        {
-            let store = self.store.lock().unwrap();
-            inode = store.get(ino);
-        }
-
-        {
-            let mut inode = inode.write().unwrap();
-            stale = inode.deref(nlookup);
+    pub fn getlk(
+        &mut self,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::ENOSYS);
+            return;
         }
-
-        if stale {
-            debug!("<-- forgot 0x{:016x}", ino);
-            let mut store = self.store.lock().unwrap();
-            store.remove_ino(ino);
+        let path = self.get_inode(ino).read().unwrap().get_path().to_path_buf();
+        match self.locks.getlk(&*self.src, ino, lock_owner, &path, typ as libc::c_int, start, end) {
+            Ok((typ, start, end)) => {
+                oplog!(
+                    "getlk",
+                    { path: &path, ino: ino },
+                    "<-- getlk {:?} {} = {} {}-{}",
+                    path,
+                    lock_owner,
+                    typ,
+                    start,
+                    end
+                );
+                // the holder may not even be on this machine
+                reply.locked(start, end, typ as u32, 0);
+            }
+            Err(e) => {
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "getlk",
+                    { path: &path, ino: ino, errno: errno },
+                    "<-- !getlk {:?} = {}",
+                    path,
+                    e
+                );
+                reply.error(errno);
+            }
         }
     }
 
-    pub fn opendir(&mut self, ino: u64, flags: u32, reply: ReplyOpen) {
-        let inode: Arc<RwLock<Inode>>;
-        {
-            let store = self.store.lock().unwrap();
-            inode = store.get(ino);
+    // ENOSYS tells the kernel to keep locks to itself, which is what
+    // happens for sources that can't hold locks
+    pub fn setlk(
+        &mut self,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        _pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::ENOSYS);
+            return;
         }
-
-        let inode = inode.read().unwrap();
-        match inode.opendir() {
-            Ok(dir) => {
-                let mut dh_store = self.dh_store.lock().unwrap();
-                let dh = dh_store.next_id;
-                dh_store.next_id += 1;
-                dh_store.handles.insert(dh, dir);
-                reply.opened(dh, flags);
-                debug!("<-- opendir {:?} = {}", inode.get_path(), dh);
+        let path = self.get_inode(ino).read().unwrap().get_path().to_path_buf();
+        match self.locks.setlk(
+            &*self.src,
+            ino,
+            lock_owner,
+            &path,
+            typ as libc::c_int,
+            start,
+            end,
+            sleep,
+        ) {
+            Ok(_) => {
+                oplog!(
+                    "setlk",
+                    { path: &path, ino: ino },
+                    "<-- setlk {:?} {} {} {}-{}",
+                    path,
+                    lock_owner,
+                    typ,
+                    start,
+                    end
+                );
+                reply.ok();
             }
             Err(e) => {
-                error!("<-- !opendir {:?} = {}", inode.get_path(), e);
-                reply.error(error::errno(&e));
+                let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                oplog!(
+                    "setlk",
+                    { path: &path, ino: ino, errno: errno },
+                    "<-- !setlk {:?} = {}",
+                    path,
+                    e
+                );
+                reply.error(errno);
             }
         }
     }
 
-    pub fn readdir(&mut self, _ino: u64, dh: u64, offset: i64, mut reply: ReplyDirectory) {
-        let mut dh_store = self.dh_store.lock().unwrap();
-        let dir = dh_store.handles.get_mut(&dh).unwrap();
-        dir.seekdir(offset);
-        loop {
-            match dir.readdir() {
-                Ok(res) => {
-                    match res {
-                        Some(entry) => {
-                            if reply.add(entry.ino(), entry.off(), entry.kind(), entry.name()) {
-                                dir.push(entry);
-                                break;
-                            } else {
-                                dir.consumed(&entry);
-                            }
-                            debug!("<-- readdir {} = {:?} {}", dh, entry.name(), entry.off());
-                        }
-                        None => {
-                            break;
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("<-- !readdir {} = {}", dh, e);
-                    reply.error(e.raw_os_error().unwrap());
-                    return;
-                }
-            }
+    pub fn unlink(&mut self, parent: u64, name: OsString, reply: ReplyEmpty) {
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let parent_inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            parent_inode = store.get(parent);
         }
 
-        reply.ok();
-    }
+        if self.whiteouts.is_none() && self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-    pub fn releasedir(&mut self, _ino: u64, dh: u64, _flags: u32, reply: ReplyEmpty) {
-        let mut dh_store = self.dh_store.lock().unwrap();
-        // the handle will be destroyed and closed
-        dh_store.handles.remove(&dh);
-        reply.ok();
+        let parent_inode = parent_inode.read().unwrap();
+        let path = parent_inode.get_child_name(&name);
+        let res = match self.whiteouts {
+            Some(ref w) => parent_inode.whiteout(&name, w),
+            None => parent_inode.unlink(&name),
+        };
+        if let Err(e) = res {
+            let errno = error::os_errno(&e);
+            oplog!("unlink", { path: &path, errno: errno }, "<-- !unlink {:?} = {}", path, e);
+            self.src_failed(errno);
+            reply.error(errno);
+        } else {
+            self.dir_changed(&path);
+            self.unlinked(&path);
+            oplog!("unlink", { path: &path }, "<-- unlink {:?}", path);
+            reply.ok();
+        }
     }
 
-    pub fn open(&mut self, ino: u64, flags: u32, reply: ReplyOpen) {
-        let inode: Arc<RwLock<Inode>>;
-        {
-            let store = self.store.lock().unwrap();
-            inode = store.get(ino);
+    pub fn rmdir(&mut self, parent: u64, name: OsString, reply: ReplyEmpty) {
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::ENOTDIR);
+            return;
         }
-
-        let mut inode = inode.write().unwrap();
-        match inode.open(flags, &self.tp) {
-            Ok(file) => {
-                let mut fh_store = self.fh_store.lock().unwrap();
-                let fh = fh_store.next_id;
-                fh_store.next_id += 1;
-                fh_store.handles.insert(fh, Arc::new(Mutex::new(file)));
-                reply.opened(fh, flags);
-                debug!("<-- open {:?} = {}", inode.get_path(), fh);
-            }
-            Err(e) => {
-                reply.error(error::errno(&e));
-                error!("<-- !open {:?} = {}", inode.get_path(), e);
-            }
+        let parent_inode: Arc<RwLock<Inode>>;
+        {
+            let store = self.store.read().unwrap();
+            parent_inode = store.get(parent);
         }
-    }
 
-    pub fn read(&mut self, _ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        let file: Arc<Mutex<file::Handle>>;
-        {
-            let fh_store = self.fh_store.lock().unwrap();
-            file = fh_store.handles.get(&fh).unwrap().clone();
+        if self.whiteouts.is_none() && self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
         }
-        // TODO spawn a thread
-        let mut buf: Vec<u8> = vec![0; size as usize];
-        let mut file = file.lock().unwrap();
-        match file.read(offset, &mut buf) {
-            Ok(nread) => {
-                reply.data(&buf[..nread]);
-            }
-            Err(e) => {
-                debug!("<-- !read {} = {}", fh, e);
-                reply.error(e.raw_os_error().unwrap());
+
+        let parent_inode = parent_inode.read().unwrap();
+        let res = match self.whiteouts {
+            Some(ref w) => parent_inode.whiteout(&name, w),
+            None => parent_inode.rmdir(&name),
+        };
+        if let Err(e) = res {
+            let errno = error::os_errno(&e);
+            oplog!(
+                "rmdir",
+                { path: parent_inode.get_child_name(&name), errno: errno },
+                "<-- !rmdir {:?}/{:?} = {}",
+                parent_inode.get_path(),
+                name,
+                e
+            );
+            self.src_failed(errno);
+            reply.error(errno);
+        } else {
+            let path = parent_inode.get_child_name(&name);
+            oplog!(
+                "rmdir",
+                { path: &path },
+                "<-- rmdir {:?}/{:?}",
+                parent_inode.get_path(),
+                name
+            );
+            self.dir_changed(&path);
+            if let Some(ref dir_cache) = self.dir_cache {
+                dir_cache.invalidate_tree(&path);
             }
+            self.remove_path(&path);
+            reply.ok();
         }
     }
 
-    pub fn create(
+    pub fn mkdir(
         &mut self,
         parent: u64,
         name: OsString,
         mode: u32,
-        flags: u32,
-        reply: ReplyCreate,
+        uid: u32,
+        gid: u32,
+        reply: ReplyEntry,
     ) {
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::EEXIST);
+            return;
+        }
         let parent_inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
+            let store = self.store.read().unwrap();
             parent_inode = store.get(parent);
         }
 
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let parent_inode = parent_inode.read().unwrap();
-        match parent_inode.create(&name, mode as libc::mode_t) {
-            Ok((inode, file)) => {
-                let fh: u64;
-                {
-                    let mut fh_store = self.fh_store.lock().unwrap();
-                    fh = fh_store.next_id;
-                    fh_store.next_id += 1;
-                    fh_store.handles.insert(fh, Arc::new(Mutex::new(file)));
+        let path = parent_inode.get_child_name(&name);
+        let was_whiteout = match self.remove_whiteout(&path) {
+            Ok(b) => b,
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(Error, "mkdir", { path: &path, errno: errno }, "<-- !mkdir {:?} = {}", path, e);
+                reply.error(errno);
+                return;
+            }
+        };
+        let mut res = parent_inode.mkdir(&name, mode as libc::mode_t);
+        if was_whiteout {
+            if let Err(ref e) = res {
+                if e.raw_os_error() == Some(libc::EEXIST) {
+                    // src still has the old directory, reuse it
+                    res = parent_inode.lookup(&name).and_then(|inode| {
+                        inode.whiteout_children(self.whiteouts.as_ref().unwrap())?;
+                        return Ok(inode);
+                    });
                 }
-
-                let attr = *inode.get_attr();
-                debug!("<-- create {:?} = {}", inode.get_path(), fh);
-                self.insert_inode(inode);
-                reply.created(&self.ttl_now(), &attr, 0, fh, flags);
+            }
+        }
+        match res {
+            Ok(mut inode) => {
+                self.set_owner(&mut inode, uid, gid);
+                self.dir_changed(inode.get_path());
+                oplog!(
+                    "mkdir",
+                    { path: &path, ino: inode.get_ino() },
+                    "<-- mkdir {:?}/{:?}",
+                    parent_inode.get_path(),
+                    name
+                );
+                let (attr, generation) = self.insert_inode(inode, true);
+                reply.entry(&self.ttl_now(), &attr, generation);
             }
             Err(e) => {
-                error!(
-                    "<-- !create {:?} = {}",
-                    parent_inode.get_child_name(&name),
+                let errno = error::os_errno(&e);
+                oplog!(
+                    "mkdir",
+                    { path: &path, errno: errno },
+                    "<-- !mkdir {:?}/{:?} = {}",
+                    parent_inode.get_path(),
+                    name,
                     e
                 );
-                reply.error(e.raw_os_error().unwrap());
+                self.src_failed(e.errno());
+                reply.error(errno);
             }
         }
     }
 
-    pub fn write(
+    pub fn mknod(
         &mut self,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: Vec<u8>,
-        _flags: u32,
-        reply: ReplyWrite,
+        parent: u64,
+        name: OsString,
+        mode: u32,
+        rdev: u32,
+        uid: u32,
+        gid: u32,
+        reply: ReplyEntry,
     ) {
-        let nwritten: usize;
-        {
-            let fh_store = self.fh_store.lock().unwrap();
-            let file = fh_store.handles.get(&fh).unwrap();
-            let mut file = file.lock().unwrap();
-            // TODO spawn a thread
-            loop {
-                match file.write(offset, &data) {
-                    Ok(nbytes) => {
-                        nwritten = nbytes;
-                        break;
-                    }
-                    Err(e) => {
-                        if e.errno() == libc::ENOTSUP {
-                            debug!("write rejected, reopening for sequential write");
-                            // the src filesystem rejected our write,
-                            // maybe because this is random
-                            // write. reopen the src and try again
-                            let inode: Arc<RwLock<Inode>>;
-
-                            {
-                                let store = self.store.lock().unwrap();
-                                inode = store.get(ino);
-                            }
-                            let inode = inode.read().unwrap();
-
-                            if let Err(e2) = inode.reopen_src(&mut file) {
-                                reply.error(e2.raw_os_error().unwrap());
-                                return;
-                            }
-                        } else if e.errno() == libc::ENOSPC {
-                            debug!(
-                                "write(0x{:016x}, 0x{:016x}, {}) = ENOSPC",
-                                ino,
-                                fh,
-                                data.len()
-                            );
-                            let _ = Evicter::new(self.cache_dir, &DiskSpace::Percent(1.0))
-                                .loop_once();
-                        } else {
-                            error!(
-                                "<-- !write 0x{:016x} {:?} @ {} = {}",
-                                fh,
-                                OsStr::from_bytes(&data[..cmp::min(32, data.len())]),
-                                offset,
-                                e
-                            );
-                            reply.error(e.raw_os_error().unwrap());
-                            return;
-                        }
-                    }
-                }
-            }
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::EEXIST);
+            return;
         }
-
-        let inode: Arc<RwLock<Inode>>;
+        let parent_inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
-            inode = store.get(ino);
+            let store = self.store.read().unwrap();
+            parent_inode = store.get(parent);
         }
-        let mut inode = inode.write().unwrap();
-        inode.extend((offset as u64) + (nwritten as u64));
-        reply.written(nwritten as u32);
-    }
-
-    pub fn flush(&mut self, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        let s = make_self(self);
-        self.tp.lock().unwrap().execute(move || {
-            let flushed_to_src: bool;
-            let inode: Arc<RwLock<Inode>>;
-            {
-                // first flush locally
-                let file: Arc<Mutex<file::Handle>>;
-                {
-                    let fh_store = s.fh_store.lock().unwrap();
-                    file = fh_store.handles.get(&fh).unwrap().clone();
-                    let store = s.store.lock().unwrap();
-                    inode = store.get(ino);
-                }
 
-                let mut file = file.lock().unwrap();
-                match file.flush() {
-                    Ok(b) => flushed_to_src = b,
-                    Err(e) => {
-                        let mut inode = inode.write().unwrap();
-                        inode.flush_failed();
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-                        error!("<-- !flush {:016x} = {}", fh, e);
-                        reply.error(error::errno(&e));
-                        return;
-                    }
-                }
+        let parent_inode = parent_inode.read().unwrap();
+        let path = parent_inode.get_child_name(&name);
+        let was_whiteout = match self.remove_whiteout(&path) {
+            Ok(b) => b,
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(Error, "mknod", { path: &path, errno: errno }, "<-- !mknod {:?} = {}", path, e);
+                reply.error(errno);
+                return;
             }
-
-            if flushed_to_src {
-                let mut inode = inode.write().unwrap();
-                inode.flushed();
-
-                // refresh attr with the original file so it will be consistent with lookup
-                if let Err(e) = inode.refresh() {
-                    error!("<-- !flush {:?} = {}", inode.get_path(), e);
-                    reply.error(error::errno(&e));
-                    return;
-                }
-                debug!("<-- flush {:?}", inode.get_path());
-            } else {
-                let mut inode = inode.write().unwrap();
-                inode.flushed();
-                debug!("<-- flush ino: {:016x} fh: {}", ino, fh);
+        };
+        match parent_inode.mknod(&name, mode as libc::mode_t, rdev, was_whiteout) {
+            Ok(mut inode) => {
+                self.set_owner(&mut inode, uid, gid);
+                self.dir_changed(inode.get_path());
+                oplog!(
+                    "mknod",
+                    { path: &path, ino: inode.get_ino() },
+                    "<-- mknod {:?} 0{:o}",
+                    path,
+                    mode
+                );
+                let (attr, generation) = self.insert_inode(inode, true);
+                reply.entry(&self.ttl_now(), &attr, generation);
             }
-
-            reply.ok();
-        });
+            Err(e) => {
+                let errno = error::os_errno(&e);
+                oplog!(
+                    "mknod",
+                    { path: &path, errno: errno },
+                    "<-- !mknod {:?} 0{:o} = {}",
+                    path,
+                    mode,
+                    e
+                );
+                self.src_failed(e.errno());
+                reply.error(errno);
+            }
+        }
     }
 
-    pub fn release(
+    pub fn symlink(
         &mut self,
-        _ino: u64,
-        fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
-        reply: ReplyEmpty,
+        parent: u64,
+        name: OsString,
+        link: PathBuf,
+        uid: u32,
+        gid: u32,
+        reply: ReplyEntry,
     ) {
-        let mut fh_store = self.fh_store.lock().unwrap();
-        // the handle will be destroyed and closed
-        fh_store.handles.remove(&fh);
-        reply.ok();
-    }
-
-    pub fn unlink(&mut self, parent: u64, name: OsString, reply: ReplyEmpty) {
+        if self.is_stats_name(parent, &name) {
+            reply.error(libc::EEXIST);
+            return;
+        }
         let parent_inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
+            let store = self.store.read().unwrap();
             parent_inode = store.get(parent);
         }
 
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let parent_inode = parent_inode.read().unwrap();
         let path = parent_inode.get_child_name(&name);
-        if let Err(e) = parent_inode.unlink(&name) {
-            debug!("<-- !unlink {:?} = {}", path, e);
-            reply.error(e.raw_os_error().unwrap());
-        } else {
-            self.remove_path(&path);
-            debug!("<-- unlink {:?}", path);
-            reply.ok();
+        let was_whiteout = match self.remove_whiteout(&path) {
+            Ok(b) => b,
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(Error, "symlink", { path: &path, errno: errno }, "<-- !symlink {:?} = {}", path, e);
+                reply.error(errno);
+                return;
+            }
+        };
+        match parent_inode.symlink(&name, &link, was_whiteout) {
+            Ok(mut inode) => {
+                self.set_owner(&mut inode, uid, gid);
+                self.dir_changed(inode.get_path());
+                oplog!(
+                    "symlink",
+                    { path: &path, ino: inode.get_ino() },
+                    "<-- symlink {:?} -> {:?}",
+                    path,
+                    link
+                );
+                let (attr, generation) = self.insert_inode(inode, true);
+                reply.entry(&self.ttl_now(), &attr, generation);
+            }
+            Err(e) => {
+                let errno = error::os_errno(&e);
+                oplog!(
+                    "symlink",
+                    { path: &path, errno: errno },
+                    "<-- !symlink {:?} -> {:?} = {}",
+                    path,
+                    link,
+                    e
+                );
+                self.src_failed(e.errno());
+                reply.error(errno);
+            }
         }
     }
 
-    pub fn rmdir(&mut self, parent: u64, name: OsString, reply: ReplyEmpty) {
+    // the file is then known by its new name, same as when src
+    // gets a hard link behind our back and it's looked up there
+    pub fn link(&mut self, ino: u64, newparent: u64, newname: OsString, reply: ReplyEntry) {
+        if self.is_stats_file(ino) || self.is_stats_name(newparent, &newname) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let inode: Arc<RwLock<Inode>>;
         let parent_inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
-            parent_inode = store.get(parent);
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
+            parent_inode = store.get(newparent);
         }
 
-        let parent_inode = parent_inode.read().unwrap();
-        if let Err(e) = parent_inode.rmdir(&name) {
-            debug!(
-                "<-- !rmdir {:?}/{:?} = {}",
-                parent_inode.get_path(),
-                name,
-                e
-            );
-            reply.error(e.raw_os_error().unwrap());
-        } else {
-            debug!("<-- rmdir {:?}/{:?}", parent_inode.get_path(), name);
-            self.remove_path(&parent_inode.get_path().join(name));
-            reply.ok();
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let new_path = parent_inode.read().unwrap().get_child_name(&newname);
+        let was_whiteout = match self.remove_whiteout(&new_path) {
+            Ok(b) => b,
+            Err(e) => {
+                let errno = error::errno(&e);
+                oplog!(Error, "link", { path: &new_path, errno: errno }, "<-- !link {:?} = {}", new_path, e);
+                reply.error(errno);
+                return;
+            }
+        };
+        // insert_inode needs the lock on inode
+        let old_path: PathBuf;
+        let res = {
+            let inode = inode.read().unwrap();
+            old_path = inode.get_path().to_path_buf();
+            inode.link(&newname, &new_path, was_whiteout)
+        };
+        match res {
+            Ok(new_inode) => {
+                oplog!(
+                    "link",
+                    { path: &new_path, ino: ino },
+                    "<-- link {:?} nlink {}",
+                    new_path,
+                    new_inode.get_attr().nlink
+                );
+                self.dir_changed(&new_path);
+                let (attr, generation) = self.insert_inode(new_inode, false);
+                // still known by the old name too
+                let mut store = self.store.write().unwrap();
+                store.inodes_cache.insert(old_path, ino);
+                reply.entry(&self.ttl_now(), &attr, generation);
+            }
+            Err(e) => {
+                let errno = error::os_errno(&e);
+                oplog!(
+                    "link",
+                    { path: &new_path, ino: ino, errno: errno },
+                    "<-- !link {} {:?} = {}",
+                    ino,
+                    new_path,
+                    e
+                );
+                self.src_failed(e.errno());
+                reply.error(errno);
+            }
         }
     }
 
-    pub fn mkdir(&mut self, parent: u64, name: OsString, mode: u32, reply: ReplyEntry) {
-        let parent_inode: Arc<RwLock<Inode>>;
+    pub fn readlink(&mut self, ino: u64, reply: ReplyData) {
+        if self.is_stats_file(ino) {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
-            parent_inode = store.get(parent);
+            let store = self.store.read().unwrap();
+            inode = store.get(ino);
         }
 
-        let parent_inode = parent_inode.read().unwrap();
-        match parent_inode.mkdir(&name, mode as libc::mode_t) {
-            Ok(inode) => {
-                debug!("<-- mkdir {:?}/{:?}", parent_inode.get_path(), name);
-                let attr = *inode.get_attr();
-                self.insert_inode(inode);
-                reply.entry(&self.ttl_now(), &attr, 0);
+        let inode = inode.read().unwrap();
+        match inode.readlink() {
+            Ok(target) => {
+                oplog!(
+                    "readlink",
+                    { path: inode.get_path(), ino: ino },
+                    "<-- readlink {:?} = {:?}",
+                    inode.get_path(),
+                    target
+                );
+                reply.data(target.as_os_str().as_bytes());
             }
             Err(e) => {
-                debug!(
-                    "<-- !mkdir {:?}/{:?} = {}",
-                    parent_inode.get_path(),
-                    name,
+                let errno = error::errno(&e);
+                oplog!(
+                    "readlink",
+                    { path: inode.get_path(), ino: ino, errno: errno },
+                    "<-- !readlink {:?} = {}",
+                    inode.get_path(),
                     e
                 );
-                reply.error(e.raw_os_error().unwrap());
+                reply.error(errno);
             }
         }
     }
@@ -869,13 +3613,22 @@ impl CatFS {
         newname: OsString,
         reply: ReplyEmpty,
     ) {
+        if self.is_stats_name(parent, &name) || self.is_stats_name(newparent, &newname) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if self.is_src_readonly() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
         let inode: Arc<RwLock<Inode>>;
         let path: PathBuf;
         let new_path: PathBuf;
         let parent_inode: Arc<RwLock<Inode>>;
         let new_parent_inode: Arc<RwLock<Inode>>;
         {
-            let store = self.store.lock().unwrap();
+            let store = self.store.read().unwrap();
             parent_inode = store.get(parent);
             new_parent_inode = store.get(newparent);
         }
@@ -889,21 +3642,210 @@ impl CatFS {
         }
 
         {
-            let mut store = self.store.lock().unwrap();
-            match store.get_mut_by_path(&path) {
+            let store = self.store.read().unwrap();
+            match store.get_by_path(&path) {
                 Some(i) => inode = i,
                 None => panic!("rename source not in inode cache: {:?}", path),
             }
         }
 
+        if let Err(e) = self.remove_whiteout(&new_path) {
+            let errno = error::errno(&e);
+            oplog!(
+                Error,
+                "rename",
+                { path: &path, errno: errno },
+                "<-- !rename {:?} -> {:?} = {}",
+                path,
+                new_path,
+                e
+            );
+            reply.error(errno);
+            return;
+        }
+
         let mut inode = inode.write().unwrap();
+        // a hard link may be known by another of its names
+        if inode.get_path() != path {
+            inode.use_path(&path);
+        }
         if let Err(e) = inode.rename(&newname, &new_path) {
-            debug!("<-- !rename {:?} -> {:?} = {}", path, new_path, e);
-            reply.error(e.raw_os_error().unwrap());
+            let errno = error::os_errno(&e);
+            oplog!(
+                "rename",
+                { path: &path, ino: inode.get_ino(), errno: errno },
+                "<-- !rename {:?} -> {:?} = {}",
+                path,
+                new_path,
+                e
+            );
+            self.src_failed(e.errno());
+            reply.error(errno);
         } else {
-            debug!("<-- rename {:?} -> {:?}", path, new_path);
+            oplog!(
+                "rename",
+                { path: &path, ino: inode.get_ino() },
+                "<-- rename {:?} -> {:?}",
+                path,
+                new_path
+            );
+            self.dir_changed(&path);
+            self.dir_changed(&new_path);
+            if let Some(ref dir_cache) = self.dir_cache {
+                dir_cache.invalidate_tree(&path);
+                dir_cache.invalidate_tree(&new_path);
+            }
             self.replace_path(&path, new_path);
             reply.ok();
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::mem;
+    use std::thread;
+    use super::*;
+
+    fn new_fs() -> (PathBuf, CatFS) {
+        let prefix = tests::copy_resources();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cat = CatFS::new(&prefix.join("resources"), &cache).unwrap();
+        return (prefix, cat);
+    }
+
+    fn read_all(cat: &CatFS, inode: &mut Inode) -> Vec<u8> {
+        let mut f = inode
+            .open(rlibc::O_RDONLY, cat.cache_valid_ttl, &cat.tp, &cat.open_options())
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let n = f.read(0, &mut buf).unwrap();
+        return buf[..n].to_vec();
+    }
+
+    #[test]
+    fn readonly_downgrade() {
+        let append = libc::O_APPEND as u32;
+        assert_eq!(readonly_flags(rlibc::O_RDONLY), Some(rlibc::O_RDONLY));
+        assert_eq!(readonly_flags(rlibc::O_RDWR), Some(rlibc::O_RDONLY));
+        assert_eq!(readonly_flags(rlibc::O_WRONLY | append), Some(rlibc::O_RDONLY | append));
+        assert_eq!(readonly_flags(rlibc::O_RDWR | rlibc::O_TRUNC), None);
+        assert_eq!(readonly_flags(rlibc::O_WRONLY | rlibc::O_TRUNC), None);
+    }
+
+    #[test]
+    fn src_erofs() {
+        let (prefix, mut cat) = new_fs();
+        assert!(!cat.is_src_readonly());
+        cat.src_failed(libc::EACCES);
+        assert!(!cat.is_src_readonly());
+        cat.src_failed(libc::EROFS);
+        assert!(cat.is_src_readonly());
+        // reads only, open and write refuse writes themselves
+        assert!(!cat.open_options().src_readonly);
+
+        // writes go to the cache, src is only read
+        cat.set_write_back(StdDuration::from_secs(60));
+        assert!(cat.open_options().src_readonly);
+        drop(cat);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn replaced_generation() {
+        let (prefix, mut cat) = new_fs();
+        let lookup = |cat: &mut CatFS| {
+            let root = cat.get_inode(fuse::FUSE_ROOT_ID);
+            let inode = root.read().unwrap().lookup(OsStr::new("file1")).unwrap();
+            cat.insert_inode(inode, false)
+        };
+
+        let (attr, generation) = lookup(&mut cat);
+        // the same file keeps its generation
+        let (attr2, generation2) = lookup(&mut cat);
+        assert_eq!(attr2.ino, attr.ino);
+        assert_eq!(generation2, generation);
+
+        let resources = prefix.join("resources");
+        fs::write(resources.join("file1.new"), b"new\n").unwrap();
+        fs::rename(resources.join("file1.new"), resources.join("file1")).unwrap();
+        let (attr3, generation3) = lookup(&mut cat);
+        assert!(attr3.ino != attr.ino);
+        assert!(generation3 > generation);
+        drop(cat);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn cache_valid_ttl() {
+        let (prefix, mut cat) = new_fs();
+        cat.set_cache_valid_ttl(Duration::milliseconds(500));
+        let root = cat.get_inode(fuse::FUSE_ROOT_ID);
+        let mut inode = root.read().unwrap().lookup(OsStr::new("file1")).unwrap();
+        assert_eq!(read_all(&cat, &mut inode), b"file1\n");
+
+        // what's cached is trusted for a while
+        fs::write(prefix.join("resources/file1"), b"changed\n").unwrap();
+        assert_eq!(read_all(&cat, &mut inode), b"file1\n");
+
+        // and then checked against src again
+        thread::sleep(StdDuration::from_millis(600));
+        assert_eq!(read_all(&cat, &mut inode), b"changed\n");
+        drop(inode);
+        drop(cat);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn statfs_from() {
+        let (prefix, mut cat) = new_fs();
+        let cache = rlibc::fstatvfs(cat.cache_dir).unwrap();
+        let src = cat.src.statvfs().unwrap();
+        // free space moves around, the size doesn't
+        let size = |st: &rlibc::statvfs64| (st.f_blocks as u64, st.f_frsize as u64, st.f_files as u64);
+
+        assert_eq!(cat.statfs_from, StatfsFrom::Cache);
+        assert_eq!(size(&cat.statvfs().unwrap()), size(&cache));
+        cat.set_statfs_from(StatfsFrom::Source);
+        assert_eq!(size(&cat.statvfs().unwrap()), size(&src));
+        cat.set_statfs_from(StatfsFrom::Min);
+        assert_eq!(size(&cat.statvfs().unwrap()), size(&min_statvfs(&cache, &src)));
+        drop(cat);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn min_statvfs_scales() {
+        let mut a: rlibc::statvfs64 = unsafe { mem::zeroed() };
+        a.f_frsize = 4096;
+        a.f_blocks = 1000;
+        a.f_bfree = 100;
+        a.f_bavail = 50;
+        a.f_files = 10;
+        a.f_ffree = 5;
+        a.f_namemax = 255;
+        let mut b = a;
+        b.f_frsize = 1024;
+        b.f_blocks = 2000;
+        b.f_bfree = 800;
+        b.f_bavail = 800;
+        b.f_files = 20;
+        b.f_ffree = 1;
+        b.f_namemax = 143;
+
+        // counted in a's fragments, whichever is smaller
+        let st = min_statvfs(&a, &b);
+        assert_eq!(st.f_frsize, 4096);
+        assert_eq!(st.f_blocks, 500);
+        assert_eq!(st.f_bfree, 100);
+        assert_eq!(st.f_bavail, 50);
+        assert_eq!(st.f_files, 10);
+        assert_eq!(st.f_ffree, 1);
+        assert_eq!(st.f_namemax, 143);
+
+        a.f_frsize = 0;
+        assert_eq!(min_statvfs(&a, &b).f_blocks, 0);
+    }
+}
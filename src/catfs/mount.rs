@@ -0,0 +1,382 @@
+extern crate fuse;
+extern crate libc;
+extern crate time;
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use catfs::{CatFS, StateDumper, Stats};
+use catfs::backend::SourceBackend;
+use catfs::error;
+use catfs::flags::DiskSpace;
+use catfs::rlibc;
+use evicter::Evicter;
+use pcatfs;
+use pcatfs::PCatFS;
+
+// how long to wait for the kernel to talk to a new mount
+const READY_TIMEOUT_SECS: u64 = 10;
+
+/// A catfs that's mounted and served by a thread in this process,
+/// dropping it unmounts.
+pub struct Mount {
+    mountpoint: PathBuf,
+    session: Option<thread::JoinHandle<io::Result<()>>>,
+    mounted: Arc<AtomicBool>,
+    // boxed because a running evicter can't move
+    evicter: Option<Box<Evicter>>,
+    state: StateDumper,
+    cache_dir: RawFd,
+}
+
+/// Sets up a catfs for programs that embed it instead of running the
+/// catfs binary. The mount is served by threads in this process and
+/// goes away when the returned `Mount` is dropped:
+///
+/// ```ignore
+/// let m = catfs::MountBuilder::new(&"/data", &"/var/cache/data")
+///     .ttl(Duration::from_secs(60))
+///     .free_space(DiskSpace::Percent(10.0))
+///     .mount(&"/mnt/data")?;
+/// ```
+pub struct MountBuilder {
+    from: PathBuf,
+    cache: PathBuf,
+    src: Option<Arc<dyn SourceBackend>>,
+    options: Vec<OsString>,
+    free: DiskSpace,
+    ttl: Option<Duration>,
+    readahead: Option<usize>,
+    threads: usize,
+}
+
+impl MountBuilder {
+    /// Caches the directory `from` in the directory `cache`, both of
+    /// which have to exist.
+    pub fn new(from: &dyn AsRef<Path>, cache: &dyn AsRef<Path>) -> MountBuilder {
+        return MountBuilder {
+            from: from.as_ref().to_path_buf(),
+            cache: cache.as_ref().to_path_buf(),
+            src: None,
+            options: Vec::new(),
+            free: DiskSpace::Bytes(0),
+            ttl: None,
+            readahead: None,
+            threads: pcatfs::DEFAULT_THREADS,
+        };
+    }
+
+    /// Reads from `src` instead of the `from` directory, which then
+    /// only names the source in logs.
+    pub fn backend(mut self, src: Arc<dyn SourceBackend>) -> MountBuilder {
+        self.src = Some(src);
+        return self;
+    }
+
+    /// Passes a mount option such as `allow_other` to fuse.
+    pub fn option(mut self, option: &dyn AsRef<OsStr>) -> MountBuilder {
+        self.options.push(OsString::from("-o"));
+        self.options.push(option.as_ref().to_os_string());
+        return self;
+    }
+
+    /// How long the kernel can keep attributes and lookups, like
+    /// `--ttl`.
+    pub fn ttl(mut self, ttl: Duration) -> MountBuilder {
+        self.ttl = Some(ttl);
+        return self;
+    }
+
+    /// How much space to keep free in the cache directory, like
+    /// `--free`. Nothing is evicted by default.
+    pub fn free_space(mut self, free: DiskSpace) -> MountBuilder {
+        self.free = free;
+        return self;
+    }
+
+    /// How much to copy from the source at a time, like `--readahead`.
+    pub fn readahead(mut self, readahead: usize) -> MountBuilder {
+        self.readahead = Some(readahead);
+        return self;
+    }
+
+    /// How many requests are served at the same time.
+    pub fn threads(mut self, threads: usize) -> MountBuilder {
+        self.threads = threads;
+        return self;
+    }
+
+    /// Mounts at `mountpoint` and returns once the kernel is sending
+    /// requests.
+    pub fn mount(self, mountpoint: &dyn AsRef<Path>) -> error::Result<Mount> {
+        let mut fs = match self.src {
+            Some(src) => CatFS::with_backend(src, &self.from, &self.cache)?,
+            None => CatFS::new(&self.from, &self.cache)?,
+        };
+        if let Some(ttl) = self.ttl {
+            fs.set_ttl(time::Duration::from_std(ttl).unwrap_or(time::Duration::max_value()));
+        }
+        if let Some(readahead) = self.readahead {
+            fs.set_readahead(readahead);
+        }
+
+        let options: Vec<&OsStr> = self.options.iter().map(|o| o.as_os_str()).collect();
+        return fs.spawn_mount_with_threads(mountpoint, &options, &self.free, self.threads);
+    }
+}
+
+impl CatFS {
+    // mounts at mountpoint and returns once the kernel is sending
+    // requests. free is how much space the evicter keeps free in the
+    // cache, DiskSpace::Bytes(0) to never evict
+    #[allow(dead_code)]
+    pub fn spawn_mount(
+        self,
+        mountpoint: &dyn AsRef<Path>,
+        options: &[&OsStr],
+        free: &DiskSpace,
+    ) -> error::Result<Mount> {
+        return self.spawn_mount_with_threads(mountpoint, options, free, pcatfs::DEFAULT_THREADS);
+    }
+
+    pub fn spawn_mount_with_threads(
+        mut self,
+        mountpoint: &dyn AsRef<Path>,
+        options: &[&OsStr],
+        free: &DiskSpace,
+        threads: usize,
+    ) -> error::Result<Mount> {
+        let cache_dir = self.get_cache_dir()?;
+        let mut ev = Box::new(Evicter::new(cache_dir, free));
+        ev.set_src(self.src.clone());
+        self.set_evicter(ev.handle());
+        let state = self.state_dumper();
+        let ready = self.ready.clone();
+
+        let mut session = match fuse::Session::new(
+            PCatFS::with_threads(self, threads),
+            mountpoint.as_ref(),
+            options,
+        ) {
+            Ok(session) => session,
+            Err(e) => {
+                drop(ev);
+                rlibc::close(cache_dir)?;
+                return Err(e.into());
+            }
+        };
+
+        let mounted = Arc::new(AtomicBool::new(true));
+        let mounted2 = mounted.clone();
+        let ready2 = ready.clone();
+        let t = thread::Builder::new()
+            .name(String::from("session"))
+            .spawn(move || {
+                let res = session.run();
+                if let Err(ref e) = res {
+                    error!("session.run() = {}", e);
+                }
+                info!("{:?} unmounted", session.mountpoint());
+                drop(session);
+                mounted2.store(false, Ordering::SeqCst);
+                // wake up spawn_mount if we never got to init
+                let &(ref lock, ref cv) = &*ready2;
+                let _guard = lock.lock().unwrap();
+                cv.notify_all();
+                return res;
+            });
+
+        let mut m = Mount {
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            session: None,
+            mounted: mounted,
+            evicter: None,
+            state: state,
+            cache_dir: cache_dir,
+        };
+        match t {
+            Ok(t) => m.session = Some(t),
+            Err(e) => {
+                // the session went away with the closure, which
+                // unmounted it
+                m.mounted.store(false, Ordering::SeqCst);
+                m.shutdown()?;
+                return Err(e.into());
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(READY_TIMEOUT_SECS);
+        {
+            let &(ref lock, ref cv) = &*ready;
+            let mut is_ready = lock.lock().unwrap();
+            while !*is_ready && m.mounted.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                is_ready = cv.wait_timeout(is_ready, deadline - now).unwrap().0;
+            }
+            if !*is_ready {
+                drop(is_ready);
+                error!("{:?} didn't become ready", m.mountpoint);
+                if m.mounted.load(Ordering::SeqCst) {
+                    m.unmount(true)?;
+                    return error::propagate(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+                } else {
+                    // the session failed, hand out why
+                    return m.unmount(false).and_then(|_| {
+                        error::propagate(io::Error::from_raw_os_error(libc::EIO))
+                    });
+                }
+            }
+        }
+
+        ev.run();
+        m.evicter = Some(ev);
+        debug!("<-- spawn_mount {:?}", m.mountpoint);
+        return Ok(m);
+    }
+}
+
+#[allow(dead_code)]
+impl Mount {
+    pub fn mountpoint(&self) -> &Path {
+        return &self.mountpoint;
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        return self.mounted.load(Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> Stats {
+        return self.state.stats();
+    }
+
+    pub fn dump(&self) {
+        self.state.dump();
+    }
+
+    // returns the mount is busy without doing anything unless force is
+    // set, otherwise waits for the session to finish and returns its
+    // error if it failed
+    pub fn unmount(&mut self, force: bool) -> error::Result<()> {
+        if let Some(t) = self.session.take() {
+            if self.mounted.load(Ordering::SeqCst) {
+                if let Err(e) = unmount(&self.mountpoint, force) {
+                    self.session = Some(t);
+                    return error::propagate(e);
+                }
+            }
+            let res = t.join();
+            self.shutdown()?;
+            match res {
+                Ok(res) => res?,
+                Err(_) => return error::propagate(io::Error::from_raw_os_error(libc::EIO)),
+            }
+        }
+        return Ok(());
+    }
+
+    // the session has to be gone before the evicter and the cache dir
+    // it's using
+    fn shutdown(&mut self) -> error::Result<()> {
+        self.evicter = None;
+        if self.cache_dir != -1 {
+            let cache_dir = self.cache_dir;
+            self.cache_dir = -1;
+            rlibc::close(cache_dir)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Err(e) = self.unmount(false) {
+            error!("unable to unmount {:?}: {}", self.mountpoint, e);
+            if let Err(e) = self.unmount(true) {
+                // leave the cache dir open, the session may still be
+                // using it
+                error!("unable to unmount {:?}: {}", self.mountpoint, e);
+            }
+        }
+    }
+}
+
+/// Unmount an arbitrary mount point
+pub fn unmount(mountpoint: &Path, force: bool) -> io::Result<()> {
+    // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
+    // it attempts to call realpath, which in turn calls into the filesystem. So
+    // if the filesystem returns an error, the unmount does not take place, with
+    // no indication of the error available to the caller. So we call unmount
+    // directly, which is what osxfuse does anyway, since we already converted
+    // to the real path when we first mounted.
+
+    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
+                target_os = "openbsd", target_os = "bitrig", target_os = "netbsd"))]
+    #[inline]
+    fn libc_umount(mnt: &CStr, force: bool) -> c_int {
+        let flags = if force { libc::MNT_FORCE } else { 0 };
+        unsafe { libc::unmount(mnt.as_ptr(), flags) }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
+                      target_os = "openbsd", target_os = "bitrig", target_os = "netbsd")))]
+    #[inline]
+    fn libc_umount(mnt: &CStr, force: bool) -> c_int {
+        use std::io::ErrorKind::PermissionDenied;
+        use std::process;
+
+        let rc = if force {
+            unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_FORCE | libc::MNT_DETACH) }
+        } else {
+            unsafe { libc::umount(mnt.as_ptr()) }
+        };
+        if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
+            // Linux always returns EPERM for non-root users.  We have to let the
+            // library go through the setuid-root "fusermount -u" to unmount.
+            if force {
+                // the library can't do a lazy unmount, so ask fusermount
+                // directly
+                let status = process::Command::new("fusermount")
+                    .arg("-u")
+                    .arg("-z")
+                    .arg(OsStr::from_bytes(mnt.to_bytes()))
+                    .status();
+                match status {
+                    Ok(ref s) if s.success() => 0,
+                    _ => -1,
+                }
+            } else {
+                unsafe {
+                    fuse_unmount_compat22(mnt.as_ptr());
+                }
+                0
+            }
+        } else {
+            rc
+        }
+    }
+
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let rc = libc_umount(&mnt, force);
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
+                  target_os = "openbsd", target_os = "bitrig", target_os = "netbsd")))]
+extern "system" {
+    pub fn fuse_unmount_compat22(mountpoint: *const libc::c_char);
+}
@@ -0,0 +1,174 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// replies are logged to this target, so the json formatter knows
+// their message is already a list of fields
+pub const TARGET: &'static str = "catfs::catfs::op";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static STARTED: Cell<Option<Instant>> = Cell::new(None);
+}
+
+// logs the reply to a request, at debug unless a level is given, ex:
+// oplog!("lookup", { path: &path, errno: libc::ENOENT }, "<-- !lookup {:?} = whiteout", path);
+// with --log-format json the fields are logged along with the message
+macro_rules! oplog {
+    ($name:expr, { $($key:ident : $val:expr),* $(,)* }, $($arg:tt)+) => {
+        oplog!(Debug, $name, { $($key: $val),* }, $($arg)+)
+    };
+    ($lvl:ident, $name:expr, { $($key:ident : $val:expr),* $(,)* }, $($arg:tt)+) => {
+        if log_enabled!(::log::LogLevel::$lvl) {
+            if $crate::catfs::oplog::is_json() {
+                #[allow(unused_mut)]
+                let mut op = $crate::catfs::oplog::Op::new($name);
+                $( op.$key($val); )*
+                log!(
+                    target: $crate::catfs::oplog::TARGET,
+                    ::log::LogLevel::$lvl,
+                    "{}",
+                    op.fields(&format!($($arg)+))
+                );
+            } else {
+                log!(::log::LogLevel::$lvl, $($arg)+);
+            }
+        }
+    };
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    return JSON.load(Ordering::Relaxed);
+}
+
+// called when a thread starts serving a request, so its reply can
+// say how long it took
+pub fn start() {
+    STARTED.with(|s| s.set(Some(Instant::now())));
+}
+
+pub struct Op {
+    name: &'static str,
+    path: Option<String>,
+    ino: Option<u64>,
+    fh: Option<u64>,
+    errno: Option<i32>,
+}
+
+impl Op {
+    pub fn new(name: &'static str) -> Op {
+        return Op {
+            name: name,
+            path: None,
+            ino: None,
+            fh: None,
+            errno: None,
+        };
+    }
+
+    pub fn path<P: AsRef<Path>>(&mut self, path: P) {
+        self.path = Some(path.as_ref().to_string_lossy().into_owned());
+    }
+
+    pub fn ino(&mut self, ino: u64) {
+        self.ino = Some(ino);
+    }
+
+    pub fn fh(&mut self, fh: u64) {
+        self.fh = Some(fh);
+    }
+
+    pub fn errno(&mut self, errno: i32) {
+        self.errno = Some(errno);
+    }
+
+    // the fields of a json object, without the braces
+    pub fn fields(&self, msg: &str) -> String {
+        let mut s = String::from("\"op\":");
+        quote(&mut s, self.name);
+        if let Some(ref path) = self.path {
+            s.push_str(",\"path\":");
+            quote(&mut s, path);
+        }
+        if let Some(ino) = self.ino {
+            let _ = write!(s, ",\"ino\":{}", ino);
+        }
+        if let Some(fh) = self.fh {
+            let _ = write!(s, ",\"fh\":{}", fh);
+        }
+        if let Some(started) = STARTED.with(|s| s.get()) {
+            let d = started.elapsed();
+            let _ = write!(s, ",\"duration\":{}.{:06}", d.as_secs(), d.subsec_nanos() / 1000);
+        }
+        if let Some(errno) = self.errno {
+            let _ = write!(s, ",\"errno\":{}", errno);
+        }
+        s.push_str(",\"msg\":");
+        quote(&mut s, msg.trim_start_matches("<-- "));
+        return s;
+    }
+}
+
+pub fn quote(s: &mut String, v: &str) {
+    s.push('"');
+    for c in v.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(s, "\\u{:04x}", c as u32);
+            }
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+}
+
+// one log line as a json object
+pub fn format_json(timestamp: &str, level: &str, target: &str, msg: &str) -> String {
+    let mut s = String::from("{\"timestamp\":");
+    quote(&mut s, timestamp);
+    s.push_str(",\"level\":");
+    quote(&mut s, level);
+    s.push(',');
+    if target == TARGET {
+        s.push_str(msg);
+    } else {
+        s.push_str("\"msg\":");
+        quote(&mut s, msg);
+    }
+    s.push('}');
+    return s;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let mut op = Op::new("lookup");
+        op.path("dir/\"a\"\n");
+        op.ino(2);
+        op.errno(2);
+        assert_eq!(
+            format_json("2024-01-01T00:00:00Z", "DEBUG", TARGET, &op.fields("<-- !lookup")),
+            "{\"timestamp\":\"2024-01-01T00:00:00Z\",\"level\":\"DEBUG\",\"op\":\"lookup\",\
+             \"path\":\"dir/\\\"a\\\"\\n\",\"ino\":2,\"errno\":2,\"msg\":\"!lookup\"}"
+        );
+        assert_eq!(
+            format_json("2024-01-01T00:00:00Z", "INFO", "catfs", "a\tb\u{1}"),
+            "{\"timestamp\":\"2024-01-01T00:00:00Z\",\"level\":\"INFO\",\"msg\":\"a\\tb\\u0001\"}"
+        );
+    }
+}
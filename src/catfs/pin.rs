@@ -0,0 +1,183 @@
+extern crate xattr;
+
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use self::xattr::FileExt;
+
+use catfs::ENOATTR;
+use catfs::rlibc::File;
+
+// set on a cache file to keep the evicter away from it, ex: setfattr
+// -n user.catfs.pin <mnt>/model.bin, which also caches all of it
+pub const PIN_XATTR: &'static str = "user.catfs.pin";
+
+// files that are never evicted, by their path in the source. A glob
+// that matches a directory pins everything under it
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Pins {
+    globs: Vec<Vec<u8>>,
+}
+
+impl FromStr for Pins {
+    type Err = String;
+
+    // a comma separated list of globs such as "models/*.bin,indices"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pins: Pins = Default::default();
+
+        for g in s.split(',') {
+            let g = g.trim().trim_matches('/');
+            if g.is_empty() {
+                return Err("empty pin in ".to_owned() + s);
+            }
+            pins.globs.push(g.as_bytes().to_vec());
+        }
+
+        return Ok(pins);
+    }
+}
+
+// * and ? stay within a path component, ** matches across them
+fn glob_match(pat: &[u8], s: &[u8]) -> bool {
+    if pat.starts_with(b"**") {
+        let rest = &pat[2..];
+        // a/**/b also matches a/b
+        if rest.starts_with(b"/") && glob_match(&rest[1..], s) {
+            return true;
+        }
+        for i in 0..s.len() + 1 {
+            if glob_match(rest, &s[i..]) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    match pat.first() {
+        None => return s.is_empty(),
+        Some(&b'*') => {
+            for i in 0..s.len() + 1 {
+                if glob_match(&pat[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            return false;
+        }
+        Some(&b'?') => return !s.is_empty() && s[0] != b'/' && glob_match(&pat[1..], &s[1..]),
+        Some(c) => return s.first() == Some(c) && glob_match(&pat[1..], &s[1..]),
+    }
+}
+
+impl Pins {
+    pub fn is_empty(&self) -> bool {
+        return self.globs.is_empty();
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let mut p = Some(path);
+        while let Some(path) = p {
+            let s = path.as_os_str().as_bytes();
+            if !s.is_empty() && self.globs.iter().any(|g| glob_match(g, s)) {
+                return true;
+            }
+            p = path.parent();
+        }
+        return false;
+    }
+
+    // whether anything under dir can be pinned, so the rest of the
+    // source doesn't have to be listed
+    pub fn may_match_under(&self, dir: &Path) -> bool {
+        'globs: for g in self.globs.iter() {
+            let mut parts = g.split(|b| *b == b'/');
+            for c in dir.components() {
+                match parts.next() {
+                    Some(p) if p == b"**" => return true,
+                    Some(p) => {
+                        if !glob_match(p, c.as_os_str().as_bytes()) {
+                            continue 'globs;
+                        }
+                    }
+                    // dir itself is pinned
+                    None => return true,
+                }
+            }
+            return true;
+        }
+        return false;
+    }
+}
+
+pub fn is_pinned(f: &File) -> bool {
+    match f.get_xattr(PIN_XATTR) {
+        Ok(v) => return v.is_some(),
+        Err(_) => return false,
+    }
+}
+
+pub fn set_pinned(f: &File, pinned: bool) -> io::Result<()> {
+    if pinned {
+        return f.set_xattr(PIN_XATTR, b"1");
+    }
+    if let Err(e) = f.remove_xattr(PIN_XATTR) {
+        if e.raw_os_error() != Some(ENOATTR) {
+            return Err(e);
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let pins: Pins = "models/*.bin, /indices/".parse().unwrap();
+        assert_eq!(pins.globs, vec![b"models/*.bin".to_vec(), b"indices".to_vec()]);
+        assert!("a,,b".parse::<Pins>().is_err());
+        assert!(Pins::default().is_empty());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match(b"*.bin", b"a.bin"));
+        assert!(!glob_match(b"*.bin", b"dir/a.bin"));
+        assert!(glob_match(b"dir/?.bin", b"dir/a.bin"));
+        assert!(!glob_match(b"dir/?.bin", b"dir/ab.bin"));
+        assert!(glob_match(b"**/*.bin", b"a.bin"));
+        assert!(glob_match(b"**/*.bin", b"a/b/c.bin"));
+        assert!(glob_match(b"a/**/c", b"a/c"));
+        assert!(glob_match(b"a/**/c", b"a/b/b/c"));
+        assert!(!glob_match(b"a/**/c", b"a/b/d"));
+        assert!(glob_match(b"a*c", b"abbc"));
+        assert!(!glob_match(b"a*c", b"ab/c"));
+    }
+
+    #[test]
+    fn matches() {
+        let pins: Pins = "models/*.bin,indices".parse().unwrap();
+        assert!(pins.matches(Path::new("models/a.bin")));
+        assert!(!pins.matches(Path::new("models/a.txt")));
+        assert!(!pins.matches(Path::new("models/old/a.bin")));
+        assert!(pins.matches(Path::new("indices/a/b")));
+        assert!(!pins.matches(Path::new("")));
+
+        assert!(pins.may_match_under(Path::new("")));
+        assert!(pins.may_match_under(Path::new("models")));
+        assert!(pins.may_match_under(Path::new("indices/a")));
+        assert!(!pins.may_match_under(Path::new("other")));
+        assert!(!pins.may_match_under(Path::new("models/old")));
+
+        let pins: Pins = "**/weights".parse().unwrap();
+        assert!(pins.may_match_under(Path::new("a/b")));
+        assert!(pins.matches(Path::new("a/b/weights/1")));
+    }
+}
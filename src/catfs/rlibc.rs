@@ -3,14 +3,20 @@ extern crate libc;
 extern crate time;
 extern crate xattr;
 
+use std::cmp;
+use std::collections::HashSet;
 use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fmt;
+use std::fs;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::io;
 use std::mem::MaybeUninit;
-use std::path::Path;
-#[cfg(not(target_os = "macos"))]
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
 use std::os::unix::fs::FileExt;
@@ -20,9 +26,13 @@ use self::time::Timespec;
 use self::xattr::FileExt as XattrFileExt;
 
 #[cfg(not(target_os = "macos"))]
-use self::libc::{fstat64, fstatvfs64, ftruncate64, open64, openat64, pread64, pwrite64, stat64, statvfs64};
+use self::libc::{fstat64, fstatvfs64, ftruncate64, open64, openat64, pread64, pwrite64};
+#[cfg(not(target_os = "macos"))]
+pub use self::libc::{stat64, statvfs64};
 #[cfg(target_os = "macos")]
-use self::libc::{fstat as fstat64, fstatvfs as fstatvfs64, ftruncate as ftruncate64, open as open64, openat as openat64, pread as pread64, pwrite as pwrite64, stat as stat64, statvfs as statvfs64};
+use self::libc::{fstat as fstat64, fstatvfs as fstatvfs64, ftruncate as ftruncate64, open as open64, openat as openat64, pread as pread64, pwrite as pwrite64};
+#[cfg(target_os = "macos")]
+pub use self::libc::{stat as stat64, statvfs as statvfs64};
 
 use catfs::error;
 use catfs::error::RError;
@@ -37,11 +47,15 @@ pub static O_RDWR: u32 = libc::O_RDWR as u32;
 pub static O_CLOEXEC: u32 = libc::O_CLOEXEC as u32;
 pub static O_CREAT: u32 = libc::O_CREAT as u32;
 pub static O_EXCL: u32 = libc::O_EXCL as u32;
+pub static O_NOFOLLOW: u32 = libc::O_NOFOLLOW as u32;
+pub static O_NONBLOCK: u32 = libc::O_NONBLOCK as u32;
 // XXX for some reason this is not found
 //pub static O_PATH: u32 = libc::O_PATH as u32;
 #[allow(dead_code)]
 pub static O_PATH: u32 = 2097152;
 pub static O_TRUNC: u32 = libc::O_TRUNC as u32;
+#[cfg(not(target_os = "macos"))]
+pub static O_DIRECT: u32 = libc::O_DIRECT as u32;
 
 pub fn to_cstring(path: &dyn AsRef<Path>) -> CString {
     let bytes = path.as_ref().as_os_str().to_os_string().into_vec();
@@ -66,6 +80,286 @@ macro_rules! libc_wrap {
 libc_wrap!{
     pub fn setuid(uid: libc::uid_t) {}
     pub fn setgid(gid: libc::gid_t) {}
+    pub fn setreuid(ruid: libc::uid_t, euid: libc::uid_t) {}
+    pub fn setregid(rgid: libc::gid_t, egid: libc::gid_t) {}
+}
+
+pub fn setgroups(groups: &[libc::gid_t]) -> io::Result<()> {
+    let res = unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+// returns the uid and primary gid of the named user
+pub fn getpwnam(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name)?;
+    let mut pwd: libc::passwd = unsafe { MaybeUninit::zeroed().assume_init() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+
+    let res = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    } else if result.is_null() {
+        return Err(io::Error::from_raw_os_error(libc::ENOENT));
+    } else {
+        return Ok((pwd.pw_uid, pwd.pw_gid));
+    }
+}
+
+// returns the name and primary gid of the user
+pub fn getpwuid(uid: libc::uid_t) -> io::Result<(OsString, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { MaybeUninit::zeroed().assume_init() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+
+    let res = unsafe {
+        libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    } else if result.is_null() {
+        return Err(io::Error::from_raw_os_error(libc::ENOENT));
+    } else {
+        let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+        return Ok((OsStr::from_bytes(name.to_bytes()).to_os_string(), pwd.pw_gid));
+    }
+}
+
+pub fn initgroups(user: &OsStr, gid: libc::gid_t) -> io::Result<()> {
+    let cuser = CString::new(user.as_bytes())?;
+    let res = unsafe { libc::initgroups(cuser.as_ptr(), gid as _) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+// capability numbers from linux/capability.h
+#[cfg(not(target_os = "macos"))]
+pub const CAP_CHOWN: u32 = 0;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_DAC_OVERRIDE: u32 = 1;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_DAC_READ_SEARCH: u32 = 2;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_FOWNER: u32 = 3;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_FSETID: u32 = 4;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_SYS_ADMIN: u32 = 21;
+#[cfg(not(target_os = "macos"))]
+pub const CAP_MKNOD: u32 = 27;
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+// the capabilities limit_caps leaves every thread with
+#[cfg(not(target_os = "macos"))]
+static CAPS_MASK: AtomicU64 = AtomicU64::new(0);
+// how many threads limit_caps_handler has run on
+#[cfg(not(target_os = "macos"))]
+static CAPS_DONE: AtomicUsize = AtomicUsize::new(0);
+
+/// Restrict the permitted and effective capability sets to `caps`
+/// and remove everything else from the bounding set, so neither we
+/// nor anything we exec can get the rest back. The kernel keeps these
+/// per thread, so like setuid in libc every other thread is signaled
+/// to do the same, and this waits until they all have. SIGRTMIN is
+/// only borrowed for that, whatever handled it before does again once
+/// this returns.
+#[cfg(not(target_os = "macos"))]
+pub fn limit_caps(caps: &[u32]) -> io::Result<()> {
+    let mut mask = 0u64;
+    for c in caps {
+        mask |= 1u64 << c;
+    }
+    CAPS_MASK.store(mask, Ordering::SeqCst);
+    CAPS_DONE.store(0, Ordering::SeqCst);
+
+    let sig = libc::SIGRTMIN();
+    let mut sa: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+    let mut old: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+    sa.sa_sigaction = limit_caps_handler as usize;
+    // most threads are waiting in a syscall when this arrives
+    sa.sa_flags = libc::SA_RESTART;
+    let res = unsafe {
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaction(sig, &sa, &mut old)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    limit_thread_caps(mask)?;
+    // if this fails a thread may still get the signal, so the handler
+    // has to stay
+    limit_other_threads(mask, sig)?;
+    if unsafe { libc::sigaction(sig, &old, ptr::null_mut()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+// each thread is signaled once, since real-time signals queue and one
+// that arrives after the handler is gone would do whatever SIGRTMIN
+// normally does. Threads that started since we looked are checked
+// again once the ones we signaled are done
+#[cfg(not(target_os = "macos"))]
+fn limit_other_threads(mask: u64, sig: libc::c_int) -> io::Result<()> {
+    let pid = unsafe { libc::getpid() };
+    let me = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    let mut signaled = HashSet::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let left: Vec<libc::pid_t> = task_caps("CapPrm")?
+            .into_iter()
+            .filter(|&(tid, caps)| tid != me && caps & !mask != 0)
+            .map(|(tid, _)| tid)
+            .collect();
+        if left.is_empty() {
+            return Ok(());
+        }
+        for tid in left {
+            if !signaled.insert(tid) {
+                continue;
+            }
+            let res = unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, sig) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                // it exited
+                if e.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(e);
+                }
+                signaled.remove(&tid);
+            }
+        }
+
+        // a thread that exits before it gets to the signal never
+        // counts, which is why /proc is looked at again regardless
+        let rescan = Instant::now() + Duration::from_millis(100);
+        while CAPS_DONE.load(Ordering::SeqCst) < signaled.len() && Instant::now() < rescan {
+            thread::sleep(Duration::from_millis(1));
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "some threads kept their capabilities",
+            ));
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+extern "C" fn limit_caps_handler(_: libc::c_int) {
+    // whatever this interrupted may still look at errno
+    let errno = unsafe { *libc::__errno_location() };
+    let _ = limit_thread_caps(CAPS_MASK.load(Ordering::SeqCst));
+    CAPS_DONE.fetch_add(1, Ordering::SeqCst);
+    unsafe { *libc::__errno_location() = errno };
+}
+
+// runs in a signal handler, so no allocating or locking in here
+#[cfg(not(target_os = "macos"))]
+fn limit_thread_caps(mask: u64) -> io::Result<()> {
+    let mut res = Ok(());
+    for c in 0..64 {
+        if mask & (1u64 << c) == 0 {
+            let err = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, c as libc::c_ulong, 0, 0, 0) };
+            if err < 0 {
+                let e = io::Error::last_os_error();
+                // EINVAL means we are past the last capability the
+                // kernel knows about. Otherwise this thread may have
+                // lost CAP_SETPCAP already, it can still give up the
+                // rest of what it has below
+                if e.raw_os_error() != Some(libc::EINVAL) {
+                    res = Err(e);
+                }
+                break;
+            }
+        }
+    }
+
+    let mut header = CapHeader {
+        // _LINUX_CAPABILITY_VERSION_3
+        version: 0x20080522,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for i in 0..2 {
+        // never more than it has, which the kernel wouldn't allow
+        let bits = data[i].permitted & (mask >> (32 * i)) as u32;
+        data[i].effective = bits;
+        data[i].permitted = bits;
+        data[i].inheritable = 0;
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return res;
+}
+
+// field is one of the Cap* lines in /proc/<pid>/task/<tid>/status,
+// returns it for each of our threads
+#[cfg(not(target_os = "macos"))]
+fn task_caps(field: &str) -> io::Result<Vec<(libc::pid_t, u64)>> {
+    let mut caps = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        let status = match fs::read_to_string(entry.path().join("status")) {
+            Ok(status) => status,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    // it exited
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+        for line in status.lines() {
+            let mut kv = line.splitn(2, ':');
+            if kv.next() == Some(field) {
+                let v = kv.next().unwrap_or("").trim();
+                match u64::from_str_radix(v, 16) {
+                    Ok(v) => caps.push((tid, v)),
+                    Err(_) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+                }
+            }
+        }
+    }
+    return Ok(caps);
 }
 
 pub fn opendir(path: &dyn AsRef<Path>) -> io::Result<*mut libc::DIR> {
@@ -153,6 +447,39 @@ fn array_to_osstring(cslice: &[libc::c_char]) -> OsString {
 }
 
 impl Dirent {
+    // for sources that don't come with a libc::dirent
+    pub fn new(ino: u64, off: i64, kind: fuse::FileType, name: &OsStr) -> Dirent {
+        let mut en: Dirent = Default::default();
+        en.en.d_ino = ino as _;
+        #[cfg(not(target_os = "macos"))]
+        {
+            en.en.d_off = off as _;
+        }
+        #[cfg(target_os = "macos")]
+        {
+            en.en.d_seekoff = off as _;
+        }
+        en.en.d_type = match kind {
+            FileType::BlockDevice => libc::DT_BLK,
+            FileType::CharDevice => libc::DT_CHR,
+            FileType::Directory => libc::DT_DIR,
+            FileType::NamedPipe => libc::DT_FIFO,
+            FileType::Symlink => libc::DT_LNK,
+            _ => libc::DT_REG,
+        };
+
+        let name = name.as_bytes();
+        let len = cmp::min(name.len(), en.en.d_name.len() - 1);
+        for i in 0..len {
+            en.en.d_name[i] = name[i] as libc::c_char;
+        }
+        #[cfg(target_os = "macos")]
+        {
+            en.en.d_namlen = len as _;
+        }
+        return en;
+    }
+
     pub fn ino(&self) -> u64 {
         return self.en.d_ino as u64;
     }
@@ -254,6 +581,79 @@ pub fn splice(
     }
 }
 
+#[cfg(not(target_os = "macos"))]
+pub fn copy_file_range(
+    fd_in: libc::c_int,
+    off_in: i64,
+    fd_out: libc::c_int,
+    off_out: i64,
+    len: usize,
+) -> io::Result<usize> {
+    let mut off_in = off_in;
+    let mut off_out = off_out;
+
+    // through syscall since not every libc has a wrapper for it
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_copy_file_range,
+            fd_in,
+            &mut off_in as *mut i64,
+            fd_out,
+            &mut off_out as *mut i64,
+            len,
+            0 as libc::c_uint,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(res as usize);
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn copy_file_range(
+    _fd_in: libc::c_int,
+    _off_in: i64,
+    _fd_out: libc::c_int,
+    _off_out: i64,
+    _len: usize,
+) -> io::Result<usize> {
+    return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+}
+
+// _IOW(0x94, 9, int)
+#[cfg(not(target_os = "macos"))]
+const FICLONE: libc::c_ulong = 0x40049409;
+
+// makes dst share all of src's blocks, on filesystems that can reflink
+// such as btrfs and xfs
+#[cfg(not(target_os = "macos"))]
+pub fn ficlone(dst: RawFd, src: RawFd) -> io::Result<()> {
+    let res = unsafe { libc::ioctl(dst, FICLONE as _, src) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn ficlone(_dst: RawFd, _src: RawFd) -> io::Result<()> {
+    return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+}
+
+// a file in dir without a name, which is gone once it's closed
+#[cfg(not(target_os = "macos"))]
+pub fn tmpfile(dir: RawFd) -> io::Result<File> {
+    return File::openat(dir, &".", O_RDWR | libc::O_TMPFILE as u32, 0o600);
+}
+
+#[cfg(target_os = "macos")]
+pub fn tmpfile(_dir: RawFd) -> io::Result<File> {
+    return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+}
+
 pub fn close(fd: libc::c_int) -> io::Result<()> {
     let res = unsafe { libc::close(fd) };
     if res < 0 {
@@ -263,6 +663,88 @@ pub fn close(fd: libc::c_int) -> io::Result<()> {
     }
 }
 
+// op is one of libc::LOCK_SH, LOCK_EX or LOCK_UN, optionally with LOCK_NB
+pub fn flock(fd: RawFd, op: libc::c_int) -> io::Result<()> {
+    let res = unsafe { libc::flock(fd, op) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+// true if we got the lock, false if someone else is holding it
+pub fn try_flock(fd: RawFd, op: libc::c_int) -> io::Result<bool> {
+    match flock(fd, op | libc::LOCK_NB) {
+        Ok(_) => return Ok(true),
+        Err(e) => {
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Ok(false);
+            } else {
+                return Err(e);
+            }
+        }
+    }
+}
+
+// a posix record lock that belongs to the open file description
+// rather than to the process, so each fd conflicts with the others.
+// start and end are inclusive, end is i64::MAX for up to EOF
+#[cfg(not(target_os = "macos"))]
+fn ofd_lock(fd: RawFd, cmd: libc::c_int, typ: libc::c_int, start: u64, end: u64) -> io::Result<libc::flock> {
+    let mut fl: libc::flock = unsafe { MaybeUninit::zeroed().assume_init() };
+    fl.l_type = typ as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = start as libc::off_t;
+    fl.l_len = if end >= i64::MAX as u64 {
+        0
+    } else {
+        (end - start + 1) as libc::off_t
+    };
+    let res = unsafe { libc::fcntl(fd, cmd, &mut fl) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(fl);
+    }
+}
+
+// returns the type and range of a lock that conflicts with this one,
+// or F_UNLCK if there's none
+#[cfg(not(target_os = "macos"))]
+pub fn getlk(fd: RawFd, typ: libc::c_int, start: u64, end: u64) -> io::Result<(libc::c_int, u64, u64)> {
+    let fl = ofd_lock(fd, libc::F_OFD_GETLK, typ, start, end)?;
+    let end = if fl.l_len == 0 {
+        i64::MAX as u64
+    } else {
+        (fl.l_start + fl.l_len - 1) as u64
+    };
+    return Ok((fl.l_type as libc::c_int, fl.l_start as u64, end));
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn setlk(fd: RawFd, typ: libc::c_int, start: u64, end: u64, wait: bool) -> io::Result<()> {
+    let cmd = if wait {
+        libc::F_OFD_SETLKW
+    } else {
+        libc::F_OFD_SETLK
+    };
+    ofd_lock(fd, cmd, typ, start, end)?;
+    return Ok(());
+}
+
+// macOS doesn't have open file description locks, the kernel keeps
+// locks to itself then
+#[cfg(target_os = "macos")]
+pub fn getlk(_fd: RawFd, _typ: libc::c_int, _start: u64, _end: u64) -> io::Result<(libc::c_int, u64, u64)> {
+    return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+}
+
+#[cfg(target_os = "macos")]
+pub fn setlk(_fd: RawFd, _typ: libc::c_int, _start: u64, _end: u64, _wait: bool) -> io::Result<()> {
+    return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+}
+
 pub fn unlinkat(dir: RawFd, path: &dyn AsRef<Path>, flags: u32) -> io::Result<()> {
     let s = to_cstring(path);
     let res = unsafe { libc::unlinkat(dir, s.as_ptr(), flags as i32) };
@@ -273,6 +755,57 @@ pub fn unlinkat(dir: RawFd, path: &dyn AsRef<Path>, flags: u32) -> io::Result<()
     }
 }
 
+pub fn symlinkat(target: &dyn AsRef<Path>, dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<()> {
+    let t = to_cstring(target);
+    let s = to_cstring(path);
+    let res = unsafe { libc::symlinkat(t.as_ptr(), dir, s.as_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+pub fn mknodat(
+    dir: RawFd,
+    path: &dyn AsRef<Path>,
+    mode: libc::mode_t,
+    rdev: libc::dev_t,
+) -> io::Result<()> {
+    let s = to_cstring(path);
+    let res = unsafe { libc::mknodat(dir, s.as_ptr(), mode, rdev) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+pub fn linkat(dir: RawFd, path: &dyn AsRef<Path>, newpath: &dyn AsRef<Path>) -> io::Result<()> {
+    let s = to_cstring(path);
+    let new_s = to_cstring(newpath);
+    let res = unsafe { libc::linkat(dir, s.as_ptr(), dir, new_s.as_ptr(), 0) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
+pub fn readlinkat(dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<PathBuf> {
+    let s = to_cstring(path);
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let res = unsafe {
+        libc::readlinkat(dir, s.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        buf.truncate(res as usize);
+        return Ok(PathBuf::from(OsString::from_vec(buf)));
+    }
+}
+
 pub fn existat(dir: RawFd, path: &dyn AsRef<Path>) -> error::Result<bool> {
     if let Err(e) = fstatat(dir, path) {
         if error::try_enoent(e)? {
@@ -302,6 +835,25 @@ pub fn renameat(dir: RawFd, path: &dyn AsRef<Path>, newpath: &dyn AsRef<Path>) -
     }
 }
 
+// from one directory to another, EXDEV if they are on different
+// filesystems
+pub fn renameat_between(
+    dir: RawFd,
+    path: &dyn AsRef<Path>,
+    newdir: RawFd,
+    newpath: &dyn AsRef<Path>,
+) -> io::Result<()> {
+    let s = to_cstring(path);
+    let new_s = to_cstring(newpath);
+
+    let res = unsafe { libc::renameat(dir, s.as_ptr(), newdir, new_s.as_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(());
+    }
+}
+
 pub fn fstat(fd: libc::c_int) -> io::Result<stat64> {
     let mut st = MaybeUninit::<stat64>::uninit();
 
@@ -317,10 +869,18 @@ pub fn fstatat(dir: RawFd, path: &dyn AsRef<Path>) -> io::Result<stat64> {
     let mut st = MaybeUninit::<stat64>::uninit();
     let s = to_cstring(path);
 
+    // a symlink is what's asked about, not what it points to
     #[cfg(not(target_os = "macos"))]
-    let res = unsafe { libc::fstatat64(dir, s.as_ptr(), st.as_mut_ptr(), libc::AT_EMPTY_PATH) };
+    let res = unsafe {
+        libc::fstatat64(
+            dir,
+            s.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
     #[cfg(target_os = "macos")]
-    let res = unsafe { libc::fstatat(dir, s.as_ptr(), st.as_mut_ptr(), 0) };
+    let res = unsafe { libc::fstatat(dir, s.as_ptr(), st.as_mut_ptr(), libc::AT_SYMLINK_NOFOLLOW) };
 
     if res < 0 {
         return Err(io::Error::last_os_error());
@@ -339,6 +899,18 @@ pub fn fstatvfs(fd: RawFd) -> io::Result<statvfs64> {
     }
 }
 
+// unlike statvfs this has f_type, which says what filesystem it is
+#[cfg(not(target_os = "macos"))]
+pub fn fstatfs(fd: RawFd) -> io::Result<libc::statfs> {
+    let mut st = MaybeUninit::<libc::statfs>::uninit();
+    let res = unsafe { libc::fstatfs(fd, st.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    } else {
+        return Ok(unsafe { st.assume_init() });
+    }
+}
+
 pub fn openat(dir: RawFd, path: &dyn AsRef<Path>, flags: u32, mode: libc::mode_t) -> io::Result<RawFd> {
     let s = to_cstring(path);
     let fd = unsafe { openat64(dir, s.as_ptr(), (flags | O_CLOEXEC) as i32, mode as libc::c_uint) };
@@ -389,6 +961,26 @@ pub fn utimensat(
     }
 }
 
+pub fn futimens(fd: RawFd, atime: &Timespec, mtime: &Timespec) -> io::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: atime.sec as libc::time_t,
+            tv_nsec: atime.nsec as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: mtime.sec as libc::time_t,
+            tv_nsec: mtime.nsec as libc::c_long,
+        },
+    ];
+
+    let res = unsafe { libc::futimens(fd, times.as_ptr()) };
+    if res == 0 {
+        return Ok(());
+    } else {
+        return Err(io::Error::last_os_error());
+    }
+}
+
 pub fn fchmodat(dir: RawFd, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u32) -> io::Result<()> {
     let s = to_cstring(path);
     let res = unsafe { libc::fchmodat(dir, s.as_ptr(), mode, flags as i32) };
@@ -399,6 +991,282 @@ pub fn fchmodat(dir: RawFd, path: &dyn AsRef<Path>, mode: libc::mode_t, flags: u
     }
 }
 
+pub fn fchownat(
+    dir: RawFd,
+    path: &dyn AsRef<Path>,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    flags: u32,
+) -> io::Result<()> {
+    let s = to_cstring(path);
+    let res = unsafe { libc::fchownat(dir, s.as_ptr(), uid, gid, flags as i32) };
+    if res == 0 {
+        return Ok(());
+    } else {
+        return Err(io::Error::last_os_error());
+    }
+}
+
+pub fn faccessat(dir: RawFd, path: &dyn AsRef<Path>, mode: libc::c_int, flags: u32) -> io::Result<()> {
+    let s = to_cstring(path);
+    let res = unsafe { libc::faccessat(dir, s.as_ptr(), mode, flags as i32) };
+    if res == 0 {
+        return Ok(());
+    } else {
+        return Err(io::Error::last_os_error());
+    }
+}
+
+// how File reads and writes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoBackend {
+    Syscall,
+    // needs the io-uring feature
+    Uring,
+}
+
+impl FromStr for IoBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "syscall" => Ok(IoBackend::Syscall),
+            "uring" => Ok(IoBackend::Uring),
+            _ => Err("unrecognized io backend ".to_owned() + s),
+        };
+    }
+}
+
+static IO_BACKEND: AtomicUsize = AtomicUsize::new(IoBackend::Syscall as usize);
+
+// for the whole process, fails if io_uring isn't built in or the
+// kernel doesn't have it
+pub fn set_io_backend(backend: IoBackend) -> io::Result<()> {
+    if backend == IoBackend::Uring {
+        uring::probe()?;
+    }
+    IO_BACKEND.store(backend as usize, Ordering::Relaxed);
+    return Ok(());
+}
+
+pub fn io_backend() -> IoBackend {
+    if IO_BACKEND.load(Ordering::Relaxed) == IoBackend::Uring as usize {
+        return IoBackend::Uring;
+    } else {
+        return IoBackend::Syscall;
+    }
+}
+
+// copies from fd_in to fd_out at the same offsets, with as many reads
+// and then writes in flight at once as buf has room for. Returns
+// how much was copied, which is short only at eof
+pub fn copy_at_batched(
+    fd_in: libc::c_int,
+    fd_out: libc::c_int,
+    offset: i64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    return uring::copy_at(fd_in, fd_out, offset, buf);
+}
+
+#[cfg(feature = "io-uring")]
+mod uring {
+    extern crate io_uring;
+    extern crate libc;
+
+    use std::cell::RefCell;
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use self::io_uring::{opcode, types, IoUring};
+
+    const QUEUE_DEPTH: u32 = 32;
+    // each read of a batched copy is at least this big
+    const MIN_BATCH_IO: usize = 64 * 1024;
+
+    thread_local! {
+        // one per thread so nothing has to be locked
+        static RING: RefCell<Option<IoUring>> = RefCell::new(None);
+    }
+
+    pub fn probe() -> io::Result<()> {
+        IoUring::new(2)?;
+        return Ok(());
+    }
+
+    // runs the entries and returns their results in order
+    fn run(entries: &[io_uring::squeue::Entry]) -> io::Result<Vec<i32>> {
+        return RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            if ring.is_none() {
+                *ring = Some(IoUring::new(QUEUE_DEPTH)?);
+            }
+
+            let res = submit(ring.as_mut().unwrap(), entries);
+            if res.is_err() {
+                // nothing is in flight anymore, but what wasn't
+                // submitted is still queued, start over with a new ring
+                *ring = None;
+            }
+            return res;
+        });
+    }
+
+    fn submit(ring: &mut IoUring, entries: &[io_uring::squeue::Entry]) -> io::Result<Vec<i32>> {
+        let entries: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| e.clone().user_data(i as u64))
+            .collect();
+        unsafe {
+            // all or nothing, so no entry is left for the next submit
+            if ring.submission().push_multiple(&entries).is_err() {
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+
+        let mut results = vec![0; entries.len()];
+        let mut done = 0;
+        let mut err = None;
+        while done < entries.len() {
+            if err.is_none() {
+                match ring.submit_and_wait(entries.len() - done) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        match e.raw_os_error() {
+                            // a signal, or completions have to be
+                            // reaped before there's room for more
+                            Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => (),
+                            _ => err = Some(e),
+                        }
+                    }
+                }
+            }
+
+            for cqe in ring.completion() {
+                results[cqe.user_data() as usize] = cqe.result();
+                done += 1;
+            }
+
+            if err.is_some() {
+                // the kernel may still be writing to the buffers of
+                // what it took, which can't be returned before it's
+                // done with them
+                let in_flight = entries.len() - done - ring.submission().len();
+                if in_flight == 0 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        if let Some(e) = err {
+            return Err(e);
+        }
+        return Ok(results);
+    }
+
+    fn to_result(res: i32) -> io::Result<usize> {
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        } else {
+            return Ok(res as usize);
+        }
+    }
+
+    pub fn read_at(fd: libc::c_int, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        let e = opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+            .offset(offset as u64)
+            .build();
+        return to_result(run(&[e])?[0]);
+    }
+
+    pub fn write_at(fd: libc::c_int, buf: &[u8], offset: i64) -> io::Result<usize> {
+        let e = opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+            .offset(offset as u64)
+            .build();
+        return to_result(run(&[e])?[0]);
+    }
+
+    pub fn copy_at(
+        fd_in: libc::c_int,
+        fd_out: libc::c_int,
+        offset: i64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let n = (buf.len() / MIN_BATCH_IO).max(1).min(QUEUE_DEPTH as usize);
+        let size = (buf.len() + n - 1) / n;
+
+        let reads: Vec<_> = buf.chunks_mut(size)
+            .enumerate()
+            .map(|(i, b)| {
+                opcode::Read::new(types::Fd(fd_in), b.as_mut_ptr(), b.len() as u32)
+                    .offset((offset + (i * size) as i64) as u64)
+                    .build()
+            })
+            .collect();
+        // only what's read up to the first short read is written
+        let mut nread = 0;
+        for res in run(&reads)? {
+            let n = to_result(res)?;
+            nread += n;
+            if n < size {
+                break;
+            }
+        }
+        let nread = nread.min(buf.len());
+
+        let writes: Vec<_> = buf[..nread]
+            .chunks(size)
+            .enumerate()
+            .map(|(i, b)| {
+                opcode::Write::new(types::Fd(fd_out), b.as_ptr(), b.len() as u32)
+                    .offset((offset + (i * size) as i64) as u64)
+                    .build()
+            })
+            .collect();
+        for (res, b) in run(&writes)?.into_iter().zip(buf[..nread].chunks(size)) {
+            if to_result(res)? < b.len() {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+        }
+        return Ok(nread);
+    }
+}
+
+#[cfg(not(feature = "io-uring"))]
+mod uring {
+    extern crate libc;
+
+    use std::io;
+
+    fn not_built() -> io::Error {
+        return io::Error::new(io::ErrorKind::Other, "catfs was built without io-uring");
+    }
+
+    pub fn probe() -> io::Result<()> {
+        return Err(not_built());
+    }
+
+    pub fn read_at(_fd: libc::c_int, _buf: &mut [u8], _offset: i64) -> io::Result<usize> {
+        return Err(not_built());
+    }
+
+    pub fn write_at(_fd: libc::c_int, _buf: &[u8], _offset: i64) -> io::Result<usize> {
+        return Err(not_built());
+    }
+
+    pub fn copy_at(
+        _fd_in: libc::c_int,
+        _fd_out: libc::c_int,
+        _offset: i64,
+        _buf: &mut [u8],
+    ) -> io::Result<usize> {
+        return Err(not_built());
+    }
+}
+
 pub struct File {
     fd: libc::c_int,
 }
@@ -464,6 +1332,45 @@ impl File {
         fstat(self.fd)
     }
 
+    // the flags this was opened with
+    pub fn get_flags(&self) -> io::Result<u32> {
+        let res = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        } else {
+            return Ok(res as u32);
+        }
+    }
+
+    // writes out [offset, offset + len) and drops it from the page
+    // cache
+    #[cfg(not(target_os = "macos"))]
+    pub fn drop_cache(&self, offset: i64, len: i64) -> io::Result<()> {
+        let res = unsafe {
+            libc::sync_file_range(
+                self.fd,
+                offset,
+                len,
+                libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE |
+                    libc::SYNC_FILE_RANGE_WAIT_AFTER,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = unsafe { libc::posix_fadvise(self.fd, offset, len, libc::POSIX_FADV_DONTNEED) };
+        if res == 0 {
+            return Ok(());
+        } else {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn drop_cache(&self, _offset: i64, _len: i64) -> io::Result<()> {
+        return Ok(());
+    }
+
     pub fn truncate(&self, size: u64) -> io::Result<()> {
         let res = unsafe { ftruncate64(self.fd, size as i64) };
         if res < 0 {
@@ -512,7 +1419,19 @@ impl File {
         }
     }
 
+    pub fn chown(&self, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+        let res = unsafe { libc::fchown(self.fd, uid, gid) };
+        if res == 0 {
+            return Ok(());
+        } else {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
     pub fn read_at(&self, buf: &mut [u8], offset: i64) -> io::Result<usize> {
+        if io_backend() == IoBackend::Uring {
+            return uring::read_at(self.fd, buf, offset);
+        }
         let nbytes =
             unsafe { pread64(self.fd, as_mut_void_ptr(buf), buf.len(), offset) };
         if nbytes < 0 {
@@ -523,6 +1442,9 @@ impl File {
     }
 
     pub fn write_at(&self, buf: &[u8], offset: i64) -> io::Result<usize> {
+        if io_backend() == IoBackend::Uring {
+            return uring::write_at(self.fd, buf, offset);
+        }
         let nbytes = unsafe { pwrite64(self.fd, as_void_ptr(buf), buf.len(), offset) };
         if nbytes < 0 {
             return Err(io::Error::last_os_error());
@@ -619,3 +1541,122 @@ impl AsRawFd for File {
 }
 
 impl XattrFileExt for File {}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process::Command;
+    use std::sync::mpsc;
+    use super::*;
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn limit_caps_all_threads() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        // there's no getting them back, so this runs in a process of
+        // its own
+        if env::var_os("CATFS_TEST_LIMIT_CAPS").is_none() {
+            let status = Command::new(env::current_exe().unwrap())
+                .args(
+                    &[
+                        "--exact",
+                        "catfs::rlibc::tests::limit_caps_all_threads",
+                        "--test-threads=1",
+                    ],
+                )
+                .env("CATFS_TEST_LIMIT_CAPS", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let mut waiting = Vec::new();
+        let mut threads = Vec::new();
+        for _ in 0..4 {
+            let (tx, rx) = mpsc::channel::<()>();
+            waiting.push(tx);
+            threads.push(thread::spawn(move || { let _ = rx.recv(); }));
+        }
+
+        // whoever had SIGRTMIN before gets it back
+        let sig = libc::SIGRTMIN();
+        let mut sa: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+        sa.sa_sigaction = libc::SIG_IGN;
+        unsafe { libc::sigaction(sig, &sa, ptr::null_mut()) };
+
+        limit_caps(&[CAP_SYS_ADMIN]).unwrap();
+        let mut cur: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+        unsafe { libc::sigaction(sig, ptr::null(), &mut cur) };
+        assert_eq!(cur.sa_sigaction, libc::SIG_IGN);
+
+        let caps = task_caps("CapEff").unwrap();
+        assert!(caps.len() > threads.len());
+        for (tid, eff) in caps {
+            assert_eq!(eff & !(1u64 << CAP_SYS_ADMIN), 0, "thread {} kept {:x}", tid, eff);
+        }
+
+        drop(waiting);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "io-uring")]
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn copy_at_batched_interrupted() {
+        use std::fs::OpenOptions;
+        use std::io::Write;
+
+        if uring::probe().is_err() {
+            return;
+        }
+
+        let dir = env::temp_dir().join(format!("catfs-uring-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..(8 * 1024 * 1024)).map(|i| (i % 251) as u8).collect();
+        fs::File::create(dir.join("src")).unwrap().write_all(&data).unwrap();
+
+        // no SA_RESTART, so waiting on the ring comes back with EINTR
+        let sig = libc::SIGRTMIN() + 1;
+        unsafe {
+            let mut sa: libc::sigaction = MaybeUninit::zeroed().assume_init();
+            sa.sa_sigaction = ignore_signal as usize;
+            libc::sigaction(sig, &sa, ptr::null_mut());
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        let copier = thread::spawn(move || {
+            tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t).unwrap();
+            let from = fs::File::open(&src).unwrap();
+            let mut buf = vec![0u8; 1024 * 1024];
+            for _ in 0..20 {
+                let to = OpenOptions::new().write(true).create(true).truncate(true).open(&dst).unwrap();
+                let mut off = 0;
+                loop {
+                    let n = copy_at_batched(from.as_raw_fd(), to.as_raw_fd(), off, &mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    off += n as i64;
+                }
+                assert_eq!(off as usize, data.len());
+                assert!(fs::read(&dst).unwrap() == data);
+            }
+        });
+
+        let tid = rx.recv().unwrap();
+        while unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, sig) } == 0 {
+            thread::sleep(Duration::from_micros(100));
+        }
+        copier.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
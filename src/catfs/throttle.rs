@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// a token bucket without the bucket: whoever moves bytes waits until
+// the ones before them would have been done at bytes_per_sec
+pub struct Throttle {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        return Throttle {
+            bytes_per_sec: bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        };
+    }
+
+    pub fn wait(&self, bytes: usize) {
+        thread::sleep(self.delay(bytes));
+    }
+
+    // how long to wait for bytes, for those that would rather wait
+    // some other way
+    pub fn delay(&self, bytes: usize) -> Duration {
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            if *next < now {
+                *next = now;
+            }
+            *next += Duration::from_nanos(bytes as u64 * 1_000_000_000 / self.bytes_per_sec);
+            *next
+        };
+
+        let now = Instant::now();
+        if until > now {
+            return until - now;
+        } else {
+            return Duration::from_secs(0);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Running {
+    n: usize,
+    queued: VecDeque<Box<dyn FnOnce() + Send>>,
+}
+
+// caps how much background page in can take from src, so caching a
+// big cold file doesn't starve reads that are waiting on src. Page
+// ins over max_running are queued instead of blocking, they run on
+// the same thread pool as flushes and uploads
+#[derive(Default)]
+pub struct PageInLimit {
+    throttle: Option<Throttle>,
+    max_running: usize,
+    running: Mutex<Running>,
+}
+
+impl PageInLimit {
+    // 0 is no limit for either
+    pub fn new(bytes_per_sec: u64, max_running: usize) -> PageInLimit {
+        return PageInLimit {
+            throttle: if bytes_per_sec == 0 {
+                None
+            } else {
+                Some(Throttle::new(bytes_per_sec))
+            },
+            max_running: max_running,
+            running: Default::default(),
+        };
+    }
+
+    // runs f now if there's room, otherwise after one of the page ins
+    // that are running finishes, on its thread
+    pub fn run<F: FnOnce() + Send + 'static>(&self, f: F) {
+        {
+            let mut running = self.running.lock().unwrap();
+            if self.max_running != 0 && running.n >= self.max_running {
+                running.queued.push_back(Box::new(f));
+                return;
+            }
+            running.n += 1;
+        }
+
+        let mut f: Box<dyn FnOnce() + Send> = Box::new(f);
+        loop {
+            f();
+            let mut running = self.running.lock().unwrap();
+            match running.queued.pop_front() {
+                Some(next) => f = next,
+                None => {
+                    running.n -= 1;
+                    return;
+                }
+            }
+        }
+    }
+
+    // bytes were just copied from src, returns how long to wait
+    // before copying more
+    pub fn paged_in(&self, bytes: usize) -> Duration {
+        return match self.throttle {
+            Some(ref throttle) => throttle.delay(bytes),
+            None => Duration::from_secs(0),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use super::*;
+
+    #[test]
+    fn page_in_limit() {
+        let limit = Arc::new(PageInLimit::new(0, 1));
+        let (started_tx, started) = mpsc::channel();
+        let (finish, finish_rx) = mpsc::channel::<()>();
+
+        let t = {
+            let limit = limit.clone();
+            thread::spawn(move || {
+                limit.run(move || {
+                    started_tx.send(()).unwrap();
+                    finish_rx.recv().unwrap();
+                })
+            })
+        };
+        started.recv().unwrap();
+
+        // queued behind the first one, and run on its thread
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let ran = ran.clone();
+            limit.run(move || { ran.fetch_add(1, Ordering::Relaxed); });
+        }
+        assert_eq!(ran.load(Ordering::Relaxed), 0);
+        finish.send(()).unwrap();
+        t.join().unwrap();
+        assert_eq!(ran.load(Ordering::Relaxed), 2);
+        assert_eq!(limit.running.lock().unwrap().n, 0);
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.wait(100);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,150 @@
+extern crate libc;
+extern crate time;
+extern crate xattr;
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use self::time::Timespec;
+use self::xattr::FileExt;
+
+use catfs::error;
+use catfs::file;
+use catfs::rlibc;
+use catfs::rlibc::File;
+
+const COPY_SIZE: usize = 1024 * 1024;
+
+// moves the cache file at path in one cache dir to new_path in
+// another, xattrs and times included so it's as valid and as cold
+// there as it was here. That's a rename when both are on the same
+// filesystem, otherwise it's copied and then removed from where it
+// was. If the copy fails, what was copied of it is removed instead
+pub fn move_at(
+    from: RawFd,
+    path: &dyn AsRef<Path>,
+    to: RawFd,
+    new_path: &dyn AsRef<Path>,
+    dir_mode: libc::mode_t,
+) -> io::Result<()> {
+    if let Some(parent) = new_path.as_ref().parent() {
+        file::mkdirat_all(to, &parent, dir_mode)?;
+    }
+
+    match rlibc::renameat_between(from, path, to, new_path) {
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            if e.raw_os_error() != Some(libc::EXDEV) {
+                return Err(e);
+            }
+        }
+    }
+
+    if let Err(e) = copy_at(from, path, to, new_path) {
+        if let Err(e2) = rlibc::unlinkat(to, new_path, 0) {
+            if !error::is_enoent(&e2) {
+                warn!("!unlink {:?} = {}", new_path.as_ref(), e2);
+            }
+        }
+        return Err(e);
+    }
+    return rlibc::unlinkat(from, path, 0);
+}
+
+fn copy_at(
+    from: RawFd,
+    path: &dyn AsRef<Path>,
+    to: RawFd,
+    new_path: &dyn AsRef<Path>,
+) -> io::Result<()> {
+    let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+    let mut src = File::openat(from, path, flags, 0)?;
+    let res = (|| -> io::Result<()> {
+        let st = src.stat()?;
+        let flags = rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC | rlibc::O_NOFOLLOW;
+        let mut dst = File::openat(to, new_path, flags, st.st_mode & 0o7777)?;
+        let res = (|| -> io::Result<()> {
+            let mut buf = vec![0u8; COPY_SIZE];
+            let mut offset = 0;
+            loop {
+                let nread = src.read_at(&mut buf, offset)?;
+                if nread == 0 {
+                    break;
+                }
+                let mut nwritten = 0;
+                while nwritten < nread {
+                    nwritten += dst.write_at(&buf[nwritten..nread], offset + nwritten as i64)?;
+                }
+                offset += nread as i64;
+            }
+
+            // the checksum goes along with everything else, last, so
+            // what's there isn't taken for valid until it's all there
+            for name in src.list_xattr()? {
+                if let Some(v) = src.get_xattr(&name)? {
+                    dst.set_xattr(&name, &v)?;
+                }
+            }
+            return rlibc::futimens(
+                dst.as_raw_fd(),
+                &Timespec::new(st.st_atime as i64, st.st_atime_nsec as i32),
+                &Timespec::new(st.st_mtime as i64, st.st_mtime_nsec as i32),
+            );
+        })();
+        let closed = dst.close();
+        res?;
+        return closed;
+    })();
+    let _ = src.close();
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate xattr;
+    use std::fs;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn move_between_dirs() {
+        let prefix = catfs::tests::copy_resources();
+        let l1 = prefix.join("l1");
+        let l2 = prefix.join("l2");
+        fs::create_dir_all(l1.join("dir1")).unwrap();
+        fs::create_dir_all(&l2).unwrap();
+        fs::copy(prefix.join("resources/file1"), l1.join("dir1/file1")).unwrap();
+        xattr::set(l1.join("dir1/file1"), "user.catfs.src_chksum", b"42").unwrap();
+
+        let from = rlibc::open(&l1, rlibc::O_RDONLY, 0).unwrap();
+        let to = rlibc::open(&l2, rlibc::O_RDONLY, 0).unwrap();
+
+        // same filesystem, a rename
+        move_at(from, &"dir1/file1", to, &"dir1/file1", 0o777).unwrap();
+        assert!(!l1.join("dir1/file1").exists());
+        assert_eq!(
+            fs::read(l2.join("dir1/file1")).unwrap(),
+            fs::read(prefix.join("resources/file1")).unwrap()
+        );
+
+        // different filesystems, a copy
+        copy_at(to, &"dir1/file1", from, &"dir1/file1").unwrap();
+        assert_eq!(
+            fs::read(l1.join("dir1/file1")).unwrap(),
+            fs::read(prefix.join("resources/file1")).unwrap()
+        );
+        assert_eq!(
+            xattr::get(l1.join("dir1/file1"), "user.catfs.src_chksum").unwrap(),
+            Some(b"42".to_vec())
+        );
+        assert_eq!(
+            fs::metadata(l1.join("dir1/file1")).unwrap().modified().unwrap(),
+            fs::metadata(l2.join("dir1/file1")).unwrap().modified().unwrap()
+        );
+
+        rlibc::close(from).unwrap();
+        rlibc::close(to).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
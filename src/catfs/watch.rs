@@ -0,0 +1,299 @@
+extern crate libc;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+// how often the watcher checks if it's being stopped
+#[cfg(target_os = "linux")]
+const POLL_MS: libc::c_int = 1000;
+
+// watches a local src tree with inotify, so what's changed in src by
+// someone other than catfs can be dropped from the cache right away
+// instead of when it's next opened. Only directories are watched, one
+// watch each, which is bounded by fs.inotify.max_user_watches
+pub struct SourceWatcher {
+    root: PathBuf,
+    stopping: Arc<AtomicBool>,
+    t: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl SourceWatcher {
+    pub fn new(root: &Path) -> SourceWatcher {
+        return SourceWatcher {
+            root: root.to_path_buf(),
+            stopping: Arc::new(AtomicBool::new(false)),
+            t: Mutex::new(None),
+        };
+    }
+
+    // handler is called with the path under root that was written,
+    // created, removed or renamed. An empty path means events were
+    // lost and anything may have changed
+    #[cfg(target_os = "linux")]
+    pub fn start<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&Path) + Send + 'static,
+    {
+        let mut watches = Watches::new(&self.root)?;
+        watches.add_tree(Path::new(""))?;
+        info!("watching {} directories under {:?}", watches.dirs.len(), self.root);
+
+        let stopping = self.stopping.clone();
+        let t = thread::Builder::new()
+            .name(String::from("watch"))
+            .spawn(move || while !stopping.load(Ordering::Relaxed) {
+                if let Err(e) = watches.read(&handler) {
+                    error!("!watch {:?} = {}", watches.root, e);
+                    return;
+                }
+            })?;
+        *self.t.lock().unwrap() = Some(t);
+        return Ok(());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn start<F>(&self, _handler: F) -> io::Result<()>
+    where
+        F: Fn(&Path) + Send + 'static,
+    {
+        return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+    }
+
+    pub fn stop(&self) {
+        if let Some(t) = self.t.lock().unwrap().take() {
+            self.stopping.store(true, Ordering::Relaxed);
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+const MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM |
+    libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+
+#[cfg(target_os = "linux")]
+struct Watches {
+    fd: libc::c_int,
+    root: PathBuf,
+    // the directory under root each watch is for
+    dirs: HashMap<libc::c_int, PathBuf>,
+    buf: Vec<u8>,
+    // already warned about running out of watches
+    warned: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watches {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Watches {
+    fn new(root: &Path) -> io::Result<Watches> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(Watches {
+            fd: fd,
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+            buf: vec![0u8; 64 * 1024],
+            warned: false,
+        });
+    }
+
+    // dir and every directory under it, which may have been there
+    // before the watch on its parent saw it created
+    fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+        let full = self.root.join(dir);
+        let s = super::rlibc::to_cstring(&full);
+        let wd = unsafe { libc::inotify_add_watch(self.fd, s.as_ptr(), MASK) };
+        if wd == -1 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // gone already, or not a directory after all
+                Some(libc::ENOENT) | Some(libc::ENOTDIR) => return Ok(()),
+                Some(libc::ENOSPC) => {
+                    if !self.warned {
+                        warn!(
+                            "out of inotify watches at {:?}, raise fs.inotify.max_user_watches",
+                            full
+                        );
+                        self.warned = true;
+                    }
+                    return Ok(());
+                }
+                _ => return Err(e),
+            }
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        let entries = match fs::read_dir(&full) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        for entry in entries {
+            if let Ok(entry) = entry {
+                // symlinks are not followed
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.add_tree(&dir.join(entry.file_name()))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // dir was moved away, the watches under it now point at the wrong
+    // paths. Where it went is watched again if that's under root
+    fn remove_tree(&mut self, dir: &Path) {
+        let fd = self.fd;
+        self.dirs.retain(|wd, path| if path.starts_with(dir) {
+            unsafe {
+                libc::inotify_rm_watch(fd, *wd);
+            }
+            false
+        } else {
+            true
+        });
+    }
+
+    fn read<F: Fn(&Path)>(&mut self, handler: &F) -> io::Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, POLL_MS) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EINTR) {
+                return Ok(());
+            }
+            return Err(e);
+        }
+        if pfd.revents & libc::POLLIN == 0 {
+            return Ok(());
+        }
+
+        let n = unsafe {
+            libc::read(
+                self.fd,
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+            )
+        };
+        if n == -1 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EINTR) => return Ok(()),
+                _ => return Err(e),
+            }
+        }
+
+        let events = parse_events(&self.buf[..n as usize]);
+        for (wd, mask, name) in events {
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("inotify queue overflowed, invalidating everything under {:?}", self.root);
+                handler(Path::new(""));
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&wd);
+                continue;
+            }
+            let path = match self.dirs.get(&wd) {
+                Some(dir) => dir.join(&name),
+                None => continue,
+            };
+            debug!("<-- watch {:?} {:#x}", path, mask);
+            if mask & libc::IN_ISDIR != 0 {
+                if mask & libc::IN_MOVED_FROM != 0 {
+                    self.remove_tree(&path);
+                } else if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    self.add_tree(&path)?;
+                }
+            }
+            handler(&path);
+        }
+        return Ok(());
+    }
+}
+
+// the events in what was read from an inotify fd, as (wd, mask, name)
+#[cfg(target_os = "linux")]
+fn parse_events(buf: &[u8]) -> Vec<(libc::c_int, u32, PathBuf)> {
+    use std::ffi::OsStr;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    let header = mem::size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    let mut off = 0;
+    while off + header <= buf.len() {
+        let ev: libc::inotify_event = unsafe {
+            ptr::read_unaligned(buf[off..].as_ptr() as *const libc::inotify_event)
+        };
+        let start = off + header;
+        let end = start + ev.len as usize;
+        if end > buf.len() {
+            break;
+        }
+        // the name is padded with NULs
+        let name = &buf[start..end];
+        let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        events.push((ev.wd, ev.mask, PathBuf::from(OsStr::from_bytes(&name[..len]))));
+        off = end;
+    }
+    return events;
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    extern crate env_logger;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn watch() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = prefix.join("resources");
+
+        let watcher = SourceWatcher::new(&src);
+        let (tx, rx) = mpsc::channel();
+        watcher.start(move |path| { let _ = tx.send(path.to_path_buf()); }).unwrap();
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        fs::write(src.join("dir1/file1"), "changed").unwrap();
+        assert_eq!(next(), Path::new("dir1/file1"));
+
+        fs::rename(src.join("dir1"), src.join("dir3")).unwrap();
+        assert_eq!(next(), Path::new("dir1"));
+        assert_eq!(next(), Path::new("dir3"));
+
+        // the new name is watched, the old one isn't
+        fs::remove_file(src.join("dir3/file2")).unwrap();
+        assert_eq!(next(), Path::new("dir3/file2"));
+
+        fs::create_dir(src.join("dir3/dir4")).unwrap();
+        assert_eq!(next(), Path::new("dir3/dir4"));
+        fs::write(src.join("dir3/dir4/file"), "").unwrap();
+        assert_eq!(next(), Path::new("dir3/dir4/file"));
+        assert_eq!(next(), Path::new("dir3/dir4/file"));
+
+        watcher.stop();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
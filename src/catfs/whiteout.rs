@@ -0,0 +1,118 @@
+extern crate libc;
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use catfs::error;
+use catfs::file::CachePerms;
+
+// where whiteouts live in the cache dir, the evicter leaves it alone
+pub const WHITEOUT_DIR: &'static str = ".catfs-whiteout";
+
+// deletes that are recorded in the cache instead of being done on
+// the source. The whiteout of a path is an empty file at the same
+// path under WHITEOUT_DIR, a directory there only holds whiteouts of
+// its children
+pub struct Whiteouts {
+    dir: PathBuf,
+    perms: CachePerms,
+}
+
+impl Whiteouts {
+    pub fn new(cache: &dyn AsRef<Path>, perms: CachePerms) -> io::Result<Whiteouts> {
+        let w = Whiteouts {
+            dir: cache.as_ref().join(WHITEOUT_DIR),
+            perms: perms,
+        };
+        w.create_dir_all(&w.dir)?;
+        return Ok(w);
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        return fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.perms.dir_mode() as u32)
+            .create(dir);
+    }
+
+    pub fn contains(&self, path: &dyn AsRef<Path>) -> bool {
+        if path.as_ref() == Path::new("") {
+            return false;
+        }
+        match fs::symlink_metadata(self.dir.join(path)) {
+            Ok(m) => return m.is_file(),
+            Err(_) => return false,
+        }
+    }
+
+    pub fn add(&self, path: &dyn AsRef<Path>) -> io::Result<()> {
+        let wh = self.dir.join(path);
+        if let Some(parent) = wh.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        match fs::symlink_metadata(&wh) {
+            Ok(m) => {
+                if !m.is_dir() {
+                    return Ok(());
+                }
+                // the whiteout of a directory covers everything in it
+                fs::remove_dir_all(&wh)?;
+            }
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(self.perms.file_mode(0o666) as u32)
+            .open(&wh)?;
+        return Ok(());
+    }
+
+    // returns whether there was a whiteout
+    pub fn remove(&self, path: &dyn AsRef<Path>) -> io::Result<bool> {
+        if !self.contains(path) {
+            return Ok(false);
+        }
+        fs::remove_file(self.dir.join(path))?;
+        return Ok(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn add_remove() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let w = Whiteouts::new(&prefix, Default::default()).unwrap();
+
+        assert!(!w.contains(&"dir1/file1"));
+        w.add(&"dir1/file1").unwrap();
+        assert!(w.contains(&"dir1/file1"));
+        assert!(!w.contains(&"dir1"));
+
+        // covers the whiteouts already in the directory
+        w.add(&"dir1").unwrap();
+        assert!(w.contains(&"dir1"));
+        assert!(!w.contains(&"dir1/file1"));
+
+        assert!(w.remove(&"dir1").unwrap());
+        assert!(!w.remove(&"dir1").unwrap());
+        assert!(!w.contains(&"dir1"));
+        assert!(!w.contains(&""));
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
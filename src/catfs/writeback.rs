@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use catfs::file::Handle;
+
+// a file written with --write-back whose changes are only in the
+// cache so far
+struct Upload {
+    file: Arc<RwLock<Handle>>,
+    path: PathBuf,
+    due: Instant,
+    // handed to the thread pool
+    started: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    uploads: HashMap<u64, Upload>,
+    stopping: bool,
+}
+
+// files that still have to be copied to src, by ino. A timer thread
+// hands each one to the flush thread pool once it's due. The handle
+// is kept around until then, even after the file is closed, so the
+// upload doesn't depend on the cache file still being there
+pub struct WriteBack {
+    delay: Duration,
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    timer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl WriteBack {
+    pub fn new(delay: Duration) -> WriteBack {
+        return WriteBack {
+            delay: delay,
+            queue: Arc::new((Default::default(), Condvar::new())),
+            timer: Mutex::new(None),
+        };
+    }
+
+    // upload is called with each file that's due, it has to call
+    // done once the file is uploaded
+    pub fn start<F>(&self, upload: F)
+    where
+        F: Fn(u64, Arc<RwLock<Handle>>, PathBuf) + Send + 'static,
+    {
+        let queue = self.queue.clone();
+        let t = thread::Builder::new()
+            .name(String::from("write-back"))
+            .spawn(move || {
+                let &(ref lock, ref cv) = &*queue;
+                let mut q = lock.lock().unwrap();
+                loop {
+                    let now = Instant::now();
+                    let stopping = q.stopping;
+                    let mut next: Option<Instant> = None;
+                    for (ino, u) in q.uploads.iter_mut() {
+                        if u.started {
+                            continue;
+                        }
+                        if stopping || u.due <= now {
+                            u.started = true;
+                            upload(*ino, u.file.clone(), u.path.clone());
+                        } else if next.map_or(true, |n| u.due < n) {
+                            next = Some(u.due);
+                        }
+                    }
+
+                    if stopping {
+                        return;
+                    }
+                    q = match next {
+                        Some(next) => cv.wait_timeout(q, next - now).unwrap().0,
+                        None => cv.wait(q).unwrap(),
+                    };
+                }
+            });
+
+        match t {
+            Ok(t) => *self.timer.lock().unwrap() = Some(t),
+            Err(e) => error!("!spawn write-back = {}", e),
+        }
+    }
+
+    // called with file locked, so it's not written to again before
+    // it's queued
+    pub fn add(&self, ino: u64, file: Arc<RwLock<Handle>>, path: PathBuf) {
+        let &(ref lock, ref cv) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        if let Some(u) = q.uploads.get(&ino) {
+            // wait for the one that was due first
+            if !u.started && Arc::ptr_eq(&u.file, &file) {
+                return;
+            }
+        }
+        q.uploads.insert(
+            ino,
+            Upload {
+                file: file,
+                path: path,
+                due: Instant::now() + self.delay,
+                started: false,
+            },
+        );
+        cv.notify_all();
+    }
+
+    // called with file locked after it's uploaded, unless it was
+    // queued again in the meantime
+    pub fn done(&self, ino: u64, file: &Arc<RwLock<Handle>>) {
+        let mut q = self.queue.0.lock().unwrap();
+        let uploaded = match q.uploads.get(&ino) {
+            Some(u) => u.started && Arc::ptr_eq(&u.file, file),
+            None => false,
+        };
+        if uploaded {
+            q.uploads.remove(&ino);
+        }
+    }
+
+    // to upload right away instead. Locking the file waits for an
+    // upload that already started
+    pub fn take(&self, ino: u64) -> Option<(Arc<RwLock<Handle>>, PathBuf)> {
+        let mut q = self.queue.0.lock().unwrap();
+        return q.uploads.remove(&ino).map(|u| (u.file, u.path));
+    }
+
+    pub fn is_pending(&self, ino: u64) -> bool {
+        return self.queue.0.lock().unwrap().uploads.contains_key(&ino);
+    }
+
+    pub fn pending(&self) -> Vec<u64> {
+        return self.queue.0.lock().unwrap().uploads.keys().cloned().collect();
+    }
+
+    // hands everything that's left to the thread pool
+    pub fn stop(&self) {
+        {
+            let &(ref lock, ref cv) = &*self.queue;
+            lock.lock().unwrap().stopping = true;
+            cv.notify_all();
+        }
+        if let Some(t) = self.timer.lock().unwrap().take() {
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    extern crate threadpool;
+    use std::fs;
+    use std::sync::mpsc;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use catfs::file::OpenOptions;
+    use catfs::rlibc;
+    use self::threadpool::ThreadPool;
+    use super::*;
+
+    #[test]
+    fn queue() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        let tp = Mutex::new(ThreadPool::new(1));
+        let open = |path| {
+            Arc::new(RwLock::new(
+                Handle::open(
+                    &src,
+                    cache_dir,
+                    Default::default(),
+                    Default::default(),
+                    &path,
+                    rlibc::O_RDWR,
+                    false,
+                    &tp,
+                    &OpenOptions {
+                        readahead: 4096,
+                        ..Default::default()
+                    },
+                ).unwrap(),
+            ))
+        };
+
+        let wb = WriteBack::new(Duration::from_secs(3600));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        wb.start(move |ino, file, _| {
+            tx.lock().unwrap().send((ino, file)).unwrap();
+        });
+
+        let f1 = open("file1");
+        let f2 = open("file2");
+        wb.add(1, f1.clone(), PathBuf::from("file1"));
+        wb.add(2, f2.clone(), PathBuf::from("file2"));
+        wb.add(2, f2.clone(), PathBuf::from("file2"));
+        assert!(wb.is_pending(1));
+        let mut pending = wb.pending();
+        pending.sort();
+        assert_eq!(pending, vec![1, 2]);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let (f, path) = wb.take(1).unwrap();
+        assert!(Arc::ptr_eq(&f, &f1));
+        assert_eq!(path, PathBuf::from("file1"));
+        assert!(!wb.is_pending(1));
+        assert!(wb.take(1).is_none());
+
+        // whatever is left is uploaded when stopping
+        wb.stop();
+        let (ino, file) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(ino, 2);
+        assert!(Arc::ptr_eq(&file, &f2));
+        assert!(rx.try_recv().is_err());
+        assert!(wb.is_pending(2));
+        wb.done(2, &file);
+        assert!(!wb.is_pending(2));
+        drop(file);
+
+        drop(f);
+        drop(f1);
+        drop(f2);
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+extern crate libc;
+
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path};
+
+use catfs::backend::SourceBackend;
+use catfs::compress;
+use catfs::error;
+use catfs::file;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use evicter::dir_walker::DirWalker;
+use export::MANIFEST;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    // src has changed since it was cached
+    Stale,
+    // src no longer has it, or catfs would never look for it here
+    Orphaned,
+    // can't tell what it's a cache of, or doesn't match src when it
+    // says it does
+    Corrupt,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Problem::Stale => "stale",
+            Problem::Orphaned => "orphaned",
+            Problem::Corrupt => "corrupt",
+        };
+        return f.write_str(s);
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct CheckStats {
+    pub valid: usize,
+    pub stale: usize,
+    pub orphaned: usize,
+    pub corrupt: usize,
+    // written through the mount and not in src yet, or still being
+    // cached. These are left alone
+    pub dirty: usize,
+    pub deleted: usize,
+}
+
+impl CheckStats {
+    pub fn problems(&self) -> usize {
+        return self.stale + self.orphaned + self.corrupt;
+    }
+}
+
+// goes through every file in a cache dir and checks it against src,
+// like catfs does when the file is opened. With delete, what's no
+// good is removed, unless a mount is paging it in
+pub struct Checker<'a> {
+    src: &'a dyn SourceBackend,
+    cache_dir: RawFd,
+    layout: file::CacheLayout,
+    delete: bool,
+    stats: CheckStats,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(src: &'a dyn SourceBackend, cache_dir: RawFd, delete: bool) -> error::Result<Checker<'a>> {
+        return Ok(Checker {
+            src: src,
+            cache_dir: cache_dir,
+            layout: file::CacheLayout::load(cache_dir)?,
+            delete: delete,
+            stats: Default::default(),
+        });
+    }
+
+    pub fn stats(&self) -> &CheckStats {
+        return &self.stats;
+    }
+
+    pub fn check(&mut self) -> error::Result<()> {
+        for cache_path in DirWalker::new(self.cache_dir)? {
+            if cache_path == Path::new(MANIFEST) {
+                continue;
+            }
+
+            let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+            let mut f = match File::openat(self.cache_dir, &cache_path, flags, 0) {
+                Ok(f) => f,
+                Err(e) => {
+                    // evicted since we listed it, or a cached symlink
+                    // which has nothing to check
+                    if error::is_enoent(&e) || e.raw_os_error() == Some(libc::ELOOP) {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
+            let res = self.check_file(&cache_path, &f);
+            let res = match res {
+                Ok(Some(problem)) => self.found(&cache_path, &f, problem),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            f.close()?;
+            res?;
+        }
+        return Ok(());
+    }
+
+    fn check_file(&mut self, cache_path: &Path, f: &File) -> error::Result<Option<Problem>> {
+        let st = f.stat()?;
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+            return Ok(None);
+        }
+        let path = match self.layout.src_path(&cache_path, f)? {
+            Some(ref path) if is_relative(path) => path.clone(),
+            _ => return Ok(Some(Problem::Corrupt)),
+        };
+        if self.layout.cache_path(&path) != cache_path {
+            // left over from another layout
+            return Ok(Some(Problem::Orphaned));
+        }
+
+        let pristine = file::was_pristine(f);
+        let by_block = file::is_filled_by_block(f);
+        let src_st = match self.src.stat(&path) {
+            Ok(st) => st,
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e.into());
+                }
+                if pristine || by_block {
+                    return Ok(Some(Problem::Orphaned));
+                }
+                // created through the mount
+                self.stats.dirty += 1;
+                return Ok(None);
+            }
+        };
+
+        if pristine {
+            if !file::Handle::validate_cache(self.src, self.cache_dir, self.layout, &path, false, true)? {
+                return Ok(Some(Problem::Stale));
+            }
+            match compress::Reader::new(f).and_then(|r| r.size()) {
+                Ok(size) if size == src_st.st_size as i64 => (),
+                _ => return Ok(Some(Problem::Corrupt)),
+            }
+        } else if by_block {
+            if !file::Handle::has_blocks(self.src, &path, f)? {
+                return Ok(Some(Problem::Stale));
+            }
+        } else {
+            self.stats.dirty += 1;
+            return Ok(None);
+        }
+
+        self.stats.valid += 1;
+        return Ok(None);
+    }
+
+    fn found(&mut self, cache_path: &Path, f: &File, problem: Problem) -> error::Result<()> {
+        match problem {
+            Problem::Stale => self.stats.stale += 1,
+            Problem::Orphaned => self.stats.orphaned += 1,
+            Problem::Corrupt => self.stats.corrupt += 1,
+        }
+
+        if !self.delete {
+            warn!("{:?} is {}", cache_path, problem);
+            return Ok(());
+        }
+        // a mount that's paging it in holds the lock
+        if !rlibc::try_flock(f.as_raw_fd(), libc::LOCK_EX)? {
+            warn!("{:?} is {} but in use, not deleting", cache_path, problem);
+            return Ok(());
+        }
+        warn!("{:?} is {}, deleting", cache_path, problem);
+        rlibc::unlinkat(self.cache_dir, &cache_path, 0)?;
+        self.stats.deleted += 1;
+        return Ok(());
+    }
+}
+
+fn is_relative(path: &Path) -> bool {
+    return path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use super::*;
+
+    #[test]
+    fn check() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+
+        fs::create_dir_all(cache.join("dir1")).unwrap();
+        for p in ["file1", "file2", "file3", "dir1/file1"].iter() {
+            fs::copy(prefix.join("resources").join(p), cache.join(p)).unwrap();
+        }
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        for p in ["file1", "file2", "file3", "dir1/file1"].iter() {
+            file::Handle::make_pristine(&src, cache_dir, file::CacheLayout::Path, p).unwrap();
+        }
+        // src has changed since
+        fs::write(prefix.join("resources/file2"), "changed").unwrap();
+        fs::remove_file(prefix.join("resources/dir1/file1")).unwrap();
+        // and so has the cache
+        fs::write(cache.join("file3"), "").unwrap();
+        // not written back yet
+        fs::write(cache.join("new"), "new").unwrap();
+
+        {
+            let mut checker = Checker::new(&src, cache_dir, false).unwrap();
+            checker.check().unwrap();
+            assert_eq!(
+                *checker.stats(),
+                CheckStats {
+                    valid: 1,
+                    stale: 1,
+                    orphaned: 1,
+                    corrupt: 1,
+                    dirty: 1,
+                    deleted: 0,
+                }
+            );
+        }
+        assert!(cache.join("file2").exists());
+
+        {
+            let mut checker = Checker::new(&src, cache_dir, true).unwrap();
+            checker.check().unwrap();
+            assert_eq!(checker.stats().deleted, 3);
+        }
+        rlibc::close(cache_dir).unwrap();
+        assert!(cache.join("file1").exists());
+        assert!(cache.join("new").exists());
+        assert!(!cache.join("file2").exists());
+        assert!(!cache.join("file3").exists());
+        assert!(!cache.join("dir1/file1").exists());
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -5,13 +5,22 @@ use std::ptr;
 use std::os::unix::io::RawFd;
 
 use catfs::error;
+use catfs::backend::cow::COW_DIR;
 use catfs::rlibc;
+use catfs::whiteout::WHITEOUT_DIR;
 
+// lists the files under a cache dir, whatever its layout is. Only
+// files are returned so the aa/bb directories of the fanout layout
+// are never evicted. Symlinks are returned like files and never
+// followed
 pub struct DirWalker {
     dir: RawFd,
     cur: *mut libc::DIR,
     cur_path: PathBuf,
     stack: Vec<PathBuf>,
+    // how deep the cache dirs are, 1 if dir is shared by mounts that
+    // each cache under their own prefix
+    depth: usize,
 }
 
 impl DirWalker {
@@ -22,16 +31,43 @@ impl DirWalker {
             cur: rlibc::fdopendir(fd)?,
             cur_path: Default::default(),
             stack: Default::default(),
+            depth: 0,
         })
     }
 
+    // for a cache dir shared by several mounts, see
+    // Evicter::set_quota
+    pub fn with_prefixes(dir: RawFd) -> error::Result<DirWalker> {
+        let mut w = DirWalker::new(dir)?;
+        w.depth = 1;
+        return Ok(w);
+    }
+
+    fn is_dir(&self, entry: &rlibc::Dirent) -> bool {
+        match entry.en.d_type {
+            libc::DT_DIR => return true,
+            // not every filesystem fills in d_type
+            libc::DT_UNKNOWN => {
+                match rlibc::fstatat(self.dir, &self.cur_path.join(entry.name())) {
+                    Ok(st) => return (st.st_mode & libc::S_IFMT) == libc::S_IFDIR,
+                    Err(_) => return false,
+                }
+            }
+            _ => return false,
+        }
+    }
+
     fn next_internal(&mut self) -> error::Result<Option<PathBuf>> {
         loop {
             match rlibc::readdir(self.cur)? {
                 Some(entry) => {
-                    if entry.en.d_type == libc::DT_DIR {
+                    if self.is_dir(&entry) {
                         let name = entry.name();
-                        if name != Path::new(".") && name != Path::new("..") {
+                        // whiteouts and copied up files are not cached
+                        // files, they must stay
+                        let is_whiteouts = self.cur_path.components().count() == self.depth &&
+                            (name == Path::new(WHITEOUT_DIR) || name == Path::new(COW_DIR));
+                        if name != Path::new(".") && name != Path::new("..") && !is_whiteouts {
                             self.stack.push(self.cur_path.join(entry.name()));
                         }
                     } else {
@@ -43,7 +79,12 @@ impl DirWalker {
                     self.cur = ptr::null_mut();
 
                     if let Some(next) = self.stack.pop() {
-                        let fd = rlibc::openat(self.dir, &next, rlibc::O_RDONLY, 0)?;
+                        let fd = rlibc::openat(
+                            self.dir,
+                            &next,
+                            rlibc::O_RDONLY | rlibc::O_NOFOLLOW,
+                            0,
+                        )?;
                         self.cur = rlibc::fdopendir(fd)?;
                         self.cur_path = next;
                     } else {
@@ -83,7 +124,10 @@ impl Iterator for DirWalker {
 mod tests {
     extern crate env_logger;
     use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
     use std::path::{Path, PathBuf};
+    use catfs;
     use catfs::rlibc;
     use super::*;
 
@@ -107,4 +151,33 @@ mod tests {
         assert_eq!(iter.next().unwrap(), Path::new("file3"));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn symlinks() {
+        let _ = env_logger::init();
+
+        let prefix = catfs::tests::copy_resources();
+        let resources = prefix.join("resources");
+        symlink("dir1", resources.join("link1")).unwrap();
+        symlink("nowhere", resources.join("dir1/link2")).unwrap();
+        let fd = rlibc::open(&resources, rlibc::O_RDONLY, 0).unwrap();
+        let mut files: Vec<PathBuf> = DirWalker::new(fd).unwrap().collect();
+        files.sort();
+        rlibc::close(fd).unwrap();
+
+        // what link1 points to is only listed once
+        assert_eq!(
+            files,
+            vec![
+                Path::new("dir1/file1"),
+                Path::new("dir1/file2"),
+                Path::new("dir1/link2"),
+                Path::new("file1"),
+                Path::new("file2"),
+                Path::new("file3"),
+                Path::new("link1"),
+            ]
+        );
+        fs::remove_dir_all(&prefix).unwrap();
+    }
 }
@@ -3,24 +3,35 @@ extern crate libc;
 extern crate rand;
 extern crate twox_hash;
 
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use catfs;
+use catfs::backend::SourceBackend;
+use catfs::crypt;
 use catfs::flags::DiskSpace;
 use catfs::error;
+use catfs::file;
+use catfs::file::CacheLayout;
+use catfs::pin;
+use catfs::pin::Pins;
 use catfs::rlibc;
+use catfs::tier;
 
 pub mod dir_walker;
+pub mod policy;
 use self::dir_walker::DirWalker;
+use self::policy::EvictionPolicy;
 use self::itertools::Itertools;
 use self::twox_hash::XxHash;
 
@@ -29,33 +40,193 @@ use self::libc::statvfs64;
 #[cfg(target_os = "macos")]
 use self::libc::{statvfs as statvfs64};
 
-pub struct Evicter {
-    dir: RawFd,
+pub const DEFAULT_SCAN_SECS: u64 = 60;
+
+struct State {
     high_watermark: DiskSpace,
     low_watermark: DiskSpace,
+    // given to us instead of following high_watermark
+    fixed_low_watermark: bool,
+    // asked to check now instead of at the next scan
+    evict_now: bool,
+    // how many checks that were asked for have started and are
+    // done, and when the last one was done
+    forced_started: u64,
+    forced_done: u64,
+    last_forced: Option<Instant>,
+    shutting_down: bool,
+    // how many files were evicted, and how big the cache was when
+    // it was last walked, which is only done for --max-cache-size
+    evicted: u64,
+    cache_size: Option<u64>,
+    // checks in a row that failed
+    errors: u64,
+    // whose write ran out of space, for the next forced check
+    kick_uid: Option<libc::uid_t>,
+}
+
+type SharedState = Arc<(Mutex<State>, Condvar)>;
+
+pub struct Evicter {
+    dir: RawFd,
+    state: SharedState,
     scan_freq: Duration,
-    hot_percent: usize, // 25 to keep most recently used 25%
-    request_weight: u32,
+    policy: Box<dyn EvictionPolicy>,
+    pins: Pins,
+    // to find cache files that are no use, if we know it
+    src: Option<Arc<dyn SourceBackend>>,
+    // cap on how much the cache uses, on top of the free space
+    // target. Bytes(0) is no cap
+    max_size: DiskSpace,
+    // where cold files that are still of use go instead of being
+    // deleted, and the mode of the directories made there
+    l2: Option<(RawFd, libc::mode_t)>,
+    // with a quota, dir is shared by mounts that each cache under
+    // their own top level directory of it, none of which may take up
+    // more than quota. prefix is ours, src, pins and l2 only apply
+    // to what's under it
+    quota: DiskSpace,
+    prefix: Option<PathBuf>,
+    // no uid's cache files may take up more than this, 0 is no limit
+    quota_per_uid: u64,
     statvfs: fn(RawFd) -> io::Result<statvfs64>,
-    cv: Arc<Condvar>,
-    shutting_down: Arc<Mutex<bool>>,
     t: Option<JoinHandle<()>>,
 }
 
-struct EvictItem {
+// lets other parts of catfs adjust a running evicter
+#[derive(Clone)]
+pub struct EvicterHandle {
+    state: SharedState,
+}
+
+impl EvicterHandle {
+    pub fn set_free(&self, free: &DiskSpace) {
+        let mut state = self.state.0.lock().unwrap();
+        state.high_watermark = free.clone();
+        if !state.fixed_low_watermark {
+            state.low_watermark = low_watermark_of(free);
+        }
+        debug!("free space target is now {:?}", free);
+    }
+
+    // evicts right away whatever is above the low watermark, instead
+    // of waiting for free space to go below the high one
+    pub fn evict_now(&self) {
+        let &(ref lock, ref cv) = &*self.state;
+        lock.lock().unwrap().evict_now = true;
+        // whoever is waiting in kick also waits on this
+        cv.notify_all();
+    }
+
+    pub fn evicted(&self) -> u64 {
+        return self.state.0.lock().unwrap().evicted;
+    }
+
+    pub fn cache_size(&self) -> Option<u64> {
+        return self.state.0.lock().unwrap().cache_size;
+    }
+
+    pub fn errors(&self) -> u64 {
+        return self.state.0.lock().unwrap().errors;
+    }
+
+    // like evict_now, then waits for up to wait for it to be done.
+    // Returns false if it wasn't done in time, or if one was already
+    // done less than wait ago, since then there's probably nothing
+    // more to evict
+    pub fn kick(&self, wait: Duration) -> bool {
+        return self.kick_for(None, wait);
+    }
+
+    // like kick, for a write of uid's that ran out of space. If uid is
+    // over --quota-per-uid, only its files are evicted
+    pub fn kick_uid(&self, uid: libc::uid_t, wait: Duration) -> bool {
+        return self.kick_for(Some(uid), wait);
+    }
+
+    fn kick_for(&self, uid: Option<libc::uid_t>, wait: Duration) -> bool {
+        let &(ref lock, ref cv) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if let Some(last) = state.last_forced {
+            if last.elapsed() < wait {
+                return false;
+            }
+        }
+        state.kick_uid = uid;
+
+        // the next one to start, one that's running may have started
+        // before whoever is kicking us needed the space
+        let target = state.forced_started + 1;
+        state.evict_now = true;
+        cv.notify_all();
+
+        let deadline = Instant::now() + wait;
+        while state.forced_done < target && !state.shutting_down {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cv.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        return state.forced_done >= target;
+    }
+}
+
+fn low_watermark_of(high_watermark: &DiskSpace) -> DiskSpace {
+    match *high_watermark {
+        DiskSpace::Percent(p) => DiskSpace::Percent((p * 1.1).min(100.0)),
+        DiskSpace::Bytes(b) => DiskSpace::Bytes((b as f64 * 1.1) as u64),
+    }
+}
+
+// what to shrink the cache to once it's over max_size
+fn low_max_size_of(max_size: &DiskSpace) -> DiskSpace {
+    match *max_size {
+        DiskSpace::Percent(p) => DiskSpace::Percent(p * 0.9),
+        DiskSpace::Bytes(b) => DiskSpace::Bytes((b as f64 * 0.9) as u64),
+    }
+}
+
+// page in of a file that was modified this recently may still be
+// going, so it's not partial yet
+const PARTIAL_SECS: u64 = 60;
+
+// in the order they are evicted, before the policy gets a say
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Class {
+    // src is gone
+    Orphaned,
+    // page in didn't finish
+    Partial,
+    Valid,
+}
+
+pub struct EvictItem {
     hash: u64,
-    atime: SystemTime,
-    size: usize,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub size: usize,
+    // how many times it's been opened, only if the policy wants it
+    pub hits: u64,
+    pub class: Class,
+    // which mount it's from in a shared cache dir
+    prefix: Option<OsString>,
+    pub uid: libc::uid_t,
 }
 
 impl EvictItem {
-    fn new(dir: RawFd, path: &dyn AsRef<Path>) -> error::Result<EvictItem> {
+    fn new(dir: RawFd, path: &dyn AsRef<Path>, hits: bool) -> error::Result<EvictItem> {
         let st = rlibc::fstatat(dir, path)?;
 
         Ok(EvictItem {
             hash: EvictItem::hash_of(path),
             size: (st.st_blocks * 512) as usize,
             atime: UNIX_EPOCH + Duration::new(st.st_atime as u64, st.st_atime_nsec as u32),
+            mtime: UNIX_EPOCH + Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32),
+            hits: if hits { EvictItem::hits_of(dir, path) } else { 0 },
+            class: Class::Valid,
+            prefix: None,
+            uid: st.st_uid,
         })
     }
 
@@ -64,6 +235,22 @@ impl EvictItem {
             hash: EvictItem::hash_of(path),
             size: Default::default(),
             atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            hits: 0,
+            class: Class::Valid,
+            prefix: None,
+            uid: 0,
+        }
+    }
+
+    fn hits_of(dir: RawFd, path: &dyn AsRef<Path>) -> u64 {
+        match rlibc::File::openat(dir, path, rlibc::O_RDONLY | rlibc::O_NOFOLLOW, 0) {
+            Ok(mut f) => {
+                let hits = file::hits(&f);
+                let _ = f.close();
+                return hits;
+            }
+            Err(_) => return 0,
         }
     }
 
@@ -114,121 +301,558 @@ fn to_evict(spec: &DiskSpace, st: &statvfs64) -> u64 {
     return if x > 0 { x as u64 } else { 0 };
 }
 
+// in bytes, used is how much the cache takes up
+fn over_max_size(spec: &DiskSpace, st: &statvfs64, used: u64) -> u64 {
+    let max = match *spec {
+        DiskSpace::Percent(p) => ((st.f_blocks as u64 * st.f_frsize as u64) as f64 * p / 100.0) as u64,
+        DiskSpace::Bytes(b) => b,
+    };
+    return used.saturating_sub(max);
+}
+
+// the top level directory path is under, which in a shared cache dir
+// is the prefix of the mount that cached it
+fn prefix_of(path: &Path) -> Option<OsString> {
+    let mut c = path.components();
+    let first = c.next()?;
+    if c.next().is_none() {
+        return None;
+    }
+    return Some(first.as_os_str().to_os_string());
+}
+
+// how much each prefix and each uid that went over its quota has to
+// give up to be back under it
+#[derive(Clone, Default)]
+struct OverQuota {
+    prefixes: HashMap<OsString, u64>,
+    uids: HashMap<libc::uid_t, u64>,
+}
+
+impl OverQuota {
+    fn is_empty(&self) -> bool {
+        return self.prefixes.is_empty() && self.uids.is_empty();
+    }
+
+    fn total(&self) -> u64 {
+        return self.prefixes.values().sum::<u64>() + self.uids.values().sum::<u64>();
+    }
+
+    // whether evicting x gets someone closer to their quota, and
+    // counts it against them if so
+    fn take(&mut self, x: &EvictItem) -> bool {
+        fn take_from(left: Option<&mut u64>, size: usize) -> bool {
+            match left {
+                Some(left) if *left > 0 => {
+                    *left = left.saturating_sub(size as u64);
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+
+        let mut over = false;
+        if let Some(ref prefix) = x.prefix {
+            over |= take_from(self.prefixes.get_mut(prefix), x.size);
+        }
+        over |= take_from(self.uids.get_mut(&x.uid), x.size);
+        return over;
+    }
+}
+
 impl Evicter {
-    fn should_evict(&self, st: &statvfs64) -> u64 {
-        return to_evict(&self.high_watermark, st);
+    fn should_evict(&self, st: &statvfs64, used: u64) -> u64 {
+        let mut x = to_evict(&self.state.0.lock().unwrap().high_watermark, st);
+        if self.has_max_size() {
+            x = cmp::max(x, over_max_size(&self.max_size, st, used));
+        }
+        return x;
+    }
+
+    fn to_evict(&self, st: &statvfs64, used: u64) -> u64 {
+        let mut x = {
+            let state = self.state.0.lock().unwrap();
+            // a low watermark below the high one still has to get us
+            // to the high one
+            cmp::max(
+                to_evict(&state.low_watermark, st),
+                to_evict(&state.high_watermark, st),
+            )
+        };
+        if self.has_max_size() {
+            x = cmp::max(x, over_max_size(&low_max_size_of(&self.max_size), st, used));
+        }
+        return x;
+    }
+
+    fn has_max_size(&self) -> bool {
+        return self.max_size != DiskSpace::Bytes(0);
+    }
+
+    // how much the cache takes up, only needed with a max_size
+    fn cache_size(&self) -> error::Result<u64> {
+        if !self.has_max_size() {
+            return Ok(0);
+        }
+        return Ok(self.walk()?
+            .filter_map(|p| rlibc::fstatat(self.dir, &p).ok())
+            .fold(0u64, |t, st| t + st.st_blocks as u64 * 512));
+    }
+
+    fn is_shared(&self) -> bool {
+        return self.quota != DiskSpace::Bytes(0);
+    }
+
+    fn walk(&self) -> error::Result<DirWalker> {
+        if self.is_shared() {
+            return DirWalker::with_prefixes(self.dir);
+        }
+        return DirWalker::new(self.dir);
+    }
+
+    // path relative to our cache dir, if it's one of ours
+    fn ours<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        if !self.is_shared() {
+            return Some(path);
+        }
+        return match self.prefix {
+            Some(ref prefix) => path.strip_prefix(prefix).ok(),
+            None => None,
+        };
+    }
+
+    fn layout(&self) -> error::Result<CacheLayout> {
+        let prefix = match self.prefix {
+            Some(ref prefix) if self.is_shared() => prefix,
+            _ => return CacheLayout::load(self.dir),
+        };
+        let dir = rlibc::openat(self.dir, prefix, rlibc::O_RDONLY, 0)?;
+        let layout = CacheLayout::load(dir);
+        rlibc::close(dir)?;
+        return layout;
+    }
+
+    // how much each prefix that went over the quota has to give up to
+    // be back under it
+    fn over_quota(&self, st: &statvfs64) -> error::Result<OverQuota> {
+        let mut over: OverQuota = Default::default();
+        if !self.is_shared() && self.quota_per_uid == 0 {
+            return Ok(over);
+        }
+
+        let mut by_prefix: HashMap<OsString, u64> = HashMap::new();
+        let mut by_uid: HashMap<libc::uid_t, u64> = HashMap::new();
+        for p in self.walk()? {
+            let st = match rlibc::fstatat(self.dir, &p) {
+                Ok(st) => st,
+                Err(_) => continue,
+            };
+            let size = st.st_blocks as u64 * 512;
+            if let (true, Some(prefix)) = (self.is_shared(), prefix_of(&p)) {
+                *by_prefix.entry(prefix).or_insert(0) += size;
+            }
+            *by_uid.entry(st.st_uid).or_insert(0) += size;
+        }
+        for (prefix, used) in by_prefix {
+            if over_max_size(&self.quota, st, used) > 0 {
+                over.prefixes.insert(prefix, over_max_size(&low_max_size_of(&self.quota), st, used));
+            }
+        }
+        if self.quota_per_uid != 0 {
+            let quota = DiskSpace::Bytes(self.quota_per_uid);
+            for (uid, used) in by_uid {
+                if over_max_size(&quota, st, used) > 0 {
+                    over.uids.insert(uid, over_max_size(&low_max_size_of(&quota), st, used));
+                }
+            }
+        }
+        return Ok(over);
+    }
+
+    #[cfg(test)]
+    fn low_watermark(&self) -> DiskSpace {
+        return self.state.0.lock().unwrap().low_watermark.clone();
     }
 
-    fn to_evict(&self, st: &statvfs64) -> u64 {
-        return to_evict(&self.low_watermark, st);
+    pub fn handle(&self) -> EvicterHandle {
+        return EvicterHandle { state: self.state.clone() };
     }
 
     pub fn loop_once(&self) -> error::Result<()> {
+        return self.check(false);
+    }
+
+    fn check(&self, force: bool) -> error::Result<()> {
         let st = (self.statvfs)(self.dir)?;
+        let used = self.cache_size()?;
+        if self.has_max_size() {
+            self.state.0.lock().unwrap().cache_size = Some(used);
+        }
 
-        let to_evict_bytes = self.should_evict(&st);
+        let over_quota = self.over_quota(&st)?;
+        let to_evict_bytes = cmp::max(
+            if force {
+                self.to_evict(&st, used)
+            } else {
+                self.should_evict(&st, used)
+            },
+            over_quota.total(),
+        );
+        // a write that ran out of space only costs others their files
+        // if whoever wrote it isn't over their own quota
+        let only_uid = if force {
+            self.state.0.lock().unwrap().kick_uid.take().filter(
+                |uid| over_quota.uids.contains_key(uid),
+            )
+        } else {
+            None
+        };
         debug!(
-            "total: {} free: {} to_evict: {}",
+            "total: {} free: {} used: {} to_evict: {}",
             st.f_blocks,
             st.f_bfree,
+            used,
             to_evict_bytes
         );
 
         if to_evict_bytes > 0 {
-            let to_evict_bytes = self.to_evict(&st);
-            let mut evicted_bytes = 0;
-
-            let mut items = DirWalker::new(self.dir)?
-                .map(|x| EvictItem::new(self.dir, &x))
-                .map_results(Box::new)
-                .fold_results(Box::new(Vec::new()), |mut v, x| {
-                    v.push(x);
-                    v
-                })?;
-
-            if items.is_empty() {
+            // another catfs sharing the cache dir is already evicting
+            if !rlibc::try_flock(self.dir, libc::LOCK_EX)? {
+                debug!("cache dir is being evicted by someone else");
                 return Ok(());
             }
+            let res = self.evict(&st, used, &over_quota, only_uid);
+            rlibc::flock(self.dir, libc::LOCK_UN)?;
+            return res;
+        }
 
-            items.sort_by_key(|x| x.atime);
+        return Ok(());
+    }
 
-            let mut total_size = 0u64;
-            for i in 0..items.len() {
-                total_size += items[i].size as u64;
+    fn evict(
+        &self,
+        st: &statvfs64,
+        used: u64,
+        over_quota: &OverQuota,
+        only_uid: Option<libc::uid_t>,
+    ) -> error::Result<()> {
+        let mut to_evict_bytes = self.to_evict(st, used);
+        let mut evicted_bytes = 0;
+        let mut evicted = 0;
+
+        let layout = self.layout()?;
+        let hits = self.policy.uses_hits();
+        let now = SystemTime::now();
+        let items = self.walk()?
+            .filter(|x| !self.is_pinned(layout, x))
+            .map(|x| {
+                EvictItem::new(self.dir, &x, hits).map(|mut i| {
+                    i.class = self.classify(layout, &x, i.mtime, now);
+                    if self.is_shared() {
+                        i.prefix = prefix_of(&x);
+                    }
+                    i
+                })
+            })
+            .map_results(Box::new)
+            .fold_results(Box::new(Vec::new()), |mut v, x| {
+                v.push(x);
+                v
+            })?;
+
+        if items.is_empty() {
+            return Ok(());
+        }
 
-                if total_size >= to_evict_bytes &&
-                    i >= items.len() * (100 - self.hot_percent) / 100
-                {
-                    items.truncate(i + 1);
-                    break;
-                }
-            }
+        let items: Vec<_> = match only_uid {
+            Some(uid) => items.into_iter().filter(|x| x.uid == uid).collect(),
+            None => items.into_iter().collect(),
+        };
+        // what's of no use goes first, whatever the policy
+        let (mut items, mut valid): (Vec<_>, Vec<_>) =
+            items.into_iter().partition(|x| x.class != Class::Valid);
+        items.sort_by_key(|x| x.class);
+        let wasted_bytes = items.iter().fold(0u64, |t, x| t + x.size as u64);
+        self.policy.order(&mut valid, to_evict_bytes.saturating_sub(wasted_bytes), now);
+
+        // then the files of prefixes and uids over their quota, until
+        // they are back under it
+        if !over_quota.is_empty() {
+            let mut excess = over_quota.clone();
+            let (mut over, rest): (Vec<_>, Vec<_>) =
+                valid.into_iter().partition(|x| excess.take(x));
+            let over_bytes = over.iter().fold(0u64, |t, x| t + x.size as u64);
+            to_evict_bytes = cmp::max(to_evict_bytes, wasted_bytes + over_bytes);
+            over.extend(rest);
+            valid = over;
+        }
+        items.extend(valid);
+
+        let mut candidates_to_evict = 0u64;
+
+        type EvictItemSet = HashSet<Box<EvictItem>, BuildHasherDefault<IdentU64Hasher>>;
+        let mut item_set = EvictItemSet::default();
 
-            let now = SystemTime::now();
-            let oldest = now.duration_since(items[0].atime).unwrap().as_secs();
+        for i in items.into_iter() {
+            candidates_to_evict += i.size as u64;
+            item_set.insert(i);
+
+            if candidates_to_evict >= to_evict_bytes {
+                break;
+            }
+        }
 
-            // now I have items that have not been accessed recently,
-            // weight them according to size and age
-            items.sort_by_key(|x| {
-                let cost = x.size as u64 + self.request_weight as u64;
-                let age = now.duration_since(x.atime).unwrap().as_secs();
-                if oldest == 0 {
-                    cost
+        self.walk()?
+            .map(|p| (Box::new(EvictItem::new_for_lookup(&p)), p))
+            .foreach(|i| if let Some(item) = item_set.get(&i.0) {
+                if Evicter::is_paging_in(self.dir, &i.1) {
+                    debug!("not evicting {:?}, it's being cached", i.1);
+                    return;
+                }
+                evicted_bytes += item.size;
+                if let Err(e) = self.remove(&i.1, item.class) {
+                    debug!("wanted to evict {:?}={} but got {}", i.1, item.size, e);
                 } else {
-                    cost * age / oldest
+                    debug!("evicting {:?}={}", i.1, item.size);
+                    evicted += 1;
                 }
             });
+        self.state.0.lock().unwrap().evicted += evicted;
+
+        return Ok(());
+    }
 
-            let mut candidates_to_evict = 0u64;
+    // cache files that were fully paged in and are still of use are
+    // demoted to l2 if there is one, everything else is deleted
+    fn remove(&self, path: &Path, class: Class) -> io::Result<()> {
+        if let (Some((l2, dir_mode)), Some(rel)) = (self.l2, self.ours(path)) {
+            if class == Class::Valid && Evicter::is_pristine(self.dir, &path) {
+                match tier::move_at(self.dir, &path, l2, &rel, dir_mode) {
+                    Ok(_) => {
+                        debug!("demoted {:?} to l2", path);
+                        return Ok(());
+                    }
+                    Err(e) => debug!("!demote {:?} = {}", path, e),
+                }
+            }
+        }
+        return rlibc::unlinkat(self.dir, &path, 0);
+    }
 
-            type EvictItemSet = HashSet<Box<EvictItem>, BuildHasherDefault<IdentU64Hasher>>;
-            let mut item_set = EvictItemSet::default();
+    fn is_pristine(dir: RawFd, path: &dyn AsRef<Path>) -> bool {
+        match rlibc::File::openat(dir, path, rlibc::O_RDONLY | rlibc::O_NOFOLLOW, 0) {
+            Ok(mut f) => {
+                let pristine = file::was_pristine(&f);
+                let _ = f.close();
+                return pristine;
+            }
+            Err(_) => return false,
+        }
+    }
 
-            for i in items.into_iter().rev() {
-                candidates_to_evict += i.size as u64;
-                item_set.insert(i);
+    // only src knows if a cache file is still of use. A file without
+    // a checksum may have been written and not copied to src yet, so
+    // it's only partial if it's smaller than src, and never orphaned
+    fn classify(&self, layout: CacheLayout, path: &Path, mtime: SystemTime, now: SystemTime) -> Class {
+        let src = match self.src {
+            Some(ref src) => src,
+            None => return Class::Valid,
+        };
+        let rel = match self.ours(path) {
+            Some(rel) => rel,
+            None => return Class::Valid,
+        };
+        let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+        let mut f = match rlibc::File::openat(self.dir, &path, flags, 0) {
+            Ok(f) => f,
+            Err(_) => return Class::Valid,
+        };
 
-                if candidates_to_evict >= to_evict_bytes {
-                    break;
+        let class = (|| -> error::Result<Class> {
+            let src_path = match layout.src_path(&rel, &f)? {
+                Some(src_path) => src_path,
+                None => return Ok(Class::Valid),
+            };
+            let pristine = file::was_pristine(&f);
+            let st = match src.stat(&src_path) {
+                Ok(st) => st,
+                Err(e) => {
+                    if error::is_enoent(&e) && pristine {
+                        return Ok(Class::Orphaned);
+                    }
+                    return Ok(Class::Valid);
                 }
+            };
+            let recent = match now.duration_since(mtime) {
+                Ok(d) => d.as_secs() < PARTIAL_SECS,
+                Err(_) => true,
+            };
+            if !pristine && !recent && !file::is_filled_by_block(&f) &&
+                (crypt::size(&f)? as i64) < st.st_size
+            {
+                return Ok(Class::Partial);
             }
+            return Ok(Class::Valid);
+        })();
+        let _ = f.close();
 
-            DirWalker::new(self.dir)?
-                .map(|p| (Box::new(EvictItem::new_for_lookup(&p)), p))
-                .foreach(|i| if let Some(item) = item_set.get(&i.0) {
-                    evicted_bytes += item.size;
-                    if let Err(e) = rlibc::unlinkat(self.dir, &i.1, 0) {
-                        debug!("wanted to evict {:?}={} but got {}", i.1, item.size, e);
-                    } else {
-                        debug!("evicting {:?}={}", i.1, item.size);
+        return class.unwrap_or(Class::Valid);
+    }
+
+    // other mounts sharing the cache dir have their own pins, only
+    // the xattr is seen on their files
+    fn is_pinned(&self, layout: CacheLayout, path: &Path) -> bool {
+        let rel = self.ours(path);
+        if let Some(rel) = rel {
+            if layout == CacheLayout::Path && self.pins.matches(rel) {
+                return true;
+            }
+        }
+
+        let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+        match rlibc::File::openat(self.dir, &path, flags, 0) {
+            Ok(mut f) => {
+                let mut pinned = pin::is_pinned(&f);
+                if let Some(rel) = rel {
+                    if !pinned && layout != CacheLayout::Path && !self.pins.is_empty() {
+                        if let Ok(Some(src_path)) = layout.src_path(&rel, &f) {
+                            pinned = self.pins.matches(&src_path);
+                        }
                     }
-                });
+                }
+                let _ = f.close();
+                return pinned;
+            }
+            Err(_) => return false,
         }
+    }
 
-        return Ok(());
+    // page in holds a lock on the cache file when the cache dir is
+    // shared, see file::Handle::wait_for_page_in. Cached symlinks
+    // fail to open and are never paged in
+    fn is_paging_in(dir: RawFd, path: &dyn AsRef<Path>) -> bool {
+        match rlibc::File::openat(dir, path, rlibc::O_RDONLY | rlibc::O_NOFOLLOW, 0) {
+            Ok(mut f) => {
+                let locked = match rlibc::try_flock(f.as_raw_fd(), libc::LOCK_EX) {
+                    Ok(got_lock) => !got_lock,
+                    Err(_) => false,
+                };
+                let _ = f.close();
+                return locked;
+            }
+            Err(_) => return false,
+        }
     }
 
     pub fn new(dir: RawFd, free: &DiskSpace) -> Evicter {
-        Evicter::new_internal(dir, free, Duration::from_secs(60), rlibc::fstatvfs)
+        Evicter::new_internal(
+            dir,
+            free,
+            Duration::from_secs(DEFAULT_SCAN_SECS),
+            rlibc::fstatvfs,
+        )
+    }
+
+    // how often to check if we need to evict, only before run
+    pub fn set_scan_interval(&mut self, scan_freq: Duration) {
+        self.scan_freq = scan_freq;
+    }
+
+    // the free space to evict up to once we go below free, instead
+    // of 10% more than free
+    pub fn set_low_watermark(&self, low: &DiskSpace) {
+        let mut state = self.state.0.lock().unwrap();
+        state.low_watermark = low.clone();
+        state.fixed_low_watermark = true;
+    }
+
+    // on top of the files that have the pin xattr
+    pub fn set_pins(&mut self, pins: Pins) {
+        self.pins = pins;
+    }
+
+    // lets the evicter go for cache files that are no use first
+    pub fn set_src(&mut self, src: Arc<dyn SourceBackend>) {
+        self.src = Some(src);
+    }
+
+    pub fn set_policy(&mut self, policy: Box<dyn EvictionPolicy>) {
+        self.policy = policy;
+    }
+
+    // cold files are moved to l2 instead of being deleted, where
+    // another evicter is expected to make room for them. In a shared
+    // cache dir l2 is for our prefix, others' files are deleted
+    pub fn set_l2(&mut self, l2: RawFd, dir_mode: libc::mode_t) {
+        self.l2 = Some((l2, dir_mode));
+    }
+
+    // dir is shared by several mounts, each caching under a top level
+    // directory of its own, and none of them is let take up more
+    // than quota. A percentage is of the whole filesystem
+    pub fn set_quota(&mut self, quota: &DiskSpace) {
+        self.quota = quota.clone();
+    }
+
+    // files of uids that take up more than this are evicted first.
+    // Cache files belong to us unless they are given to whoever owns
+    // them in src, see file::CachePerms::src_owner
+    pub fn set_quota_per_uid(&mut self, bytes: u64) {
+        self.quota_per_uid = bytes;
+    }
+
+    // which of the top level directories is ours, when the cache dir
+    // is shared
+    pub fn set_prefix(&mut self, prefix: &Path) {
+        self.prefix = Some(prefix.to_path_buf());
+    }
+
+    // a percentage is of the whole filesystem, like free space
+    pub fn set_max_size(&mut self, max_size: &DiskSpace) {
+        self.max_size = max_size.clone();
     }
 
     pub fn run(&mut self) {
-        if self.scan_freq != Default::default() && self.high_watermark != Default::default() {
+        // the free space target can be set after we start, so run
+        // even if there's nothing to do now
+        if self.scan_freq != Default::default() {
             let evicter = catfs::make_self(self);
             let builder = thread::Builder::new().name(String::from("evicter"));
 
             self.t = Some(
                 builder
-                    .spawn(move || loop {
-                        if let Err(e) = evicter.loop_once() {
-                            error!("evicter error: {}", e);
-                        }
-
-                        let guard = evicter.shutting_down.lock().unwrap();
-                        let res = evicter.cv.wait_timeout(guard, evicter.scan_freq).unwrap();
-                        if *res.0 {
-                            debug!("shutting down");
-                            break;
+                    .spawn(move || {
+                        let mut force = false;
+                        loop {
+                            let res = evicter.check(force);
+                            if let Err(ref e) = res {
+                                error!("evicter error: {}", e);
+                            }
+
+                            let &(ref lock, ref cv) = &*evicter.state;
+                            let mut state = lock.lock().unwrap();
+                            if res.is_ok() {
+                                state.errors = 0;
+                            } else {
+                                state.errors += 1;
+                            }
+                            if force {
+                                state.forced_done = state.forced_started;
+                                state.last_forced = Some(Instant::now());
+                                cv.notify_all();
+                            }
+                            if !state.evict_now && !state.shutting_down {
+                                state = cv.wait_timeout(state, evicter.scan_freq).unwrap().0;
+                            }
+                            if state.shutting_down {
+                                debug!("shutting down");
+                                break;
+                            }
+                            force = state.evict_now;
+                            if force {
+                                state.evict_now = false;
+                                state.forced_started += 1;
+                            }
                         }
                     })
                     .unwrap(),
@@ -242,32 +866,43 @@ impl Evicter {
         scan_freq: Duration,
         statvfs: fn(RawFd) -> io::Result<statvfs64>,
     ) -> Evicter {
-        let mut ev = Evicter {
+        let mut low_watermark = Default::default();
+        if *free != DiskSpace::Bytes(0) {
+            low_watermark = low_watermark_of(free);
+        }
+
+        let ev = Evicter {
             dir: dir,
-            high_watermark: free.clone(),
-            low_watermark: Default::default(),
+            state: Arc::new((
+                Mutex::new(State {
+                    high_watermark: free.clone(),
+                    low_watermark: low_watermark,
+                    fixed_low_watermark: false,
+                    evict_now: false,
+                    forced_started: 0,
+                    forced_done: 0,
+                    last_forced: None,
+                    shutting_down: false,
+                    evicted: 0,
+                    cache_size: None,
+                    errors: 0,
+                    kick_uid: None,
+                }),
+                Condvar::new(),
+            )),
             scan_freq: scan_freq,
-            hot_percent: 25,
-            // modeling by the google nearline operation cost:
-            // $0.01/10000 requests and $0.01/GB = 0.000001/r and
-            // $.00000000000931322574/byte = 107374/r and 1/byte
-            request_weight: 107374,
+            policy: Box::new(policy::Cost::default()),
+            pins: Default::default(),
+            src: None,
+            max_size: Default::default(),
+            l2: None,
+            quota: Default::default(),
+            prefix: None,
+            quota_per_uid: 0,
             statvfs: statvfs,
-            cv: Arc::new(Condvar::new()),
-            shutting_down: Arc::new(Mutex::new(false)),
             t: Default::default(),
         };
 
-        if ev.high_watermark != DiskSpace::Bytes(0) {
-            if ev.low_watermark == DiskSpace::Bytes(0) {
-                ev.low_watermark = match ev.high_watermark {
-                    DiskSpace::Percent(p) => DiskSpace::Percent((p * 1.1).min(100.0)),
-                    DiskSpace::Bytes(b) => DiskSpace::Bytes((b as f64 * 1.1) as u64),
-                };
-            }
-
-        }
-
         return ev;
     }
 }
@@ -275,10 +910,11 @@ impl Evicter {
 impl Drop for Evicter {
     fn drop(&mut self) {
         {
-            let mut b = self.shutting_down.lock().unwrap();
-            *b = true;
+            let &(ref lock, ref cv) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            state.shutting_down = true;
             debug!("requesting to shutdown");
-            self.cv.notify_one();
+            cv.notify_all();
         }
 
         let mut t: Option<JoinHandle<()>> = None;
@@ -294,6 +930,7 @@ impl Drop for Evicter {
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
+    extern crate xattr;
     use std::fs;
     use std::path::PathBuf;
     use catfs::rlibc;
@@ -344,6 +981,84 @@ mod tests {
         assert_eq!(to_evict(&DiskSpace::Percent(30.0), &st), (30 - 16) * 4096);
     }
 
+    #[test]
+    fn over_max_size_bytes() {
+        let mut st: statvfs64 = unsafe { mem::zeroed() };
+        st.f_frsize = 4096;
+        st.f_blocks = 100;
+
+        assert_eq!(over_max_size(&DiskSpace::Bytes(8192), &st, 4096), 0);
+        assert_eq!(over_max_size(&DiskSpace::Bytes(8192), &st, 3 * 4096), 4096);
+        assert_eq!(over_max_size(&DiskSpace::Percent(10.0), &st, 20 * 4096), 10 * 4096);
+        assert_eq!(low_max_size_of(&DiskSpace::Bytes(1000)), DiskSpace::Bytes(900));
+    }
+
+    #[test]
+    fn low_watermark() {
+        let mut st: statvfs64 = unsafe { mem::zeroed() };
+        st.f_frsize = 4096;
+        st.f_blocks = 100;
+        st.f_bfree = 10;
+
+        let ev = Evicter::new_internal(-1, &DiskSpace::Bytes(20 * 4096), Default::default(), rlibc::fstatvfs);
+        assert_eq!(ev.to_evict(&st, 0), (22 - 10) * 4096);
+
+        ev.set_low_watermark(&DiskSpace::Percent(50.0));
+        assert_eq!(ev.to_evict(&st, 0), (50 - 10) * 4096);
+        // stays put when free changes
+        ev.handle().set_free(&DiskSpace::Bytes(30 * 4096));
+        assert_eq!(ev.low_watermark(), DiskSpace::Percent(50.0));
+        // but still gets us to free
+        ev.set_low_watermark(&DiskSpace::Bytes(0));
+        assert_eq!(ev.to_evict(&st, 0), (30 - 10) * 4096);
+    }
+
+    #[test]
+    fn evict_now() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(
+            fd,
+            &DiskSpace::Percent(50.0),
+            Duration::from_secs(3600),
+            fake_statvfs,
+        );
+        ev.set_low_watermark(&DiskSpace::Percent(100.0));
+        ev.run();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(count_cache_size(fd).unwrap(), 5 * 4096);
+
+        ev.handle().evict_now();
+        for _ in 0..50 {
+            if count_cache_size(fd).unwrap() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(count_cache_size(fd).unwrap(), 0);
+
+        // waits for the eviction, but not again right after one
+        fs::write(prefix.join("resources/file1"), b"x").unwrap();
+        let wait = Duration::from_millis(500);
+        thread::sleep(wait);
+        assert!(ev.handle().kick(wait));
+        assert_eq!(count_cache_size(fd).unwrap(), 0);
+        assert!(!ev.handle().kick(wait));
+        drop(ev);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
     #[test]
     fn evict_none() {
         let _ = env_logger::init();
@@ -381,7 +1096,7 @@ mod tests {
             st.f_blocks = 100;
             // want 1 free block at beginning. cache_size is 5 * 4K blocks so pretend
             // 94 blocks are used by other things
-            st.f_bfree = st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94;
+            st.f_bfree = (st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94) as _;
             return Ok(st);
         }
 
@@ -394,7 +1109,7 @@ mod tests {
 
         let st = fake_statvfs(fd).unwrap();
         assert_eq!(st.f_bfree, 1);
-        assert_eq!(ev.should_evict(&st), 2048);
+        assert_eq!(ev.should_evict(&st, 0), 2048);
         let used = count_cache_size(fd).unwrap();
         ev.loop_once().unwrap();
         // evicted one file
@@ -417,7 +1132,7 @@ mod tests {
             st.f_blocks = 100;
             // want 1 free block at beginning. cache_size is 5 * 4K blocks so pretend
             // 94 blocks are used by other things
-            st.f_bfree = st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94;
+            st.f_bfree = (st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94) as _;
             return Ok(st);
         }
 
@@ -431,11 +1146,301 @@ mod tests {
 
         let st = fake_statvfs(fd).unwrap();
         assert_eq!(st.f_bfree, 1);
-        assert_eq!(ev.low_watermark, DiskSpace::Percent(100.0));
-        assert_eq!(ev.should_evict(&st), 99 * 4096);
+        assert_eq!(ev.low_watermark(), DiskSpace::Percent(100.0));
+        assert_eq!(ev.should_evict(&st, 0), 99 * 4096);
         ev.loop_once().unwrap();
         // evicted one file
         assert_eq!(count_cache_size(fd).unwrap(), 0);
         fs::remove_dir_all(&prefix).unwrap();
     }
+
+    #[test]
+    fn evict_lfu() {
+        use self::xattr::FileExt;
+
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(fd, &DiskSpace::Bytes(1), Default::default(), fake_statvfs);
+        ev.set_policy(policy::parse("lfu").unwrap());
+        ev.set_max_size(&DiskSpace::Bytes(4 * 4096));
+
+        // everything else was opened more often
+        for p in DirWalker::new(fd).unwrap() {
+            if p != Path::new("resources/file1") {
+                let mut f = rlibc::File::openat(fd, &p, rlibc::O_RDONLY, 0).unwrap();
+                f.set_xattr("user.catfs.hits", b"3").unwrap();
+                f.close().unwrap();
+            }
+        }
+        let mut f = rlibc::File::openat(fd, &"resources/file1", rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(file::hits(&f), 0);
+        f.close().unwrap();
+
+        ev.loop_once().unwrap();
+        assert_eq!(count_cache_size(fd).unwrap(), 3 * 4096);
+        assert!(!prefix.join("resources/file1").exists());
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_useless_first() {
+        use self::xattr::FileExt;
+        use catfs::backend::LocalBackend;
+
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(fd, &DiskSpace::Bytes(1), Default::default(), fake_statvfs);
+        ev.set_src(Arc::new(LocalBackend::new(&src).unwrap()));
+        // the cost policy would keep what was used last
+        for p in DirWalker::new(fd).unwrap() {
+            let t = if p == Path::new("resources/file3") { 0 } else { 1000 };
+            rlibc::utimes(&prefix.join(&p), t, t).unwrap();
+        }
+
+        // file1 was cached and then removed from src
+        let mut f = rlibc::File::openat(fd, &"resources/file1", rlibc::O_RDONLY, 0).unwrap();
+        f.set_xattr("user.catfs.src_chksum", b"x").unwrap();
+        f.close().unwrap();
+        fs::remove_file(src.join("resources/file1")).unwrap();
+        // file2 didn't finish paging in
+        fs::write(src.join("resources/file2"), vec![0u8; 8192]).unwrap();
+
+        ev.set_max_size(&DiskSpace::Bytes(4 * 4096 + 2048));
+        ev.loop_once().unwrap();
+        assert!(!prefix.join("resources/file1").exists());
+        assert!(prefix.join("resources/file2").exists());
+        assert!(prefix.join("resources/file3").exists());
+
+        ev.set_max_size(&DiskSpace::Bytes(3 * 4096 + 2048));
+        ev.loop_once().unwrap();
+        assert!(!prefix.join("resources/file2").exists());
+        assert!(prefix.join("resources/file3").exists());
+
+        fs::remove_dir_all(&prefix).unwrap();
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn evict_max_size() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        // plenty of free space
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(fd, &DiskSpace::Bytes(1), Default::default(), fake_statvfs);
+        ev.loop_once().unwrap();
+        assert_eq!(count_cache_size(fd).unwrap(), 5 * 4096);
+
+        ev.set_max_size(&DiskSpace::Bytes(4 * 4096));
+        let st = fake_statvfs(fd).unwrap();
+        assert_eq!(ev.should_evict(&st, 5 * 4096), 4096);
+        ev.loop_once().unwrap();
+        // down to below 90% of the cap
+        assert_eq!(count_cache_size(fd).unwrap(), 3 * 4096);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_skips_pinned() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(dir: RawFd) -> io::Result<statvfs64> {
+            let cache_size = count_cache_size(dir).unwrap();
+
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = (st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94) as _;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(
+            fd,
+            &DiskSpace::Percent(100.0),
+            Default::default(),
+            fake_statvfs,
+        );
+        ev.set_pins("resources/dir1".parse().unwrap());
+
+        let mut f = rlibc::File::open(
+            &prefix.join("resources/file1"),
+            rlibc::O_RDONLY,
+            0,
+        ).unwrap();
+        pin::set_pinned(&f, true).unwrap();
+        f.close().unwrap();
+
+        ev.loop_once().unwrap();
+        // file1 and everything in dir1 are left
+        assert_eq!(count_cache_size(fd).unwrap(), 3 * 4096);
+        assert!(prefix.join("resources/file1").exists());
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_skips_paging_in() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(dir: RawFd) -> io::Result<statvfs64> {
+            let cache_size = count_cache_size(dir).unwrap();
+
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = (st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94) as _;
+            return Ok(st);
+        }
+
+        let ev = Evicter::new_internal(
+            fd,
+            &DiskSpace::Percent(100.0),
+            Default::default(),
+            fake_statvfs,
+        );
+
+        // pretend someone sharing the cache dir is paging this in
+        let mut f = rlibc::File::open(
+            &prefix.join("resources/file1"),
+            rlibc::O_RDONLY,
+            0,
+        ).unwrap();
+        rlibc::flock(f.as_raw_fd(), libc::LOCK_EX).unwrap();
+        ev.loop_once().unwrap();
+        assert_eq!(count_cache_size(fd).unwrap(), 4096);
+        f.close().unwrap();
+
+        ev.loop_once().unwrap();
+        assert_eq!(count_cache_size(fd).unwrap(), 0);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_to_l2() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix.join("resources"), rlibc::O_RDONLY, 0).unwrap();
+        let l2 = prefix.join("l2");
+        fs::create_dir_all(&l2).unwrap();
+        let l2_fd = rlibc::open(&l2, rlibc::O_RDONLY, 0).unwrap();
+
+        fn fake_statvfs(dir: RawFd) -> io::Result<statvfs64> {
+            let cache_size = count_cache_size(dir).unwrap();
+
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = (st.f_blocks as u64 - cache_size / (st.f_frsize as u64) - 94) as _;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(
+            fd,
+            &DiskSpace::Percent(100.0),
+            Default::default(),
+            fake_statvfs,
+        );
+        ev.set_l2(l2_fd, 0o777);
+
+        // only what was paged in whole is worth keeping
+        xattr::set(prefix.join("resources/dir1/file1"), "user.catfs.src_chksum", b"42").unwrap();
+        ev.loop_once().unwrap();
+        assert_eq!(count_cache_size(fd).unwrap(), 0);
+        assert!(l2.join("dir1/file1").exists());
+        assert!(!l2.join("file1").exists());
+
+        rlibc::close(l2_fd).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_over_quota() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        fs::create_dir(prefix.join("other")).unwrap();
+        fs::copy(prefix.join("resources/file1"), prefix.join("other/file1")).unwrap();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        // plenty of free space
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(fd, &DiskSpace::Bytes(1), Default::default(), fake_statvfs);
+        ev.set_quota(&DiskSpace::Bytes(4 * 4096));
+        ev.set_prefix(Path::new("other"));
+        ev.loop_once().unwrap();
+        // resources is down to below 90% of the quota, other was
+        // under it
+        assert_eq!(count_cache_size(fd).unwrap(), 4 * 4096);
+        assert!(prefix.join("other/file1").exists());
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn evict_over_uid_quota() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let fd = rlibc::open(&prefix, rlibc::O_RDONLY, 0).unwrap();
+
+        // plenty of free space
+        fn fake_statvfs(_dir: RawFd) -> io::Result<statvfs64> {
+            let mut st: statvfs64 = unsafe { mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = 4096;
+            st.f_blocks = 100;
+            st.f_bfree = 90;
+            return Ok(st);
+        }
+
+        let mut ev = Evicter::new_internal(fd, &DiskSpace::Bytes(1), Default::default(), fake_statvfs);
+        ev.set_quota_per_uid(4 * 4096);
+        ev.loop_once().unwrap();
+        // everything is ours, and is down to below 90% of the quota
+        assert_eq!(count_cache_size(fd).unwrap(), 3 * 4096);
+        fs::remove_dir_all(&prefix).unwrap();
+    }
 }
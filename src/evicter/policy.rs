@@ -0,0 +1,192 @@
+use std::cmp;
+use std::time::SystemTime;
+
+use super::EvictItem;
+
+// decides which cache files go first once we need to evict
+pub trait EvictionPolicy: Send + Sync {
+    // sorts items so the ones to evict come first, and drops the ones
+    // that shouldn't be evicted at all. to_evict is how many bytes
+    // need to go
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, to_evict: u64, now: SystemTime);
+
+    // whether items need to know how many times they've been opened,
+    // which takes reading an xattr of each cache file
+    fn uses_hits(&self) -> bool {
+        return false;
+    }
+}
+
+// ex: "lru"
+pub fn parse(s: &str) -> Result<Box<dyn EvictionPolicy>, String> {
+    return match s {
+        "cost" => Ok(Box::new(Cost::default())),
+        "lru" => Ok(Box::new(Lru)),
+        "lfu" => Ok(Box::new(Lfu)),
+        "fifo" => Ok(Box::new(Fifo)),
+        "size" => Ok(Box::new(Size)),
+        _ => Err("unrecognized eviction policy ".to_owned() + s),
+    };
+}
+
+// least recently accessed first
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, _to_evict: u64, _now: SystemTime) {
+        items.sort_by_key(|x| x.atime);
+    }
+}
+
+// least often opened first, and least recently accessed among those
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, _to_evict: u64, _now: SystemTime) {
+        items.sort_by_key(|x| (x.hits, x.atime));
+    }
+
+    fn uses_hits(&self) -> bool {
+        return true;
+    }
+}
+
+// oldest first, by when it was last written to the cache, which for
+// most files is when it was cached
+pub struct Fifo;
+
+impl EvictionPolicy for Fifo {
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, _to_evict: u64, _now: SystemTime) {
+        items.sort_by_key(|x| x.mtime);
+    }
+}
+
+// biggest first, so as few files as possible have to be fetched again
+pub struct Size;
+
+impl EvictionPolicy for Size {
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, _to_evict: u64, _now: SystemTime) {
+        items.sort_by_key(|x| (!x.size, x.atime));
+    }
+}
+
+// the most recently used are never evicted, the rest are weighted by
+// how much it costs to fetch them again and how long ago they were
+// used
+pub struct Cost {
+    hot_percent: usize, // 25 to keep most recently used 25%
+    request_weight: u64,
+}
+
+pub const DEFAULT_HOT_PERCENT: usize = 25;
+// modeling by the google nearline operation cost:
+// $0.01/10000 requests and $0.01/GB = 0.000001/r and
+// $.00000000000931322574/byte = 107374/r and 1/byte
+pub const DEFAULT_REQUEST_WEIGHT: u64 = 107374;
+
+impl Cost {
+    // request_weight is how many bytes fetching a file costs on top
+    // of its size
+    pub fn new(hot_percent: usize, request_weight: u64) -> Cost {
+        return Cost {
+            hot_percent: cmp::min(hot_percent, 100),
+            request_weight: request_weight,
+        };
+    }
+}
+
+impl Default for Cost {
+    fn default() -> Cost {
+        return Cost::new(DEFAULT_HOT_PERCENT, DEFAULT_REQUEST_WEIGHT);
+    }
+}
+
+impl EvictionPolicy for Cost {
+    fn order(&self, items: &mut Vec<Box<EvictItem>>, to_evict: u64, now: SystemTime) {
+        if items.is_empty() {
+            return;
+        }
+
+        items.sort_by_key(|x| x.atime);
+
+        let mut total_size = 0u64;
+        for i in 0..items.len() {
+            total_size += items[i].size as u64;
+
+            if total_size >= to_evict && i >= items.len() * (100 - self.hot_percent) / 100 {
+                items.truncate(i + 1);
+                break;
+            }
+        }
+
+        let oldest = now.duration_since(items[0].atime).unwrap().as_secs();
+
+        // now I have items that have not been accessed recently,
+        // weight them according to size and age
+        items.sort_by_key(|x| {
+            let cost = x.size as u64 + self.request_weight;
+            let age = now.duration_since(x.atime).unwrap().as_secs();
+            let weight = if oldest == 0 { cost } else { cost * age / oldest };
+            !weight
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::*;
+
+    fn item(name: &str, size: usize, age: u64, hits: u64) -> Box<EvictItem> {
+        let mut i = EvictItem::new_for_lookup(&name);
+        i.size = size;
+        i.atime = UNIX_EPOCH + Duration::from_secs(1000 - age);
+        // cached in the opposite order of when they were used
+        i.mtime = UNIX_EPOCH + Duration::from_secs(age);
+        i.hits = hits;
+        return Box::new(i);
+    }
+
+    fn order(policy: &str, to_evict: u64) -> Vec<u64> {
+        let mut items = vec![
+            item("a", 4096, 10, 3),
+            item("b", 8192, 30, 1),
+            item("c", 1024, 20, 2),
+            item("d", 2048, 40, 3),
+        ];
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        parse(policy).unwrap().order(&mut items, to_evict, now);
+        return items.iter().map(|x| x.hits * 10000 + x.size as u64).collect();
+    }
+
+    #[test]
+    fn policies() {
+        assert!(parse("random").is_err());
+        assert!(!parse("lru").unwrap().uses_hits());
+        assert!(parse("lfu").unwrap().uses_hits());
+
+        assert_eq!(order("lru", 0), vec![32048, 18192, 21024, 34096]);
+        assert_eq!(order("lfu", 0), vec![18192, 21024, 32048, 34096]);
+        assert_eq!(order("fifo", 0), vec![34096, 21024, 18192, 32048]);
+        assert_eq!(order("size", 0), vec![18192, 34096, 32048, 21024]);
+    }
+
+    #[test]
+    fn cost() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let items = || (0..8).map(|i| item("x", 4096, 10 * (i + 1), i)).collect::<Vec<_>>();
+
+        // the most recently used are kept
+        let mut v = items();
+        Cost::default().order(&mut v, 1, now);
+        assert_eq!(v.len(), 7);
+        // used longest ago comes first, when they are about the same size
+        let hits: Vec<u64> = v.iter().map(|x| x.hits).collect();
+        assert_eq!(hits, vec![7, 6, 5, 4, 3, 2, 1]);
+
+        // unless all of them are needed
+        let mut v = items();
+        Cost::default().order(&mut v, 8 * 4096, now);
+        assert_eq!(v.len(), 8);
+    }
+}
@@ -0,0 +1,406 @@
+extern crate libc;
+extern crate xattr;
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::xattr::FileExt;
+
+use catfs::backend::peer::to_hex;
+use catfs::compress;
+use catfs::crypt;
+use catfs::error;
+use catfs::file::CacheLayout;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use evicter::dir_walker::DirWalker;
+
+// lists what was exported, one file per line:
+// <hex src checksum>\t<size>\t<path>
+pub const MANIFEST: &'static str = ".catfs-manifest";
+
+pub const SRC_CHKSUM_XATTR: &'static str = "user.catfs.src_chksum";
+pub const BLOCK: usize = 512;
+
+// where a snapshot of the cache goes
+trait Sink {
+    // st has the size of what's in f after decompressing
+    fn add(
+        &mut self,
+        path: &Path,
+        st: &rlibc::stat64,
+        chksum: &[u8],
+        f: &compress::Reader,
+    ) -> io::Result<()>;
+    // the file changed while it was added, it must not look pristine
+    fn discard(&mut self, path: &Path) -> io::Result<()>;
+    fn finish(&mut self, manifest: &[u8]) -> io::Result<()>;
+}
+
+// copies the files that are pristine (match what was in the source
+// when they were cached) to dest, which becomes a cache directory
+// that's already warm. With tar, dest is a tar archive instead, or
+// stdout if it's -. Returns how many files were exported
+pub fn export(cache_dir: RawFd, dest: &Path, tar: bool) -> error::Result<usize> {
+    let mut sink: Box<dyn Sink> = if !tar {
+        Box::new(DirSink::new(dest)?)
+    } else if dest == Path::new("-") {
+        Box::new(TarSink::new(io::stdout()))
+    } else {
+        Box::new(TarSink::new(fs::File::create(dest)?))
+    };
+
+    // what's exported is always named like the source
+    let layout = CacheLayout::load(cache_dir)?;
+    let mut manifest = Vec::new();
+    let mut count = 0;
+    for cache_path in DirWalker::new(cache_dir)? {
+        if cache_path == Path::new(MANIFEST) {
+            continue;
+        }
+
+        let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+        let mut f = match File::openat(cache_dir, &cache_path, flags, 0) {
+            Ok(f) => f,
+            Err(e) => {
+                // evicted since we listed it, or a cached symlink
+                // which has no data to export
+                if error::is_enoent(&e) || e.raw_os_error() == Some(libc::ELOOP) {
+                    continue;
+                }
+                return Err(e.into());
+            }
+        };
+        let path = match layout.src_path(&cache_path, &f) {
+            Ok(Some(ref path)) if is_relative(path) => path.clone(),
+            Ok(_) => {
+                f.close()?;
+                continue;
+            }
+            Err(e) => {
+                f.close()?;
+                return Err(e.into());
+            }
+        };
+        let res = export_file(&mut *sink, &path, &f);
+        f.close()?;
+
+        if let Some((chksum, size)) = res? {
+            write!(manifest, "{}\t{}\t", to_hex(&chksum), size)?;
+            manifest.extend_from_slice(path.as_os_str().as_bytes());
+            manifest.push(b'\n');
+            count += 1;
+        }
+    }
+
+    sink.finish(&manifest)?;
+    return Ok(count);
+}
+
+fn is_relative(path: &Path) -> bool {
+    return path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+}
+
+fn export_file(sink: &mut dyn Sink, path: &Path, f: &File) -> error::Result<Option<(Vec<u8>, u64)>> {
+    let st = f.stat()?;
+    if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+        return Ok(None);
+    }
+    let chksum = match f.get_xattr(SRC_CHKSUM_XATTR)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    // exports aren't encrypted, so neither is what goes in them
+    if crypt::is_sealed(f)? {
+        debug!("skipping encrypted {:?}", path);
+        return Ok(None);
+    }
+
+    // what's exported is always plain
+    let r = compress::Reader::new(f)?;
+    let mut data_st = st;
+    data_st.st_size = r.size()?;
+
+    debug!("<-- export {:?}", path);
+    sink.add(path, &data_st, &chksum, &r)?;
+
+    // catfs may have written to it while we were copying
+    let st2 = f.stat()?;
+    if f.get_xattr(SRC_CHKSUM_XATTR)?.as_ref() != Some(&chksum) || st2.st_size != st.st_size ||
+        st2.st_mtime != st.st_mtime || st2.st_mtime_nsec != st.st_mtime_nsec
+    {
+        debug!("{:?} changed while exporting", path);
+        sink.discard(path)?;
+        return Ok(None);
+    }
+
+    return Ok(Some((chksum, data_st.st_size as u64)));
+}
+
+// copies size bytes of f, anything that's gone since is zeros
+fn copy_to(f: &compress::Reader, size: u64, to: &mut dyn Write) -> io::Result<()> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut offset = 0;
+    while offset < size {
+        let want = cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut nread = f.read_at(&mut buf[..want], offset as i64)?;
+        if nread == 0 {
+            for b in buf[..want].iter_mut() {
+                *b = 0;
+            }
+            nread = want;
+        }
+        to.write_all(&buf[..nread])?;
+        offset += nread as u64;
+    }
+    return Ok(());
+}
+
+struct DirSink {
+    dir: PathBuf,
+}
+
+impl DirSink {
+    fn new(dir: &Path) -> io::Result<DirSink> {
+        fs::create_dir_all(dir)?;
+        return Ok(DirSink { dir: dir.to_path_buf() });
+    }
+}
+
+impl Sink for DirSink {
+    fn add(
+        &mut self,
+        path: &Path,
+        st: &rlibc::stat64,
+        chksum: &[u8],
+        f: &compress::Reader,
+    ) -> io::Result<()> {
+        let to_path = self.dir.join(path);
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut to = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode((st.st_mode & 0o7777) as u32)
+            .open(&to_path)?;
+        copy_to(f, st.st_size as u64, &mut to)?;
+        return to.set_xattr(SRC_CHKSUM_XATTR, chksum);
+    }
+
+    fn discard(&mut self, path: &Path) -> io::Result<()> {
+        return fs::remove_file(self.dir.join(path));
+    }
+
+    fn finish(&mut self, manifest: &[u8]) -> io::Result<()> {
+        return fs::write(self.dir.join(MANIFEST), manifest);
+    }
+}
+
+// a pax archive, the checksums are kept as SCHILY.xattr records which
+// GNU tar and bsdtar restore with --xattrs
+struct TarSink<W: Write> {
+    out: BufWriter<W>,
+}
+
+impl<W: Write> TarSink<W> {
+    fn new(out: W) -> TarSink<W> {
+        return TarSink { out: BufWriter::new(out) };
+    }
+
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rem = (len % BLOCK as u64) as usize;
+        if rem != 0 {
+            self.out.write_all(&[0u8; BLOCK][rem..])?;
+        }
+        return Ok(());
+    }
+
+    // a pax header that covers the entry after it, since ustar can't
+    // hold long paths, big sizes or xattrs
+    fn add_pax(&mut self, path: &Path, size: u64, chksum: Option<&[u8]>) -> io::Result<()> {
+        let mut records = pax_record(b"path", path.as_os_str().as_bytes());
+        if size >= 0o77777777777 {
+            records.extend(pax_record(b"size", size.to_string().as_bytes()));
+        }
+        if let Some(chksum) = chksum {
+            let mut key = b"SCHILY.xattr.".to_vec();
+            key.extend_from_slice(SRC_CHKSUM_XATTR.as_bytes());
+            records.extend(pax_record(&key, chksum));
+        }
+
+        self.out.write_all(&tar_header(b"././@PaxHeader", records.len() as u64, 0o644, 0, b'x'))?;
+        self.out.write_all(&records)?;
+        return self.pad(records.len() as u64);
+    }
+}
+
+impl<W: Write> Sink for TarSink<W> {
+    fn add(
+        &mut self,
+        path: &Path,
+        st: &rlibc::stat64,
+        chksum: &[u8],
+        f: &compress::Reader,
+    ) -> io::Result<()> {
+        let size = st.st_size as u64;
+        self.add_pax(path, size, Some(chksum))?;
+        self.out.write_all(&tar_header(
+            path.as_os_str().as_bytes(),
+            size,
+            (st.st_mode & 0o7777) as u32,
+            st.st_mtime as i64,
+            b'0',
+        ))?;
+        copy_to(f, size, &mut self.out)?;
+        return self.pad(size);
+    }
+
+    // it's already in the archive, replace it with an empty file that
+    // catfs will fetch again
+    fn discard(&mut self, path: &Path) -> io::Result<()> {
+        self.add_pax(path, 0, None)?;
+        return self.out.write_all(&tar_header(path.as_os_str().as_bytes(), 0, 0o644, 0, b'0'));
+    }
+
+    fn finish(&mut self, manifest: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        self.out.write_all(&tar_header(
+            MANIFEST.as_bytes(),
+            manifest.len() as u64,
+            0o644,
+            now,
+            b'0',
+        ))?;
+        self.out.write_all(manifest)?;
+        self.pad(manifest.len() as u64)?;
+        // end of archive
+        self.out.write_all(&[0u8; BLOCK * 2])?;
+        return self.out.flush();
+    }
+}
+
+// "<len> <key>=<value>\n" where len counts itself
+fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+
+    let mut r = format!("{} ", len).into_bytes();
+    r.extend_from_slice(key);
+    r.push(b'=');
+    r.extend_from_slice(value);
+    r.push(b'\n');
+    return r;
+}
+
+fn octal(field: &mut [u8], v: u64) {
+    let width = field.len() - 1;
+    let s = format!("{:01$o}", v, width);
+    // too big values are in the pax header
+    if s.len() == width {
+        field[..width].copy_from_slice(s.as_bytes());
+    } else {
+        field[..width].copy_from_slice(&vec![b'0'; width]);
+    }
+    field[width] = 0;
+}
+
+fn tar_header(name: &[u8], size: u64, mode: u32, mtime: i64, typeflag: u8) -> [u8; BLOCK] {
+    let mut h = [0u8; BLOCK];
+    let n = cmp::min(name.len(), 100);
+    h[..n].copy_from_slice(&name[..n]);
+    octal(&mut h[100..108], mode as u64);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], cmp::max(mtime, 0) as u64);
+    h[156] = typeflag;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // the checksum is computed as if its field were spaces
+    for b in h[148..156].iter_mut() {
+        *b = b' ';
+    }
+    let sum: u64 = h.iter().map(|b| *b as u64).sum();
+    octal(&mut h[148..155], sum);
+    return h;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::process::Command;
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn export_pristine() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let cache = prefix.join("resources");
+        let dest = prefix.join("export");
+
+        for p in ["file1", "dir1/file2"].iter() {
+            let mut f = File::open(&cache.join(p), rlibc::O_RDONLY, 0).unwrap();
+            f.set_xattr(SRC_CHKSUM_XATTR, &[0x42, 0xca]).unwrap();
+            f.close().unwrap();
+        }
+
+        let fd = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        assert_eq!(export(fd, &dest, false).unwrap(), 2);
+        assert!(dest.join("dir1/file2").exists());
+        assert!(!dest.join("file2").exists());
+        assert_eq!(
+            fs::read(dest.join("file1")).unwrap(),
+            fs::read(cache.join("file1")).unwrap()
+        );
+        assert_eq!(
+            xattr::get(dest.join("file1"), SRC_CHKSUM_XATTR).unwrap(),
+            Some(vec![0x42, 0xca])
+        );
+        let manifest = String::from_utf8(fs::read(dest.join(MANIFEST)).unwrap()).unwrap();
+        let mut lines: Vec<&str> = manifest.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                format!("42ca\t{}\tdir1/file2", fs::metadata(cache.join("dir1/file2")).unwrap().len()),
+                format!("42ca\t{}\tfile1", fs::metadata(cache.join("file1")).unwrap().len()),
+            ]
+        );
+
+        let archive = prefix.join("cache.tar");
+        assert_eq!(export(fd, &archive, true).unwrap(), 2);
+        assert_eq!(fs::metadata(&archive).unwrap().len() % BLOCK as u64, 0);
+        rlibc::close(fd).unwrap();
+
+        // check the archive with tar if it's around
+        if let Ok(out) = Command::new("tar").arg("-tf").arg(&archive).output() {
+            let listing = String::from_utf8(out.stdout).unwrap();
+            let mut names: Vec<&str> = listing.lines().collect();
+            names.sort();
+            assert_eq!(names, vec![MANIFEST, "dir1/file2", "file1"]);
+        }
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+extern crate libc;
+
+use std::ffi::{CStr, OsStr};
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::str::FromStr;
+
+use catfs;
+use catfs::error;
+use catfs::flags::DiskSpace;
+use catfs::mount::Mount;
+
+// C interface for running catfs inside another process, see
+// include/catfs.h. Functions return 0 or an errno
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct catfs_stats {
+    inodes: u64,
+    open_dirs: u64,
+    open_files: u64,
+    dir_idle_secs: u64,
+    file_idle_secs: u64,
+}
+
+unsafe fn to_path<'a>(s: *const c_char) -> &'a Path {
+    return Path::new(OsStr::from_bytes(CStr::from_ptr(s).to_bytes()));
+}
+
+unsafe fn mount(
+    from: *const c_char,
+    to: *const c_char,
+    mountpoint: *const c_char,
+    free: *const c_char,
+) -> error::Result<Mount> {
+    if from.is_null() || to.is_null() || mountpoint.is_null() {
+        return error::propagate(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let free = if free.is_null() {
+        DiskSpace::Bytes(0)
+    } else {
+        match CStr::from_ptr(free).to_str().ok().and_then(|s| DiskSpace::from_str(s).ok()) {
+            Some(free) => free,
+            None => return error::propagate(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    };
+
+    let fs = catfs::CatFS::new(&to_path(from), &to_path(to))?;
+    return fs.spawn_mount(&to_path(mountpoint), &[], &free);
+}
+
+// mounts from at mountpoint, caching to to. free is how much space to
+// keep free in to's filesystem (ex: "10G", "5%"), or NULL to not
+// evict anything
+#[no_mangle]
+pub unsafe extern "C" fn catfs_mount(
+    from: *const c_char,
+    to: *const c_char,
+    mountpoint: *const c_char,
+    free: *const c_char,
+    out: *mut *mut Mount,
+) -> libc::c_int {
+    if out.is_null() {
+        return libc::EINVAL;
+    }
+    *out = ptr::null_mut();
+
+    match mount(from, to, mountpoint, free) {
+        Ok(m) => {
+            debug!("<-- catfs_mount {:?}", m.mountpoint());
+            *out = Box::into_raw(Box::new(m));
+            return 0;
+        }
+        Err(e) => {
+            error!("!catfs_mount = {}", e);
+            return error::errno(&e);
+        }
+    }
+}
+
+// unmounts and frees m. If the mount is busy, this returns EBUSY
+// and m is still mounted
+#[no_mangle]
+pub unsafe extern "C" fn catfs_unmount(m: *mut Mount) -> libc::c_int {
+    if m.is_null() {
+        return libc::EINVAL;
+    }
+    if let Err(e) = (*m).unmount(false) {
+        error!("!catfs_unmount {:?} = {}", (*m).mountpoint(), e);
+        return error::errno(&e);
+    }
+    drop(Box::from_raw(m));
+    return 0;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn catfs_stats(m: *const Mount, out: *mut catfs_stats) -> libc::c_int {
+    if m.is_null() || out.is_null() {
+        return libc::EINVAL;
+    }
+    let stats = (*m).stats();
+    *out = catfs_stats {
+        inodes: stats.inodes as u64,
+        open_dirs: stats.open_dirs as u64,
+        open_files: stats.open_files as u64,
+        dir_idle_secs: stats.dir_idle_secs,
+        file_idle_secs: stats.file_idle_secs,
+    };
+    return 0;
+}
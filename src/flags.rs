@@ -3,76 +3,209 @@ extern crate libc;
 
 use std::any::Any;
 use std::env;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
 
 use catfs::flags::DiskSpace;
+use catfs::rlibc;
 
 pub struct Flag<'a, 'b> {
     pub arg: clap::Arg<'a, 'a>,
     pub value: &'b mut dyn Any,
 }
 
-pub fn parse_options<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &'b mut [Flag<'a, 'b>]) {
-    for f in flags.iter() {
-        app = app.arg(f.arg.clone());
-    }
+// returns the fully populated app, which can be used to generate
+// shell completions, and the matches to tell which flags were given
+pub fn parse_options<'a, 'b>(
+    app: clap::App<'a, 'a>,
+    flags: &'b mut [Flag<'a, 'b>],
+) -> (clap::App<'a, 'a>, clap::ArgMatches<'a>) {
     let mut argv = env::args_os().collect::<Vec<OsString>>();
-    if argv.len() == 5 && argv[3] == OsString::from("-o") {
-	// looks like it's coming from fstab!
-        // [0]: catfs, [1] = src_dir#cache_dir, [2] = mnt
-        // [3]: -o, [4] = opt1,opt2
-        // XXX str has split but OsString doesn't
-
-        // XXX2 this is more convoluted than necessary because paths
-        // immutably borrows from argv and we can't modify elements of
-        // argv again because that requires another mutable borrow
-
-        // XXX3 need to initialize src/cache because the compiler is
-        // not smart enough
-        let mut src: OsString = Default::default();
-        let mut cache: OsString = Default::default();
-        let mut is_fstab = false;
-
-        {
-            let paths = argv[1].to_str().unwrap().splitn(2, '#').collect::<Vec<&str>>();
-            if paths.len() == 2 {
-                src = OsString::from(paths[0]);
-                cache = OsString::from(paths[1]);
-                is_fstab = true;
+    if is_mount_helper() || (argv.len() == 5 && argv[3] == OsString::from("-o")) {
+        // looks like it's coming from fstab or mount(8)
+        if let Some(args) = from_mount_args(&argv) {
+            argv = args;
+        }
+    }
+
+    return parse_options_from(app, flags, argv);
+}
+
+// true if mount(8) invoked us as /sbin/mount.catfs
+pub fn is_mount_helper() -> bool {
+    return match env::args_os().next() {
+        Some(arg0) => Path::new(&arg0).file_name() == Some(OsStr::new("mount.catfs")),
+        None => false,
+    };
+}
+
+// options that only mean something to mount(8), fusermount would
+// reject them
+fn is_mount_only_option(opt: &str) -> bool {
+    return match opt {
+        "defaults" | "auto" | "noauto" | "user" | "users" | "nouser" | "owner" | "group" |
+        "_netdev" | "nofail" => true,
+        _ => {
+            opt.starts_with("user=") || opt.starts_with("x-") || opt.starts_with("comment=")
+        }
+    };
+}
+
+// mount(8) calls us as: mount.catfs src#cache mnt [-sfnv] [-o opt1,opt2]
+// returns the equivalent of: catfs [args] src cache mnt [-o opt1,opt2],
+// where options that start with - are turned back into arguments.
+// Returns None if the spec doesn't look like src#cache
+fn from_mount_args(argv: &Vec<OsString>) -> Option<Vec<OsString>> {
+    let mut positional: Vec<&OsString> = Vec::new();
+    let mut options: Vec<String> = Vec::new();
+    let mut arguments: Vec<OsString> = Vec::new();
+    let mut user: Option<String> = None;
+
+    let mut i = 1;
+    while i < argv.len() {
+        let arg = argv[i].to_string_lossy().into_owned();
+        i += 1;
+
+        if arg == "-o" {
+            if i < argv.len() {
+                options.push(argv[i].to_string_lossy().into_owned());
+                i += 1;
             }
+        } else if arg.starts_with("-o") {
+            options.push(arg[2..].to_owned());
+        } else if arg == "-t" || arg == "-N" {
+            // type and namespace, we know who we are
+            i += 1;
+        } else if arg.starts_with("-") && !arg.starts_with("--") {
+            // -s (sloppy), -n (no mtab) and -v (verbose) don't apply
+            // to us, -f (fake) means don't actually mount
+            if arg.contains('f') {
+                arguments.push(OsString::from("--test"));
+            }
+        } else if arg.starts_with("--") {
+            arguments.push(argv[i - 1].clone());
+        } else {
+            positional.push(&argv[i - 1]);
+        }
+    }
+
+    if positional.len() != 2 {
+        return None;
+    }
+
+    let paths = positional[0].to_str()?.splitn(2, '#').collect::<Vec<&str>>();
+    if paths.len() != 2 {
+        return None;
+    }
+
+    let mut fuse_options = String::new();
+    for opt in options.iter().flat_map(|o| o.split(',')) {
+        if opt.starts_with("-") {
+            arguments.push(OsString::from(opt));
+        } else if opt.starts_with("user=") {
+            user = Some(opt[5..].to_owned());
+        } else if !opt.is_empty() && !is_mount_only_option(opt) {
+            fuse_options += opt;
+            fuse_options += ",";
         }
+    }
 
-        if is_fstab {
-            argv[1] = src;
-            argv.insert(2, cache);
-            let mut options = String::new();
-            let mut arguments: Vec<OsString> = Default::default();
-
-            // options are now pushed down
-            for opt in argv[5].to_str().unwrap().split(',') {
-                if opt.starts_with("-") {
-                    arguments.push(OsString::from(opt));
-                } else {
-                    options += opt;
-                    options += ",";
-                }
+    // a user mount is set up by root on behalf of that user, run
+    // the daemon as them unless told otherwise
+    if let Some(user) = user {
+        let has_uid = arguments.iter().any(|a| a.to_string_lossy().starts_with("--uid"));
+        if !has_uid && unsafe { libc::geteuid() } == 0 {
+            if let Ok((uid, gid)) = rlibc::getpwnam(&user) {
+                arguments.push(OsString::from(format!("--uid={}", uid)));
+                arguments.push(OsString::from(format!("--gid={}", gid)));
             }
+        }
+    }
+
+    let mut args = vec![argv[0].clone()];
+    args.extend(arguments);
+    args.push(OsString::from(paths[0]));
+    args.push(OsString::from(paths[1]));
+    args.push(positional[1].clone());
+    if fuse_options.len() != 0 {
+        fuse_options.pop();
+        args.push(OsString::from("-o"));
+        args.push(OsString::from(fuse_options));
+    }
+
+    return Some(args);
+}
+
+// our own flags can also be given as -o name=value, so they can go in
+// fstab without the --. Those are taken out of the -o lists and passed
+// as --name=value, the rest are left for fuse
+fn from_mount_options(flags: &[Flag], argv: Vec<OsString>) -> Vec<OsString> {
+    let is_flag = |name: &str| flags.iter().any(|f| f.arg.s.long == Some(name));
+
+    let mut args: Vec<OsString> = Vec::new();
+    let mut arguments: Vec<OsString> = Vec::new();
+    let mut i = 0;
+    while i < argv.len() {
+        let arg = argv[i].clone();
+        i += 1;
+
+        let options = if arg == OsString::from("-o") && i < argv.len() {
+            i += 1;
+            argv[i - 1].clone()
+        } else if arg.len() > 2 && arg.to_string_lossy().starts_with("-o") {
+            OsString::from(&arg.to_string_lossy()[2..])
+        } else {
+            args.push(arg);
+            continue;
+        };
 
-            if options.len() != 0 {
-                options.pop();
-                argv[5] = OsString::from(options);
-            } else {
-                // no options, pop -o and empty string
-                argv.pop();
-                argv.pop();
+        let mut fuse_options: Vec<&str> = Vec::new();
+        match options.to_str() {
+            Some(options) => {
+                for opt in options.split(',') {
+                    if is_flag(opt.splitn(2, '=').next().unwrap()) {
+                        arguments.push(OsString::from(format!("--{}", opt)));
+                    } else {
+                        fuse_options.push(opt);
+                    }
+                }
             }
-            for arg in arguments {
-                argv.insert(3, arg);
+            None => {
+                args.push(OsString::from("-o"));
+                args.push(options.clone());
             }
         }
+        if !fuse_options.is_empty() {
+            args.push(OsString::from("-o"));
+            args.push(OsString::from(fuse_options.join(",")));
+        }
     }
 
-    let matches = app.get_matches_from(argv);
+    if !args.is_empty() {
+        let rest = args.split_off(1);
+        args.extend(arguments);
+        args.extend(rest);
+    }
+    return args;
+}
+
+// app with the flags added, without parsing anything
+pub fn with_flags<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &[Flag<'a, 'b>]) -> clap::App<'a, 'a> {
+    for f in flags.iter() {
+        app = app.arg(f.arg.clone());
+    }
+    return app;
+}
+
+pub fn parse_options_from<'a, 'b>(
+    app: clap::App<'a, 'a>,
+    flags: &'b mut [Flag<'a, 'b>],
+    argv: Vec<OsString>,
+) -> (clap::App<'a, 'a>, clap::ArgMatches<'a>) {
+    let app = with_flags(app, flags);
+
+    let argv = from_mount_options(flags, argv);
+    let matches = app.clone().get_matches_from(argv);
 
     for f in flags.iter_mut() {
         let name = f.arg.b.name;
@@ -86,6 +219,12 @@ pub fn parse_options<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &'b mut [Flag<'a
                 *v = String::from(s);
                 continue;
             }
+            // a flag whose value is optional, Some("") if it's not
+            // given
+            if let Some(v) = f.value.downcast_mut::<Option<String>>() {
+                *v = Some(String::from(matches.value_of(name).unwrap_or("")));
+                continue;
+            }
             if let Some(v) = f.value.downcast_mut::<OsString>() {
                 let s = matches.value_of_os(name).unwrap();
                 *v = s.to_os_string();
@@ -95,6 +234,11 @@ pub fn parse_options<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &'b mut [Flag<'a
                 *v = true;
                 continue;
             }
+            // a flag that can be repeated
+            if let Some(v) = f.value.downcast_mut::<Vec<String>>() {
+                *v = matches.values_of(name).unwrap().map(String::from).collect();
+                continue;
+            }
             if let Some(v) = f.value.downcast_mut::<Vec<OsString>>() {
                 let options = matches.values_of(name).unwrap();
                 for s in options {
@@ -110,6 +254,11 @@ pub fn parse_options<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &'b mut [Flag<'a
                 *v = s.parse().unwrap();
                 continue;
             }
+            if let Some(v) = f.value.downcast_mut::<u64>() {
+                let s = matches.value_of(name).unwrap();
+                *v = s.parse().unwrap();
+                continue;
+            }
             if let Some(v) = f.value.downcast_mut::<libc::uid_t>() {
                 let s = matches.value_of(name).unwrap();
                 *v = s.parse().unwrap();
@@ -124,4 +273,160 @@ pub fn parse_options<'a, 'b>(mut app: clap::App<'a, 'a>, flags: &'b mut [Flag<'a
             panic!("unknown type for {}", name);
         }
     }
+
+    return (app, matches);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(args: &[&str]) -> Vec<OsString> {
+        return args.iter().map(OsString::from).collect();
+    }
+
+    #[test]
+    fn mount_args() {
+        let cases: &[(&[&str], Option<&[&str]>)] = &[
+            // mount(8)
+            (
+                &["mount.catfs", "/src#/cache", "/mnt", "-o", "rw,allow_other"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt", "-o", "rw,allow_other"]),
+            ),
+            (
+                &["mount.catfs", "/src#/cache", "/mnt"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt"]),
+            ),
+            // fstab, with what only means something to mount(8)
+            (
+                &[
+                    "mount.catfs",
+                    "/src#/cache",
+                    "/mnt",
+                    "-o",
+                    "defaults,noauto,_netdev,nofail,x-systemd.automount,comment=x,ro",
+                ],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt", "-o", "ro"]),
+            ),
+            (
+                &["mount.catfs", "/src#/cache", "/mnt", "-o", "defaults"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt"]),
+            ),
+            // -s, -n and -v don't apply, -f doesn't mount
+            (
+                &["mount.catfs", "-snv", "/src#/cache", "/mnt"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt"]),
+            ),
+            (
+                &["mount.catfs", "/src#/cache", "/mnt", "-f"],
+                Some(&["mount.catfs", "--test", "/src", "/cache", "/mnt"]),
+            ),
+            (
+                &["mount.catfs", "-t", "catfs", "-N", "/proc/1/ns/mnt", "/src#/cache", "/mnt"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt"]),
+            ),
+            // -o and its options in one, and more than one -o
+            (
+                &["mount.catfs", "/src#/cache", "/mnt", "-orw", "-o", "allow_other"],
+                Some(&["mount.catfs", "/src", "/cache", "/mnt", "-o", "rw,allow_other"]),
+            ),
+            // our own flags, in the options or not
+            (
+                &["mount.catfs", "/src#/cache", "/mnt", "-o", "--ttl=5,rw,--nonempty"],
+                Some(&["mount.catfs", "--ttl=5", "--nonempty", "/src", "/cache", "/mnt", "-o", "rw"]),
+            ),
+            (
+                &["mount.catfs", "--ttl=5", "/src#/cache", "/mnt"],
+                Some(&["mount.catfs", "--ttl=5", "/src", "/cache", "/mnt"]),
+            ),
+            // only the first # splits
+            (
+                &["mount.catfs", "/src#/cache#1", "/mnt"],
+                Some(&["mount.catfs", "/src", "/cache#1", "/mnt"]),
+            ),
+            // not src#cache
+            (&["mount.catfs", "/src", "/mnt", "-o", "rw"], None),
+            (&["catfs", "/src", "/cache", "/mnt"], None),
+            (&["mount.catfs", "/src#/cache"], None),
+        ];
+
+        for &(argv, expected) in cases {
+            assert_eq!(
+                from_mount_args(&os(argv)),
+                expected.map(os),
+                "{:?}",
+                argv
+            );
+        }
+    }
+
+    #[test]
+    fn mount_options() {
+        let mut nonempty = false;
+        let mut test = false;
+        let mut ttl = String::new();
+        let mut signals = String::new();
+        let flags = [
+            Flag {
+                arg: clap::Arg::with_name("nonempty").long("nonempty"),
+                value: &mut nonempty,
+            },
+            Flag {
+                arg: clap::Arg::with_name("test").long("test"),
+                value: &mut test,
+            },
+            Flag {
+                arg: clap::Arg::with_name("ttl").long("ttl").takes_value(true),
+                value: &mut ttl,
+            },
+            Flag {
+                arg: clap::Arg::with_name("signals").long("signals").takes_value(true),
+                value: &mut signals,
+            },
+        ];
+
+        let cases: &[(&[&str], &[&str])] = &[
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-o", "nonempty,allow_other"],
+                &["catfs", "--nonempty", "/src", "/cache", "/mnt", "-o", "allow_other"],
+            ),
+            // nothing left for fuse
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-o", "test"],
+                &["catfs", "--test", "/src", "/cache", "/mnt"],
+            ),
+            (
+                &["catfs", "-o", "ttl=5,ro", "/src", "/cache", "/mnt"],
+                &["catfs", "--ttl=5", "-o", "ro", "/src", "/cache", "/mnt"],
+            ),
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-ottl=5"],
+                &["catfs", "--ttl=5", "/src", "/cache", "/mnt"],
+            ),
+            // only the first = is ours
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-o", "signals=USR1=dump"],
+                &["catfs", "--signals=USR1=dump", "/src", "/cache", "/mnt"],
+            ),
+            // what we don't know is fuse's, even if it looks like ours
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-o", "fsname=a=b,ttls=5"],
+                &["catfs", "/src", "/cache", "/mnt", "-o", "fsname=a=b,ttls=5"],
+            ),
+            (
+                &["catfs", "/src", "/cache", "/mnt", "-o", "rw", "-o", "nonempty"],
+                &["catfs", "--nonempty", "/src", "/cache", "/mnt", "-o", "rw"],
+            ),
+            (
+                &["catfs", "--ttl", "5", "/src", "/cache", "/mnt"],
+                &["catfs", "--ttl", "5", "/src", "/cache", "/mnt"],
+            ),
+            // a trailing -o is for clap to complain about
+            (&["catfs", "/src", "/cache", "/mnt", "-o"], &["catfs", "/src", "/cache", "/mnt", "-o"]),
+        ];
+
+        for &(argv, expected) in cases {
+            assert_eq!(from_mount_options(&flags, os(argv)), os(expected), "{:?}", argv);
+        }
+    }
 }
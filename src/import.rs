@@ -0,0 +1,557 @@
+extern crate libc;
+extern crate xattr;
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+
+use self::xattr::FileExt;
+
+use catfs::backend::SourceBackend;
+use catfs::compress;
+use catfs::crypt;
+use catfs::error;
+use catfs::file;
+use catfs::rlibc;
+use catfs::rlibc::File;
+use evicter::dir_walker::DirWalker;
+use export::{BLOCK, MANIFEST, SRC_CHKSUM_XATTR};
+
+#[derive(Default, Debug, PartialEq)]
+pub struct ImportStats {
+    pub imported: usize,
+    // src has changed since these were cached
+    pub stale: usize,
+    // the cache already has these
+    pub existing: usize,
+}
+
+// fills a cache dir with files from another cache, or what catfs
+// export made of one. A file is only taken if its checksum still
+// matches what's in src, and files that are already cached are left
+// alone so this can run next to a mount
+pub struct Importer<'a> {
+    src: &'a dyn SourceBackend,
+    cache_dir: RawFd,
+    layout: file::CacheLayout,
+    stats: ImportStats,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(src: &'a dyn SourceBackend, cache_dir: RawFd) -> error::Result<Importer<'a>> {
+        return Ok(Importer {
+            src: src,
+            cache_dir: cache_dir,
+            layout: file::CacheLayout::load(cache_dir)?,
+            stats: Default::default(),
+        });
+    }
+
+    pub fn stats(&self) -> &ImportStats {
+        return &self.stats;
+    }
+
+    fn wanted(&mut self, path: &Path, chksum: &[u8]) -> error::Result<bool> {
+        if path == Path::new(MANIFEST) {
+            return Ok(false);
+        }
+        if rlibc::existat(self.cache_dir, &self.layout.cache_path(&path))? {
+            self.stats.existing += 1;
+            return Ok(false);
+        }
+
+        match file::Handle::src_chksum_at(self.src, &path) {
+            Ok(expected) => {
+                if expected.as_slice() == chksum {
+                    return Ok(true);
+                }
+            }
+            Err(e) => {
+                if !error::is_enoent(&e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        debug!("{:?} is stale", path);
+        self.stats.stale += 1;
+        return Ok(false);
+    }
+
+    // writes next to path first so a mount never sees half a file,
+    // still_valid is checked before it's renamed into place
+    fn add<F>(
+        &mut self,
+        path: &Path,
+        chksum: &[u8],
+        mode: u32,
+        data: &mut dyn Read,
+        still_valid: F,
+    ) -> error::Result<()>
+    where
+        F: FnOnce() -> io::Result<bool>,
+    {
+        let cache_path = self.layout.cache_path(&path);
+        if let Some(parent) = cache_path.parent() {
+            file::mkdirat_all(self.cache_dir, &parent, 0o777)?;
+        }
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(cache_path.file_name().unwrap_or(cache_path.as_os_str()));
+        tmp_name.push(".catfs-import");
+        let tmp_path = cache_path.with_file_name(tmp_name);
+
+        let mut f = File::openat(
+            self.cache_dir,
+            &tmp_path,
+            rlibc::O_WRONLY | rlibc::O_CREAT | rlibc::O_TRUNC,
+            mode as libc::mode_t,
+        )?;
+        let res = (|| {
+            copy_from(data, &f)?;
+            if self.layout != file::CacheLayout::Path {
+                f.set_xattr(file::PATH_XATTR, path.as_os_str().as_bytes())?;
+            }
+            f.set_xattr(SRC_CHKSUM_XATTR, chksum)?;
+            return still_valid();
+        })();
+        f.close()?;
+
+        match res {
+            Ok(true) => {
+                rlibc::renameat(self.cache_dir, &tmp_path, &cache_path)?;
+                debug!("<-- import {:?}", path);
+                self.stats.imported += 1;
+                return Ok(());
+            }
+            Ok(false) => {
+                debug!("{:?} changed while importing", path);
+                rlibc::unlinkat(self.cache_dir, &tmp_path, 0)?;
+                return Ok(());
+            }
+            Err(e) => {
+                let _ = rlibc::unlinkat(self.cache_dir, &tmp_path, 0);
+                return Err(e.into());
+            }
+        }
+    }
+
+    // from is a cache dir, which may be in use by another catfs
+    pub fn import_dir(&mut self, from: &Path) -> error::Result<()> {
+        let fd = rlibc::open(&from, rlibc::O_RDONLY, 0)?;
+        let res = (|| {
+            let layout = file::CacheLayout::load(fd)?;
+            for cache_path in DirWalker::new(fd)? {
+                let flags = rlibc::O_RDONLY | rlibc::O_NOFOLLOW;
+                let mut f = match File::openat(fd, &cache_path, flags, 0) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        // cached symlinks aren't imported
+                        if error::is_enoent(&e) || e.raw_os_error() == Some(libc::ELOOP) {
+                            continue;
+                        }
+                        return Err(e.into());
+                    }
+                };
+                let res = self.import_file(&layout, &cache_path, &f);
+                f.close()?;
+                res?;
+            }
+            return Ok(());
+        })();
+        rlibc::close(fd)?;
+        return res;
+    }
+
+    fn import_file(
+        &mut self,
+        layout: &file::CacheLayout,
+        cache_path: &Path,
+        f: &File,
+    ) -> error::Result<()> {
+        let st = f.stat()?;
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+            return Ok(());
+        }
+        let chksum = match f.get_xattr(SRC_CHKSUM_XATTR)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let path = match layout.src_path(&cache_path, f)? {
+            Some(path) => {
+                match clean_path(&path) {
+                    Some(path) => path,
+                    None => return Ok(()),
+                }
+            }
+            None => return Ok(()),
+        };
+
+        // there's no key to decrypt it with
+        if crypt::is_sealed(f)? {
+            debug!("skipping encrypted {:?}", path);
+            return Ok(());
+        }
+
+        if self.wanted(&path, &chksum)? {
+            // what's imported is always plain
+            let mut data = ReadFrom {
+                r: compress::Reader::new(f)?,
+                offset: 0,
+            };
+            // writing to a cached file takes away its checksum
+            let expected = chksum.clone();
+            let still_valid = move || Ok(f.get_xattr(SRC_CHKSUM_XATTR)? == Some(expected));
+            self.add(&path, &chksum, st.st_mode & 0o7777, &mut data, still_valid)?;
+        }
+        return Ok(());
+    }
+
+    // an archive from catfs export --tar, or anything else that keeps
+    // the checksums as pax SCHILY.xattr records
+    pub fn import_tar(&mut self, r: &mut dyn Read) -> error::Result<()> {
+        let mut next: TarExt = Default::default();
+        let mut h = [0u8; BLOCK];
+
+        while read_block(r, &mut h)? && h.iter().any(|b| *b != 0) {
+            let size = next.size.take().unwrap_or(parse_octal(&h[124..136]));
+
+            match h[156] {
+                // pax extended header, applies to the next entry
+                b'x' => {
+                    next.parse_pax(&read_data(r, size)?)?;
+                    continue;
+                }
+                // GNU long name
+                b'L' => {
+                    let name = read_data(r, size)?;
+                    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                    next.path = Some(PathBuf::from(OsStr::from_bytes(&name[..len])));
+                    continue;
+                }
+                b'0' | 0 => {
+                    let path = next.path.take().unwrap_or_else(|| ustar_name(&h));
+                    let mode = parse_octal(&h[100..108]) as u32 & 0o7777;
+                    let mut data = (&mut *r).take(size);
+
+                    if let (Some(path), Some(chksum)) = (clean_path(&path), next.chksum.take()) {
+                        if self.wanted(&path, &chksum)? {
+                            self.add(&path, &chksum, mode, &mut data, || Ok(true))?;
+                        }
+                    }
+                    io::copy(&mut data, &mut io::sink())?;
+                }
+                _ => {
+                    io::copy(&mut (&mut *r).take(size), &mut io::sink())?;
+                }
+            }
+
+            skip_padding(r, size)?;
+            next = Default::default();
+        }
+
+        return Ok(());
+    }
+}
+
+fn copy_from(r: &mut dyn Read, to: &File) -> io::Result<()> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut offset = 0;
+    loop {
+        let nread = r.read(&mut buf)?;
+        if nread == 0 {
+            return Ok(());
+        }
+        let mut nwritten = 0;
+        while nwritten < nread {
+            nwritten += to.write_at(&buf[nwritten..nread], offset + nwritten as i64)?;
+        }
+        offset += nread as i64;
+    }
+}
+
+// reads a cache file from the start, decompressing it if need be
+struct ReadFrom<'a> {
+    r: compress::Reader<'a>,
+    offset: i64,
+}
+
+impl<'a> Read for ReadFrom<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nread = self.r.read_at(buf, self.offset)?;
+        self.offset += nread as i64;
+        return Ok(nread);
+    }
+}
+
+// what pax and GNU headers say about the entry after them
+#[derive(Default)]
+struct TarExt {
+    path: Option<PathBuf>,
+    size: Option<u64>,
+    chksum: Option<Vec<u8>>,
+}
+
+impl TarExt {
+    // "<len> <key>=<value>\n" records, len counts all of it
+    fn parse_pax(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut i = 0;
+        while i < data.len() {
+            let sp = match data[i..].iter().position(|b| *b == b' ') {
+                Some(sp) => i + sp,
+                None => return Err(bad_pax()),
+            };
+            let len = match String::from_utf8_lossy(&data[i..sp]).parse::<usize>() {
+                Ok(len) => len,
+                Err(_) => return Err(bad_pax()),
+            };
+            let end = match i.checked_add(len) {
+                Some(end) => end,
+                None => return Err(bad_pax()),
+            };
+            if len < sp - i + 2 || end > data.len() {
+                return Err(bad_pax());
+            }
+
+            let record = &data[sp + 1..end - 1];
+            if let Some(eq) = record.iter().position(|b| *b == b'=') {
+                let (key, value) = (&record[..eq], &record[eq + 1..]);
+                if key == b"path" {
+                    self.path = Some(PathBuf::from(OsStr::from_bytes(value)));
+                } else if key == b"size" {
+                    self.size = String::from_utf8_lossy(value).parse().ok();
+                } else if key.starts_with(b"SCHILY.xattr.") &&
+                           &key[13..] == SRC_CHKSUM_XATTR.as_bytes()
+                {
+                    self.chksum = Some(value.to_vec());
+                }
+            }
+            i = end;
+        }
+        return Ok(());
+    }
+}
+
+fn bad_pax() -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, "bad pax header");
+}
+
+// false if r has ended
+fn read_block(r: &mut dyn Read, buf: &mut [u8; BLOCK]) -> io::Result<bool> {
+    let mut n = 0;
+    while n < BLOCK {
+        let nread = r.read(&mut buf[n..])?;
+        if nread == 0 {
+            if n == 0 {
+                return Ok(false);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar header"));
+        }
+        n += nread;
+    }
+    return Ok(true);
+}
+
+fn read_data(r: &mut dyn Read, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    (&mut *r).take(size).read_to_end(&mut data)?;
+    skip_padding(r, size)?;
+    return Ok(data);
+}
+
+fn skip_padding(r: &mut dyn Read, size: u64) -> io::Result<()> {
+    let rem = (size % BLOCK as u64) as usize;
+    if rem != 0 {
+        let mut pad = [0u8; BLOCK];
+        r.read_exact(&mut pad[rem..])?;
+    }
+    return Ok(());
+}
+
+fn parse_octal(field: &[u8]) -> u64 {
+    // GNU base-256 for numbers that don't fit
+    if field[0] & 0x80 != 0 {
+        return field[1..].iter().fold(0, |n, b| (n << 8) | *b as u64);
+    }
+    return field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| **b >= b'0' && **b <= b'7')
+        .fold(0, |n, b| n * 8 + (*b - b'0') as u64);
+}
+
+fn ustar_name(h: &[u8; BLOCK]) -> PathBuf {
+    let field = |f: &[u8]| {
+        let len = f.iter().position(|b| *b == 0).unwrap_or(f.len());
+        return PathBuf::from(OsStr::from_bytes(&f[..len]));
+    };
+    let name = field(&h[..100]);
+    if &h[257..262] == b"ustar" && h[345] != 0 {
+        return field(&h[345..500]).join(name);
+    }
+    return name;
+}
+
+// archives can have ./ in front, anything that would land outside
+// the cache dir is skipped
+fn clean_path(path: &Path) -> Option<PathBuf> {
+    let mut p = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(n) => p.push(n),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    if p.as_os_str().is_empty() {
+        return None;
+    }
+    return Some(p);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use std::fs;
+    use catfs;
+    use catfs::backend::LocalBackend;
+    use export;
+    use super::*;
+
+    #[test]
+    fn import_valid() {
+        let _ = env_logger::init();
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let old = prefix.join("old");
+
+        fs::create_dir_all(old.join("dir1")).unwrap();
+        for p in ["file1", "file2", "dir1/file1"].iter() {
+            fs::copy(prefix.join("resources").join(p), old.join(p)).unwrap();
+        }
+        // from a --cache-compress mount
+        {
+            let data = fs::read(prefix.join("resources/file3")).unwrap();
+            let flags = rlibc::O_RDWR | rlibc::O_CREAT;
+            let mut f = File::open(&old.join("file3"), flags, 0o644).unwrap();
+            let mut chunks = compress::Chunks::new(compress::Codec::Lz4);
+            for chunk in data.chunks(compress::CHUNK_SIZE) {
+                chunks.append(&f, chunk).unwrap();
+            }
+            chunks.finish(&f).unwrap();
+            f.close().unwrap();
+        }
+        // and an --cache-encrypt one, which can't be imported
+        {
+            let data = fs::read(prefix.join("resources/dir1/file2")).unwrap();
+            let key = crypt::Key::new(&[7u8; 32]).unwrap();
+            let flags = rlibc::O_RDWR | rlibc::O_CREAT;
+            let mut f = File::open(&old.join("dir1/file2"), flags, 0o644).unwrap();
+            key.seal(&f).unwrap();
+            key.write_at(&f, &data, 0).unwrap();
+            f.close().unwrap();
+        }
+        let old_dir = rlibc::open(&old, rlibc::O_RDONLY, 0).unwrap();
+        for p in ["file1", "file2", "dir1/file1", "file3", "dir1/file2"].iter() {
+            file::Handle::make_pristine(&src, old_dir, file::CacheLayout::Path, p).unwrap();
+        }
+        // src has changed since
+        fs::write(prefix.join("resources/file2"), "changed").unwrap();
+
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("file1"), "cached").unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        {
+            let mut importer = Importer::new(&src, cache_dir).unwrap();
+            importer.import_dir(&old).unwrap();
+            assert_eq!(
+                *importer.stats(),
+                ImportStats {
+                    imported: 2,
+                    stale: 1,
+                    existing: 1,
+                }
+            );
+        }
+        rlibc::close(cache_dir).unwrap();
+        assert_eq!(
+            fs::read(cache.join("dir1/file1")).unwrap(),
+            fs::read(prefix.join("resources/dir1/file1")).unwrap()
+        );
+        assert!(!cache.join("file2").exists());
+        assert_eq!(fs::read(cache.join("file1")).unwrap(), b"cached");
+        assert!(!cache.join("dir1/file2").exists());
+        assert_eq!(
+            fs::read(cache.join("file3")).unwrap(),
+            fs::read(prefix.join("resources/file3")).unwrap()
+        );
+        assert!(
+            xattr::get(cache.join("file3"), compress::COMPRESS_XATTR)
+                .unwrap()
+                .is_none()
+        );
+
+        let archive = prefix.join("old.tar");
+        assert_eq!(export::export(old_dir, &archive, true).unwrap(), 4);
+        rlibc::close(old_dir).unwrap();
+
+        let cache = prefix.join("cache2");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+        {
+            let mut importer = Importer::new(&src, cache_dir).unwrap();
+            importer.import_tar(&mut fs::File::open(&archive).unwrap()).unwrap();
+            assert_eq!(importer.stats().imported, 3);
+            assert_eq!(importer.stats().stale, 1);
+        }
+        rlibc::close(cache_dir).unwrap();
+        assert!(
+            xattr::get(cache.join("dir1/file1"), SRC_CHKSUM_XATTR)
+                .unwrap()
+                .is_some()
+        );
+        assert!(!cache.join(MANIFEST).exists());
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn malformed_pax() {
+        let mut ext: TarExt = Default::default();
+        ext.parse_pax(b"19 path=dir1/file1\n12 size=100\n").unwrap();
+        assert_eq!(ext.path, Some(PathBuf::from("dir1/file1")));
+        assert_eq!(ext.size, Some(100));
+
+        for data in [
+            &b"1 x"[..],
+            &b"2 x"[..],
+            &b"0 x\n"[..],
+            &b"12 size=100\n18446744073709551615 x=y\n"[..],
+            &b"99 path=file1\n"[..],
+            &b"path=file1\n"[..],
+        ].iter()
+        {
+            let mut ext: TarExt = Default::default();
+            let e = ext.parse_pax(data).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let prefix = catfs::tests::copy_resources();
+        let src = LocalBackend::new(&prefix.join("resources")).unwrap();
+        let cache = prefix.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let cache_dir = rlibc::open(&cache, rlibc::O_RDONLY, 0).unwrap();
+
+        let mut archive = vec![0u8; BLOCK * 2];
+        archive[124..135].copy_from_slice(b"00000000003");
+        archive[156] = b'x';
+        archive[BLOCK..BLOCK + 3].copy_from_slice(b"1 x");
+        let res = Importer::new(&src, cache_dir).unwrap().import_tar(&mut &archive[..]);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        rlibc::close(cache_dir).unwrap();
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
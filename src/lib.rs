@@ -1,7 +1,10 @@
 pub mod catfs;
 pub mod evicter;
+pub mod ffi;
 pub mod pcatfs;
 #[macro_use]
 extern crate log;
 
 pub use catfs::CatFS;
+pub use catfs::backend::{SourceBackend, SourceDir, SourceHints};
+pub use catfs::mount::{Mount, MountBuilder};
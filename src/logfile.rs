@@ -0,0 +1,129 @@
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+struct Current {
+    file: File,
+    size: u64,
+}
+
+// a log that's started over once it gets to max_size, keeping the
+// last few as path.1, path.2 and so on, path.1 being the newest
+pub struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u64,
+    current: Mutex<Current>,
+}
+
+impl LogFile {
+    // max_size of 0 never rotates
+    pub fn open(path: &dyn AsRef<Path>, max_size: u64, keep: u64) -> io::Result<LogFile> {
+        let file = LogFile::open_file(path.as_ref())?;
+        let size = file.metadata()?.len();
+        return Ok(LogFile {
+            path: path.as_ref().to_path_buf(),
+            max_size: max_size,
+            keep: keep,
+            current: Mutex::new(Current {
+                file: file,
+                size: size,
+            }),
+        });
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        return OpenOptions::new().create(true).append(true).open(path);
+    }
+
+    fn old_path(&self, n: u64) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        return PathBuf::from(name);
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        if self.keep == 0 {
+            current.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.old_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.old_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.old_path(1))?;
+            current.file = LogFile::open_file(&self.path)?;
+        }
+        current.size = 0;
+        return Ok(());
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let mut current = self.current.lock().unwrap();
+        let len = line.len() as u64 + 1;
+        if self.max_size != 0 && current.size != 0 && current.size + len > self.max_size {
+            if let Err(e) = self.rotate(&mut current) {
+                // such as when we can't write to the directory after
+                // dropping privileges, start over so it doesn't grow
+                // without bound
+                let _ = current.file.set_len(0);
+                current.size = 0;
+                let msg = format!("unable to rotate {:?}: {}", self.path, e);
+                if writeln!(current.file, "{}", msg).is_ok() {
+                    current.size += msg.len() as u64 + 1;
+                }
+            }
+        }
+        // not much we can do if we can't log
+        if writeln!(current.file, "{}", line).is_ok() {
+            current.size += len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use catfs;
+    use super::*;
+
+    #[test]
+    fn rotate() {
+        let prefix = catfs::tests::copy_resources();
+        let path = prefix.join("catfs.log");
+        let log = LogFile::open(&path, 20, 2).unwrap();
+
+        for i in 0..5 {
+            log.write_line(&format!("line {}", i));
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 4\n");
+        assert_eq!(fs::read_to_string(log.old_path(1)).unwrap(), "line 2\nline 3\n");
+        assert_eq!(fs::read_to_string(log.old_path(2)).unwrap(), "line 0\nline 1\n");
+        assert!(!log.old_path(3).exists());
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn rotate_failed() {
+        let prefix = catfs::tests::copy_resources();
+        let path = prefix.join("catfs.log");
+        let log = LogFile::open(&path, 20, 1).unwrap();
+        // can't rename a file over a directory
+        fs::create_dir(log.old_path(1)).unwrap();
+
+        for i in 0..5 {
+            log.write_line(&format!("line {}", i));
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("unable to rotate"));
+        assert!(contents.ends_with("\nline 4\n"));
+        assert!(!contents.contains("line 3"));
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
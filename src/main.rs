@@ -12,15 +12,20 @@ extern crate time;
 
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
+use std::fs;
+use std::collections::HashMap;
+use std::fs::File;
 use std::io;
-use std::path::Path;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use chan_signal::Signal;
-use clap::{App, Arg};
+use clap::{App, Arg, Shell};
 use daemonize::{Daemonize};
 use env_logger::LogBuilder;
 use log::LogRecord;
@@ -30,35 +35,71 @@ mod pcatfs;
 mod catfs;
 mod flags;
 mod evicter;
+mod check;
+mod export;
+mod import;
+mod virtiofs;
+mod logfile;
 
+use catfs::backend;
 use catfs::error;
-use catfs::flags::{DiskSpace, FlagStorage};
+use catfs::flags::{DiskSpace, FlagStorage, SignalAction};
+use catfs::mount;
+use catfs::oplog;
 use catfs::rlibc;
 
 fn main() {
     if let Err(e) = main_internal() {
         error!("Cannot mount: {}", e);
-        std::process::exit(1);
+        process::exit(exit_code(&e));
     }
 }
 
+fn exit_code(e: &error::RError<io::Error>) -> i32 {
+    if !flags::is_mount_helper() {
+        return 1;
+    }
+
+    // mount(8) distinguishes usage and permission errors from
+    // failing to mount
+    return match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput => 1,
+        _ => 32,
+    };
+}
+
 static mut SYSLOG: bool = false;
 static mut SYSLOGGER: Option<Box<syslog::Logger>> = None;
+static mut LOGFILE: Option<logfile::LogFile> = None;
 
 fn main_internal() -> error::Result<()> {
     let format = |record: &LogRecord| {
         let t = time::now();
-        let syslog: bool;
-        unsafe {
-            syslog = SYSLOG;
-        }
-        if !syslog {
+        let line = if oplog::is_json() {
+            oplog::format_json(
+                &time::strftime("%Y-%m-%dT%H:%M:%S%z", &t).unwrap(),
+                &record.level().to_string(),
+                record.target(),
+                &format!("{}", record.args()),
+            )
+        } else {
             format!(
                 "{} {:5} - {}",
                 time::strftime("%Y-%m-%d %H:%M:%S", &t).unwrap(),
                 record.level(),
                 record.args()
             )
+        };
+        let syslog: bool;
+        unsafe {
+            syslog = SYSLOG;
+            if let Some(ref log) = LOGFILE {
+                log.write_line(&line);
+                return format!("\u{08}");
+            }
+        }
+        if !syslog {
+            line
         } else {
             unsafe {
                 if let Some(ref logger) = SYSLOGGER {
@@ -69,7 +110,13 @@ fn main_internal() -> error::Result<()> {
                         log::LogLevel::Warn => Severity::LOG_WARNING,
                         log::LogLevel::Error => Severity::LOG_ERR,
                     };
-                    let msg = format!("{}", record.args());
+                    // syslog has its own timestamp and level, except
+                    // json lines are meant to be read as they are
+                    let msg = if oplog::is_json() {
+                        line
+                    } else {
+                        format!("{}", record.args())
+                    };
                     for line in msg.split('\n') {
                         // ignore error if we can't log, not much we can do anyway
                         let _ = logger.send_3164(level, line);
@@ -92,48 +139,507 @@ fn main_internal() -> error::Result<()> {
 
     builder.init().unwrap();
 
+    if env::args_os().nth(1) == Some(OsString::from("evict")) {
+        return evict_main();
+    }
+    if env::args_os().nth(1) == Some(OsString::from("export")) {
+        return export_main();
+    }
+    if env::args_os().nth(1) == Some(OsString::from("import")) {
+        return import_main();
+    }
+    if env::args_os().nth(1) == Some(OsString::from("check")) {
+        return check_main();
+    }
+
     let mut flags: FlagStorage = Default::default();
     let mut test = false;
+    let mut completions = String::new();
+
+    flags.signals = catfs::flags::DEFAULT_SIGNAL_ACTIONS.to_owned();
+    flags.groups = String::from("user");
+    flags.idle_handle_warn = 3600;
+    flags.log_file_size = DiskSpace::Bytes(10 * 1024 * 1024);
+    flags.log_file_count = 5;
+    set_evict_defaults(&mut flags);
 
     flags.mount_options.push(OsString::from("-o"));
     flags.mount_options.push(OsString::from("atomic_o_trunc"));
-    flags.mount_options.push(OsString::from("-o"));
-    flags.mount_options.push(
-        OsString::from("default_permissions"),
-    );
+    if cfg!(target_os = "macos") {
+        // catfs doesn't store xattrs, without this macOS would put
+        // them (and Finder metadata) as ._ files in the source
+        flags.mount_options.push(OsString::from("-o"));
+        flags.mount_options.push(OsString::from("noappledouble"));
+    }
 
     let app = App::new("catfs")
         .about("Cache Anything FileSystem")
         .version(crate_version!());
 
-    {
-        fn diskspace_validator(s: String) -> Result<(), String> {
-            DiskSpace::from_str(&s).map(|_| ()).map_err(
-                |e| e.to_str().to_owned(),
-            )
-        }
-
-        fn path_validator(s: String) -> Result<(), String> {
-            Path::new(&s)
-                .canonicalize()
-                .map_err(|e| e.to_string().to_owned())
-                .and_then(|p| if p.is_dir() {
-                    Ok(())
-                } else {
-                    Err("is not a directory".to_owned())
-                })
-        }
-
+    let (mut app, matches) = {
         let mut args = [
             flags::Flag {
-                arg: Arg::with_name("space")
-                    .long("free")
+                arg: evict_arg("space"),
+                value: &mut flags.free_space,
+            },
+            flags::Flag {
+                arg: evict_arg("max-cache-size"),
+                value: &mut flags.max_cache_size,
+            },
+            flags::Flag {
+                arg: evict_arg("cache-quota").requires("cache-prefix"),
+                value: &mut flags.cache_quota,
+            },
+            flags::Flag {
+                arg: evict_arg("quota-per-uid"),
+                value: &mut flags.quota_per_uid,
+            },
+            flags::Flag {
+                arg: evict_arg("eviction-policy"),
+                value: &mut flags.eviction_policy,
+            },
+            flags::Flag {
+                arg: evict_arg("evict-scan-interval"),
+                value: &mut flags.evict_scan_interval,
+            },
+            flags::Flag {
+                arg: evict_arg("evict-low-watermark"),
+                value: &mut flags.evict_low_watermark,
+            },
+            flags::Flag {
+                arg: evict_arg("evict-hot-percent"),
+                value: &mut flags.evict_hot_percent,
+            },
+            flags::Flag {
+                arg: evict_arg("evict-request-weight"),
+                value: &mut flags.evict_request_weight,
+            },
+            flags::Flag {
+                arg: evict_arg("pin").help(
+                    "Cache files matching these globs at mount and never evict them \
+                     (ex: models/*.bin,indices)",
+                ),
+                value: &mut flags.pin,
+            },
+            flags::Flag {
+                arg: Arg::with_name("ttl")
+                    .long("ttl")
+                    .takes_value(true)
+                    .help("Cache metadata for this many seconds (default: 0)")
+                    .validator(seconds_validator),
+                value: &mut flags.ttl,
+            },
+            flags::Flag {
+                arg: Arg::with_name("dir-cache-ttl")
+                    .long("dir-cache-ttl")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .help("Keep directory listings for this many seconds, changes made through \
+                           the mount show up right away (default: 0)")
+                    .validator(seconds_validator),
+                value: &mut flags.dir_cache_ttl,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-valid-ttl")
+                    .long("cache-valid-ttl")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .help("Check a cached file against the source again once it was last \
+                           checked this many seconds ago (default: only after the kernel forgets it)")
+                    .validator(seconds_validator),
+                value: &mut flags.cache_valid_ttl,
+            },
+            flags::Flag {
+                arg: Arg::with_name("warn-idle-handles")
+                    .long("warn-idle-handles")
+                    .takes_value(true)
+                    .help("Warn about files and dirs that stay open this many seconds without IO, \
+                           0 to never warn (default: 3600)")
+                    .validator(seconds_validator),
+                value: &mut flags.idle_handle_warn,
+            },
+            flags::Flag {
+                arg: Arg::with_name("readahead")
+                    .long("readahead")
+                    .takes_value(true)
+                    .help("Copy this much at a time when caching a file or copying it back to the source, \
+                           at most 64M (default: 128K)")
+                    .validator(readahead_validator),
+                value: &mut flags.readahead,
+            },
+            flags::Flag {
+                arg: Arg::with_name("max-pagein-bandwidth")
+                    .long("max-pagein-bandwidth")
+                    .takes_value(true)
+                    .help("Cache files in the background at most this much per second (ex: 50M)")
+                    .validator(size_validator),
+                value: &mut flags.max_pagein_bandwidth,
+            },
+            flags::Flag {
+                arg: Arg::with_name("max-concurrent-pagein")
+                    .long("max-concurrent-pagein")
+                    .takes_value(true)
+                    .help("Cache at most this many files in the background at once, others wait their turn")
+                    .validator(positive_validator),
+                value: &mut flags.max_concurrent_pagein,
+            },
+            flags::Flag {
+                arg: Arg::with_name("shared-cache")
+                    .long("shared-cache")
+                    .help("The cache directory is used by other catfs at the same time"),
+                value: &mut flags.shared_cache,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-odirect")
+                    .long("cache-odirect")
+                    .help("Read and write cache files with O_DIRECT so they are not also in the page cache"),
+                value: &mut flags.cache_odirect,
+            },
+            flags::Flag {
+                arg: Arg::with_name("no-splice")
+                    .long("no-splice")
+                    .alias("disable-splice")
+                    .help("Copy through a buffer instead of with splice() or copy_file_range()"),
+                value: &mut flags.no_splice,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-l2")
+                    .long("cache-l2")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Move cold files here instead of evicting them, and look here before the source")
+                    .validator(path_validator),
+                value: &mut flags.cache_l2,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-prefix")
+                    .long("cache-prefix")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .help("Cache under this directory of the cache directory, so other mounts \
+                           can share it")
+                    .validator(prefix_validator),
+                value: &mut flags.cache_prefix,
+            },
+            flags::Flag {
+                arg: Arg::with_name("whiteout")
+                    .long("whiteout")
+                    .help("Keep deletes in the cache as whiteouts instead of deleting from the source"),
+                value: &mut flags.whiteout,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cow")
+                    .long("cow")
+                    .conflicts_with("whiteout")
+                    .help("Never change the source, keep new and changed files in the cache instead"),
+                value: &mut flags.cow,
+            },
+            flags::Flag {
+                arg: validation_arg("validate"),
+                value: &mut flags.validate,
+            },
+            flags::Flag {
+                arg: validation_arg("validate-xattrs"),
+                value: &mut flags.validate_xattrs,
+            },
+            flags::Flag {
+                arg: Arg::with_name("immutable-src")
+                    .long("immutable-src")
+                    .help("Files in the source never change once written, trust any complete cached copy"),
+                value: &mut flags.immutable_src,
+            },
+            flags::Flag {
+                arg: Arg::with_name("fetch-cmd")
+                    .long("fetch-cmd")
+                    .takes_value(true)
+                    .help("Run this shell command to put a file in the source when it's missing, \
+                           it gets the path as $1"),
+                value: &mut flags.fetch_cmd,
+            },
+            flags::Flag {
+                arg: Arg::with_name("src-retries")
+                    .long("src-retries")
+                    .takes_value(true)
+                    .help("Try reads from the source this many more times when they fail with EIO, \
+                           EAGAIN or a timeout (default: 0)")
+                    .validator(count_validator),
+                value: &mut flags.src_retries,
+            },
+            flags::Flag {
+                arg: Arg::with_name("src-retry-delay")
+                    .long("src-retry-delay")
+                    .takes_value(true)
+                    .value_name("MS")
+                    .help("How long to wait before the first retry, doubling after each one (default: 100)")
+                    .validator(positive_validator),
+                value: &mut flags.src_retry_delay,
+            },
+            flags::Flag {
+                arg: Arg::with_name("no-cache")
+                    .long("no-cache")
+                    .conflicts_with_all(&["cow", "pin"])
+                    .help("Don't cache anything, only pass IO through to the source"),
+                value: &mut flags.no_cache,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-include")
+                    .long("cache-include")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("GLOBS")
+                    .help("Only cache files matching these globs, can be repeated \
+                           (ex: **/*.parquet,**/*.idx)")
+                    .validator(pin_validator),
+                value: &mut flags.cache_include,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-exclude")
+                    .long("cache-exclude")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("GLOBS")
+                    .help("Don't cache files matching these globs, can be repeated (ex: scratch)")
+                    .validator(pin_validator),
+                value: &mut flags.cache_exclude,
+            },
+            flags::Flag {
+                arg: Arg::with_name("max-cacheable-size")
+                    .long("max-cacheable-size")
+                    .takes_value(true)
+                    .help("Don't cache files bigger than this (ex: 20G)")
+                    .validator(size_validator),
+                value: &mut flags.max_cacheable_size,
+            },
+            flags::Flag {
+                arg: Arg::with_name("bypass")
+                    .long("bypass")
+                    .takes_value(true)
+                    .help("Don't cache files for these processes, by pid, executable name, or \
+                           env:VAR to match processes with VAR set (ex: restic,env:CATFS_BYPASS)")
+                    .validator(bypass_validator),
+                value: &mut flags.bypass,
+            },
+            flags::Flag {
+                arg: Arg::with_name("virtiofs")
+                    .long("virtiofs")
+                    .takes_value(true)
+                    .help("Also serve the mount to VMs with virtiofsd on this vhost-user socket"),
+                value: &mut flags.virtiofs,
+            },
+            flags::Flag {
+                arg: Arg::with_name("mirror-to")
+                    .long("mirror-to")
+                    .takes_value(true)
+                    .help("Also copy files written through the mount to this directory, in the background")
+                    .validator(path_validator),
+                value: &mut flags.mirror_to,
+            },
+            flags::Flag {
+                arg: Arg::with_name("control-socket")
+                    .long("control-socket")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .help("Accept commands such as stats and flush-all on this unix socket"),
+                value: &mut flags.control_socket,
+            },
+            flags::Flag {
+                arg: Arg::with_name("stats-file")
+                    .long("stats-file")
+                    .help("Expose cache hit/miss counters in a .catfs_stats file at the root of the mount"),
+                value: &mut flags.stats_file,
+            },
+            flags::Flag {
+                arg: Arg::with_name("watch-source")
+                    .long("watch-source")
+                    .help("Watch <from> with inotify and drop what others change there from the cache right away"),
+                value: &mut flags.watch_source,
+            },
+            flags::Flag {
+                arg: Arg::with_name("peers")
+                    .long("peers")
+                    .takes_value(true)
+                    .help("Try to read files from the caches of these catfs instances first (ex: host1:9090,host2:9090)"),
+                value: &mut flags.peers,
+            },
+            flags::Flag {
+                arg: Arg::with_name("serve-peers")
+                    .long("serve-peers")
+                    .takes_value(true)
+                    .help("Let other catfs instances read cached files on this address (ex: 0.0.0.0:9090)"),
+                value: &mut flags.serve_peers,
+            },
+            flags::Flag {
+                arg: Arg::with_name("peer-allow")
+                    .long("peer-allow")
+                    .takes_value(true)
+                    .default_value("127.0.0.0/8,::1")
+                    .help("Addresses or networks that may use --serve-peers (ex: 10.0.0.0/8)")
+                    .validator(allow_list_validator),
+                value: &mut flags.peer_allow,
+            },
+            flags::Flag {
+                arg: Arg::with_name("peer-bandwidth")
+                    .long("peer-bandwidth")
+                    .takes_value(true)
+                    .help("Serve peers at most this much per second (ex: 50M)")
+                    .validator(size_validator),
+                value: &mut flags.peer_bandwidth,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-layout")
+                    .long("cache-layout")
+                    .takes_value(true)
+                    .possible_values(&["path", "hashed", "fanout"])
+                    .help(
+                        "Name cached files like the source, or after a hash of their path, \
+                         with fanout spreading them out under aa/bb/. \
+                         (default: what the cache dir was last used with, or path)",
+                    ),
+                value: &mut flags.cache_layout,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-fill")
+                    .long("cache-fill")
+                    .takes_value(true)
+                    .possible_values(&["full", "partial"])
+                    .default_value("full")
+                    .help("Cache all of a file in the background when it's opened for read, \
+                           or only the parts that are read"),
+                value: &mut flags.cache_fill,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-compress")
+                    .long("cache-compress")
+                    .takes_value(true)
+                    .possible_values(&["lz4", "zstd"])
+                    .help("Compress cache files of files that are only read"),
+                value: &mut flags.cache_compress,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-encrypt")
+                    .long("cache-encrypt")
+                    .takes_value(true)
+                    .value_name("KEYFILE")
+                    .conflicts_with("cache-compress")
+                    .help("Encrypt what's in cache files with the AES-256 key in this file")
+                    .validator(path_validator),
+                value: &mut flags.cache_encrypt,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-dedupe")
+                    .long("cache-dedupe")
+                    .help("Share the blocks of cache files with the same content, if the cache dir can reflink"),
+                value: &mut flags.cache_dedupe,
+            },
+            flags::Flag {
+                arg: Arg::with_name("statfs")
+                    .long("statfs")
+                    .takes_value(true)
+                    .possible_values(&["cache", "source", "min"])
+                    .help("Report the size and free space of the cache, of the source, \
+                           or the smaller of the two (default: cache)"),
+                value: &mut flags.statfs,
+            },
+            flags::Flag {
+                arg: Arg::with_name("io-backend")
+                    .long("io-backend")
+                    .takes_value(true)
+                    .possible_values(&["syscall", "uring"])
+                    .default_value("syscall")
+                    .help("Read and write files with a syscall each, or through io_uring \
+                           (needs catfs built with --features io-uring)"),
+                value: &mut flags.io_backend,
+            },
+            flags::Flag {
+                arg: Arg::with_name("write-back")
+                    .long("write-back")
+                    .value_name("SECS")
+                    .takes_value(true)
+                    .min_values(0)
+                    .require_equals(true)
+                    .help("Only write to the cache, and copy files to <from> in the background \
+                           this many seconds after they are closed (default: 0)")
+                    .validator(seconds_validator),
+                value: &mut flags.write_back,
+            },
+            flags::Flag {
+                arg: Arg::with_name("on-conflict")
+                    .long("on-conflict")
+                    .takes_value(true)
+                    .possible_values(&["fail", "overwrite", "keep-both"])
+                    .default_value("fail")
+                    .help("What to do when a file changed in the source before our changes to it \
+                           are copied back"),
+                value: &mut flags.on_conflict,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-mode")
+                    .long("cache-mode")
+                    .takes_value(true)
+                    .help("Create cache files with this mode instead of the source's, \
+                           directories also get x where there's r (ex: 0600)")
+                    .validator(mode_validator),
+                value: &mut flags.cache_mode,
+            },
+            flags::Flag {
+                arg: Arg::with_name("cache-umask")
+                    .long("cache-umask")
+                    .takes_value(true)
+                    .help("Take these permissions away from what's created in the cache (ex: 077)")
+                    .validator(mode_validator),
+                value: &mut flags.cache_umask,
+            },
+            flags::Flag {
+                arg: Arg::with_name("file-mode")
+                    .long("file-mode")
+                    .takes_value(true)
+                    .help("Show files with this mode and create them with it, whatever the source has (ex: 0644)")
+                    .validator(mode_validator),
+                value: &mut flags.file_mode,
+            },
+            flags::Flag {
+                arg: Arg::with_name("dir-mode")
+                    .long("dir-mode")
+                    .takes_value(true)
+                    .help("Show directories with this mode and create them with it (ex: 0755)")
+                    .validator(mode_validator),
+                value: &mut flags.dir_mode,
+            },
+            flags::Flag {
+                arg: Arg::with_name("umask")
+                    .long("umask")
+                    .takes_value(true)
+                    .help("Take these permissions away from what's shown and created through the mount (ex: 022)")
+                    .validator(mode_validator),
+                value: &mut flags.umask,
+            },
+            flags::Flag {
+                arg: profile_arg()
+                    .help("Use defaults tuned for this kind of source, explicit flags still apply"),
+                value: &mut flags.profile,
+            },
+            flags::Flag {
+                arg: Arg::with_name("signals")
+                    .long("signals")
                     .takes_value(true)
                     .help(
-                        "Ensure filesystem has at least this much free space. (ex: 9.5%, 10G)",
+                        "What to do on each signal, actions are unmount, force-unmount, dump, \
+                         dump-unmount, evict, latency and ignore. Repeating an unmount signal \
+                         forces the unmount. \
+                         (default: INT=unmount,TERM=unmount,QUIT=dump-unmount,USR1=evict,USR2=latency)",
                     )
-                    .validator(diskspace_validator),
-                value: &mut flags.free_space,
+                    .validator(signals_validator),
+                value: &mut flags.signals,
+            },
+            flags::Flag {
+                arg: Arg::with_name("threads")
+                    .long("threads")
+                    .takes_value(true)
+                    .help("Serve this many requests at the same time (default: 100)")
+                    .validator(positive_validator),
+                value: &mut flags.threads,
             },
             flags::Flag {
                 arg: Arg::with_name("foreground").short("f").help(
@@ -141,6 +647,40 @@ fn main_internal() -> error::Result<()> {
                 ),
                 value: &mut flags.foreground,
             },
+            flags::Flag {
+                arg: Arg::with_name("log-format")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .help("Log as text, or as one json object per line"),
+                value: &mut flags.log_format,
+            },
+            flags::Flag {
+                arg: Arg::with_name("log-file")
+                    .long("log-file")
+                    .takes_value(true)
+                    .help("Log to this file instead of stderr or syslog"),
+                value: &mut flags.log_file,
+            },
+            flags::Flag {
+                arg: Arg::with_name("log-file-size")
+                    .long("log-file-size")
+                    .takes_value(true)
+                    .requires("log-file")
+                    .help("Rotate --log-file once it gets this big, 0 to never rotate (default: 10M)")
+                    .validator(size_validator),
+                value: &mut flags.log_file_size,
+            },
+            flags::Flag {
+                arg: Arg::with_name("log-file-count")
+                    .long("log-file-count")
+                    .takes_value(true)
+                    .requires("log-file")
+                    .help("Keep this many rotated log files (default: 5)")
+                    .validator(count_validator),
+                value: &mut flags.log_file_count,
+            },
             flags::Flag{
                 arg: Arg::with_name("uid")
                     .long("uid")
@@ -155,6 +695,86 @@ fn main_internal() -> error::Result<()> {
                     .help("Run as this gid"),
                 value: &mut flags.gid,
             },
+            flags::Flag {
+                arg: Arg::with_name("groups")
+                    .long("groups")
+                    .takes_value(true)
+                    .possible_values(&["user", "none", "keep"])
+                    .help(
+                        "Supplementary groups to run with after switching --uid/--gid: those of \
+                         the user, none, or keep the current ones. (default: user)",
+                    ),
+                value: &mut flags.groups,
+            },
+            flags::Flag {
+                arg: Arg::with_name("map-uid")
+                    .long("map-uid")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("SRC:SHOWN")
+                    .help("Show files owned by this uid in the source as owned by another, can be repeated")
+                    .validator(id_pair_validator),
+                value: &mut flags.map_uid,
+            },
+            flags::Flag {
+                arg: Arg::with_name("map-gid")
+                    .long("map-gid")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("SRC:SHOWN")
+                    .help("Show files of this gid in the source as of another, can be repeated")
+                    .validator(id_pair_validator),
+                value: &mut flags.map_gid,
+            },
+            flags::Flag {
+                arg: Arg::with_name("squash-uid")
+                    .long("squash-uid")
+                    .takes_value(true)
+                    .value_name("UID")
+                    .help("Show files owned by any uid that's not in --map-uid as owned by this one")
+                    .validator(id_validator),
+                value: &mut flags.squash_uid,
+            },
+            flags::Flag {
+                arg: Arg::with_name("squash-gid")
+                    .long("squash-gid")
+                    .takes_value(true)
+                    .value_name("GID")
+                    .help("Show files of any gid that's not in --map-gid as of this one")
+                    .validator(id_validator),
+                value: &mut flags.squash_gid,
+            },
+            flags::Flag {
+                arg: Arg::with_name("allow_other")
+                    .long("allow-other")
+                    .conflicts_with("allow_root")
+                    .help("Allow other users to access the mount point."),
+                value: &mut flags.allow_other,
+            },
+            flags::Flag {
+                arg: Arg::with_name("allow_root")
+                    .long("allow-root")
+                    .help("Allow root to access the mount point."),
+                value: &mut flags.allow_root,
+            },
+            flags::Flag {
+                arg: Arg::with_name("permissions")
+                    .long("permissions")
+                    .takes_value(true)
+                    .possible_values(&["kernel", "fs"])
+                    .default_value("kernel")
+                    .help("Have the kernel check permissions against the mode bits, or let \
+                           catfs answer access() and the source decide the rest"),
+                value: &mut flags.permissions,
+            },
+            flags::Flag {
+                arg: Arg::with_name("nonempty")
+                    .long("nonempty")
+                    .help("Allow mounting over a non-empty directory, hiding its contents."),
+                value: &mut flags.nonempty,
+            },
             flags::Flag {
                 arg: Arg::with_name("option")
                     .short("o")
@@ -169,18 +789,28 @@ fn main_internal() -> error::Result<()> {
                 ),
                 value: &mut test,
             },
+            flags::Flag {
+                arg: Arg::with_name("completions")
+                    .long("completions")
+                    .takes_value(true)
+                    .possible_values(&["bash", "zsh", "fish"])
+                    .hidden(true)
+                    .help("Print shell completions and exit"),
+                value: &mut completions,
+            },
             flags::Flag {
                 arg: Arg::with_name("from")
                     .index(1)
-                    .required(true)
-                    .help("Cache files from this directory.")
-                    .validator(path_validator),
+                    .required_unless("completions")
+                    .help("Cache files from this directory, several merged as dirA:dirB, \
+                           or from an http:// (WebDAV) or sftp:// URL.")
+                    .validator(source_validator),
                 value: &mut flags.cat_from,
             },
             flags::Flag {
                 arg: Arg::with_name("to")
                     .index(2)
-                    .required(true)
+                    .required_unless("completions")
                     .help("Cache files to this directory.")
                     .validator(path_validator),
                 value: &mut flags.cat_to,
@@ -188,7 +818,7 @@ fn main_internal() -> error::Result<()> {
             flags::Flag {
                 arg: Arg::with_name("mountpoint")
                     .index(3)
-                    .required(true)
+                    .required_unless("completions")
                     .help("Expose the mount point at this directory.")
                     .validator(path_validator),
                 value: &mut flags.mount_point,
@@ -196,26 +826,128 @@ fn main_internal() -> error::Result<()> {
         ];
 
 
-        flags::parse_options(app, &mut args);
-    }
+        flags::parse_options(app, &mut args)
+    };
 
-    if test {
+    oplog::set_json(flags.log_format == "json");
+
+    apply_profile(&mut flags, &matches);
+
+    if !completions.is_empty() {
+        let shell = completions.parse::<Shell>().unwrap();
+        app = app.subcommands(subcommands());
+        app.gen_completions_to("catfs", shell, &mut io::stdout());
         return Ok(());
     }
 
-    if flags.gid != 0 {
-        rlibc::setgid(flags.gid)?;
-    }
-    if flags.uid != 0 {
-        rlibc::setuid(flags.uid)?;
+    if flags.watch_source &&
+        (backend::is_url(&flags.cat_from) || backend::layers::is_layers(&flags.cat_from))
+    {
+        return Err(error::RError::propagate(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--watch-source needs <from> to be a single local directory",
+        )));
     }
 
-    if !flags.foreground {
-        let daemonize = Daemonize::new()
-            .working_directory(env::current_dir()?.as_path())
-            ;
+    if flags.allow_other || flags.allow_root {
+        // fusermount refuses both options for non-root users unless
+        // fuse.conf says otherwise, and the resulting EPERM is
+        // anything but obvious
+        if unsafe { libc::geteuid() } != 0 && !user_allow_other(Path::new("/etc/fuse.conf"))? {
+            return Err(error::RError::propagate(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "--allow-other/--allow-root require user_allow_other in /etc/fuse.conf",
+            )));
+        }
 
-        match daemonize.start() {
+        flags.mount_options.push(OsString::from("-o"));
+        if flags.allow_other {
+            flags.mount_options.push(OsString::from("allow_other"));
+        } else {
+            flags.mount_options.push(OsString::from("allow_root"));
+        }
+    }
+
+    if flags.permissions == "kernel" {
+        flags.mount_options.push(OsString::from("-o"));
+        flags.mount_options.push(OsString::from("default_permissions"));
+    }
+
+    if flags.mount_options.contains(&OsString::from("nonempty")) {
+        flags.nonempty = true;
+    }
+
+    if fs::read_dir(&flags.mount_point)?.next().is_some() {
+        if !flags.nonempty {
+            return Err(error::RError::propagate(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{:?} is not empty, use --nonempty to mount over it anyway",
+                    flags.mount_point
+                ),
+            )));
+        }
+
+        warn!(
+            "{:?} is not empty, its contents will be hidden while mounted",
+            flags.mount_point
+        );
+        // macOS mounts over non-empty directories without being told
+        if !cfg!(target_os = "macos") &&
+            !flags.mount_options.contains(&OsString::from("nonempty"))
+        {
+            flags.mount_options.push(OsString::from("-o"));
+            flags.mount_options.push(OsString::from("nonempty"));
+        }
+    }
+
+    // caching would only copy files onto the disk they are already on
+    if !flags.no_cache && !backend::is_url(&flags.cat_from) &&
+        !backend::layers::is_layers(&flags.cat_from) &&
+        same_filesystem(Path::new(&flags.cat_from), Path::new(&flags.cat_to))?
+    {
+        if flags.cow || !flags.pin.is_empty() {
+            warn!(
+                "{:?} and {:?} are on the same filesystem",
+                flags.cat_from,
+                flags.cat_to
+            );
+        } else {
+            warn!(
+                "{:?} and {:?} are on the same filesystem, not caching",
+                flags.cat_from,
+                flags.cat_to
+            );
+            flags.no_cache = true;
+        }
+    }
+
+    if test {
+        return Ok(());
+    }
+
+    if flags.log_file.len() != 0 {
+        let max_size = match flags.log_file_size {
+            DiskSpace::Bytes(b) => b,
+            _ => 0,
+        };
+        let created = !Path::new(&flags.log_file).exists();
+        let log = logfile::LogFile::open(&flags.log_file, max_size, flags.log_file_count)?;
+        if created {
+            // so it can still be rotated once we drop_privileges
+            chown_to_user(Path::new(&flags.log_file), flags.uid, flags.gid)?;
+        }
+        unsafe {
+            LOGFILE = Some(log);
+        }
+    }
+
+    if !flags.foreground {
+        let daemonize = Daemonize::new()
+            .working_directory(env::current_dir()?.as_path())
+            ;
+
+        match daemonize.start() {
             Ok(_) => {
                 if let Ok(mut logger) = syslog::unix(Facility::LOG_USER) {
                     unsafe {
@@ -230,12 +962,256 @@ fn main_internal() -> error::Result<()> {
         }
     }
 
-    let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
-    let path_from = Path::new(&flags.cat_from).canonicalize()?;
+    let mut signal_actions = HashMap::new();
+    for (name, action) in catfs::flags::parse_signal_actions(&flags.signals).unwrap() {
+        signal_actions.insert(to_signal(&name).unwrap(), action);
+    }
+    // we signal ourselves with TERM once the fs is unmounted from
+    // outside, so always catch it
+    signal_actions.entry(Signal::TERM).or_insert(SignalAction::Ignore);
+    let signals: Vec<Signal> = signal_actions.keys().cloned().collect();
+    let signal = chan_signal::notify(&signals);
     let path_to = Path::new(&flags.cat_to).canonicalize()?;
-    let fs = catfs::CatFS::new(&path_from, &path_to)?;
-    let fs = pcatfs::PCatFS::new(fs);
-    let cache_dir = fs.get_cache_dir()?;
+    // other mounts may cache in the same directory, each under a
+    // prefix of its own
+    let cache_root = path_to.clone();
+    let path_to = if flags.cache_prefix.len() != 0 {
+        let path_to = path_to.join(&flags.cache_prefix);
+        make_prefix_dir(&path_to, flags.uid, flags.gid)?;
+        path_to
+    } else {
+        path_to
+    };
+    let mut hints: backend::SourceHints = if flags.profile == "goofys" {
+        backend::SourceHints::goofys()
+    } else {
+        Default::default()
+    };
+    hints.immutable = flags.immutable_src;
+    hints.validation = validation(&flags);
+    hints.retry = backend::retry::Retry::new(
+        flags.src_retries as u32,
+        std::time::Duration::from_millis(if flags.src_retry_delay == 0 {
+            backend::retry::DEFAULT_DELAY_MS
+        } else {
+            flags.src_retry_delay
+        }),
+    );
+    let (src, path_from) = if backend::is_url(&flags.cat_from) {
+        (
+            backend::from_url(&flags.cat_from, &path_to, hints)?,
+            Path::new(&flags.cat_from).to_path_buf(),
+        )
+    } else {
+        local_src(&flags.cat_from, hints)?
+    };
+    let src: Arc<dyn backend::SourceBackend> = if flags.src_retries != 0 {
+        Arc::new(backend::retry::RetryBackend::new(src))
+    } else {
+        src
+    };
+    let src: Arc<dyn backend::SourceBackend> = if flags.fetch_cmd.len() != 0 {
+        Arc::new(backend::fetch::FetchBackend::new(
+            src,
+            &flags.fetch_cmd,
+            path_from.as_os_str(),
+        ))
+    } else {
+        src
+    };
+    let src: Arc<dyn backend::SourceBackend> = if flags.peers.len() != 0 {
+        let peers: Vec<String> = flags.peers.split(',').map(String::from).collect();
+        Arc::new(backend::peer::PeerBackend::new(src, &peers, &path_to)?)
+    } else {
+        src
+    };
+    let perms = catfs::file::CachePerms {
+        mode: if flags.cache_mode.is_empty() {
+            None
+        } else {
+            Some(catfs::flags::parse_mode(&flags.cache_mode).unwrap())
+        },
+        umask: if flags.cache_umask.is_empty() {
+            0
+        } else {
+            catfs::flags::parse_mode(&flags.cache_umask).unwrap()
+        },
+        src_owner: flags.quota_per_uid != DiskSpace::Bytes(0) && unsafe { libc::geteuid() } == 0,
+    };
+    if flags.quota_per_uid != DiskSpace::Bytes(0) && !perms.src_owner {
+        warn!("--quota-per-uid needs root to tell whose cache files are whose, only counting ours");
+    }
+    let src: Arc<dyn backend::SourceBackend> = if flags.cow {
+        Arc::new(backend::cow::CowBackend::new(src, &path_to, perms)?)
+    } else {
+        src
+    };
+    let mut fs = catfs::CatFS::with_backend(src.clone(), &path_from, &path_to)?;
+    fs.set_ttl(time::Duration::seconds(flags.ttl as i64));
+    if flags.dir_cache_ttl != 0 {
+        fs.set_dir_cache_ttl(std::time::Duration::from_secs(flags.dir_cache_ttl));
+    }
+    if !flags.cache_valid_ttl.is_empty() {
+        fs.set_cache_valid_ttl(time::Duration::seconds(flags.cache_valid_ttl.parse().unwrap()));
+    }
+    if let DiskSpace::Bytes(readahead) = flags.readahead {
+        if readahead != 0 {
+            fs.set_readahead(readahead as usize);
+        }
+    }
+    fs.set_shared_cache(flags.shared_cache);
+    if flags.cache_odirect {
+        fs.set_cache_odirect();
+    }
+    if flags.no_splice {
+        fs.set_disable_splice();
+    }
+    if !flags.cache_layout.is_empty() {
+        fs.set_cache_layout(flags.cache_layout.parse().unwrap())?;
+    }
+    fs.set_cache_perms(perms);
+    if !flags.map_uid.is_empty() || !flags.map_gid.is_empty() || !flags.squash_uid.is_empty() ||
+        !flags.squash_gid.is_empty()
+    {
+        let pairs = |ids: &Vec<String>| -> Vec<(u32, u32)> {
+            ids.iter().map(|s| catfs::idmap::parse_pair(s).unwrap()).collect()
+        };
+        fs.set_id_map(catfs::idmap::IdMap::new(
+            pairs(&flags.map_uid),
+            flags.squash_uid.parse().ok(),
+            pairs(&flags.map_gid),
+            flags.squash_gid.parse().ok(),
+        ))?;
+    }
+    if !flags.file_mode.is_empty() || !flags.dir_mode.is_empty() || !flags.umask.is_empty() {
+        let mode = |s: &String| if s.is_empty() {
+            None
+        } else {
+            Some(catfs::flags::parse_mode(s).unwrap())
+        };
+        fs.set_mount_perms(catfs::file::MountPerms {
+            file_mode: mode(&flags.file_mode),
+            dir_mode: mode(&flags.dir_mode),
+            umask: mode(&flags.umask).unwrap_or(0),
+        })?;
+    }
+    if flags.cache_l2.len() != 0 {
+        let mut l2 = Path::new(&flags.cache_l2).canonicalize()?;
+        if flags.cache_prefix.len() != 0 {
+            l2.push(&flags.cache_prefix);
+            make_prefix_dir(&l2, flags.uid, flags.gid)?;
+        }
+        fs.set_cache_l2(&l2)?;
+    }
+    fs.set_conflict_policy(flags.on_conflict.parse().unwrap());
+    fs.set_cache_fill(flags.cache_fill.parse().unwrap());
+    if !flags.cache_compress.is_empty() {
+        fs.set_cache_compress(flags.cache_compress.parse().unwrap());
+    }
+    if flags.cache_encrypt.len() != 0 {
+        fs.set_cache_key(catfs::crypt::Key::load(Path::new(&flags.cache_encrypt))?);
+    }
+    if flags.cache_dedupe {
+        fs.set_cache_dedupe();
+    }
+    if !flags.statfs.is_empty() {
+        fs.set_statfs_from(flags.statfs.parse().unwrap());
+    }
+    rlibc::set_io_backend(flags.io_backend.parse().unwrap())?;
+    if let Some(ref delay) = flags.write_back {
+        let delay = if delay.is_empty() { 0 } else { delay.parse().unwrap() };
+        fs.set_write_back(std::time::Duration::from_secs(delay));
+    }
+    if flags.whiteout {
+        fs.set_whiteout()?;
+    }
+    if !flags.bypass.is_empty() {
+        fs.set_bypass(flags.bypass.parse().unwrap());
+    }
+    if let DiskSpace::Bytes(size) = flags.max_cacheable_size {
+        fs.set_max_cacheable_size(size);
+    }
+    if flags.no_cache {
+        fs.set_no_cache();
+    }
+    fs.set_cache_filter(
+        catfs::filter::CacheFilter::new(&flags.cache_include, &flags.cache_exclude).unwrap(),
+    );
+    if flags.mirror_to.len() != 0 {
+        fs.set_mirror(&Path::new(&flags.mirror_to).canonicalize()?);
+    }
+    if flags.control_socket.len() != 0 {
+        // we may be in another directory by the time it's created
+        fs.set_control_socket(&env::current_dir()?.join(&flags.control_socket));
+    }
+    let pagein_bandwidth = match flags.max_pagein_bandwidth {
+        DiskSpace::Bytes(b) => b,
+        _ => 0,
+    };
+    if pagein_bandwidth != 0 || flags.max_concurrent_pagein != 0 {
+        fs.set_page_in_limit(pagein_bandwidth, flags.max_concurrent_pagein as usize);
+    }
+    if flags.stats_file {
+        fs.set_stats_file();
+    }
+    if flags.watch_source {
+        fs.set_watch_source();
+    }
+    let cache_dir = if flags.cache_quota != DiskSpace::Bytes(0) {
+        // the evicter looks after everyone sharing the cache dir
+        rlibc::open(&cache_root, rlibc::O_RDONLY, 0)?
+    } else {
+        fs.get_cache_dir()?
+    };
+    if flags.serve_peers.len() != 0 {
+        let allow = backend::peer::parse_allow_list(&flags.peer_allow).unwrap();
+        let bandwidth = match flags.peer_bandwidth {
+            DiskSpace::Bytes(b) => b,
+            _ => 0,
+        };
+        let addr = backend::peer::PeerServer::new(fs.get_cache_dir()?, allow, bandwidth)
+            .start(&flags.serve_peers)?;
+        info!("serving peers on {}", addr);
+    }
+    // the evicter is created early so the filesystem can adjust its
+    // free space target at runtime
+    let mut ev = evicter::Evicter::new(cache_dir, &flags.free_space);
+    ev.set_src(src.clone());
+    configure_evicter(&mut ev, &flags);
+    if flags.cache_prefix.len() != 0 {
+        ev.set_prefix(Path::new(&flags.cache_prefix));
+    }
+    // l2 makes room for what's demoted to it by --free alone, the
+    // other limits are for the cache dir
+    let cache_l2 = fs.get_cache_l2()?;
+    let mut l2_ev = match cache_l2 {
+        Some(l2) => {
+            ev.set_l2(l2, perms.dir_mode());
+            let mut l2_ev = evicter::Evicter::new(l2, &flags.free_space);
+            l2_ev.set_src(src);
+            l2_ev.set_scan_interval(std::time::Duration::from_secs(flags.evict_scan_interval));
+            Some(l2_ev)
+        }
+        None => None,
+    };
+    if !flags.pin.is_empty() {
+        fs.set_pins(flags.pin.parse().unwrap());
+    }
+    fs.set_evicter(ev.handle());
+    let state = fs.state_dumper();
+    if flags.idle_handle_warn != 0 {
+        let state = state.clone();
+        let max_idle = std::time::Duration::from_secs(flags.idle_handle_warn);
+        thread::spawn(move || loop {
+            thread::sleep(std::cmp::max(max_idle / 4, std::time::Duration::from_secs(1)));
+            state.warn_idle(max_idle);
+        });
+    }
+    let fs = if flags.threads != 0 {
+        pcatfs::PCatFS::with_threads(fs, flags.threads as usize)
+    } else {
+        pcatfs::PCatFS::new(fs)
+    };
     let mut options: Vec<&OsStr> = Vec::new();
     for i in 0..flags.mount_options.len() {
         options.push(&flags.mount_options[i]);
@@ -244,7 +1220,19 @@ fn main_internal() -> error::Result<()> {
     debug!("options are {:?}", flags.mount_options);
 
     {
-        let mut session = fuse::Session::new(fs, Path::new(&flags.mount_point), &options)?;
+        let gid = user_gid(flags.uid, flags.gid, &flags.groups);
+        let mut session = mount_as(flags.uid, gid, || {
+            fuse::Session::new(fs, Path::new(&flags.mount_point), &options)
+        })?;
+        // virtiofsd may need the privileges we are about to drop
+        let virtiofsd = if flags.virtiofs.len() != 0 {
+            Some(virtiofs::Virtiofsd::spawn(&flags.virtiofs, Path::new(&flags.mount_point))?)
+        } else {
+            None
+        };
+        // the mount and the src/cache fds are established, we don't
+        // need most of our privileges anymore
+        drop_privileges(flags.uid, gid, &flags.groups)?;
         let need_unmount = Arc::new(Mutex::new(true));
         let need_unmount2 = need_unmount.clone();
         thread::spawn(move || {
@@ -257,70 +1245,927 @@ fn main_internal() -> error::Result<()> {
             unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
         });
 
-        let mut ev = evicter::Evicter::new(cache_dir, &flags.free_space);
+        let ev_handle = ev.handle();
         ev.run();
+        if let Some(ref mut l2_ev) = l2_ev {
+            l2_ev.run();
+        }
         // unmount after we get signaled becausep session will go out of scope
-        let s = signal.recv().unwrap();
+        let mut unmounting = false;
+        loop {
+            let s = signal.recv().unwrap();
+            if !*need_unmount.lock().unwrap() {
+                break;
+            }
+            let mut force = false;
+            match signal_actions[&s] {
+                SignalAction::Ignore => continue,
+                SignalAction::Dump => {
+                    state.dump();
+                    continue;
+                }
+                SignalAction::Evict => {
+                    info!("Received {:?}, evicting", s);
+                    ev_handle.evict_now();
+                    continue;
+                }
+                SignalAction::Latency => {
+                    state.dump_latencies();
+                    continue;
+                }
+                SignalAction::DumpAndUnmount => state.dump(),
+                SignalAction::Unmount => force = unmounting,
+                SignalAction::ForceUnmount => force = true,
+            }
+            info!(
+                "Received {:?}, attempting to {}unmount {:?}",
+                s,
+                if force { "force " } else { "" },
+                flags.mount_point
+            );
+            unmounting = true;
+            if let Some(ref virtiofsd) = virtiofsd {
+                virtiofsd.stop();
+            }
+            match mount::unmount(Path::new(&flags.mount_point), force) {
+                Ok(_) => break,
+                // the mount is probably busy, wait for the next signal
+                Err(e) => error!("unable to unmount {:?}: {}", flags.mount_point, e),
+            }
+        }
+    }
+    rlibc::close(cache_dir)?;
+    if let Some(l2) = cache_l2 {
+        rlibc::close(l2)?;
+    }
+    return Ok(());
+}
+
+fn diskspace_validator(s: String) -> Result<(), String> {
+    DiskSpace::from_str(&s).map(|_| ()).map_err(
+        |e| e.to_str().to_owned(),
+    )
+}
+
+// import and check take it too since it changes --validate
+fn profile_arg<'a>() -> Arg<'a, 'a> {
+    return Arg::with_name("profile")
+        .long("profile")
+        .takes_value(true)
+        .possible_values(&["s3", "goofys", "nfs", "local"])
+        .help("The --profile the cache was mounted with, for its --validate");
+}
+
+// explicit flags win over the profile. goofys needs more than the s3
+// defaults, so it gets its own even if it's not asked for, see
+// SourceHints::goofys
+fn apply_profile(flags: &mut FlagStorage, matches: &clap::ArgMatches) {
+    if flags.profile.is_empty() && !backend::is_url(&flags.cat_from) &&
+        backend::is_goofys(&flags.cat_from)
+    {
+        info!("{:?} is on goofys, using --profile goofys", flags.cat_from);
+        flags.profile = String::from("goofys");
+    }
+
+    if !flags.profile.is_empty() {
+        let profile: catfs::flags::Profile = flags.profile.parse().unwrap();
+        profile.apply(flags, &|name| matches.is_present(name));
+    }
+}
+
+// from --validate and --validate-xattrs, which have to be the same
+// everywhere the cache dir is used or files would look changed
+fn validation(flags: &FlagStorage) -> backend::Validation {
+    let mut v: backend::Validation = if flags.validate.is_empty() {
+        Default::default()
+    } else {
+        flags.validate.parse().unwrap()
+    };
+    if !flags.validate_xattrs.is_empty() {
+        let xattrs = flags.validate_xattrs.split(',').map(|x| x.trim().to_owned());
+        v.version_xattrs = Some(Arc::new(xattrs.collect()));
+    }
+    return v;
+}
+
+// the flags that validation() reads, see there for why everything
+// that opens the cache dir takes them
+fn validation_arg<'a>(name: &'a str) -> Arg<'a, 'a> {
+    let arg = Arg::with_name(name).long(name).takes_value(true);
+    return match name {
+        "validate" => {
+            arg.help("What tells a cached file is still current, any of etag, mtime and \
+                      size, or none to trust cached files (default: etag,mtime,size)")
+                .validator(validation_validator)
+        }
+        "validate-xattrs" => {
+            arg.value_name("XATTRS")
+                .help("The xattrs that --validate etag looks at (default: s3.etag,\
+                       user.gcsfuse.generation,user.rclone.md5,user.rclone.sha1)")
+        }
+        _ => panic!("{} isn't a validation flag", name),
+    };
+}
+
+fn validation_validator(s: String) -> Result<(), String> {
+    return s.parse::<backend::Validation>().map(|_| ());
+}
+
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    return Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev());
+}
+
+// a directory name, not a path
+fn prefix_validator(s: String) -> Result<(), String> {
+    let mut c = Path::new(&s).components();
+    match (c.next(), c.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(String::from("expected a directory name")),
+    }
+}
+
+fn size_validator(s: String) -> Result<(), String> {
+    match DiskSpace::from_str(&s) {
+        Ok(DiskSpace::Bytes(_)) => Ok(()),
+        Ok(DiskSpace::Percent(_)) => Err(String::from("expected a size, not a percentage")),
+        Err(e) => Err(e.to_str().to_owned()),
+    }
+}
+
+fn to_signal(name: &str) -> Result<Signal, String> {
+    return match name {
+        "HUP" => Ok(Signal::HUP),
+        "INT" => Ok(Signal::INT),
+        "QUIT" => Ok(Signal::QUIT),
+        "TERM" => Ok(Signal::TERM),
+        "USR1" => Ok(Signal::USR1),
+        "USR2" => Ok(Signal::USR2),
+        _ => Err(format!("unsupported signal {}", name)),
+    };
+}
+
+fn signals_validator(s: String) -> Result<(), String> {
+    for (name, _) in catfs::flags::parse_signal_actions(&s)? {
+        to_signal(&name)?;
+    }
+    return Ok(());
+}
+
+fn mode_validator(s: String) -> Result<(), String> {
+    return catfs::flags::parse_mode(&s).map(|_| ());
+}
+
+fn bypass_validator(s: String) -> Result<(), String> {
+    return s.parse::<catfs::bypass::Bypass>().map(|_| ());
+}
+
+fn id_pair_validator(s: String) -> Result<(), String> {
+    return catfs::idmap::parse_pair(&s).map(|_| ());
+}
+
+fn id_validator(s: String) -> Result<(), String> {
+    s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn pin_validator(s: String) -> Result<(), String> {
+    return s.parse::<catfs::pin::Pins>().map(|_| ());
+}
+
+fn allow_list_validator(s: String) -> Result<(), String> {
+    return backend::peer::parse_allow_list(&s).map(|_| ());
+}
+
+fn seconds_validator(s: String) -> Result<(), String> {
+    s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn count_validator(s: String) -> Result<(), String> {
+    s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn readahead_validator(s: String) -> Result<(), String> {
+    match DiskSpace::from_str(&s) {
+        Ok(DiskSpace::Bytes(n)) if n > catfs::file::MAX_READAHEAD as u64 => {
+            Err(String::from("must be at most 64M"))
+        }
+        _ => size_validator(s),
+    }
+}
+
+fn positive_validator(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("must be more than 0".to_owned()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn percent_validator(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(p) if p > 100 => Err("must be at most 100".to_owned()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn source_validator(s: String) -> Result<(), String> {
+    if backend::is_url(OsStr::new(&s)) {
+        return backend::from_url(OsStr::new(&s), &"/", Default::default())
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+    if backend::layers::is_layers(OsStr::new(&s)) {
+        for dir in backend::layers::split_layers(OsStr::new(&s)) {
+            path_validator(dir.to_string_lossy().into_owned())
+                .map_err(|e| format!("{:?}: {}", dir, e))?;
+        }
+        return Ok(());
+    }
+    return path_validator(s);
+}
+
+fn path_validator(s: String) -> Result<(), String> {
+    Path::new(&s)
+        .canonicalize()
+        .map_err(|e| e.to_string().to_owned())
+        .and_then(|p| if p.is_dir() {
+            Ok(())
+        } else {
+            Err("is not a directory".to_owned())
+        })
+}
+
+// the flags that catfs and catfs evict share, see configure_evicter
+fn evict_arg<'a>(name: &'a str) -> Arg<'a, 'a> {
+    let arg = Arg::with_name(name).takes_value(true);
+    return match name {
+        "space" => {
+            arg.long("free")
+                .help("Ensure filesystem has at least this much free space. (ex: 9.5%, 10G)")
+                .validator(diskspace_validator)
+        }
+        "max-cache-size" => {
+            arg.long(name)
+                .help("Keep the cache from taking up more than this. (ex: 50G, 20%)")
+                .validator(diskspace_validator)
+        }
+        "cache-quota" => {
+            arg.long(name)
+                .help("Evict from any prefix of the cache directory that takes up more than \
+                       this (ex: 50G, 20%)")
+                .validator(diskspace_validator)
+        }
+        "quota-per-uid" => {
+            arg.long(name)
+                .help("Evict the files of any uid that has more than this cached first \
+                       (ex: 10G)")
+                .validator(size_validator)
+        }
+        "eviction-policy" => {
+            arg.long(name)
+                .possible_values(&["cost", "lru", "lfu", "fifo", "size"])
+                .help("Which cache files to evict first (default: cost)")
+        }
+        "evict-scan-interval" => {
+            arg.long(name)
+                .value_name("SECS")
+                .help("How often to check if anything needs to be evicted (default: 60)")
+                .validator(positive_validator)
+        }
+        "evict-low-watermark" => {
+            arg.long(name)
+                .help("Once evicting, keep going until there is this much free space \
+                       (default: 10% more than --free)")
+                .validator(diskspace_validator)
+        }
+        "evict-hot-percent" => {
+            arg.long(name)
+                .help("Never evict this percentage of the most recently used files with \
+                       the cost policy (default: 25)")
+                .validator(percent_validator)
+        }
+        "evict-request-weight" => {
+            arg.long(name)
+                .value_name("BYTES")
+                .help("How much fetching a file costs on top of its size with the cost \
+                       policy (default: 107374)")
+                .validator(size_validator)
+        }
+        "pin" => {
+            arg.long(name)
+                .value_name("GLOBS")
+                .help("Never evict files matching these globs (ex: models/*.bin,indices)")
+                .validator(pin_validator)
+        }
+        _ => panic!("{} isn't an evicter flag", name),
+    };
+}
+
+// what the evicter uses when its flags aren't given
+fn set_evict_defaults(flags: &mut FlagStorage) {
+    flags.evict_scan_interval = evicter::DEFAULT_SCAN_SECS;
+    flags.evict_hot_percent = evicter::policy::DEFAULT_HOT_PERCENT as u64;
+    flags.evict_request_weight = DiskSpace::Bytes(evicter::policy::DEFAULT_REQUEST_WEIGHT);
+}
+
+// from the flags that catfs and catfs evict share
+fn configure_evicter(ev: &mut evicter::Evicter, flags: &FlagStorage) {
+    ev.set_max_size(&flags.max_cache_size);
+    ev.set_quota(&flags.cache_quota);
+    if let DiskSpace::Bytes(quota) = flags.quota_per_uid {
+        ev.set_quota_per_uid(quota);
+    }
+    ev.set_scan_interval(std::time::Duration::from_secs(flags.evict_scan_interval));
+    if flags.evict_low_watermark != DiskSpace::Bytes(0) {
+        ev.set_low_watermark(&flags.evict_low_watermark);
+    }
+    if flags.eviction_policy.is_empty() || flags.eviction_policy == "cost" {
+        let request_weight = match flags.evict_request_weight {
+            DiskSpace::Bytes(b) => b,
+            _ => evicter::policy::DEFAULT_REQUEST_WEIGHT,
+        };
+        ev.set_policy(Box::new(evicter::policy::Cost::new(
+            flags.evict_hot_percent as usize,
+            request_weight,
+        )));
+    } else {
+        ev.set_policy(evicter::policy::parse(&flags.eviction_policy).unwrap());
+    }
+    if !flags.pin.is_empty() {
+        ev.set_pins(flags.pin.parse().unwrap());
+    }
+}
+
+// main() hands catfs evict and the others their own argv before clap
+// sees it, so they are only subcommands for --completions
+fn subcommands() -> Vec<App<'static, 'static>> {
+    let mut flags: FlagStorage = Default::default();
+    let mut b = false;
+    let mut s = OsString::new();
+
+    let mut apps = Vec::new();
+    {
+        let (app, args) = evict_app(&mut flags, &mut b);
+        apps.push(flags::with_flags(app, &args));
+    }
+    {
+        let (app, args) = export_app(&mut flags, &mut b, &mut s);
+        apps.push(flags::with_flags(app, &args));
+    }
+    {
+        let (app, args) = import_app(&mut flags, &mut b, &mut s);
+        apps.push(flags::with_flags(app, &args));
+    }
+    {
+        let (app, args) = check_app(&mut flags, &mut b);
+        apps.push(flags::with_flags(app, &args));
+    }
+    return apps;
+}
+
+fn evict_app<'a>(
+    flags: &'a mut FlagStorage,
+    once: &'a mut bool,
+) -> (App<'static, 'static>, Vec<flags::Flag<'static, 'a>>) {
+    let app = App::new("evict")
+        .bin_name("catfs evict")
+        .about("Evict files from a catfs cache directory without mounting")
+        .version(crate_version!());
+    let args = vec![
+        flags::Flag {
+            arg: Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .required(true)
+                .help("Cache directory to evict from.")
+                .validator(path_validator),
+            value: &mut flags.cat_to,
+        },
+        flags::Flag {
+            arg: evict_arg("space")
+                .required_unless_one(&["max-cache-size", "cache-quota", "quota-per-uid"]),
+            value: &mut flags.free_space,
+        },
+        flags::Flag {
+            arg: evict_arg("max-cache-size"),
+            value: &mut flags.max_cache_size,
+        },
+        flags::Flag {
+            arg: evict_arg("cache-quota"),
+            value: &mut flags.cache_quota,
+        },
+        flags::Flag {
+            arg: evict_arg("quota-per-uid"),
+            value: &mut flags.quota_per_uid,
+        },
+        flags::Flag {
+            arg: evict_arg("eviction-policy"),
+            value: &mut flags.eviction_policy,
+        },
+        flags::Flag {
+            arg: evict_arg("evict-scan-interval"),
+            value: &mut flags.evict_scan_interval,
+        },
+        flags::Flag {
+            arg: evict_arg("evict-low-watermark"),
+            value: &mut flags.evict_low_watermark,
+        },
+        flags::Flag {
+            arg: evict_arg("evict-hot-percent"),
+            value: &mut flags.evict_hot_percent,
+        },
+        flags::Flag {
+            arg: evict_arg("evict-request-weight"),
+            value: &mut flags.evict_request_weight,
+        },
+        flags::Flag {
+            arg: evict_arg("pin"),
+            value: &mut flags.pin,
+        },
+        flags::Flag {
+            arg: Arg::with_name("once").long("once").help(
+                "Evict once and exit instead of running until signaled",
+            ),
+            value: once,
+        },
+    ];
+    return (app, args);
+}
+
+// catfs evict --cache <dir> --free <space> [--once]
+//
+// runs the evicter against a cache directory without mounting
+// anything, useful to clean up after instances that are no longer
+// running
+fn evict_main() -> error::Result<()> {
+    let mut flags: FlagStorage = Default::default();
+    let mut once = false;
+    set_evict_defaults(&mut flags);
+
+    {
+        let (app, mut args) = evict_app(&mut flags, &mut once);
+
+        let mut argv = env::args_os().collect::<Vec<OsString>>();
+        argv.remove(1);
+        flags::parse_options_from(app, &mut args, argv);
+    }
+
+    let cache_dir = rlibc::open(&Path::new(&flags.cat_to).canonicalize()?, rlibc::O_RDONLY, 0)?;
+    let mut ev = evicter::Evicter::new(cache_dir, &flags.free_space);
+    configure_evicter(&mut ev, &flags);
+
+    if once {
+        let res = ev.loop_once();
+        drop(ev);
+        rlibc::close(cache_dir)?;
+        return res;
+    }
+
+    let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
+    ev.run();
+    let s = signal.recv().unwrap();
+    info!("Received {:?}, stopping evicter", s);
+    drop(ev);
+    rlibc::close(cache_dir)?;
+    return Ok(());
+}
+
+fn export_app<'a>(
+    flags: &'a mut FlagStorage,
+    tar: &'a mut bool,
+    dest: &'a mut OsString,
+) -> (App<'static, 'static>, Vec<flags::Flag<'static, 'a>>) {
+    let app = App::new("export")
+        .bin_name("catfs export")
+        .about("Copy the cached files that are still valid out of a catfs cache directory")
+        .version(crate_version!());
+    let args = vec![
+        flags::Flag {
+            arg: Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .required(true)
+                .help("Cache directory to export.")
+                .validator(path_validator),
+            value: &mut flags.cat_to,
+        },
+        flags::Flag {
+            arg: Arg::with_name("tar").long("tar").help(
+                "Write a tar archive (- for stdout) instead of copying to a directory",
+            ),
+            value: tar,
+        },
+        flags::Flag {
+            arg: Arg::with_name("dest")
+                .index(1)
+                .required(true)
+                .help("Directory to copy the files and manifest to, or the archive with --tar."),
+            value: dest,
+        },
+    ];
+    return (app, args);
+}
+
+// catfs export --cache <dir> [--tar] <dest>
+//
+// copies the files in a cache directory that are still good to dest,
+// so a warm cache can be shipped to another machine
+fn export_main() -> error::Result<()> {
+    let mut flags: FlagStorage = Default::default();
+    let mut tar = false;
+    let mut dest = OsString::new();
+
+    {
+        let (app, mut args) = export_app(&mut flags, &mut tar, &mut dest);
+
+        let mut argv = env::args_os().collect::<Vec<OsString>>();
+        argv.remove(1);
+        flags::parse_options_from(app, &mut args, argv);
+    }
+
+    let cache_dir = rlibc::open(&Path::new(&flags.cat_to).canonicalize()?, rlibc::O_RDONLY, 0)?;
+    let res = export::export(cache_dir, Path::new(&dest), tar);
+    rlibc::close(cache_dir)?;
+    info!("exported {} files", res?);
+    return Ok(());
+}
+
+fn import_app<'a>(
+    flags: &'a mut FlagStorage,
+    tar: &'a mut bool,
+    snapshot: &'a mut OsString,
+) -> (App<'static, 'static>, Vec<flags::Flag<'static, 'a>>) {
+    let app = App::new("import")
+        .bin_name("catfs import")
+        .about("Copy the files that are still valid from another catfs cache into a cache directory")
+        .version(crate_version!());
+    let args = vec![
+        flags::Flag {
+            arg: Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .required(true)
+                .help("Cache directory to import into.")
+                .validator(path_validator),
+            value: &mut flags.cat_to,
+        },
+        flags::Flag {
+            arg: Arg::with_name("tar").long("tar").help(
+                "Read a tar archive (- for stdin) instead of a directory",
+            ),
+            value: tar,
+        },
+        flags::Flag {
+            arg: profile_arg(),
+            value: &mut flags.profile,
+        },
+        flags::Flag {
+            arg: validation_arg("validate"),
+            value: &mut flags.validate,
+        },
+        flags::Flag {
+            arg: validation_arg("validate-xattrs"),
+            value: &mut flags.validate_xattrs,
+        },
+        flags::Flag {
+            arg: Arg::with_name("from")
+                .index(1)
+                .required(true)
+                .help("Directory or URL the cache is for, files are checked against it.")
+                .validator(source_validator),
+            value: &mut flags.cat_from,
+        },
+        flags::Flag {
+            arg: Arg::with_name("snapshot")
+                .index(2)
+                .required(true)
+                .help("Cache directory or catfs export output to import from."),
+            value: snapshot,
+        },
+    ];
+    return (app, args);
+}
+
+// catfs import --cache <dir> [--tar] <from> <snapshot>
+//
+// copies files from another cache dir, or what catfs export made,
+// into a cache dir. Files that no longer match <from> are skipped
+fn import_main() -> error::Result<()> {
+    let mut flags: FlagStorage = Default::default();
+    let mut tar = false;
+    let mut snapshot = OsString::new();
+
+    let matches = {
+        let (app, mut args) = import_app(&mut flags, &mut tar, &mut snapshot);
+
+        let mut argv = env::args_os().collect::<Vec<OsString>>();
+        argv.remove(1);
+        flags::parse_options_from(app, &mut args, argv).1
+    };
+    apply_profile(&mut flags, &matches);
+
+    let path_to = Path::new(&flags.cat_to).canonicalize()?;
+    let src = cache_src(&flags, &path_to)?;
+
+    let cache_dir = rlibc::open(&path_to, rlibc::O_RDONLY, 0)?;
+    let res = import::Importer::new(&*src, cache_dir).and_then(|mut importer| {
+        let res = if !tar {
+            importer.import_dir(Path::new(&snapshot))
+        } else if snapshot == OsStr::new("-") {
+            importer.import_tar(&mut io::stdin())
+        } else {
+            match File::open(&snapshot) {
+                Ok(mut f) => importer.import_tar(&mut f),
+                Err(e) => Err(e.into()),
+            }
+        };
+        let stats = importer.stats();
         info!(
-            "Received {:?}, attempting to unmount {:?}",
-            s,
-            flags.mount_point
+            "imported {} files, {} stale, {} already cached",
+            stats.imported,
+            stats.stale,
+            stats.existing
         );
-        let need_unmount = need_unmount.lock().unwrap();
-        if *need_unmount {
-            unmount(Path::new(&flags.mount_point))?;
+        res
+    });
+    rlibc::close(cache_dir)?;
+    return res;
+}
+
+// the src a cache dir is checked against outside of a mount, set up
+// so its checksums come out the same as they do in one
+fn cache_src(flags: &FlagStorage, path_to: &Path) -> error::Result<Arc<dyn backend::SourceBackend>> {
+    let mut hints: backend::SourceHints = Default::default();
+    hints.validation = validation(&flags);
+    if backend::is_url(&flags.cat_from) {
+        return Ok(backend::from_url(&flags.cat_from, &path_to, hints)?);
+    }
+    // the checksums depend on it
+    if backend::is_goofys(&flags.cat_from) {
+        hints = backend::SourceHints {
+            validation: hints.validation,
+            ..backend::SourceHints::goofys()
+        };
+    }
+    return Ok(local_src(&flags.cat_from, hints)?.0);
+}
+
+// <from> as a local directory, or several of them merged into one.
+// Also returns the directory that's written to
+fn local_src(
+    from: &OsStr,
+    hints: backend::SourceHints,
+) -> io::Result<(Arc<dyn backend::SourceBackend>, PathBuf)> {
+    if backend::layers::is_layers(from) {
+        let dirs = backend::layers::split_layers(from);
+        let mut layers: Vec<Arc<dyn backend::SourceBackend>> = Vec::new();
+        for dir in &dirs {
+            let mut src = backend::LocalBackend::new(&dir.canonicalize()?)?;
+            src.set_hints(hints.clone());
+            layers.push(Arc::new(src));
         }
+        let path_from = dirs[0].canonicalize()?;
+        return Ok((Arc::new(backend::layers::UnionBackend::new(layers)), path_from));
     }
+
+    let path_from = Path::new(from).canonicalize()?;
+    let mut src = backend::LocalBackend::new(&path_from)?;
+    src.set_hints(hints);
+    return Ok((Arc::new(src), path_from));
+}
+
+fn check_app<'a>(
+    flags: &'a mut FlagStorage,
+    delete: &'a mut bool,
+) -> (App<'static, 'static>, Vec<flags::Flag<'static, 'a>>) {
+    let app = App::new("check")
+        .bin_name("catfs check")
+        .about("Find the files in a catfs cache directory that no longer match the source")
+        .version(crate_version!());
+    let args = vec![
+        flags::Flag {
+            arg: Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .required(true)
+                .help("Cache directory to check.")
+                .validator(path_validator),
+            value: &mut flags.cat_to,
+        },
+        flags::Flag {
+            arg: Arg::with_name("delete").long("delete").help(
+                "Delete stale, orphaned and corrupt files instead of only listing them",
+            ),
+            value: delete,
+        },
+        flags::Flag {
+            arg: profile_arg(),
+            value: &mut flags.profile,
+        },
+        flags::Flag {
+            arg: validation_arg("validate"),
+            value: &mut flags.validate,
+        },
+        flags::Flag {
+            arg: validation_arg("validate-xattrs"),
+            value: &mut flags.validate_xattrs,
+        },
+        flags::Flag {
+            arg: Arg::with_name("from")
+                .index(1)
+                .required(true)
+                .help("Directory or URL the cache is for, files are checked against it.")
+                .validator(source_validator),
+            value: &mut flags.cat_from,
+        },
+    ];
+    return (app, args);
+}
+
+// catfs check --cache <dir> [--delete] <from>
+//
+// checks every file in a cache dir against <from>, for after a crash
+// or when the cache dir was changed by hand. Exits with 1 if anything
+// that's no good was left behind
+fn check_main() -> error::Result<()> {
+    let mut flags: FlagStorage = Default::default();
+    let mut delete = false;
+
+    let matches = {
+        let (app, mut args) = check_app(&mut flags, &mut delete);
+
+        let mut argv = env::args_os().collect::<Vec<OsString>>();
+        argv.remove(1);
+        flags::parse_options_from(app, &mut args, argv).1
+    };
+    apply_profile(&mut flags, &matches);
+
+    let path_to = Path::new(&flags.cat_to).canonicalize()?;
+    let src = cache_src(&flags, &path_to)?;
+
+    let cache_dir = rlibc::open(&path_to, rlibc::O_RDONLY, 0)?;
+    let res = check::Checker::new(&*src, cache_dir, delete).and_then(|mut checker| {
+        let res = checker.check();
+        let stats = checker.stats();
+        info!(
+            "checked {} files: {} valid, {} stale, {} orphaned, {} corrupt, {} not in {:?} yet, {} deleted",
+            stats.valid + stats.problems() + stats.dirty,
+            stats.valid,
+            stats.stale,
+            stats.orphaned,
+            stats.corrupt,
+            stats.dirty,
+            flags.cat_from,
+            stats.deleted
+        );
+        res.map(|_| stats.problems() - stats.deleted)
+    });
     rlibc::close(cache_dir)?;
+    if res? != 0 {
+        process::exit(1);
+    }
     return Ok(());
 }
 
-use libc::{c_char, c_int};
-use std::ffi::{CString, CStr};
-/// Unmount an arbitrary mount point
-pub fn unmount(mountpoint: &Path) -> io::Result<()> {
-    // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
-    // it attempts to call realpath, which in turn calls into the filesystem. So
-    // if the filesystem returns an error, the unmount does not take place, with
-    // no indication of the error available to the caller. So we call unmount
-    // directly, which is what osxfuse does anyway, since we already converted
-    // to the real path when we first mounted.
-
-    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
-                target_os = "openbsd", target_os = "bitrig", target_os = "netbsd"))]
-    #[inline]
-    fn libc_umount(mnt: &CStr) -> c_int {
-        unsafe { libc::unmount(mnt.as_ptr(), 0) }
-    }
-
-    #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
-                      target_os = "openbsd", target_os = "bitrig", target_os = "netbsd")))]
-    #[inline]
-    fn libc_umount(mnt: &CStr) -> c_int {
-        use std::io::ErrorKind::PermissionDenied;
-
-        let rc = unsafe { libc::umount(mnt.as_ptr()) };
-        if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
-            // Linux always returns EPERM for non-root users.  We have to let the
-            // library go through the setuid-root "fusermount -u" to unmount.
-            unsafe {
-                fuse_unmount_compat22(mnt.as_ptr());
+// the --cache-prefix dir is made before drop_privileges, so it's
+// handed to the user we'll run as or they couldn't cache anything
+fn make_prefix_dir(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    if let Err(e) = fs::create_dir(path) {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            return Ok(());
+        }
+        return Err(e);
+    }
+    return chown_to_user(path, uid, gid);
+}
+
+// for what we create as root before drop_privileges
+fn chown_to_user(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 || (uid == 0 && gid == 0) {
+        return Ok(());
+    }
+    // -1 leaves it alone
+    let uid = if uid == 0 { !0 } else { uid };
+    let gid = if gid == 0 { !0 } else { gid };
+    return rlibc::fchownat(libc::AT_FDCWD, &path, uid, gid, 0);
+}
+
+// --groups user without --gid runs as the uid's own group
+fn user_gid(uid: libc::uid_t, gid: libc::gid_t, groups: &str) -> libc::gid_t {
+    if gid == 0 && uid != 0 && groups == "user" {
+        if let Ok((_, user_gid)) = rlibc::getpwuid(uid) {
+            return user_gid;
+        }
+    }
+    return gid;
+}
+
+// fuse makes the mount belong to our real uid and gid, and without
+// allow_other nobody else can use it. So when root mounts for --uid
+// this pretends to be that user for the mount, which also lets them
+// fusermount -u it once we drop_privileges
+fn mount_as<T, F>(uid: libc::uid_t, gid: libc::gid_t, mount: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    if unsafe { libc::geteuid() } != 0 {
+        return mount();
+    }
+
+    // -1 leaves the effective ids, and so our privileges, alone
+    rlibc::setregid(gid, !0)?;
+    if let Err(e) = rlibc::setreuid(uid, !0) {
+        rlibc::setregid(0, !0)?;
+        return Err(e);
+    }
+    let res = mount();
+    rlibc::setreuid(0, !0)?;
+    rlibc::setregid(0, !0)?;
+    return res;
+}
+
+fn drop_privileges(uid: libc::uid_t, gid: libc::gid_t, groups: &str) -> error::Result<()> {
+    let was_root = unsafe { libc::geteuid() } == 0;
+
+    if was_root && (uid != 0 || gid != 0) {
+        match groups {
+            "user" => {
+                // take on the groups of the user we are running as,
+                // otherwise we'd keep root's or have none at all
+                match rlibc::getpwuid(uid) {
+                    Ok((name, _)) => {
+                        rlibc::initgroups(&name, gid)?;
+                    }
+                    Err(e) => {
+                        warn!("uid {} has no passwd entry: {}", uid, e);
+                        rlibc::setgroups(&[gid])?;
+                    }
+                }
             }
-            0
-        } else {
-            rc
+            // don't carry root's supplementary groups over
+            "none" => rlibc::setgroups(&[])?,
+            _ => (),
         }
     }
 
-    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
-    let rc = libc_umount(&mnt);
-    if rc < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
+    if gid != 0 {
+        rlibc::setgid(gid)?;
+    }
+
+    // becoming someone else takes all of root's capabilities away, on
+    // every thread since libc does setuid on all of them. The mount is
+    // theirs so fusermount -u can still unmount it
+    if uid != 0 {
+        rlibc::setuid(uid)?;
+        debug!("dropped privileges, uid {} gid {}", uid, gid);
+        return Ok(());
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        if was_root {
+            // staying root, keep CAP_SYS_ADMIN to unmount and what
+            // lets us access files on behalf of other users
+            let caps = [
+                rlibc::CAP_SYS_ADMIN,
+                rlibc::CAP_CHOWN,
+                rlibc::CAP_DAC_OVERRIDE,
+                rlibc::CAP_DAC_READ_SEARCH,
+                rlibc::CAP_FOWNER,
+                rlibc::CAP_FSETID,
+                rlibc::CAP_MKNOD,
+            ];
+            // the fuse and page in workers are already running, this
+            // takes it from them too
+            rlibc::limit_caps(&caps)?;
+            debug!("dropped privileges, gid {}", gid);
+        }
     }
+    return Ok(());
 }
 
-extern "system" {
-    pub fn fuse_unmount_compat22(mountpoint: *const c_char);
+fn user_allow_other(conf: &Path) -> io::Result<bool> {
+    let mut contents = String::new();
+    match File::open(conf) {
+        Ok(mut f) => {
+            f.read_to_string(&mut contents)?;
+        }
+        Err(e) => {
+            if error::is_enoent(&e) {
+                return Ok(false);
+            } else {
+                return Err(e);
+            }
+        }
+    }
+
+    for line in contents.lines() {
+        // everything after # is a comment
+        let line = line.splitn(2, '#').next().unwrap().trim();
+        if line == "user_allow_other" {
+            return Ok(true);
+        }
+    }
+
+    return Ok(false);
 }
@@ -3,14 +3,19 @@ extern crate threadpool;
 extern crate time;
 
 use self::fuse::{Filesystem, Request, ReplyEntry, ReplyAttr, ReplyOpen, ReplyEmpty,
-                 ReplyDirectory, ReplyData, ReplyWrite, ReplyCreate, ReplyStatfs};
+                 ReplyDirectory, ReplyData, ReplyWrite, ReplyCreate, ReplyStatfs, ReplyXattr,
+                 ReplyLock};
 use self::threadpool::ThreadPool;
 use self::time::Timespec;
 
 use std::ffi::OsStr;
+use std::os::raw::c_int;
 use std::ops::Deref;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use catfs::CatFS;
+use catfs::oplog;
 
 pub struct PCatFS {
     tp: ThreadPool,
@@ -27,13 +32,37 @@ pub fn make_self<T>(s: &mut T) -> &'static mut T {
     return unsafe { ::std::mem::transmute(s) };
 }
 
+// how many requests are served at the same time
+pub const DEFAULT_THREADS: usize = 100;
+
 impl PCatFS {
     pub fn new(fs: CatFS) -> PCatFS {
+        return PCatFS::with_threads(fs, DEFAULT_THREADS);
+    }
+
+    pub fn with_threads(fs: CatFS, threads: usize) -> PCatFS {
         PCatFS {
-            tp: ThreadPool::new(100),
+            tp: ThreadPool::new(threads),
             fs: fs,
         }
     }
+
+    // serves a request on the threadpool, keeping track of how long
+    // it waited for a thread and how long it took after that
+    fn run<F>(&mut self, op: &'static str, f: F)
+    where
+        F: FnOnce(&mut CatFS) + Send + 'static,
+    {
+        let s = make_self(self);
+        let queued = Instant::now();
+        self.tp.execute(move || {
+            let started = Instant::now();
+            oplog::start();
+            f(&mut s.fs);
+            s.fs.latencies().add(op, started.duration_since(queued), started.elapsed());
+            debug!("queue size is {}", s.tp.queued_count());
+        });
+    }
 }
 
 impl Deref for PCatFS {
@@ -48,33 +77,26 @@ macro_rules! run_in_threadpool {
     ($( fn $name:ident(&mut self, _req: &Request, parent: u64, name: &OsStr, $($arg:ident : $argtype:ty),* $(,)*) $body:block )*) => (
         $(
             fn $name(&mut self, _req: &Request, parent: u64, name: &OsStr, $($arg : $argtype),*) {
-                let s = make_self(self);
                 let name = name.to_os_string();
-                self.tp.execute(
-                    move || {
-                        s.fs.$name(parent, name, $($arg),*);
-                        debug!("queue size is {}", s.tp.queued_count());
-                    }
-                );
+                self.run(stringify!($name), move |fs| fs.$name(parent, name, $($arg),*));
             }
         )*
     );
     ($( fn $name:ident(&mut self, _req: &Request, $($arg:ident : $argtype:ty),* $(,)*) $body:block )*) => (
         $(
             fn $name(&mut self, _req: &Request, $($arg : $argtype),*) {
-                let s = make_self(self);
-                self.tp.execute(
-                    move || {
-                        s.fs.$name($($arg),*);
-                        debug!("queue size is {}", s.tp.queued_count());
-                    }
-                );
+                self.run(stringify!($name), move |fs| fs.$name($($arg),*));
             }
         )*
     );
 }
 
 impl Filesystem for PCatFS {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        self.fs.init();
+        return Ok(());
+    }
+
     fn write(
         &mut self,
         _req: &Request,
@@ -85,11 +107,8 @@ impl Filesystem for PCatFS {
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let s = make_self(self);
         let data = data.to_vec();
-        self.tp.execute(move || {
-            s.fs.write(ino, fh, offset, data, _flags, reply);
-        });
+        self.run("write", move |fs| fs.write(ino, fh, offset, data, _flags, reply));
     }
 
 
@@ -102,16 +121,118 @@ impl Filesystem for PCatFS {
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        let s = make_self(self);
         let name = name.to_os_string();
         let newname = newname.to_os_string();
-        self.tp.execute(move || {
-            s.fs.rename(parent, name, newparent, newname, reply);
-        });
+        self.run("rename", move |fs| fs.rename(parent, name, newparent, newname, reply));
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let newname = newname.to_os_string();
+        self.run("link", move |fs| fs.link(ino, newparent, newname, reply));
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_os_string();
+        let link = link.to_path_buf();
+        let uid = req.uid();
+        let gid = req.gid();
+        self.run("symlink", move |fs| fs.symlink(parent, name, link, uid, gid, reply));
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let name = name.to_os_string();
+        let uid = req.uid();
+        let gid = req.gid();
+        self.run("mkdir", move |fs| fs.mkdir(parent, name, mode, uid, gid, reply));
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_os_string();
+        let uid = req.uid();
+        let gid = req.gid();
+        self.run("mknod", move |fs| fs.mknod(parent, name, mode, rdev, uid, gid, reply));
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_os_string();
+        let value = value.to_vec();
+        let uid = req.uid();
+        self.run("setxattr", move |fs| fs.setxattr(ino, name, value, flags, position, uid, reply));
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_os_string();
+        self.run("getxattr", move |fs| fs.getxattr(ino, name, size, reply));
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_os_string();
+        self.run("removexattr", move |fs| fs.removexattr(ino, name, reply));
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let pid = req.pid();
+        self.run("open", move |fs| fs.open(ino, flags, pid, reply));
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_os_string();
+        let pid = req.pid();
+        let uid = req.uid();
+        let gid = req.gid();
+        self.run("create", move |fs| fs.create(parent, name, mode, flags, pid, uid, gid, reply));
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let uid = req.uid();
+        let gid = req.gid();
+        self.run("access", move |fs| fs.access(ino, mask, uid, gid, reply));
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        let started = Instant::now();
+        oplog::start();
         self.fs.forget(ino, nlookup);
+        self.fs.latencies().add("forget", Duration::from_secs(0), started.elapsed());
     }
 
     run_in_threadpool!{
@@ -153,9 +274,6 @@ impl Filesystem for PCatFS {
         fn releasedir(&mut self, _req: &Request, _ino: u64, dh: u64, _flags: u32, reply: ReplyEmpty) {
         }
 
-        fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        }
-
         fn read(
             &mut self,
             _req: &Request,
@@ -184,31 +302,55 @@ impl Filesystem for PCatFS {
 
         fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
         }
-    }
 
-    run_in_threadpool!{
-        fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        }
+
+        fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        }
+
+        fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         }
 
-        fn create(
+        fn getlk(
             &mut self,
             _req: &Request,
-            parent: u64,
-            name: &OsStr,
-            mode: u32,
-            flags: u32,
-            reply: ReplyCreate,
+            ino: u64,
+            fh: u64,
+            lock_owner: u64,
+            start: u64,
+            end: u64,
+            typ: u32,
+            pid: u32,
+            reply: ReplyLock,
         ) {
         }
 
-        fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        fn setlk(
+            &mut self,
+            _req: &Request,
+            ino: u64,
+            fh: u64,
+            lock_owner: u64,
+            start: u64,
+            end: u64,
+            typ: u32,
+            pid: u32,
+            sleep: bool,
+            reply: ReplyEmpty,
+        ) {
         }
+    }
 
+    run_in_threadpool!{
+        fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        }
 
-        fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         }
 
-        fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+
+        fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         }
     }
 }
@@ -0,0 +1,76 @@
+extern crate libc;
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// serves a catfs mount to VMs with virtiofsd (vhost-user-fs), which
+// lets guests use the host's cache without a network filesystem. The
+// guest side is `-device vhost-user-fs-pci,chardev=...,tag=...` in
+// qemu
+pub struct Virtiofsd {
+    pid: libc::pid_t,
+    stopping: Arc<AtomicBool>,
+    exited: mpsc::Receiver<()>,
+}
+
+impl Virtiofsd {
+    // virtiofsd exits once the VM goes away, and then we are signaled
+    // to unmount since nothing else is using the mount
+    pub fn spawn(socket: &OsStr, shared_dir: &Path) -> io::Result<Virtiofsd> {
+        let mut cmd = Command::new("virtiofsd");
+        cmd.arg("--socket-path")
+            .arg(socket)
+            .arg("--shared-dir")
+            .arg(shared_dir)
+            .stdin(Stdio::null());
+        // the default sandbox needs root
+        if unsafe { libc::geteuid() } != 0 {
+            cmd.arg("--sandbox").arg("none");
+        }
+
+        let mut child = cmd.spawn()?;
+        let pid = child.id() as libc::pid_t;
+        info!("virtiofsd {} serving {:?} on {:?}", pid, shared_dir, socket);
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopping2 = stopping.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            match child.wait() {
+                Ok(status) => info!("virtiofsd {} exited: {}", pid, status),
+                Err(e) => error!("!wait(virtiofsd {}) = {}", pid, e),
+            }
+            let _ = tx.send(());
+            if !stopping2.load(Ordering::SeqCst) {
+                unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+            }
+        });
+
+        return Ok(Virtiofsd {
+            pid: pid,
+            stopping: stopping,
+            exited: rx,
+        });
+    }
+
+    // virtiofsd keeps the mount busy, so this has to happen before
+    // unmounting
+    pub fn stop(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) || self.exited.try_recv().is_ok() {
+            return;
+        }
+
+        debug!("<-- stop virtiofsd {}", self.pid);
+        unsafe { libc::kill(self.pid, libc::SIGTERM) };
+        if self.exited.recv_timeout(Duration::from_secs(5)).is_err() {
+            error!("virtiofsd {} is still running", self.pid);
+        }
+    }
+}
@@ -3,7 +3,6 @@
 extern crate log;
 extern crate libc;
 extern crate env_logger;
-extern crate fuse;
 extern crate time;
 extern crate xattr;
 
@@ -14,8 +13,12 @@ use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Seek, Write};
 use std::process::{Command, Stdio};
+use std::thread;
 use std::path::{Path, PathBuf};
-use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::os::unix::fs as unix_fs;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
 
 use env_logger::LogBuilder;
 use log::LogRecord;
@@ -23,12 +26,13 @@ use log::LogRecord;
 extern crate catfs;
 
 use catfs::CatFS;
+use catfs::catfs::backend;
+use catfs::catfs::backend::LocalBackend;
 use catfs::catfs::error;
 use catfs::catfs::flags::DiskSpace;
 use catfs::catfs::file;
 use catfs::catfs::rlibc;
-use catfs::evicter::Evicter;
-use catfs::pcatfs::PCatFS;
+use catfs::catfs::mount::Mount;
 
 #[macro_use]
 mod test_suite;
@@ -42,17 +46,16 @@ trait Fixture {
     fn teardown(self) -> error::Result<()>;
 }
 
-struct CatFSTests<'a> {
+struct CatFSTests {
     prefix: PathBuf,
     mnt: PathBuf,
     src: PathBuf,
     cache: PathBuf,
-    session: Option<fuse::BackgroundSession<'a>>,
-    evicter: Option<Evicter>,
-    nested: Option<Box<CatFSTests<'a>>>,
+    session: Option<Mount>,
+    nested: Option<Box<CatFSTests>>,
 }
 
-impl<'a> CatFSTests<'a> {
+impl CatFSTests {
     fn get_orig_dir() -> PathBuf {
         let manifest = env::var_os("CARGO_MANIFEST_DIR").unwrap();
         return PathBuf::from(manifest).join("tests/resources");
@@ -66,28 +69,31 @@ impl<'a> CatFSTests<'a> {
         return self.cache.clone();
     }
 
-    fn mount(&self) -> error::Result<(fuse::BackgroundSession<'a>, Evicter)> {
-        let fs = CatFS::new(&self.src, &self.cache)?;
-        let fs = PCatFS::new(fs);
+    fn get_control_socket(&self) -> PathBuf {
+        return self.cache.with_extension("ctl");
+    }
 
-        let cache_dir = fs.get_cache_dir()?;
-        // essentially no-op, but ensures that it starts and terminates
-        let ev = Evicter::new(cache_dir, &DiskSpace::Bytes(1));
-        return Ok((unsafe { fuse::spawn_mount(fs, &self.mnt, &[])? }, ev));
+    fn mount(&self) -> error::Result<Mount> {
+        let mut fs = CatFS::new(&self.src, &self.cache)?;
+        fs.set_control_socket(&self.get_control_socket());
+        fs.set_stats_file();
+        // essentially no-op evicter, but ensures that it starts and
+        // terminates
+        return fs.spawn_mount(&self.mnt, &[], &DiskSpace::Bytes(1));
     }
 
     fn assert_cache_valid(&self, path: &dyn AsRef<Path>) {
-        let src_dir = rlibc::open(&self.src, rlibc::O_RDONLY, 0).unwrap();
+        let src = LocalBackend::new(&self.src).unwrap();
         let cache_dir = rlibc::open(&self.cache, rlibc::O_RDONLY, 0).unwrap();
 
-        assert!(file::Handle::validate_cache(src_dir, cache_dir, path, false, true).unwrap());
-        rlibc::close(src_dir).unwrap();
+        let layout = file::CacheLayout::load(cache_dir).unwrap();
+        assert!(file::Handle::validate_cache(&src, cache_dir, layout, path, false, true).unwrap());
         rlibc::close(cache_dir).unwrap();
     }
 }
 
-impl<'a> Fixture for CatFSTests<'a> {
-    fn setup() -> error::Result<CatFSTests<'a>> {
+impl Fixture for CatFSTests {
+    fn setup() -> error::Result<CatFSTests> {
         let format = |record: &LogRecord| {
             let t = time::now();
             format!(
@@ -123,7 +129,6 @@ impl<'a> Fixture for CatFSTests<'a> {
             src: resources,
             cache: cache,
             session: Default::default(),
-            evicter: Default::default(),
             nested: Default::default(),
         };
 
@@ -148,7 +153,6 @@ impl<'a> Fixture for CatFSTests<'a> {
                     src: mnt,
                     cache: cache2,
                     session: Default::default(),
-                    evicter: Default::default(),
                     nested: Some(Box::new(t)),
                 };
 
@@ -164,24 +168,20 @@ impl<'a> Fixture for CatFSTests<'a> {
             t.init()?;
         }
 
-        let (session, ev) = self.mount()?;
-        self.session = Some(session);
-        self.evicter = Some(ev);
-        if let Some(ref mut ev) = self.evicter {
-            ev.run();
-        }
+        self.session = Some(self.mount()?);
         return Ok(());
     }
 
-    fn teardown(self) -> error::Result<()> {
-        {
-            // move out the session to let us umount first
-            std::mem::drop(self.session);
+    fn teardown(mut self) -> error::Result<()> {
+        if let Some(ref mut session) = self.session {
+            session.unmount(false)?;
         }
 
-        if let Some(t) = self.nested {
+        if let Some(mut t) = self.nested {
             // unmount the inner session
-            std::mem::drop(t.session);
+            if let Some(ref mut session) = t.session {
+                session.unmount(false)?;
+            }
         }
 
         fs::remove_dir_all(&self.prefix)?;
@@ -343,6 +343,90 @@ unit_tests!{
         fs::create_dir(&foo).unwrap();
     }
 
+    fn symlink(f: &CatFSTests) {
+        let link = f.mnt.join("dir1/link1");
+        unix_fs::symlink("file1", &link).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("file1"));
+        assert_eq!(fs::read_link(f.get_from().join("dir1/link1")).unwrap(), Path::new("file1"));
+        assert_eq!(fs::read_link(f.get_cache().join("dir1/link1")).unwrap(), Path::new("file1"));
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+
+        let mut s = String::new();
+        File::open(&link).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "dir1/file1\n");
+
+        // one that's already in the source, dangling
+        unix_fs::symlink("nowhere", f.get_from().join("link2")).unwrap();
+        assert_eq!(fs::read_link(f.mnt.join("link2")).unwrap(), Path::new("nowhere"));
+        assert!(fs::symlink_metadata(f.mnt.join("link2")).unwrap().file_type().is_symlink());
+
+        fs::remove_file(&link).unwrap();
+        assert!(fs::symlink_metadata(f.get_from().join("dir1/link1")).is_err());
+        assert!(fs::symlink_metadata(f.get_cache().join("dir1/link1")).is_err());
+    }
+
+    fn mknod(f: &CatFSTests) {
+        let fifo = f.mnt.join("dir1/fifo1");
+        rlibc::mknodat(libc::AT_FDCWD, &fifo, libc::S_IFIFO | 0o644, 0).unwrap();
+        assert!(fs::symlink_metadata(&fifo).unwrap().file_type().is_fifo());
+        assert!(
+            fs::symlink_metadata(f.get_from().join("dir1/fifo1"))
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+        assert!(fs::symlink_metadata(f.get_cache().join("dir1/fifo1")).is_err());
+
+        let fifo2 = fifo.clone();
+        let t = thread::spawn(move || {
+            OpenOptions::new().write(true).open(&fifo2).unwrap().write_all(b"hello").unwrap();
+        });
+        let mut s = String::new();
+        File::open(&fifo).unwrap().read_to_string(&mut s).unwrap();
+        t.join().unwrap();
+        assert_eq!(s, "hello");
+
+        fs::remove_file(&fifo).unwrap();
+        assert!(fs::symlink_metadata(f.get_from().join("dir1/fifo1")).is_err());
+    }
+
+    fn link(f: &CatFSTests) {
+        let file1 = f.mnt.join("file1");
+        let link = f.mnt.join("dir1/link1");
+        let mut s = String::new();
+        File::open(&file1).unwrap().read_to_string(&mut s).unwrap();
+        fs::hard_link(&file1, &link).unwrap();
+
+        let st = fs::metadata(&file1).unwrap();
+        let link_st = fs::metadata(&link).unwrap();
+        assert_eq!(link_st.ino(), st.ino());
+        assert_eq!(link_st.nlink(), 2);
+        assert_eq!(st.nlink(), 2);
+        assert_eq!(
+            fs::metadata(f.get_from().join("dir1/link1")).unwrap().ino(),
+            fs::metadata(f.get_from().join("file1")).unwrap().ino()
+        );
+
+        let mut s2 = String::new();
+        File::open(&link).unwrap().read_to_string(&mut s2).unwrap();
+        assert_eq!(s2, s);
+
+        // a write through one name shows up in the other
+        OpenOptions::new().write(true).truncate(true).open(&link).unwrap()
+            .write_all(b"linked\n").unwrap();
+        s.clear();
+        File::open(&file1).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "linked\n");
+
+        // the name it was last known by goes away
+        fs::remove_file(&link).unwrap();
+        assert_eq!(fs::metadata(&file1).unwrap().nlink(), 1);
+        s.clear();
+        File::open(&file1).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "linked\n");
+        assert!(fs::symlink_metadata(f.get_from().join("dir1/link1")).is_err());
+    }
+
     fn rmdir(f: &CatFSTests) {
         let dir2 = f.mnt.join("dir2");
         fs::remove_dir(&dir2).unwrap();
@@ -375,11 +459,152 @@ unit_tests!{
         xattr::set(&foo, "user.catfs.random", b"hello").unwrap();
         rlibc::utimes(&foo, 0, 100000000).unwrap();
         let mut fh = rlibc::File::open(&foo, rlibc::O_RDONLY, 0).unwrap();
-        let s = file::Handle::src_str_to_checksum(&fh).unwrap();
+        let s = file::Handle::src_str_to_checksum(&fh, &Default::default()).unwrap();
         assert_eq!(s, OsStr::new("100000000\n6\n"));
         fh.close().unwrap();
     }
 
+    fn checksum_str_version_xattr(f: &CatFSTests) {
+        if let Some(v) = env::var_os("CATFS_SELF_HOST") {
+            if v == OsStr::new("1") || v == OsStr::new("true") {
+                return;
+            }
+        }
+
+        let foo = f.src.join("file1");
+        xattr::set(&foo, "user.gcsfuse.generation", b"\x01\x2f").unwrap();
+        rlibc::utimes(&foo, 0, 100000000).unwrap();
+        let mut fh = rlibc::File::open(&foo, rlibc::O_RDONLY, 0).unwrap();
+        let s = file::Handle::src_str_to_checksum(&fh, &Default::default()).unwrap();
+        assert_eq!(
+            s,
+            OsStr::new("user.gcsfuse.generation=0x12f\n100000000\n6\n")
+        );
+
+        let mut hints: backend::SourceHints = Default::default();
+        hints.validation = "etag,size".parse().unwrap();
+        let s = file::Handle::src_str_to_checksum(&fh, &hints).unwrap();
+        assert_eq!(s, OsStr::new("user.gcsfuse.generation=0x12f\n6\n"));
+        hints.validation.version_xattrs = Some(Arc::new(vec![String::from("user.other")]));
+        let s = file::Handle::src_str_to_checksum(&fh, &hints).unwrap();
+        assert_eq!(s, OsStr::new("6\n"));
+        hints.validation = "none".parse().unwrap();
+        let s = file::Handle::src_str_to_checksum(&fh, &hints).unwrap();
+        assert_eq!(s, OsStr::new(""));
+        fh.close().unwrap();
+    }
+
+    fn xattrs(f: &CatFSTests) {
+        if let Some(v) = env::var_os("CATFS_SELF_HOST") {
+            if v == OsStr::new("1") || v == OsStr::new("true") {
+                return;
+            }
+        }
+
+        let file1 = f.mnt.join("file1");
+        xattr::set(&file1, "user.foo", b"bar").unwrap();
+        assert_eq!(xattr::get(f.src.join("file1"), "user.foo").unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(xattr::get(&file1, "user.foo").unwrap(), Some(b"bar".to_vec()));
+
+        // what catfs keeps for itself is never seen through the mount
+        xattr::set(f.src.join("file1"), "user.catfs.hidden", b"x").unwrap();
+        let names: Vec<OsString> = xattr::list(&file1).unwrap().collect();
+        assert_eq!(names, vec![OsString::from("user.foo")]);
+        assert_eq!(xattr::get(&file1, "user.catfs.hidden").unwrap(), None);
+        assert!(xattr::set(&file1, "user.catfs.hidden", b"y").is_err());
+
+        xattr::remove(&file1, "user.foo").unwrap();
+        assert_eq!(xattr::get(f.src.join("file1"), "user.foo").unwrap(), None);
+    }
+
+    fn pin(f: &CatFSTests) {
+        if let Some(v) = env::var_os("CATFS_SELF_HOST") {
+            if v == OsStr::new("1") || v == OsStr::new("true") {
+                return;
+            }
+        }
+
+        // pinning caches all of the file without it being read
+        let file1 = f.mnt.join("file1");
+        let cache1 = f.get_cache().join("file1");
+        xattr::set(&file1, "user.catfs.pin", b"").unwrap();
+        assert_eq!(fs::read(&cache1).unwrap(), fs::read(f.src.join("file1")).unwrap());
+        assert_eq!(xattr::get(&cache1, "user.catfs.pin").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(xattr::get(&file1, "user.catfs.pin").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(xattr::get(f.src.join("file1"), "user.catfs.pin").unwrap(), None);
+
+        xattr::remove(&file1, "user.catfs.pin").unwrap();
+        assert_eq!(xattr::get(&file1, "user.catfs.pin").unwrap(), None);
+        assert!(xattr::set(f.mnt.join("dir1"), "user.catfs.pin", b"").is_err());
+    }
+
+    fn control(f: &CatFSTests) {
+        let file1 = f.mnt.join("file1");
+        let cache1 = f.get_cache().join("file1");
+        fs::read(&file1).unwrap();
+        assert!(cache1.exists());
+
+        let mut c = UnixStream::connect(f.get_control_socket()).unwrap();
+        c.write_all(b"stats\ninvalidate /file1\nevict file2\nevict\nset-free 1\nfoo\n")
+            .unwrap();
+        c.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        c.read_to_string(&mut reply).unwrap();
+
+        assert!(reply.starts_with("inodes "));
+        // skip what stats had to say
+        let lines: Vec<&str> = reply
+            .lines()
+            .filter(|l| !l.contains(' ') || l.starts_with("ERR"))
+            .collect();
+        assert_eq!(lines[0], "OK");
+        assert_eq!(lines[1], "OK");
+        assert_eq!(lines[2], "OK");
+        assert!(lines[3].starts_with("ERR "));
+        assert_eq!(lines[4], "OK");
+        assert!(lines[5].starts_with("ERR "));
+
+        // the cache was still good
+        assert!(cache1.exists());
+        assert_eq!(fs::read(&file1).unwrap(), fs::read(f.src.join("file1")).unwrap());
+    }
+
+    fn stats_file(f: &CatFSTests) {
+        let stats = f.mnt.join(".catfs_stats");
+        fs::read(f.mnt.join("file1")).unwrap();
+        fs::read(f.mnt.join("file1")).unwrap();
+
+        let s = fs::read_to_string(&stats).unwrap();
+        let counters: Vec<(&str, u64)> = s.lines()
+            .map(|l| {
+                let mut kv = l.split(' ');
+                (kv.next().unwrap(), kv.next().unwrap().parse().unwrap())
+            })
+            .collect();
+        let names: Vec<&str> = counters.iter().map(|&(k, _)| k).collect();
+        assert_eq!(
+            names,
+            [
+                "hits",
+                "misses",
+                "bytes_from_cache",
+                "bytes_paged_in",
+                "evictions",
+                "cache_size",
+                "l2_hits",
+            ]
+        );
+        assert!(counters[0].1 >= 1);
+        assert!(counters[1].1 >= 1);
+        assert!(counters[2].1 > 0);
+
+        // it's never in src or the listing
+        assert!(!f.src.join(".catfs_stats").exists());
+        assert!(fs::read_dir(&f.mnt).unwrap().all(|e| e.unwrap().file_name() != ".catfs_stats"));
+        assert!(OpenOptions::new().write(true).open(&stats).is_err());
+        assert!(fs::remove_file(&stats).is_err());
+    }
+
     fn check_dirty(f: &CatFSTests) {
         let foo = f.mnt.join("foo");
         let foo_cache = f.get_cache().join("foo");
@@ -445,6 +670,24 @@ unit_tests!{
         diff(&f.get_from(), &f.mnt);
     }
 
+    fn rename_dir_open_child(f: &CatFSTests) {
+        let mut fh = OpenOptions::new()
+            .write(true)
+            .open(f.mnt.join("dir1/file1"))
+            .unwrap();
+        fs::rename(f.mnt.join("dir1"), f.mnt.join("dir3")).unwrap();
+        // flushed to where the file is now
+        fh.write_all(b"dir3").unwrap();
+        drop(fh);
+
+        let file1 = f.mnt.join("dir3/file1");
+        assert_eq!(fs::read_to_string(&file1).unwrap(), "dir3/file1\n");
+        assert_eq!(fs::read_to_string(f.src.join("dir3/file1")).unwrap(), "dir3/file1\n");
+        assert!(!f.src.join("dir1").exists());
+        fs::metadata(&file1).unwrap();
+        diff(&f.get_from(), &f.mnt);
+    }
+
     fn read_rename(f: &CatFSTests) {
         let file1 = f.mnt.join("dir1/file1");
         {
@@ -473,6 +716,90 @@ unit_tests!{
         assert!(perm.readonly());
     }
 
+    fn concurrent_lookups(f: &CatFSTests) {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let mnt = f.mnt.clone();
+                thread::spawn(move || for _ in 0..200 {
+                    for name in &["file1", "file2", "dir1/file1", "nonexistent"] {
+                        let res = fs::symlink_metadata(mnt.join(name));
+                        assert_eq!(res.is_ok(), *name != "nonexistent");
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    fn concurrent_reads(f: &CatFSTests) {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&f.mnt.join("big"), &data).unwrap();
+
+        let file = Arc::new(File::open(&f.mnt.join("big")).unwrap());
+        let data = Arc::new(data);
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let file = file.clone();
+                let data = data.clone();
+                thread::spawn(move || for _ in 0..50 {
+                    let offset = i * 8 * 1024;
+                    let mut buf = [0u8; 8 * 1024];
+                    assert_eq!(file.read_at(&mut buf, offset as u64).unwrap(), buf.len());
+                    assert_eq!(&buf[..], &data[offset..offset + buf.len()]);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    fn mount_builder(f: &CatFSTests) {
+        let mnt = f.prefix.join("mnt_builder");
+        let cache = f.prefix.join("cache_builder");
+        fs::create_dir_all(&mnt).unwrap();
+        fs::create_dir_all(&cache).unwrap();
+
+        let mut m = catfs::MountBuilder::new(&f.get_from(), &cache)
+            .ttl(std::time::Duration::from_secs(60))
+            .threads(4)
+            .mount(&mnt)
+            .unwrap();
+        assert!(m.is_mounted());
+        let mut s = String::new();
+        File::open(mnt.join("file1")).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "file1\n");
+
+        m.unmount(false).unwrap();
+        assert!(!m.is_mounted());
+    }
+
+    fn chown(f: &CatFSTests) {
+        let file1 = rlibc::to_cstring(&f.mnt.join("file1"));
+        let res = unsafe { libc::chown(file1.as_ptr(), 1, 1) };
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(res, 0);
+            let st = fs::symlink_metadata(&f.mnt.join("file1")).unwrap();
+            assert_eq!((st.uid(), st.gid()), (1, 1));
+            let st = fs::symlink_metadata(&f.get_from().join("file1")).unwrap();
+            assert_eq!((st.uid(), st.gid()), (1, 1));
+        } else {
+            assert_eq!(res, -1);
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ENOTSUP));
+        }
+    }
+
+    fn access(f: &CatFSTests) {
+        let file1 = rlibc::to_cstring(&f.mnt.join("file1"));
+        assert_eq!(unsafe { libc::access(file1.as_ptr(), libc::R_OK | libc::W_OK) }, 0);
+        assert_eq!(unsafe { libc::access(file1.as_ptr(), libc::X_OK) }, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EACCES));
+        let dir1 = rlibc::to_cstring(&f.mnt.join("dir1"));
+        assert_eq!(unsafe { libc::access(dir1.as_ptr(), libc::X_OK) }, 0);
+    }
+
     fn read_chmod(f: &CatFSTests) {
         let file1 = f.mnt.join("file1");
         {
@@ -511,4 +838,15 @@ unit_tests!{
             assert_eq!(s, "file1\n");
         }
     }
+
+    fn open_handles(f: &CatFSTests) {
+        let session = f.session.as_ref().unwrap();
+        let mut fh = File::open(f.mnt.join("file1")).unwrap();
+        let mut s = String::new();
+        fh.read_to_string(&mut s).unwrap();
+
+        let stats = session.stats();
+        assert_eq!(stats.open_files, 1);
+        assert!(stats.file_idle_secs < 60);
+    }
 }
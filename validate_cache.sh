@@ -22,7 +22,9 @@ if [ $# == 2 ]; then
 else
     FILE="$3"
     pushd "$SRC" > /dev/null
-    strtosign=$(getfattr -e hex --match=s3.etag -d "$FILE" 2>/dev/null | grep =; \
+    # same xattrs as VERSION_XATTRS in src/catfs/file.rs
+    strtosign=$(getfattr -e hex --match='^(s3\.etag|user\.gcsfuse\.generation|user\.rclone\.(md5|sha1))$' \
+                         -d "$FILE" 2>/dev/null | grep =; \
                 /usr/bin/stat -t --printf "%Y\n%s\n" "$FILE")
     sum=$(echo "$strtosign" | sha512sum | cut -f1 '-d ')
     setfattr -n user.catfs.src_chksum -v 0x$sum "$CACHE/$FILE"